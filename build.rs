@@ -1,6 +1,23 @@
 fn main() -> std::io::Result<()> {
+    if std::env::var_os("CARGO_FEATURE_SQLCIPHER").is_some() {
+        link_sqlcipher()?;
+    }
     let proto = "proto/proto.proto";
     tonic_build::compile_protos(proto)?;
     println!("cargo:rerun-if-changed={}", proto);
     Ok(())
 }
+
+/// Links SQLCipher, which provides the SQLite API, in place of SQLite.
+fn link_sqlcipher() -> std::io::Result<()> {
+    if std::env::var_os("CARGO_FEATURE_SQLITE3").is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "the sqlcipher feature links SQLCipher in place of SQLite: build with --no-default-features --features sqlcipher",
+        ));
+    }
+    if pkg_config::probe_library("sqlcipher").is_err() {
+        println!("cargo:rustc-link-lib=sqlcipher");
+    }
+    Ok(())
+}
@@ -0,0 +1,170 @@
+//! ChiselStore audit log.
+//!
+//! The audit log is an append-only file recording every committed write on
+//! this node, in log order, one JSON object per line:
+//!
+//! ```text
+//! {"timestamp_ms":1700000000000,"log_idx":42,"request_id":7,"client":"alice","sql":"INSERT INTO t VALUES(1)"}
+//! ```
+//!
+//! `timestamp_ms` is when the node applied the write, in milliseconds since
+//! the Unix epoch on the node's own clock, `request_id` the client-chosen id
+//! of the request, zero if unset, which traces the write across nodes, and
+//! `client` the identity the write was submitted with.
+//!
+//! The SQL trace log is a debugging aid in the same format, recording every
+//! command applied, reads included, with the values of its parameters bound
+//! in place of its placeholders, at trace level:
+//!
+//! ```text
+//! {"timestamp_ms":1700000000000,"level":"trace","log_idx":42,"sql":"INSERT INTO t VALUES(1, 'a')"}
+//! ```
+//!
+//! Both logs redact the literals of the SQL alike, bound values included.
+//! The SQL trace log is written by a thread of its own, so that binding and
+//! writing the SQL doesn't hold up applying commands.
+
+use crate::clock::Clock;
+use crate::errors::StoreError;
+use crate::server::StoreCommand;
+use crate::sql;
+use crate::util::log::log;
+use sqlite::Value;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::time::UNIX_EPOCH;
+
+/// Append-only log of committed writes, and of the bound SQL of the
+/// commands applied.
+#[derive(Debug)]
+pub(crate) struct AuditLog {
+    /// The audit log, if enabled.
+    file: Option<File>,
+    /// Sends the commands applied to the thread writing the SQL trace log,
+    /// if enabled.
+    trace: Option<mpsc::Sender<Traced>>,
+    /// Replace the literals of the SQL with `?`.
+    redact: bool,
+    clock: Arc<dyn Clock>,
+}
+
+impl AuditLog {
+    /// Opens the audit log at `path` and the SQL trace log at `trace`, if
+    /// any, creating them if needed. Returns `None` if both are disabled.
+    pub(crate) fn open(path: Option<&Path>, trace: Option<&Path>, redact: bool, clock: Arc<dyn Clock>) -> Result<Option<Self>, StoreError> {
+        if path.is_none() && trace.is_none() {
+            return Ok(None);
+        }
+        let file = path.map(open_append).transpose()?;
+        let trace = match trace {
+            Some(path) => {
+                let trace = open_append(path)?;
+                let (sender, receiver) = mpsc::channel();
+                std::thread::Builder::new()
+                    .name(String::from("sql-trace"))
+                    .spawn(move || write_trace(trace, redact, receiver))
+                    .map_err(|e| StoreError::Audit(e.to_string()))?;
+                Some(sender)
+            }
+            None => None,
+        };
+        Ok(Some(Self { file, trace, redact, clock }))
+    }
+
+    /// Records the committed write `cmd` at log index `log_idx`, and traces
+    /// its bound SQL.
+    ///
+    /// Read-only commands are traced but not recorded.
+    pub(crate) fn record(&mut self, log_idx: u64, cmd: &StoreCommand) -> Result<(), StoreError> {
+        let cmd_sql = cmd.sql_text();
+        if cmd_sql.trim().is_empty() {
+            return Ok(());
+        }
+        let timestamp_ms = self
+            .clock
+            .wall_time()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        if let Some(ref trace) = self.trace {
+            // the thread only stops if it failed to write, which it logged
+            let _ = trace.send(Traced {
+                timestamp_ms,
+                log_idx,
+                sql: cmd_sql.to_string(),
+                params: cmd.bound_params(),
+            });
+        }
+        let file = match self.file {
+            Some(ref mut file) if !sql::is_read_only(&cmd_sql) => file,
+            _ => return Ok(()),
+        };
+        let sql = if self.redact { sql::redact(&cmd_sql) } else { cmd_sql.into_owned() };
+        let line = format!(
+            "{{\"timestamp_ms\":{},\"log_idx\":{},\"request_id\":{},\"client\":{},\"sql\":{}}}\n",
+            timestamp_ms,
+            log_idx,
+            cmd.request_id,
+            json_string(&cmd.client),
+            json_string(&sql)
+        );
+        file.write_all(line.as_bytes()).map_err(|e| StoreError::Audit(e.to_string()))
+    }
+}
+
+/// A command applied, to trace.
+struct Traced {
+    timestamp_ms: u128,
+    log_idx: u64,
+    sql: String,
+    params: Vec<Value>,
+}
+
+/// Writes the bound SQL of the commands received to the SQL trace log,
+/// until the audit log is dropped or writing fails.
+fn write_trace(mut trace: File, redact: bool, receiver: mpsc::Receiver<Traced>) {
+    for traced in receiver {
+        let bound = sql::bind(&traced.sql, &traced.params);
+        let sql = if redact { sql::redact(&bound) } else { bound };
+        let line = format!(
+            "{{\"timestamp_ms\":{},\"level\":\"trace\",\"log_idx\":{},\"sql\":{}}}\n",
+            traced.timestamp_ms,
+            traced.log_idx,
+            json_string(&sql)
+        );
+        if let Err(e) = trace.write_all(line.as_bytes()) {
+            log(format!("Failed to write the SQL trace log: {}", e));
+            return;
+        }
+    }
+}
+
+/// Opens the file at `path` for appending, creating it if needed.
+fn open_append(path: &Path) -> Result<File, StoreError> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| StoreError::Audit(e.to_string()))
+}
+
+/// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,189 @@
+//! ChiselStore change feed.
+//!
+//! Subscribers receive every write committed on this node, in log order.
+//! Each subscriber has its own bounded buffer: writes are published without
+//! ever waiting on a subscriber, and one that falls behind by more than the
+//! buffer either misses the oldest writes, reported as a gap, or is
+//! disconnected.
+
+use crate::errors::StoreError;
+use crate::metrics::{Counter, Gauge, Registry};
+use crate::server::StoreCommand;
+use crate::sql;
+use futures::Stream;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// What to do when a subscriber's buffer of changes is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeOverflowPolicy {
+    /// Drop the oldest buffered change, reporting a [`ChangeEvent::Gap`].
+    DropOldest,
+    /// Disconnect the subscriber with `StoreError::SubscriberDisconnected`.
+    Disconnect,
+}
+
+/// Event of the change feed.
+#[derive(Clone, Debug)]
+pub enum ChangeEvent {
+    /// A committed write.
+    Write(Change),
+    /// `missed` writes were dropped because the subscriber fell behind.
+    Gap {
+        /// Number of writes dropped.
+        missed: u64,
+    },
+}
+
+/// A committed write.
+#[derive(Clone, Debug)]
+pub struct Change {
+    /// Configuration whose log `log_idx` indexes; log indices restart with
+    /// every configuration.
+    pub configuration_id: u32,
+    /// Index of the write in the log.
+    pub log_idx: u64,
+    /// Identity of the client that submitted the write.
+    pub client: String,
+    /// SQL of the write.
+    pub sql: String,
+    /// Parameters bound to the SQL.
+    pub params: Vec<sqlite::Value>,
+}
+
+/// Buffer of a subscriber, shared with the feed.
+#[derive(Debug)]
+struct Subscriber {
+    changes: VecDeque<Change>,
+    /// Writes dropped since the subscriber last received a change.
+    missed: u64,
+    disconnected: bool,
+    /// Whether the disconnection was reported to the subscriber.
+    reported: bool,
+    waker: Option<Waker>,
+}
+
+/// Publishes committed writes to subscribers.
+#[derive(Debug)]
+pub(crate) struct ChangeFeed {
+    subscribers: Mutex<Vec<Arc<Mutex<Subscriber>>>>,
+    /// Maximum number of changes buffered per subscriber.
+    capacity: usize,
+    policy: ChangeOverflowPolicy,
+    gauge: Arc<Gauge>,
+    gaps: Arc<Counter>,
+    disconnects: Arc<Counter>,
+}
+
+impl ChangeFeed {
+    pub(crate) fn new(capacity: usize, policy: ChangeOverflowPolicy, metrics: &Registry) -> Self {
+        Self {
+            subscribers: Mutex::new(Vec::new()),
+            capacity: capacity.max(1),
+            policy,
+            gauge: metrics.gauge("change_subscribers"),
+            gaps: metrics.counter("change_gaps"),
+            disconnects: metrics.counter("change_subscribers_disconnected"),
+        }
+    }
+
+    /// Adds a subscriber, which receives the writes published from now on.
+    pub(crate) fn subscribe(&self) -> ChangeSubscription {
+        let subscriber = Arc::new(Mutex::new(Subscriber {
+            changes: VecDeque::new(),
+            missed: 0,
+            disconnected: false,
+            reported: false,
+            waker: None,
+        }));
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.push(subscriber.clone());
+        self.gauge.set(subscribers.len() as i64);
+        ChangeSubscription { subscriber }
+    }
+
+    /// Publishes the committed write `cmd` at log index `log_idx` of
+    /// configuration `configuration_id`.
+    ///
+    /// Read-only commands are not published.
+    pub(crate) fn publish(&self, configuration_id: u32, log_idx: u64, cmd: &StoreCommand) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let cmd_sql = cmd.sql_text();
+        if subscribers.is_empty() || cmd_sql.trim().is_empty() || sql::is_read_only(&cmd_sql) {
+            return;
+        }
+        // Subscriptions that were dropped or disconnected are forgotten.
+        subscribers.retain(|subscriber| Arc::strong_count(subscriber) > 1 && !subscriber.lock().unwrap().disconnected);
+        for subscriber in subscribers.iter() {
+            let mut subscriber = subscriber.lock().unwrap();
+            if subscriber.changes.len() >= self.capacity {
+                match self.policy {
+                    ChangeOverflowPolicy::DropOldest => {
+                        if subscriber.missed == 0 {
+                            self.gaps.inc();
+                        }
+                        subscriber.changes.pop_front();
+                        subscriber.missed += 1;
+                    }
+                    ChangeOverflowPolicy::Disconnect => {
+                        subscriber.changes.clear();
+                        subscriber.disconnected = true;
+                        self.disconnects.inc();
+                    }
+                }
+            }
+            if !subscriber.disconnected {
+                subscriber.changes.push_back(Change {
+                    configuration_id,
+                    log_idx,
+                    client: cmd.client.clone(),
+                    sql: cmd_sql.to_string(),
+                    params: cmd.bound_params(),
+                });
+            }
+            if let Some(waker) = subscriber.waker.take() {
+                waker.wake();
+            }
+        }
+        subscribers.retain(|subscriber| !subscriber.lock().unwrap().disconnected);
+        self.gauge.set(subscribers.len() as i64);
+    }
+}
+
+/// Subscription to the change feed of a node.
+///
+/// Ends with `StoreError::SubscriberDisconnected` if the subscriber is
+/// disconnected for falling behind.
+#[derive(Debug)]
+pub struct ChangeSubscription {
+    subscriber: Arc<Mutex<Subscriber>>,
+}
+
+impl Stream for ChangeSubscription {
+    type Item = Result<ChangeEvent, StoreError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut subscriber = self.subscriber.lock().unwrap();
+        if subscriber.disconnected {
+            // The disconnection is reported once, then the stream ends.
+            if subscriber.reported {
+                return Poll::Ready(None);
+            }
+            subscriber.reported = true;
+            return Poll::Ready(Some(Err(StoreError::SubscriberDisconnected)));
+        }
+        if subscriber.missed > 0 {
+            let missed = std::mem::take(&mut subscriber.missed);
+            return Poll::Ready(Some(Ok(ChangeEvent::Gap { missed })));
+        }
+        match subscriber.changes.pop_front() {
+            Some(change) => Poll::Ready(Some(Ok(ChangeEvent::Write(change)))),
+            None => {
+                subscriber.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
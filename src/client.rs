@@ -0,0 +1,708 @@
+//! ChiselStore client.
+
+use crate::errors::ClientError;
+use crate::import::quote_identifier;
+use crate::rpc::proto::rpc_client::RpcClient;
+use crate::rpc::proto::{Consistency, MigrateReq, MultiReadReq, Query, QueryResults, QueryRow, ReadSnapshot, StatusReply, Value, Void};
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tonic::transport::Channel;
+use tonic::Streaming;
+
+/// Maximum number of parameters bound to a single upsert statement, which
+/// is the lowest default of SQLite's `SQLITE_MAX_VARIABLE_NUMBER`.
+const UPSERT_MAX_PARAMS: usize = 999;
+
+/// Retry policy of a [`Client`].
+///
+/// Requests that fail with `unavailable` are retried with exponential
+/// backoff, as long as the retry budget allows. The budget is a token
+/// bucket: every retry takes a token, and every successful request adds
+/// `budget_ratio` tokens, up to `budget_capacity`. Retries are thereby
+/// capped relative to successful requests, so during an outage the client
+/// fails fast once the budget is depleted, instead of piling retries onto
+/// the cluster.
+///
+/// A write that failed because leadership changed may have been applied,
+/// so writes that are retried should be idempotent.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Maximum number of retries of a single request.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for each further one.
+    pub initial_backoff: Duration,
+    /// Maximum delay between retries.
+    pub max_backoff: Duration,
+    /// Tokens added to the budget by each successful request.
+    pub budget_ratio: f64,
+    /// Maximum number of tokens in the budget, which starts full.
+    pub budget_capacity: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            budget_ratio: 0.1,
+            budget_capacity: 10.0,
+        }
+    }
+}
+
+/// Retry budget of a [`Client`], shared by its clones.
+#[derive(Debug)]
+pub struct RetryBudget {
+    policy: RetryPolicy,
+    tokens: Mutex<f64>,
+    retries: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+impl RetryBudget {
+    fn new(policy: RetryPolicy) -> Self {
+        Self {
+            tokens: Mutex::new(policy.budget_capacity),
+            policy,
+            retries: AtomicU64::new(0),
+            exhausted: AtomicU64::new(0),
+        }
+    }
+
+    /// Tokens left, each allowing one retry.
+    pub fn tokens(&self) -> f64 {
+        *self.tokens.lock().unwrap()
+    }
+
+    /// Number of retries made.
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::SeqCst)
+    }
+
+    /// Number of requests that failed without a retry because the budget
+    /// was depleted.
+    pub fn exhausted(&self) -> u64 {
+        self.exhausted.load(Ordering::SeqCst)
+    }
+
+    fn deposit(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens + self.policy.budget_ratio).min(self.policy.budget_capacity);
+    }
+
+    /// Takes a token for a retry, returning false if there is none left.
+    fn withdraw(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        if *tokens < 1.0 {
+            self.exhausted.fetch_add(1, Ordering::SeqCst);
+            return false;
+        }
+        *tokens -= 1.0;
+        self.retries.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    /// Delay before retry number `retry`, counting from zero.
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self.policy.initial_backoff.saturating_mul(1 << retry.min(16));
+        backoff.min(self.policy.max_backoff)
+    }
+}
+
+/// Client for executing SQL statements on a ChiselStore node.
+///
+/// Reads see the writes made earlier through the client, or its clones,
+/// even when served from a node that has not applied them yet: the node
+/// waits until it has.
+#[derive(Debug, Clone)]
+pub struct Client {
+    rpc: RpcClient<Channel>,
+    /// Retry budget, `None` if requests are not retried.
+    retry: Option<Arc<RetryBudget>>,
+    /// Configuration and length of its log up to the last write made
+    /// through the client, which its reads wait for.
+    written: Arc<Mutex<(u32, u64)>>,
+}
+
+impl Client {
+    /// Connects to the node listening at `addr`, e.g. `http://127.0.0.1:50001`.
+    pub async fn connect(addr: String) -> Result<Self, ClientError> {
+        let rpc = RpcClient::connect(addr).await?;
+        Ok(Self {
+            rpc,
+            retry: None,
+            written: Arc::new(Mutex::new((0, 0))),
+        })
+    }
+
+    /// Creates a client for the node listening at `addr` without connecting
+    /// to it: the connection is made by the first request, and made again
+    /// by later ones if the node was unreachable.
+    pub fn connect_lazy(addr: String) -> Result<Self, ClientError> {
+        let channel = tonic::transport::Endpoint::new(addr)?.connect_lazy()?;
+        Ok(Self {
+            rpc: RpcClient::new(channel),
+            retry: None,
+            written: Arc::new(Mutex::new((0, 0))),
+        })
+    }
+
+    /// Retries requests that fail with `unavailable` according to `policy`.
+    ///
+    /// By default, requests are not retried.
+    pub fn with_retries(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(Arc::new(RetryBudget::new(policy)));
+        self
+    }
+
+    /// Retry budget of the client, `None` if requests are not retried.
+    pub fn retry_budget(&self) -> Option<&RetryBudget> {
+        self.retry.as_deref()
+    }
+
+    /// Records the log index of the replicated command of `results`, so
+    /// that later reads wait for it.
+    fn record_written(&self, results: &QueryResults) {
+        // Only commands replicated through the log have a ballot.
+        if results.ballot.is_some() {
+            // Log indices restart with every configuration, so an index of a
+            // later configuration supersedes any of an earlier one.
+            let mut written = self.written.lock().unwrap();
+            *written = std::cmp::max(*written, (results.configuration_id, results.log_idx + 1));
+        }
+    }
+
+    /// Configuration and log index that reads wait for.
+    fn written(&self) -> (u32, u64) {
+        *self.written.lock().unwrap()
+    }
+
+    /// Makes the request `call` issues, retrying it within the retry budget.
+    async fn call<T, F, Fut>(&mut self, mut call: F) -> Result<T, ClientError>
+    where
+        F: FnMut(RpcClient<Channel>) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, tonic::Status>>,
+    {
+        let mut retry = 0;
+        loop {
+            let result = call(self.rpc.clone()).await;
+            let budget = match self.retry {
+                Some(ref budget) => budget,
+                None => return Ok(result?.into_inner()),
+            };
+            match result {
+                Ok(response) => {
+                    budget.deposit();
+                    return Ok(response.into_inner());
+                }
+                Err(status) if status.code() == tonic::Code::Unavailable && retry < budget.policy.max_retries => {
+                    if !budget.withdraw() {
+                        return Err(status.into());
+                    }
+                    tokio::time::sleep(budget.backoff(retry)).await;
+                    retry += 1;
+                }
+                Err(status) => return Err(status.into()),
+            }
+        }
+    }
+
+    /// Execute a SQL statement with `params` bound to its `?` placeholders.
+    pub async fn execute<S: Into<String>>(
+        &mut self,
+        sql: S,
+        params: Vec<Value>,
+    ) -> Result<QueryResults, ClientError> {
+        self.execute_with_consistency(sql, params, Consistency::Strong).await
+    }
+
+    /// Execute a SQL statement at the given consistency level.
+    ///
+    /// The statement keeps the same request id across retries, so that a
+    /// write that committed before its retry is not applied twice.
+    pub async fn execute_with_consistency<S: Into<String>>(
+        &mut self,
+        sql: S,
+        params: Vec<Value>,
+        consistency: Consistency,
+    ) -> Result<QueryResults, ClientError> {
+        let (min_index_configuration, min_index) = self.written();
+        let query = Query {
+            sql: sql.into(),
+            params,
+            consistency: consistency as i32,
+            min_index,
+            min_index_configuration,
+            request_id: new_request_id(),
+            ..Default::default()
+        };
+        let results = self
+            .call(|mut rpc| {
+                let query = query.clone();
+                async move { rpc.execute(tonic::Request::new(query)).await }
+            })
+            .await?;
+        self.record_written(&results);
+        Ok(results)
+    }
+
+    /// Execute read-only statements, each with its parameters, against a
+    /// single snapshot of the node's database, so that no write lands
+    /// between them.
+    pub async fn multi_read<S: Into<String>>(
+        &mut self,
+        queries: Vec<(S, Vec<Value>)>,
+        consistency: Consistency,
+    ) -> Result<Vec<QueryResults>, ClientError> {
+        let req = MultiReadReq {
+            queries: queries
+                .into_iter()
+                .map(|(sql, params)| Query {
+                    sql: sql.into(),
+                    params,
+                    ..Default::default()
+                })
+                .collect(),
+            consistency: consistency as i32,
+        };
+        let reply = self
+            .call(|mut rpc| {
+                let req = req.clone();
+                async move { rpc.multi_read(tonic::Request::new(req)).await }
+            })
+            .await?;
+        Ok(reply.results)
+    }
+
+    /// Validate a write without committing it.
+    ///
+    /// The write runs on the leader in a transaction that is rolled back,
+    /// and is never proposed, so it may still fail once actually executed.
+    pub async fn dry_run<S: Into<String>>(&mut self, sql: S, params: Vec<Value>) -> Result<QueryResults, ClientError> {
+        let query = Query {
+            sql: sql.into(),
+            params,
+            dry_run: true,
+            ..Default::default()
+        };
+        self.call(|mut rpc| {
+            let query = query.clone();
+            async move { rpc.execute(tonic::Request::new(query)).await }
+        })
+        .await
+    }
+
+    /// Submit a write to the leader without waiting for it to commit,
+    /// returning the results of a dry run of it.
+    ///
+    /// Unsafe for strict durability: the write may fail to commit, or commit
+    /// with other results, without the client learning of it. See
+    /// `QueryOptions::unacknowledged`.
+    pub async fn execute_unacknowledged<S: Into<String>>(&mut self, sql: S, params: Vec<Value>) -> Result<QueryResults, ClientError> {
+        let query = Query {
+            sql: sql.into(),
+            params,
+            unacknowledged: true,
+            request_id: new_request_id(),
+            ..Default::default()
+        };
+        self.call(|mut rpc| {
+            let query = query.clone();
+            async move { rpc.execute(tonic::Request::new(query)).await }
+        })
+        .await
+    }
+
+    /// Run a read-only query, returning its query plan and execution
+    /// statistics along with its rows.
+    ///
+    /// The query really runs, on whichever node the client is connected to,
+    /// so analyzing it costs as much as running it.
+    pub async fn explain_analyze<S: Into<String>>(&mut self, sql: S, params: Vec<Value>) -> Result<QueryResults, ClientError> {
+        let (min_index_configuration, min_index) = self.written();
+        let query = Query {
+            sql: sql.into(),
+            params,
+            explain_analyze: true,
+            min_index,
+            min_index_configuration,
+            ..Default::default()
+        };
+        self.call(|mut rpc| {
+            let query = query.clone();
+            async move { rpc.execute(tonic::Request::new(query)).await }
+        })
+        .await
+    }
+
+    /// Create a read snapshot of the node's database, returning its id.
+    ///
+    /// Queries run with [`Client::execute_at_snapshot`] all see the database
+    /// as it was when the snapshot was created, until it is released with
+    /// [`Client::release_read_snapshot`]. A snapshot that goes unused for a
+    /// while is released by the node.
+    pub async fn create_read_snapshot(&mut self) -> Result<u64, ClientError> {
+        let snapshot = self
+            .call(|mut rpc| async move { rpc.create_read_snapshot(tonic::Request::new(Void {})).await })
+            .await?;
+        Ok(snapshot.id)
+    }
+
+    /// Execute a read-only SQL statement against the read snapshot `snapshot`.
+    pub async fn execute_at_snapshot<S: Into<String>>(
+        &mut self,
+        snapshot: u64,
+        sql: S,
+        params: Vec<Value>,
+    ) -> Result<QueryResults, ClientError> {
+        let query = Query {
+            sql: sql.into(),
+            params,
+            read_snapshot: snapshot,
+            ..Default::default()
+        };
+        self.call(|mut rpc| {
+            let query = query.clone();
+            async move { rpc.execute(tonic::Request::new(query)).await }
+        })
+        .await
+    }
+
+    /// Release the read snapshot `snapshot`.
+    pub async fn release_read_snapshot(&mut self, snapshot: u64) -> Result<(), ClientError> {
+        self.call(|mut rpc| async move { rpc.release_read_snapshot(tonic::Request::new(ReadSnapshot { id: snapshot })).await })
+            .await?;
+        Ok(())
+    }
+
+    /// Execute a read-only SQL statement, streaming its rows.
+    ///
+    /// The server reads rows only as fast as they are consumed, so large
+    /// results can be processed without holding them in memory.
+    pub async fn execute_stream<S: Into<String>>(
+        &mut self,
+        sql: S,
+        params: Vec<Value>,
+        consistency: Consistency,
+    ) -> Result<Streaming<QueryRow>, ClientError> {
+        let (min_index_configuration, min_index) = self.written();
+        let query = Query {
+            sql: sql.into(),
+            params,
+            consistency: consistency as i32,
+            min_index,
+            min_index_configuration,
+            ..Default::default()
+        };
+        let response = self.rpc.execute_stream(tonic::Request::new(query)).await?;
+        Ok(response.into_inner())
+    }
+
+    /// Migrate the cluster's schema to `version` by executing `sql`.
+    ///
+    /// Once this returns, the new version is reported in the `schema_version`
+    /// of [`Client::status`] by every node that has applied the migration.
+    pub async fn migrate<S: Into<String>>(&mut self, version: u64, sql: S) -> Result<QueryResults, ClientError> {
+        let migration = MigrateReq {
+            version,
+            sql: sql.into(),
+        };
+        let results = self
+            .call(|mut rpc| {
+                let migration = migration.clone();
+                async move { rpc.migrate(tonic::Request::new(migration)).await }
+            })
+            .await?;
+        self.record_written(&results);
+        Ok(results)
+    }
+
+    /// Status of the node.
+    pub async fn status(&mut self) -> Result<StatusReply, ClientError> {
+        self.call(|mut rpc| async move { rpc.status(tonic::Request::new(Void {})).await }).await
+    }
+
+    /// Submit a SQL statement without waiting for it to commit.
+    ///
+    /// Returns immediately with a future that resolves to the results once
+    /// the statement commits, so many writes can be pipelined. The statement
+    /// is sent in the background: dropping the future does not cancel it,
+    /// and it commits regardless.
+    pub fn submit<S: Into<String>>(
+        &self,
+        sql: S,
+        params: Vec<Value>,
+    ) -> impl Future<Output = Result<QueryResults, ClientError>> {
+        let mut client = self.clone();
+        let sql = sql.into();
+        let handle = tokio::task::spawn(async move { client.execute(sql, params).await });
+        async move {
+            match handle.await {
+                Ok(results) => results,
+                Err(e) => Err(ClientError::from(tonic::Status::internal(format!("{}", e)))),
+            }
+        }
+    }
+
+    /// Insert `rows` into `table`, updating the rows that conflict on `key_columns`.
+    ///
+    /// The table and column names are quoted, so they may be keywords or
+    /// hold any character. Each row holds a value for every column in
+    /// `columns`. Rows that don't
+    /// conflict with an existing row are inserted; rows that do have their
+    /// non-key columns overwritten. The whole batch is replicated as a single
+    /// log entry and applied in one transaction, so either all rows are
+    /// upserted or, if any row fails (e.g. on another constraint), none are.
+    pub async fn batch_upsert(
+        &mut self,
+        table: &str,
+        columns: &[&str],
+        key_columns: &[&str],
+        rows: Vec<Vec<Value>>,
+    ) -> Result<QueryResults, ClientError> {
+        if columns.is_empty() || rows.iter().any(|row| row.len() != columns.len()) {
+            return Err(ClientError::from(tonic::Status::invalid_argument("every row must have a value for each column")));
+        }
+        let updates: Vec<String> = columns
+            .iter()
+            .filter(|c| !key_columns.contains(c))
+            .map(|c| format!("{} = excluded.{}", quote_identifier(c), quote_identifier(c)))
+            .collect();
+        let on_conflict = if updates.is_empty() {
+            String::from("DO NOTHING")
+        } else {
+            format!("DO UPDATE SET {}", updates.join(", "))
+        };
+        let quoted = |names: &[&str]| names.iter().map(|name| quote_identifier(name)).collect::<Vec<_>>().join(", ");
+        let placeholders = format!("({})", vec!["?"; columns.len()].join(", "));
+        let rows_per_stmt = std::cmp::max(1, UPSERT_MAX_PARAMS / columns.len());
+
+        let mut sql = String::from("BEGIN;");
+        let mut params = Vec::with_capacity(rows.len() * columns.len());
+        for chunk in rows.chunks(rows_per_stmt) {
+            sql.push_str(&format!(
+                "INSERT INTO {} ({}) VALUES {} ON CONFLICT({}) {};",
+                quote_identifier(table),
+                quoted(columns),
+                vec![placeholders.as_str(); chunk.len()].join(", "),
+                quoted(key_columns),
+                on_conflict,
+            ));
+            for row in chunk {
+                params.extend(row.iter().cloned());
+            }
+        }
+        sql.push_str("COMMIT;");
+        self.execute(sql, params).await
+    }
+}
+
+/// Returns a new request id, for deduplicating the retries of a write.
+///
+/// Ids are random, so that they are unique among the requests of every
+/// client with high probability.
+fn new_request_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(NEXT.fetch_add(1, Ordering::Relaxed));
+    // Zero means no request id.
+    hasher.finish().max(1)
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    addr: String,
+    client: Client,
+    /// Node id, once known.
+    id: Option<u64>,
+    /// Last measured round-trip time, or `None` if the node is unreachable.
+    latency: Option<Duration>,
+    /// Last reported length of the decided log.
+    decided_idx: u64,
+}
+
+/// Client for a whole ChiselStore cluster.
+///
+/// Strong queries and writes are sent to the leader. Relaxed reads are sent
+/// to the node with the lowest latency, as long as it is at most
+/// `max_staleness` log entries behind the most up-to-date node. Latencies
+/// are measured with the `Status` RPC and refreshed every `refresh_interval`.
+///
+/// With `hedge_delay` set, a relaxed read that the nearest node hasn't
+/// answered within the delay is also sent to the next nearest node, and the
+/// first answer wins. This trades extra load for lower tail latency.
+///
+/// Reads see the writes made earlier through the client, whichever node
+/// serves them.
+#[derive(Debug)]
+pub struct ClusterClient {
+    nodes: Vec<Node>,
+    leader: Option<u64>,
+    last_refresh: Option<Instant>,
+    /// How many log entries a node may lag behind and still serve relaxed reads.
+    pub max_staleness: u64,
+    /// How often node latencies are measured.
+    pub refresh_interval: Duration,
+    /// How long a relaxed read waits before being hedged to a second node.
+    /// `None` disables hedging.
+    pub hedge_delay: Option<Duration>,
+}
+
+impl ClusterClient {
+    /// Connects to the nodes listening at `addrs`.
+    ///
+    /// Nodes are connected to lazily, so that the nodes that are down don't
+    /// fail the connect: they are skipped until they can be reached again.
+    /// Only an invalid address fails.
+    pub async fn connect(addrs: Vec<String>) -> Result<Self, ClientError> {
+        let mut nodes = Vec::new();
+        // Every node's client shares the same session.
+        let written = Arc::new(Mutex::new((0, 0)));
+        for addr in addrs {
+            let mut client = Client::connect_lazy(addr.clone())?;
+            client.written = written.clone();
+            nodes.push(Node {
+                addr,
+                client,
+                id: None,
+                latency: None,
+                decided_idx: 0,
+            });
+        }
+        Ok(Self {
+            nodes,
+            leader: None,
+            last_refresh: None,
+            max_staleness: 100,
+            refresh_interval: Duration::from_secs(10),
+            hedge_delay: None,
+        })
+    }
+
+    /// Measures the latency of every node.
+    pub async fn refresh(&mut self) {
+        for node in self.nodes.iter_mut() {
+            let start = Instant::now();
+            match node.client.status().await {
+                Ok(status) => {
+                    node.id = Some(status.id);
+                    node.latency = Some(start.elapsed());
+                    node.decided_idx = status.decided_idx;
+                    if status.leader != 0 {
+                        self.leader = Some(status.leader);
+                    }
+                }
+                Err(_) => node.latency = None,
+            }
+        }
+        self.last_refresh = Some(Instant::now());
+    }
+
+    /// Records a latency and decided index measured for the node at `addr`.
+    ///
+    /// Useful to feed in measurements from elsewhere; they are overwritten by
+    /// the next refresh.
+    pub fn record_latency(&mut self, addr: &str, latency: Duration, decided_idx: u64) {
+        if let Some(node) = self.nodes.iter_mut().find(|n| n.addr == addr) {
+            node.latency = Some(latency);
+            node.decided_idx = decided_idx;
+        }
+    }
+
+    /// Address of the node relaxed reads are sent to.
+    pub fn nearest_node(&self) -> Option<&str> {
+        self.nearest().map(|i| self.nodes[i].addr.as_str())
+    }
+
+    fn nearest(&self) -> Option<usize> {
+        self.by_latency().first().copied()
+    }
+
+    /// Nodes that may serve relaxed reads, nearest first.
+    fn by_latency(&self) -> Vec<usize> {
+        let most_recent = match self.nodes.iter().filter(|n| n.latency.is_some()).map(|n| n.decided_idx).max() {
+            Some(most_recent) => most_recent,
+            None => return Vec::new(),
+        };
+        let mut nodes: Vec<(usize, Duration)> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| most_recent.saturating_sub(n.decided_idx) <= self.max_staleness)
+            .filter_map(|(i, n)| n.latency.map(|l| (i, l)))
+            .collect();
+        nodes.sort_by_key(|&(_, l)| l);
+        nodes.into_iter().map(|(i, _)| i).collect()
+    }
+
+    fn leader(&self) -> Option<usize> {
+        let leader = self.leader?;
+        self.nodes.iter().position(|n| n.id == Some(leader))
+    }
+
+    /// Execute a SQL statement at the given consistency level.
+    pub async fn execute<S: Into<String>>(
+        &mut self,
+        sql: S,
+        params: Vec<Value>,
+        consistency: Consistency,
+    ) -> Result<QueryResults, ClientError> {
+        let stale = match self.last_refresh {
+            Some(last_refresh) => last_refresh.elapsed() >= self.refresh_interval,
+            None => true,
+        };
+        if stale {
+            self.refresh().await;
+        }
+        if consistency == Consistency::RelaxedReads {
+            if let Some(delay) = self.hedge_delay {
+                let nodes = self.by_latency();
+                if nodes.len() > 1 {
+                    let first = self.nodes[nodes[0]].client.clone();
+                    let second = self.nodes[nodes[1]].client.clone();
+                    return hedged(first, second, delay, sql.into(), params).await;
+                }
+            }
+        }
+        let target = match consistency {
+            Consistency::RelaxedReads => self.nearest(),
+            // The leader serves reads at any consistency.
+            Consistency::Strong | Consistency::TableDefault => self.leader(),
+        };
+        // Without a leader or a node to read from, try the first node that
+        // could be reached.
+        let target = target.or_else(|| self.nodes.iter().position(|n| n.latency.is_some())).unwrap_or(0);
+        let client = &mut self.nodes[target].client;
+        client.execute_with_consistency(sql, params, consistency).await
+    }
+}
+
+/// Sends a relaxed read to `first`, and also to `second` if `first` hasn't
+/// answered within `delay`. Returns the first answer, dropping the other
+/// request.
+async fn hedged(
+    mut first: Client,
+    mut second: Client,
+    delay: Duration,
+    sql: String,
+    params: Vec<Value>,
+) -> Result<QueryResults, ClientError> {
+    let hedge_sql = sql.clone();
+    let hedge_params = params.clone();
+    let primary = first.execute_with_consistency(sql, params, Consistency::RelaxedReads);
+    tokio::pin!(primary);
+    tokio::select! {
+        results = &mut primary => return results,
+        _ = tokio::time::sleep(delay) => {}
+    }
+    let hedge = second.execute_with_consistency(hedge_sql, hedge_params, Consistency::RelaxedReads);
+    tokio::select! {
+        results = primary => results,
+        results = hedge => results,
+    }
+}
@@ -0,0 +1,149 @@
+//! ChiselStore clocks.
+//!
+//! Timeouts in the server, such as heartbeat and election timeouts, are
+//! driven by a [`Clock`], so that tests can control the passing of time.
+//!
+//! The clock also tells the wall time that timestamps, such as those of the
+//! audit log and of query results, are taken from. Wall clocks of different
+//! nodes drift apart, so the server never compares timestamps taken on
+//! different nodes: durations that span nodes, such as how stale a follower
+//! is, are measured on the leader's clock alone. Running NTP on every node is
+//! still recommended, to keep timestamps of different nodes comparable for
+//! operators.
+
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::oneshot;
+use tokio::time::{Duration, Instant};
+
+/// Source of time for the server's timeouts.
+#[async_trait]
+pub trait Clock: std::fmt::Debug + Send + Sync {
+    /// Current time.
+    fn now(&self) -> Instant;
+
+    /// Wait until `duration` has passed.
+    async fn sleep(&self, duration: Duration);
+
+    /// Current wall time, for timestamps.
+    fn wall_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Clock that follows the system time.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
+
+/// Clock that follows the system time, but whose wall time is off by a
+/// fixed offset, like a node whose clock has drifted.
+#[derive(Debug)]
+pub struct SkewedClock {
+    offset: Duration,
+    ahead: bool,
+}
+
+impl SkewedClock {
+    /// Creates a clock whose wall time is `offset` ahead of the system's.
+    pub fn ahead(offset: Duration) -> Arc<Self> {
+        Arc::new(Self { offset, ahead: true })
+    }
+
+    /// Creates a clock whose wall time is `offset` behind the system's.
+    pub fn behind(offset: Duration) -> Arc<Self> {
+        Arc::new(Self { offset, ahead: false })
+    }
+}
+
+#[async_trait]
+impl Clock for SkewedClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        if self.ahead {
+            SystemTime::now() + self.offset
+        } else {
+            SystemTime::now() - self.offset
+        }
+    }
+}
+
+/// Clock that only moves when advanced.
+#[derive(Debug)]
+pub struct ManualClock {
+    state: Mutex<ManualClockState>,
+}
+
+#[derive(Debug)]
+struct ManualClockState {
+    now: Instant,
+    /// Sleepers waiting for the clock to reach their deadline.
+    sleepers: Vec<(Instant, oneshot::Sender<()>)>,
+}
+
+impl ManualClock {
+    /// Creates a new clock, stopped at the current time.
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(ManualClockState {
+                now: Instant::now(),
+                sleepers: Vec::new(),
+            }),
+        })
+    }
+
+    /// Move the clock forward by `duration`, waking the sleepers whose
+    /// deadline has passed.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.now += duration;
+        let now = state.now;
+        let (expired, pending) = std::mem::take(&mut state.sleepers)
+            .into_iter()
+            .partition(|(deadline, _)| *deadline <= now);
+        state.sleepers = pending;
+        drop(state);
+        for (_, sleeper) in expired {
+            let _ = sleeper.send(());
+        }
+    }
+}
+
+#[async_trait]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.state.lock().unwrap().now
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        let woken = {
+            let mut state = self.state.lock().unwrap();
+            if duration.is_zero() {
+                return;
+            }
+            let (sender, receiver) = oneshot::channel();
+            let deadline = state.now + duration;
+            state.sleepers.push((deadline, sender));
+            receiver
+        };
+        let _ = woken.await;
+    }
+}
@@ -2,6 +2,226 @@
 
 use thiserror::Error;
 
+/// Metadata entry of a gRPC status carrying the id of the current leader.
+const LEADER_METADATA: &str = "leader";
+
+/// ChiselStore errors, by kind of failure.
+///
+/// Errors are carried across RPCs as a `tonic::Status`, whose code
+/// identifies the kind, so the same error is seen on the server and by the
+/// client.
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Consensus could not be reached, e.g. because of a reconfiguration
+    /// that failed or would lose quorum.
+    #[error("Consensus error: {0}")]
+    Consensus(String),
+    /// The database failed to execute the statement.
+    #[error("Storage error: {0}")]
+    Storage(String),
+    /// The node could not be reached.
+    #[error("Transport error: {0}")]
+    Transport(String),
+    /// The request did not complete in time.
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    /// The node can't serve the request because it is not, or no longer,
+    /// the leader.
+    #[error("Node is not the leader, the leader is node {leader}")]
+    NotLeader {
+        /// Id of the current leader, or zero if unknown.
+        leader: u64,
+    },
+    /// The request is invalid, e.g. it has the wrong number of parameters
+    /// or a statement was rejected.
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    /// The client did not present valid credentials.
+    #[error("Unauthenticated: {0}")]
+    Unauthenticated(String),
+    /// The request is not allowed on this node, or not with the client's
+    /// role.
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    /// The request exceeded a limit, such as the maximum number of rows.
+    #[error("Limit exceeded: {0}")]
+    LimitExceeded(String),
+    /// The cluster doesn't accept the request in its current state, e.g. a
+    /// write while it is in maintenance mode.
+    #[error("Failed precondition: {0}")]
+    FailedPrecondition(String),
+    /// Any other failure.
+    #[error("{0}")]
+    Other(String),
+}
+
+impl Error {
+    /// Returns true if the request may succeed when retried, possibly
+    /// against another node.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Error::Consensus(_) | Error::Transport(_) | Error::Timeout(_) | Error::NotLeader { .. }
+        )
+    }
+}
+
+impl From<StoreError> for Error {
+    fn from(e: StoreError) -> Self {
+        let message = e.to_string();
+        match e {
+            StoreError::SQLiteError(_)
+            | StoreError::Schema(_)
+            | StoreError::SchemaMismatch { .. }
+            | StoreError::NodeAddress(_)
+            | StoreError::Dedup(_)
+            | StoreError::Snapshot(_)
+            | StoreError::Audit(_) => Error::Storage(message),
+            StoreError::NotLeader => Error::NotLeader { leader: 0 },
+            StoreError::LeaderChanged { leader } => Error::NotLeader { leader },
+            StoreError::ProposalDropped { .. } | StoreError::InboundDropped { .. } | StoreError::QueryTask(_) => Error::Transport(message),
+            StoreError::CommitTimeout { .. } | StoreError::IndexNotReached { .. } => Error::Timeout(message),
+            StoreError::WouldLoseQuorum
+            | StoreError::Reconfiguration(_)
+            | StoreError::AppendFailed(_)
+            | StoreError::UnknownConfiguration { .. }
+            | StoreError::ExtensionMismatch { .. } => Error::Consensus(message),
+            StoreError::Learner
+            | StoreError::NotAMember(_)
+            | StoreError::MembershipRequired
+            | StoreError::DuplicateNodeId(_)
+            | StoreError::Misaddressed { .. }
+            | StoreError::ConflictingNodeId(_)
+            | StoreError::ParameterCount { .. }
+            | StoreError::NotReadOnly
+            | StoreError::UnknownReadSnapshot(_)
+            | StoreError::StreamedReadSnapshot
+            | StoreError::StatementRejected(_)
+            | StoreError::InvalidImport(_)
+            | StoreError::StaleSchemaVersion { .. }
+            | StoreError::TooManyReplicasRequired { .. } => Error::InvalidRequest(message),
+            StoreError::Unauthenticated => Error::Unauthenticated(message),
+            StoreError::AdminDisabled
+            | StoreError::ReadOnlyRole
+            | StoreError::AdminRoleRequired
+            | StoreError::ReadSnapshotNotOwned(_) => Error::PermissionDenied(message),
+            StoreError::MaintenanceMode | StoreError::EncryptionUnsupported | StoreError::WrongEncryptionKey(_) => {
+                Error::FailedPrecondition(message)
+            }
+            StoreError::TooManyRows { .. }
+            | StoreError::TooManyApplyRows { .. }
+            | StoreError::TooManyReadSnapshots { .. }
+            | StoreError::SubscriberDisconnected
+            | StoreError::ReplicationGap { .. } => Error::LimitExceeded(message),
+            StoreError::Replication(_) => Error::Transport(message),
+            StoreError::ReplicationConflict { .. } => Error::Storage(message),
+        }
+    }
+}
+
+impl From<Error> for tonic::Status {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Consensus(m) => tonic::Status::aborted(m),
+            Error::Storage(m) => tonic::Status::internal(m),
+            Error::Transport(m) => tonic::Status::unavailable(m),
+            Error::Timeout(m) => tonic::Status::deadline_exceeded(m),
+            Error::NotLeader { leader } => {
+                // Unavailable, so that clients retry, against the leader if known.
+                let mut status = tonic::Status::unavailable(format!("{}", e));
+                status.metadata_mut().insert(LEADER_METADATA, leader.into());
+                status
+            }
+            Error::InvalidRequest(m) => tonic::Status::invalid_argument(m),
+            Error::Unauthenticated(m) => tonic::Status::unauthenticated(m),
+            Error::PermissionDenied(m) => tonic::Status::permission_denied(m),
+            Error::LimitExceeded(m) => tonic::Status::out_of_range(m),
+            Error::FailedPrecondition(m) => tonic::Status::failed_precondition(m),
+            Error::Other(m) => tonic::Status::unknown(m),
+        }
+    }
+}
+
+impl From<tonic::Status> for Error {
+    fn from(status: tonic::Status) -> Self {
+        let message = status.message().to_string();
+        match status.code() {
+            tonic::Code::Aborted => Error::Consensus(message),
+            tonic::Code::Internal | tonic::Code::DataLoss => Error::Storage(message),
+            tonic::Code::Unavailable => match status.metadata().get(LEADER_METADATA) {
+                Some(leader) => Error::NotLeader {
+                    leader: leader.to_str().ok().and_then(|l| l.parse().ok()).unwrap_or(0),
+                },
+                None => Error::Transport(message),
+            },
+            tonic::Code::DeadlineExceeded | tonic::Code::Cancelled => Error::Timeout(message),
+            tonic::Code::InvalidArgument => Error::InvalidRequest(message),
+            tonic::Code::FailedPrecondition => Error::FailedPrecondition(message),
+            tonic::Code::Unauthenticated => Error::Unauthenticated(message),
+            tonic::Code::PermissionDenied => Error::PermissionDenied(message),
+            tonic::Code::OutOfRange | tonic::Code::ResourceExhausted => Error::LimitExceeded(message),
+            _ => Error::Other(message),
+        }
+    }
+}
+
+impl From<tonic::transport::Error> for Error {
+    fn from(e: tonic::transport::Error) -> Self {
+        Error::Transport(e.to_string())
+    }
+}
+
+impl From<ClientError> for Error {
+    fn from(e: ClientError) -> Self {
+        match e {
+            ClientError::Connect(e) => e.into(),
+            ClientError::Unavailable(status) | ClientError::Rejected(status) => status.into(),
+        }
+    }
+}
+
+/// Errors encountered by the client.
+#[derive(Error, Debug)]
+pub enum ClientError {
+    /// The node could not be connected to.
+    #[error("Connection error: {0}")]
+    Connect(#[from] tonic::transport::Error),
+    /// The node could not serve the request right now, e.g. because it lost
+    /// leadership.
+    #[error("Node unavailable: {0}")]
+    Unavailable(tonic::Status),
+    /// The node rejected the request or failed to execute it.
+    #[error("Request failed: {0}")]
+    Rejected(tonic::Status),
+}
+
+impl ClientError {
+    /// Returns true if the request may succeed when retried, possibly
+    /// against another node, as for its [`ClientError::kind`].
+    pub fn is_retryable(&self) -> bool {
+        self.kind().is_retryable()
+    }
+
+    /// Kind of failure of the request.
+    pub fn kind(&self) -> Error {
+        match self {
+            ClientError::Connect(e) => Error::Transport(e.to_string()),
+            ClientError::Unavailable(status) | ClientError::Rejected(status) => status.clone().into(),
+        }
+    }
+}
+
+impl From<tonic::Status> for ClientError {
+    fn from(status: tonic::Status) -> Self {
+        // The node is unavailable exactly when retrying may help.
+        if Error::from(status.clone()).is_retryable() {
+            ClientError::Unavailable(status)
+        } else {
+            ClientError::Rejected(status)
+        }
+    }
+}
+
 /// Errors encountered in the store layer.
 #[derive(Error, Debug)]
 pub enum StoreError {
@@ -11,4 +231,233 @@ pub enum StoreError {
     /// This node is not a leader and cannot therefore execute the command.
     #[error("Node is not a leader")]
     NotLeader,
+    /// This node is a read-only learner and cannot execute writes.
+    #[error("Node is a read-only learner")]
+    Learner,
+    /// This node's id is not part of the configured cluster membership.
+    #[error("Node id {0} is not in the cluster membership")]
+    NotAMember(u64),
+    /// The server was configured without the cluster membership.
+    #[error("The cluster membership is not configured")]
+    MembershipRequired,
+    /// Leaving the cluster would leave the remaining nodes without a quorum.
+    #[error("Leaving would leave the cluster without a quorum")]
+    WouldLoseQuorum,
+    /// A reconfiguration could not be proposed.
+    #[error("Reconfiguration failed: {0}")]
+    Reconfiguration(String),
+    /// An encryption key was configured, but SQLCipher support is not
+    /// enabled, or SQLCipher is not linked.
+    #[error("Encryption at rest requires the `sqlcipher` feature, with SQLCipher linked")]
+    EncryptionUnsupported,
+    /// The database could not be decrypted with the configured key.
+    #[error("Wrong encryption key for the database of node {0}")]
+    WrongEncryptionKey(u64),
+    /// A node id appears more than once among this node and its peers.
+    #[error("Node id {0} appears more than once in the cluster")]
+    DuplicateNodeId(u64),
+    /// Another node sent a message claiming this node's id.
+    #[error("Received a message from another node claiming this node's id {0}")]
+    ConflictingNodeId(u64),
+    /// A message addressed to another node was delivered to this node.
+    #[error("Message addressed to node {to} delivered to node {this_id}")]
+    Misaddressed {
+        /// Node the message was addressed to.
+        to: u64,
+        /// Id of this node.
+        this_id: u64,
+    },
+    /// The number of parameters does not match the placeholders of the statement.
+    #[error("Expected {expected} parameters, got {got}")]
+    ParameterCount {
+        /// Number of placeholders in the statement.
+        expected: usize,
+        /// Number of parameters given.
+        got: usize,
+    },
+    /// Leadership changed while a command was waiting to be decided.
+    ///
+    /// The command may or may not have been decided.
+    #[error("Leadership changed to node {leader} while the command was pending")]
+    LeaderChanged {
+        /// The new leader.
+        leader: u64,
+    },
+    /// A read required this node to have applied more of the log than it
+    /// did within the commit timeout.
+    #[error("Read requires the log applied up to index {min_index}, but this node applied {applied_idx} entries")]
+    IndexNotReached {
+        /// Length of the log the read required to be applied.
+        min_index: u64,
+        /// Length of the log this node applied.
+        applied_idx: u64,
+    },
+    /// A write required more members to accept it than there are.
+    #[error("Write requires {required} members to accept it, but the cluster has {members}")]
+    TooManyReplicasRequired {
+        /// Members required to accept the write.
+        required: u64,
+        /// Members of the cluster.
+        members: u64,
+    },
+    /// The command was not decided within the commit timeout.
+    ///
+    /// The command may still be decided later.
+    #[error("Command not decided within {timeout:?} (decided index {decided_idx}, still leader: {still_leader})")]
+    CommitTimeout {
+        /// The commit timeout.
+        timeout: std::time::Duration,
+        /// Decided index of the node when the command timed out.
+        decided_idx: u64,
+        /// Whether the node was still the leader when the command timed out.
+        still_leader: bool,
+    },
+    /// An admin operation was requested, but admin operations are disabled.
+    #[error("Admin operations are disabled on this node")]
+    AdminDisabled,
+    /// The client did not present a valid token.
+    #[error("Missing or invalid authentication token")]
+    Unauthenticated,
+    /// The client's role does not allow writes.
+    #[error("Writes are not allowed with a read-only role")]
+    ReadOnlyRole,
+    /// An admin operation was requested by a client without the admin role.
+    #[error("Admin operations require the admin role")]
+    AdminRoleRequired,
+    /// The cluster is in maintenance mode, where writes are rejected.
+    #[error("Writes are not allowed while the cluster is in maintenance mode")]
+    MaintenanceMode,
+    /// A snapshot of the database could not be written.
+    #[error("Snapshot failed: {0}")]
+    Snapshot(String),
+    /// The task running a query panicked or was cancelled.
+    #[error("Query task failed: {0}")]
+    QueryTask(String),
+    /// The schema this node applied could not be recorded or read.
+    #[error("Schema record error: {0}")]
+    Schema(String),
+    /// The node addresses updated at runtime could not be recorded or read.
+    #[error("Node address record error: {0}")]
+    NodeAddress(String),
+    /// The results of a deduplicated request could not be read.
+    #[error("Dedup window error: {0}")]
+    Dedup(String),
+    /// The schema of the database is not the one this node last applied.
+    #[error("Database schema {found:016x} doesn't match the schema {expected:016x} this node last applied")]
+    SchemaMismatch {
+        /// Fingerprint of the schema this node last applied.
+        expected: u64,
+        /// Fingerprint of the schema of the database.
+        found: u64,
+    },
+    /// The audit log could not be written.
+    #[error("Audit log error: {0}")]
+    Audit(String),
+    /// A message is for another configuration than the current one.
+    #[error("Message for configuration {config_id}, the current configuration is {current}")]
+    UnknownConfiguration {
+        /// Configuration of the message.
+        config_id: u32,
+        /// Current configuration of this node.
+        current: u32,
+    },
+    /// A client proposal forwarded to the leader was dropped because the
+    /// send queue to the leader was full.
+    #[error("Proposal to node {peer} dropped, its send queue is full")]
+    ProposalDropped {
+        /// Node the proposal was forwarded to.
+        peer: u64,
+    },
+    /// A message from a peer was rejected because its inbound queue is full
+    /// or closed.
+    #[error("Message from node {peer} dropped, its inbound queue is full or closed")]
+    InboundDropped {
+        /// Node the message is from.
+        peer: u64,
+    },
+    /// Sequence paxos refused to append a command to the log, e.g. because
+    /// the configuration is being stopped.
+    #[error("Failed to append the command to the log: {0}")]
+    AppendFailed(String),
+    /// A change feed subscriber fell too far behind and was disconnected.
+    #[error("Change feed subscriber disconnected for falling behind")]
+    SubscriberDisconnected,
+    /// Replication from another cluster fell behind, and the primary
+    /// dropped writes that were not replicated.
+    #[error("Replication fell behind, the primary dropped {missed} writes")]
+    ReplicationGap {
+        /// Number of writes dropped.
+        missed: u64,
+    },
+    /// The primary of a replication could not be reached, or its change
+    /// feed failed.
+    #[error("Replication from the primary failed: {0}")]
+    Replication(String),
+    /// A write replicated from another cluster failed to apply.
+    #[error("Write {log_idx} of configuration {configuration_id} replicated from the primary failed to apply: {error}")]
+    ReplicationConflict {
+        /// Configuration of the primary whose log `log_idx` indexes.
+        configuration_id: u32,
+        /// Log index of the write on the primary.
+        log_idx: u64,
+        /// Why it failed.
+        error: String,
+    },
+    /// A peer has other SQL extensions registered than this node.
+    #[error("Node {from} has other SQL extensions (fingerprint {theirs:#x}) than this node ({ours:#x})")]
+    ExtensionMismatch {
+        /// Node the message is from.
+        from: u64,
+        /// Fingerprint of this node's extensions.
+        ours: u64,
+        /// Fingerprint of the peer's extensions.
+        theirs: u64,
+    },
+    /// A query returned more rows than the configured maximum.
+    #[error("Query returned more than {limit} rows")]
+    TooManyRows {
+        /// Maximum number of rows a query may return.
+        limit: usize,
+    },
+    /// A command returned more rows than the configured maximum when
+    /// applied, and was rolled back.
+    #[error("Command returned more than {limit} rows when applied")]
+    TooManyApplyRows {
+        /// Maximum number of rows a command may return when applied.
+        limit: usize,
+    },
+    /// A statement was rejected by the SQL validator.
+    #[error("Statement rejected: {0}")]
+    StatementRejected(String),
+    /// Only read-only statements can be streamed or run against a read
+    /// snapshot.
+    #[error("Only read-only statements can be streamed or run against a read snapshot")]
+    NotReadOnly,
+    /// The read snapshot does not exist, or was released.
+    #[error("Unknown read snapshot {0}")]
+    UnknownReadSnapshot(u64),
+    /// The read snapshot was created by another client.
+    #[error("Read snapshot {0} belongs to another client")]
+    ReadSnapshotNotOwned(u64),
+    /// Queries against a read snapshot can't be streamed.
+    #[error("Queries against a read snapshot can't be streamed")]
+    StreamedReadSnapshot,
+    /// Too many read snapshots are open.
+    #[error("More than {limit} read snapshots open")]
+    TooManyReadSnapshots {
+        /// Maximum number of open read snapshots.
+        limit: usize,
+    },
+    /// A batch of a bulk import is invalid, e.g. a row has the wrong number
+    /// of values.
+    #[error("Invalid import: {0}")]
+    InvalidImport(String),
+    /// A migration does not move the schema to a newer version.
+    #[error("Schema version {requested} is not newer than the current version {current}")]
+    StaleSchemaVersion {
+        /// Schema version of the database.
+        current: u64,
+        /// Schema version the migration was for.
+        requested: u64,
+    },
 }
\ No newline at end of file
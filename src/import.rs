@@ -0,0 +1,125 @@
+//! ChiselStore bulk import.
+//!
+//! Rows are imported in batches: the rows of a batch are inserted by a
+//! single log entry, applied in one transaction, so a batch is imported
+//! entirely or not at all. A batch that fails is reported and the import
+//! carries on with the next one.
+
+use crate::errors::StoreError;
+use sqlite::Value;
+
+/// Maximum number of parameters bound to a single `INSERT`, SQLite's
+/// default limit of host parameters.
+const MAX_STATEMENT_PARAMS: usize = 999;
+
+/// Maximum number of failed batches reported in an [`ImportSummary`]; the
+/// rows of later ones are still counted as failed.
+pub const IMPORT_ERRORS_LIMIT: usize = 100;
+
+/// Row of a bulk import.
+#[derive(Clone, Debug, Default)]
+pub struct ImportRow {
+    /// Table the row is inserted into; empty to use that of the previous row.
+    pub table: String,
+    /// Columns the values are for; ignored unless `table` is set.
+    pub columns: Vec<String>,
+    /// Values of the row, one per column.
+    pub values: Vec<Value>,
+}
+
+/// Batch of a bulk import that failed, none of whose rows were imported.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportBatchError {
+    /// Position of the first row of the batch among the imported rows.
+    pub first_row: u64,
+    /// Number of rows in the batch.
+    pub rows: u64,
+    /// Why the batch failed.
+    pub error: String,
+}
+
+/// Outcome of a bulk import.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// Rows imported.
+    pub rows_imported: u64,
+    /// Rows of failed batches, which were not imported.
+    pub rows_failed: u64,
+    /// Batches, imported or failed.
+    pub batches: u64,
+    /// The first failed batches, up to [`IMPORT_ERRORS_LIMIT`].
+    pub errors: Vec<ImportBatchError>,
+}
+
+impl ImportSummary {
+    /// Records the outcome of a batch of `rows` rows.
+    pub(crate) fn record(&mut self, rows: u64, result: Result<(), StoreError>) {
+        let first_row = self.rows_imported + self.rows_failed;
+        self.batches += 1;
+        match result {
+            Ok(()) => self.rows_imported += rows,
+            Err(e) => {
+                self.rows_failed += rows;
+                if self.errors.len() < IMPORT_ERRORS_LIMIT {
+                    self.errors.push(ImportBatchError {
+                        first_row,
+                        rows,
+                        error: e.to_string(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Rows waiting to be imported into the same table and columns.
+#[derive(Debug, Default)]
+pub(crate) struct ImportBatch {
+    pub(crate) table: String,
+    pub(crate) columns: Vec<String>,
+    pub(crate) rows: Vec<Vec<Value>>,
+}
+
+impl ImportBatch {
+    /// Returns true if `row` is for another table or columns than the
+    /// rows of the batch.
+    pub(crate) fn retargets(&self, row: &ImportRow) -> bool {
+        !row.table.is_empty() && (row.table != self.table || row.columns != self.columns)
+    }
+
+    /// SQL inserting the rows of the batch in one transaction, and the
+    /// parameters bound to it.
+    pub(crate) fn into_sql(self) -> Result<(String, Vec<Value>), StoreError> {
+        if self.table.is_empty() {
+            return Err(StoreError::InvalidImport(String::from("no table given")));
+        }
+        if self.columns.is_empty() {
+            return Err(StoreError::InvalidImport(format!("no columns given for table {}", self.table)));
+        }
+        let width = self.columns.len();
+        if let Some(row) = self.rows.iter().find(|row| row.len() != width) {
+            return Err(StoreError::InvalidImport(format!(
+                "row has {} values for {} columns",
+                row.len(),
+                width
+            )));
+        }
+        let columns: Vec<String> = self.columns.iter().map(|c| quote_identifier(c)).collect();
+        let insert = format!("INSERT INTO {} ({}) VALUES ", quote_identifier(&self.table), columns.join(","));
+        let placeholders = format!("({})", vec!["?"; width].join(","));
+        let rows_per_statement = std::cmp::max(1, MAX_STATEMENT_PARAMS / width);
+        let mut sql = String::from("BEGIN;");
+        for chunk in self.rows.chunks(rows_per_statement) {
+            sql.push_str(&insert);
+            sql.push_str(&vec![placeholders.as_str(); chunk.len()].join(","));
+            sql.push(';');
+        }
+        sql.push_str("COMMIT;");
+        Ok((sql, self.rows.into_iter().flatten().collect()))
+    }
+}
+
+/// Quotes `name` as an SQL identifier.
+pub(crate) fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
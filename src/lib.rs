@@ -93,12 +93,54 @@
 
 #![warn(missing_docs, missing_debug_implementations, rust_2018_idioms)]
 
+mod audit;
+pub mod changes;
+pub mod client;
+pub mod clock;
 pub mod errors;
+pub mod import;
+pub mod metrics;
+pub mod replication;
 pub mod rpc;
 pub mod server;
+mod sql;
 pub mod util;
 
+pub use changes::ChangeEvent;
+pub use changes::ChangeOverflowPolicy;
+pub use changes::ChangeSubscription;
+pub use errors::ClientError;
+pub use errors::Error;
 pub use errors::StoreError;
+pub use import::ImportRow;
+pub use import::ImportSummary;
+pub use replication::ConflictPolicy;
+pub use replication::ReplicationConfig;
+pub use server::ApplyError;
+pub use server::ApplyErrorPolicy;
+pub use server::CommandFormat;
+pub use server::CommandKind;
+pub use server::CommandStatement;
+pub use server::Consistency;
+pub use server::DatabaseSnapshot;
+pub use server::Epoch;
+pub use server::FutureConfigPolicy;
+pub use server::JournalMode;
+pub use server::PriorityHook;
+pub use server::QueryOptions;
+pub use server::QueryStream;
+pub use server::ResultLimitPolicy;
+pub use server::SchemaMismatchPolicy;
+pub use server::{Principal, Role};
+pub use server::RowTransform;
+pub use server::SnapshotPin;
+pub use server::SqlExtension;
+pub use server::SqlValidator;
+pub use server::StorageStats;
 pub use server::StoreCommand;
 pub use server::StoreServer;
+pub use server::StoreServerConfig;
 pub use server::StoreTransport;
+pub use server::TableChecksum;
+pub use server::TableStats;
+pub use server::TempStore;
@@ -0,0 +1,223 @@
+//! ChiselStore metrics.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A monotonically increasing counter.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    /// Increment the counter by one.
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Increment the counter by `n`.
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Current value of the counter.
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that can go up and down.
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    /// Set the gauge to `v`.
+    pub fn set(&self, v: i64) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+
+    /// Increment the gauge by one.
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Decrement the gauge by one.
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Add `n` to the gauge.
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Current value of the gauge.
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Number of histogram buckets; bucket `i` holds samples below `2^i` microseconds.
+const HISTOGRAM_BUCKETS: usize = 40;
+
+/// Latency histogram with exponentially sized buckets.
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: (0..HISTOGRAM_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    /// Record a sample.
+    pub fn record(&self, d: Duration) {
+        let us = d.as_micros() as u64;
+        let bucket = (64 - us.leading_zeros() as usize).min(HISTOGRAM_BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    /// Number of recorded samples.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Mean of the recorded samples.
+    pub fn mean(&self) -> Duration {
+        match self.count() {
+            0 => Duration::from_micros(0),
+            n => Duration::from_micros(self.sum_us.load(Ordering::Relaxed) / n),
+        }
+    }
+
+    /// Estimate of the `p`th percentile (`0.0..=100.0`) of the recorded
+    /// samples, rounded up to the bucket boundary.
+    pub fn percentile(&self, p: f64) -> Duration {
+        let count = self.count();
+        if count == 0 {
+            return Duration::from_micros(0);
+        }
+        let rank = ((p / 100.0) * count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= rank {
+                return Duration::from_micros(1 << i);
+            }
+        }
+        Duration::from_micros(1 << (HISTOGRAM_BUCKETS - 1))
+    }
+}
+
+/// Records the time elapsed since its creation into a histogram when dropped.
+#[derive(Debug)]
+pub struct Timer {
+    histogram: Arc<Histogram>,
+    start: Instant,
+}
+
+impl Timer {
+    /// Starts timing into `histogram`.
+    pub fn new(histogram: Arc<Histogram>) -> Self {
+        Self {
+            histogram,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.histogram.record(self.start.elapsed());
+    }
+}
+
+/// Percentiles exported for each histogram.
+pub const EXPORTED_PERCENTILES: [f64; 3] = [50.0, 90.0, 99.0];
+
+/// Registry of named metrics.
+///
+/// Metrics are created on first use and live as long as the registry.
+#[derive(Debug, Default)]
+pub struct Registry {
+    counters: Mutex<BTreeMap<String, Arc<Counter>>>,
+    gauges: Mutex<BTreeMap<String, Arc<Gauge>>>,
+    histograms: Mutex<BTreeMap<String, Arc<Histogram>>>,
+}
+
+impl Registry {
+    /// Creates a new, empty registry.
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Returns the counter called `name`, creating it if needed.
+    pub fn counter(&self, name: &str) -> Arc<Counter> {
+        let mut counters = self.counters.lock().unwrap();
+        counters.entry(name.to_string()).or_default().clone()
+    }
+
+    /// Returns the gauge called `name`, creating it if needed.
+    pub fn gauge(&self, name: &str) -> Arc<Gauge> {
+        let mut gauges = self.gauges.lock().unwrap();
+        gauges.entry(name.to_string()).or_default().clone()
+    }
+
+    /// Returns the histogram called `name`, creating it if needed.
+    pub fn histogram(&self, name: &str) -> Arc<Histogram> {
+        let mut histograms = self.histograms.lock().unwrap();
+        histograms.entry(name.to_string()).or_default().clone()
+    }
+
+    /// Current values of all counters, ordered by name.
+    pub fn counters(&self) -> Vec<(String, u64)> {
+        let counters = self.counters.lock().unwrap();
+        counters.iter().map(|(k, v)| (k.clone(), v.get())).collect()
+    }
+
+    /// Current values of all gauges, ordered by name.
+    pub fn gauges(&self) -> Vec<(String, i64)> {
+        let gauges = self.gauges.lock().unwrap();
+        gauges.iter().map(|(k, v)| (k.clone(), v.get())).collect()
+    }
+
+    /// Exported percentiles of all histograms, ordered by name.
+    ///
+    /// Each histogram is reported as one entry per percentile in
+    /// [`EXPORTED_PERCENTILES`], labelled with `quantile`.
+    pub fn percentiles(&self) -> Vec<(String, Duration)> {
+        let histograms = self.histograms.lock().unwrap();
+        let mut percentiles = Vec::new();
+        for (name, histogram) in histograms.iter() {
+            for p in EXPORTED_PERCENTILES.iter() {
+                let name = labelled(name, "quantile", &format!("{}", p / 100.0));
+                percentiles.push((name, histogram.percentile(*p)));
+            }
+        }
+        percentiles
+    }
+}
+
+/// Name of a metric with a `label="value"` label added.
+pub fn labelled(name: &str, label: &str, value: &str) -> String {
+    match name.strip_suffix('}') {
+        Some(prefix) => format!("{},{}=\"{}\"}}", prefix, label, value),
+        None => format!("{}{{{}=\"{}\"}}", name, label, value),
+    }
+}
+
+/// Name of a metric labelled with the peer it refers to.
+pub fn peer_metric(name: &str, peer: &str) -> String {
+    labelled(name, "peer", peer)
+}
@@ -0,0 +1,147 @@
+//! ChiselStore cross-cluster replication.
+//!
+//! [`replicate`] subscribes to the change feed of a node of another cluster,
+//! the primary, and applies its writes to this node's cluster one at a time,
+//! in the primary's log order: one-way, asynchronous replication, e.g. to
+//! keep a disaster recovery cluster in another region.
+//!
+//! The change feed only carries the writes committed after subscribing, so
+//! the secondary cluster must start from a copy of the primary's database,
+//! such as a restored snapshot, taken no later than the subscription. Writes
+//! the copy already holds are skipped with `start_configuration` and
+//! `start_idx`: log indices restart with every configuration of the
+//! primary, so a write's position is its configuration and its index.
+//!
+//! Writes are replicated as statements, not as the rows they changed: each
+//! one is executed again on the secondary, through its log like any other
+//! write. A statement only has the same effect on both clusters if it is
+//! deterministic and runs against the same rows. Functions such as
+//! `random()` or `datetime('now')` are evaluated again and yield other
+//! values, and a write made directly to the secondary changes what later
+//! replicated writes do, so either makes the clusters diverge.
+
+use crate::errors::StoreError;
+use crate::rpc::proto::rpc_client::RpcClient;
+use crate::rpc::proto::{change_event, Void};
+use crate::rpc::{value_from_proto, AUTHORIZATION_METADATA};
+use crate::server::{QueryOptions, StoreServer, StoreTransport};
+use crate::util::log::log;
+use futures::StreamExt;
+use std::sync::Arc;
+use tonic::metadata::MetadataValue;
+
+/// What to do with a replicated write that fails to apply, e.g. because it
+/// conflicts with a write made directly to the secondary cluster.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Skip the write, counting it in the `replication_conflicts` metric,
+    /// and carry on with the next one. The clusters may diverge.
+    Skip,
+    /// Stop replicating, failing with `StoreError::ReplicationConflict`.
+    Stop,
+}
+
+/// Cross-cluster replication configuration.
+#[derive(Clone, Debug)]
+pub struct ReplicationConfig {
+    /// Address of the primary node whose writes are replicated, e.g.
+    /// `http://10.0.0.1:50001`. It must have admin operations enabled.
+    pub primary: String,
+    /// Authentication token of the admin role on the primary, if it has
+    /// authentication enabled.
+    pub token: Option<String>,
+    /// Configuration of the primary whose log `start_idx` indexes. Writes
+    /// of earlier configurations are skipped, and all writes of later ones
+    /// are replicated.
+    pub start_configuration: u32,
+    /// Log index of the first write of the primary to replicate; earlier
+    /// ones are skipped, e.g. because the copy of the database the
+    /// secondary started from already holds them.
+    pub start_idx: u64,
+    /// What to do with a replicated write that fails to apply.
+    pub conflict_policy: ConflictPolicy,
+}
+
+impl ReplicationConfig {
+    /// Replicates the writes of the node at `primary` from now on.
+    pub fn new<S: Into<String>>(primary: S) -> Self {
+        Self {
+            primary: primary.into(),
+            token: None,
+            start_configuration: 1,
+            start_idx: 0,
+            conflict_policy: ConflictPolicy::Stop,
+        }
+    }
+}
+
+/// Applies the writes of the primary to the cluster of `server`, until the
+/// primary's change feed ends, which returns `Ok`, or replication fails.
+///
+/// Writes the primary dropped because replication fell behind can't be
+/// recovered, so they fail replication with `StoreError::ReplicationGap`;
+/// as does losing the connection to the primary with
+/// `StoreError::Replication`. Either way, the secondary has to start over
+/// from a fresh copy of the primary's database.
+///
+/// Progress is reported by the `replicated_configuration` and
+/// `replicated_idx` gauges, the primary's configuration and log index of the
+/// last write replicated, and the `replicated_writes` counter.
+pub async fn replicate<T: StoreTransport + Send + Sync>(server: Arc<StoreServer<T>>, config: ReplicationConfig) -> Result<(), StoreError> {
+    let metrics = server.metrics();
+    let replicated_configuration = metrics.gauge("replicated_configuration");
+    let replicated_idx = metrics.gauge("replicated_idx");
+    let replicated = metrics.counter("replicated_writes");
+    let conflicts = metrics.counter("replication_conflicts");
+    let mut client = RpcClient::connect(config.primary.clone())
+        .await
+        .map_err(|e| StoreError::Replication(e.to_string()))?;
+    let mut request = tonic::Request::new(Void {});
+    if let Some(ref token) = config.token {
+        let token = MetadataValue::from_str(&format!("Bearer {}", token))
+            .map_err(|_| StoreError::Replication(String::from("authentication token is not valid metadata")))?;
+        request.metadata_mut().insert(AUTHORIZATION_METADATA, token);
+    }
+    let mut changes = client
+        .subscribe_changes(request)
+        .await
+        .map_err(|e| StoreError::Replication(e.message().to_string()))?
+        .into_inner();
+    let identity = format!("replication from {}", config.primary);
+    while let Some(event) = changes.next().await {
+        let event = event.map_err(|e| StoreError::Replication(e.message().to_string()))?;
+        let change = match event.event {
+            Some(change_event::Event::Write(change)) => change,
+            Some(change_event::Event::Gap(missed)) => return Err(StoreError::ReplicationGap { missed }),
+            None => continue,
+        };
+        if (change.configuration_id, change.log_idx) < (config.start_configuration, config.start_idx) {
+            continue;
+        }
+        let options = QueryOptions {
+            params: change.params.into_iter().map(value_from_proto).collect(),
+            client: identity.clone(),
+            ..QueryOptions::default()
+        };
+        match server.query_with_options(&change.sql, options).await {
+            Ok(_) => replicated.inc(),
+            Err(e) => match config.conflict_policy {
+                ConflictPolicy::Skip => {
+                    log(format!("Skipped write {} of configuration {} replicated from {}: {}", change.log_idx, change.configuration_id, config.primary, e));
+                    conflicts.inc();
+                }
+                ConflictPolicy::Stop => {
+                    conflicts.inc();
+                    return Err(StoreError::ReplicationConflict {
+                        configuration_id: change.configuration_id,
+                        log_idx: change.log_idx,
+                        error: e.to_string(),
+                    });
+                }
+            },
+        }
+        replicated_configuration.set(change.configuration_id as i64);
+        replicated_idx.set(change.log_idx as i64);
+    }
+    Ok(())
+}
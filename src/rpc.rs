@@ -1,13 +1,23 @@
 //! ChiselStore RPC module.
 
+use crate::metrics::{labelled, peer_metric, Counter, Gauge, Registry, Timer};
 use crate::rpc::proto::rpc_server::Rpc;
-use crate::{StoreCommand, StoreServer, StoreTransport};
+use crate::server::command_ids;
+use crate::util::log::log;
+use crate::{ChangeEvent, CommandKind, CommandStatement, Consistency, DatabaseSnapshot, ImportRow, Principal, QueryOptions, Role, StoreCommand, StoreError, StoreServer, StoreTransport};
 use async_mutex::Mutex;
 use async_trait::async_trait;
 use crossbeam::queue::ArrayQueue;
 use derivative::Derivative;
-use std::collections::HashMap;
+use futures::{Stream, StreamExt};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, Notify, OwnedSemaphorePermit, Semaphore};
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::{Channel, Endpoint, Server};
 use tonic::{Request, Response, Status};
 use omnipaxos_core::{
     ballot_leader_election::messages::{BLEMessage, HeartbeatMsg, HeartbeatRequest, HeartbeatReply},
@@ -26,92 +36,1037 @@ pub mod proto {
 
 use proto::rpc_client::RpcClient;
 use proto::{
-    Query, QueryResults, QueryRow, Void,
+    Query, QueryResults, QueryRow, StatusReply, MigrateReq, MultiReadReq, MultiReadReply, Void,
     Ballot, StopSign, PrepareReq, PromiseReq, 
     AcceptSyncReq, FirstAcceptReq, AcceptDecideReq, AcceptedReq, 
     DecideReq, ProposalForwardReq, CompactionReq, ForwardCompactionReq,
     AcceptStopSignReq, AcceptedStopSignReq, DecideStopSignReq,
     HeartbeatRequestReq, HeartbeatReplyReq,
+    LearnerFetchReq, LearnerFetchReply, SnapshotChunk, DumpLogReq, DumpLogReply, ClusterMetricsReply, NodeMetrics, TableChecksumReq, TableChecksumReply, ApplyErrorsReply, EpochsReply, ReadSnapshot,
+    Change, UpdateNodeAddressReq, MaintenanceModeReq, RemoveMemberReq,
 };
 
 type NodeAddrFn = dyn Fn(u64) -> String + Send + Sync;
 
-#[derive(Debug)]
+/// gRPC's conventional maximum message size.
+const DEFAULT_MAX_SYNC_MESSAGE_BYTES: usize = 4 * 1024 * 1024;
+/// Attempts at a chunked log synchronization before leaving it to sequence
+/// paxos to synchronize again.
+const SYNC_TRANSFER_ATTEMPTS: usize = 3;
+/// How long the chunks of a log synchronization are kept waiting for the
+/// next one, before the transfer is discarded.
+const SYNC_TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
+/// Size of the chunks a copy of the database is sent in, well under the
+/// maximum message size.
+const SNAPSHOT_CHUNK_BYTES: usize = 1024 * 1024;
+/// Transport events buffered for each subscriber, beyond which the oldest
+/// are lost.
+const TRANSPORT_EVENT_CAPACITY: usize = 256;
+
+/// RPC transport configuration.
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub struct RpcTransportConfig {
+    /// Maximum number of idle connections kept per peer.
+    pub pool_capacity: usize,
+    /// Disable Nagle's algorithm on peer connections.
+    ///
+    /// Consensus messages are small and latency-sensitive, so this is on by
+    /// default. Turning it off may slightly improve throughput of bulk
+    /// transfers, like snapshots, at the cost of delaying control messages.
+    pub tcp_nodelay: bool,
+    /// Number of requests a connection queues before sending waits for
+    /// room, tonic's `Endpoint::buffer_size`.
+    ///
+    /// This bounds requests, not bytes: it is not the socket's send buffer.
+    pub request_buffer_size: Option<usize>,
+    /// HTTP/2 receive window of each stream, in bytes.
+    ///
+    /// Larger windows speed up bulk transfers on high-latency links, but let
+    /// a single large message take more of the connection's bandwidth.
+    pub receive_stream_window_size: Option<u32>,
+    /// HTTP/2 receive window of each connection, in bytes.
+    pub receive_connection_window_size: Option<u32>,
+    /// How long a pooled connection may stay idle before it is reconnected.
+    ///
+    /// Middleboxes may silently sever idle connections, so reusing them
+    /// fails. `None` keeps idle connections indefinitely.
+    pub max_idle: Option<Duration>,
+    /// Maximum throughput of log synchronization messages, in bytes per second.
+    ///
+    /// Bringing a lagging node up to date may send a large part of the log
+    /// at once, which can saturate the link and starve heartbeats, causing
+    /// spurious elections. `None` doesn't limit synchronization.
+    pub sync_bytes_per_sec: Option<u64>,
+    /// Maximum encoded size of a log synchronization message, in bytes.
+    ///
+    /// Synchronizing a node far behind may take more of the log than fits in
+    /// a single gRPC message, so larger synchronizations are split into
+    /// chunks of entries sent one after the other, which the receiver only
+    /// hands to sequence paxos once it has them all. If a chunk fails, the
+    /// transfer starts over, up to a few times. `None` never splits.
+    pub max_sync_message_bytes: Option<usize>,
+    /// Maximum number of proposals in a message forwarding them to the
+    /// leader.
+    ///
+    /// A follower forwards all the proposals it queued while no leader was
+    /// known at once, so larger forwards are split into batches, sent one
+    /// after the other so that the leader appends them in order. Forwarded
+    /// proposals and batches are counted in the `proposals_forwarded` and
+    /// `proposal_forward_batches` metrics. `None` never splits.
+    pub max_forward_batch: Option<usize>,
+    /// Maximum number of sequence paxos messages being sent at once.
+    ///
+    /// Every message is sent by its own task, so a burst of messages to a
+    /// slow peer can pile up tasks without bound. Messages over the limit are
+    /// not sent, and counted in the `sp_sends_shed` metric; their peers are
+    /// then resynchronized, as when a message is lost. `None` doesn't limit
+    /// sends.
+    pub max_pending_sends: Option<usize>,
+    /// Maximum number of sequence paxos messages queued for each peer.
+    ///
+    /// With a queue, the messages to a peer are sent in order, one at a
+    /// time, by a task of its own. When a queue is full, the peer is
+    /// resynchronized instead of queueing more: its queued messages are
+    /// dropped and counted in its `peer_queue_dropped` metric. Forwarded
+    /// proposals are not dropped, but rejected while the queue is full. The
+    /// queue's size is its `peer_queue_depth` metric. `None` sends every
+    /// message from a task of its own.
+    pub peer_queue_depth: Option<usize>,
+    /// Consecutive failed attempts to connect to a peer after which its
+    /// circuit breaker opens.
+    ///
+    /// A message to a peer that can't be connected to is lost, and counted
+    /// in the peer's `unreachable_dropped` metric: the peer is resynchronized
+    /// and the commands of a forwarded proposal fail. While the breaker of a
+    /// peer is open, its messages are lost without attempting to connect, so
+    /// that a peer that is down costs neither connection attempts nor log
+    /// lines for every message. `None` attempts to connect for every
+    /// message.
+    pub connect_failure_threshold: Option<u32>,
+    /// How long the circuit breaker of a peer stays open before connecting
+    /// to it is attempted again.
+    pub circuit_open_duration: Duration,
+    /// Token sent as a bearer token with every request to peers, for peer
+    /// listeners that require one (see [`RpcService::peer`]). Services
+    /// serving both clients and peers require it too.
+    #[derivative(Debug = "ignore")]
+    pub peer_token: Option<PeerToken>,
+}
+
+impl Default for RpcTransportConfig {
+    fn default() -> Self {
+        Self {
+            pool_capacity: 16,
+            tcp_nodelay: true,
+            request_buffer_size: None,
+            receive_stream_window_size: None,
+            receive_connection_window_size: None,
+            max_idle: None,
+            sync_bytes_per_sec: None,
+            max_sync_message_bytes: Some(DEFAULT_MAX_SYNC_MESSAGE_BYTES),
+            max_forward_batch: None,
+            max_pending_sends: None,
+            peer_queue_depth: None,
+            connect_failure_threshold: Some(3),
+            circuit_open_duration: Duration::from_secs(1),
+            peer_token: None,
+        }
+    }
+}
+
+impl RpcTransportConfig {
+    /// Endpoint for connecting to `addr` with this configuration.
+    pub fn endpoint(&self, addr: String) -> Result<Endpoint, Box<dyn std::error::Error + Send + Sync>> {
+        let mut endpoint = Endpoint::from_shared(addr)?.tcp_nodelay(self.tcp_nodelay);
+        if let Some(size) = self.request_buffer_size {
+            endpoint = endpoint.buffer_size(size);
+        }
+        if let Some(size) = self.receive_stream_window_size {
+            endpoint = endpoint.initial_stream_window_size(size);
+        }
+        if let Some(size) = self.receive_connection_window_size {
+            endpoint = endpoint.initial_connection_window_size(size);
+        }
+        Ok(endpoint)
+    }
+}
+
+/// Bearer token authenticating the nodes of a cluster to each other.
+///
+/// Checked to be valid request metadata when it is created, so that it can
+/// be sent with every request.
+#[derive(Clone)]
+pub struct PeerToken {
+    token: String,
+    authorization: MetadataValue<Ascii>,
+}
+
+impl PeerToken {
+    /// Creates a peer token, failing if it is not valid request metadata,
+    /// e.g. because it has non-ASCII or control characters.
+    pub fn new<S: Into<String>>(token: S) -> Result<Self, tonic::metadata::errors::InvalidMetadataValue> {
+        let token = token.into();
+        let authorization = MetadataValue::from_str(&format!("Bearer {}", token))?;
+        Ok(Self { token, authorization })
+    }
+}
+
+/// RPC server configuration.
+#[derive(Clone, Debug, Default)]
+pub struct RpcServerConfig {
+    /// Maximum number of concurrent HTTP/2 streams per connection.
+    ///
+    /// Every in-flight request takes a stream, and peers pool their
+    /// connections (see `RpcTransportConfig::pool_capacity`), so a busy
+    /// node may need more streams than the default; each stream costs
+    /// buffer memory, though. Requests over the limit wait for a stream to
+    /// free up. `None` uses the HTTP/2 default.
+    pub max_concurrent_streams: Option<u32>,
+    /// Maximum number of requests served at once per connection.
+    ///
+    /// Unlike `max_concurrent_streams`, this is enforced by the server itself:
+    /// requests over the limit are accepted, but wait to be served.
+    pub concurrency_limit_per_connection: Option<usize>,
+}
+
+impl RpcServerConfig {
+    /// Server builder to serve `RpcService` with this configuration.
+    pub fn builder(&self) -> Server {
+        let mut builder = Server::builder().max_concurrent_streams(self.max_concurrent_streams);
+        if let Some(limit) = self.concurrency_limit_per_connection {
+            builder = builder.concurrency_limit_per_connection(limit);
+        }
+        builder
+    }
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
 struct ConnectionPool {
-    connections: ArrayQueue<RpcClient<tonic::transport::Channel>>,
+    addr: String,
+    /// Idle connections, with the time they were returned to the pool.
+    connections: ArrayQueue<(RpcClient<tonic::transport::Channel>, Instant)>,
+    endpoint: Endpoint,
+    max_idle: Option<Duration>,
+    /// Connections taken from the pool.
+    hits: Arc<Counter>,
+    /// Connection requests that found the pool empty.
+    misses: Arc<Counter>,
+    /// Newly established connections.
+    created: Arc<Counter>,
+    /// Connections closed for being idle longer than `max_idle`.
+    evicted: Arc<Counter>,
+    /// Idle connections in the pool.
+    depth: Arc<Gauge>,
+    /// Failed attempts to connect to the peer.
+    connect_failures: Arc<Counter>,
+    /// Whether the circuit breaker is open, 1 if it is.
+    circuit_open: Arc<Gauge>,
+    breaker: std::sync::Mutex<CircuitBreaker>,
+    /// Whether the last attempt to reach the peer succeeded.
+    up: std::sync::atomic::AtomicBool,
+    failure_threshold: Option<u32>,
+    open_duration: Duration,
+    /// Authorization metadata sent with every request.
+    #[derivative(Debug = "ignore")]
+    authorization: Option<MetadataValue<Ascii>>,
+    /// Fingerprint of this node's SQL extensions, sent with every request.
+    extensions: Arc<std::sync::atomic::AtomicU64>,
+    stats: Arc<std::sync::Mutex<TransportStats>>,
+    #[derivative(Debug = "ignore")]
+    events: broadcast::Sender<TransportEvent>,
 }
 
 struct Connection {
     conn: RpcClient<tonic::transport::Channel>,
     pool: Arc<ConnectionPool>,
+    /// Set once a request failed to reach the peer, so that the connection
+    /// is not returned to the pool.
+    broken: bool,
+}
+
+impl Connection {
+    /// Request carrying `msg` to the peer, with the peer token if any and
+    /// the fingerprint of this node's SQL extensions.
+    fn request<T: prost::Message>(&self, msg: T) -> Request<T> {
+        self.pool.stats.lock().unwrap().sent(&msg);
+        let mut request = Request::new(msg);
+        if let Some(ref authorization) = self.pool.authorization {
+            request.metadata_mut().insert(AUTHORIZATION_METADATA, authorization.clone());
+        }
+        let extensions = self.pool.extensions.load(std::sync::atomic::Ordering::SeqCst);
+        if extensions != 0 {
+            request.metadata_mut().insert(EXTENSIONS_METADATA, MetadataValue::from(extensions));
+        }
+        request
+    }
+
+    /// Records the outcome of a request sent over the connection.
+    ///
+    /// Errors of the peer's handlers are left to the caller; a request that
+    /// didn't reach the peer breaks the connection and marks the peer down.
+    /// Returns whether the peer handled the request.
+    fn finish<T>(&mut self, result: Result<Response<T>, Status>) -> bool {
+        if let Err(status) = result {
+            // Transport errors, rather than errors of the peer's handler.
+            if matches!(status.code(), tonic::Code::Unavailable | tonic::Code::Unknown) {
+                self.broken = true;
+                self.pool.down();
+            }
+            return false;
+        }
+        true
+    }
 }
 
 impl Drop for Connection {
     fn drop(&mut self) {
-        self.pool.replenish(self.conn.clone())
+        if !self.broken {
+            self.pool.replenish(self.conn.clone())
+        }
     }
 }
 
 impl ConnectionPool {
-    fn new() -> Arc<Self> {
-        Arc::new(Self {
-            connections: ArrayQueue::new(16),
-        })
+    fn new(
+        addr: &str,
+        config: &RpcTransportConfig,
+        metrics: &Registry,
+        extensions: Arc<std::sync::atomic::AtomicU64>,
+        stats: Arc<std::sync::Mutex<TransportStats>>,
+        events: broadcast::Sender<TransportEvent>,
+    ) -> Result<Arc<Self>, ConnectError> {
+        let endpoint = config.endpoint(addr.to_string()).map_err(|e| ConnectError::InvalidAddress(addr.to_string(), e.to_string()))?;
+        Ok(Arc::new(Self {
+            addr: addr.to_string(),
+            connections: ArrayQueue::new(config.pool_capacity),
+            endpoint,
+            max_idle: config.max_idle,
+            hits: metrics.counter(&peer_metric("connection_pool_hits", addr)),
+            misses: metrics.counter(&peer_metric("connection_pool_misses", addr)),
+            created: metrics.counter(&peer_metric("connection_pool_created", addr)),
+            evicted: metrics.counter(&peer_metric("connection_pool_evicted", addr)),
+            depth: metrics.gauge(&peer_metric("connection_pool_depth", addr)),
+            connect_failures: metrics.counter(&peer_metric("connect_failures", addr)),
+            circuit_open: metrics.gauge(&peer_metric("circuit_open", addr)),
+            breaker: std::sync::Mutex::new(CircuitBreaker::default()),
+            up: std::sync::atomic::AtomicBool::new(false),
+            failure_threshold: config.connect_failure_threshold,
+            open_duration: config.circuit_open_duration,
+            authorization: config.peer_token.as_ref().map(|token| token.authorization.clone()),
+            extensions,
+            stats,
+            events,
+        }))
     }
 
-    async fn connection<S: ToString>(&self, addr: S) -> RpcClient<tonic::transport::Channel> {
-        let addr = addr.to_string();
-        match self.connections.pop() {
-            Some(x) => x,
-            None => RpcClient::connect(addr).await.unwrap(),
+    async fn try_connection(&self) -> Result<RpcClient<tonic::transport::Channel>, ConnectError> {
+        let conn = match self.pop_fresh() {
+            Some(x) => {
+                self.hits.inc();
+                x
+            }
+            None => {
+                self.misses.inc();
+                self.check_breaker()?;
+                self.stats.lock().unwrap().connect_attempts += 1;
+                let channel: Channel = match self.endpoint.connect().await {
+                    Ok(channel) => channel,
+                    Err(e) => {
+                        self.connect_failed(&e);
+                        return Err(e.into());
+                    }
+                };
+                self.connected();
+                let conn = RpcClient::new(channel);
+                self.created.inc();
+                conn
+            }
+        };
+        self.depth.set(self.connections.len() as i64);
+        Ok(conn)
+    }
+
+    /// Fails if the circuit breaker is open. Once it has been open for long
+    /// enough, lets a single attempt to connect through, keeping the others
+    /// out until that attempt fails or succeeds.
+    fn check_breaker(&self) -> Result<(), ConnectError> {
+        let mut breaker = self.breaker.lock().unwrap();
+        if let Some(open_until) = breaker.open_until {
+            let now = Instant::now();
+            if now < open_until {
+                return Err(ConnectError::CircuitOpen(open_until - now));
+            }
+            breaker.open_until = Some(now + self.open_duration);
+        }
+        Ok(())
+    }
+
+    /// Records a failed attempt to connect, opening the circuit breaker if
+    /// enough attempts failed in a row.
+    fn connect_failed(&self, e: &tonic::transport::Error) {
+        self.connect_failures.inc();
+        self.stats.lock().unwrap().connect_failures += 1;
+        self.emit(TransportEvent::ConnectFailed {
+            peer: self.addr.clone(),
+            error: e.to_string(),
+        });
+        self.down();
+        let threshold = match self.failure_threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker.failures += 1;
+        if breaker.failures < threshold {
+            return;
+        }
+        if breaker.open_until.is_none() {
+            log(format!(
+                "Failed to connect to {} {} times in a row, dropping messages to it for {:?}: {}",
+                self.addr, breaker.failures, self.open_duration, e
+            ));
+            self.circuit_open.set(1);
+            self.emit(TransportEvent::CircuitOpened { peer: self.addr.clone() });
+        }
+        breaker.open_until = Some(Instant::now() + self.open_duration);
+    }
+
+    /// Records a successful attempt to connect, closing the circuit breaker.
+    fn connected(&self) {
+        if !self.up.swap(true, std::sync::atomic::Ordering::SeqCst) {
+            self.emit(TransportEvent::PeerConnected { peer: self.addr.clone() });
+        }
+        let mut breaker = self.breaker.lock().unwrap();
+        if breaker.open_until.take().is_some() {
+            log(format!("Reconnected to {} after {} failed attempts", self.addr, breaker.failures));
+            self.circuit_open.set(0);
+        }
+        breaker.failures = 0;
+    }
+
+    /// Records that the peer can't be reached, having been reachable.
+    fn down(&self) {
+        if self.up.swap(false, std::sync::atomic::Ordering::SeqCst) {
+            self.emit(TransportEvent::PeerDisconnected { peer: self.addr.clone() });
+        }
+    }
+
+    fn emit(&self, event: TransportEvent) {
+        // Fails only when nobody is subscribed.
+        let _ = self.events.send(event);
+    }
+
+    /// Takes an idle connection from the pool, closing the stale ones.
+    fn pop_fresh(&self) -> Option<RpcClient<tonic::transport::Channel>> {
+        while let Some((conn, idle_since)) = self.connections.pop() {
+            match self.max_idle {
+                Some(max_idle) if idle_since.elapsed() > max_idle => self.evicted.inc(),
+                _ => return Some(conn),
+            }
         }
+        None
     }
 
     fn replenish(&self, conn: RpcClient<tonic::transport::Channel>) {
-        let _ = self.connections.push(conn);
+        let _ = self.connections.push((conn, Instant::now()));
+        self.depth.set(self.connections.len() as i64);
     }
 }
 
-#[derive(Debug, Clone)]
-struct Connections(Arc<Mutex<HashMap<String, Arc<ConnectionPool>>>>);
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+struct Connections {
+    pools: Arc<Mutex<HashMap<String, Arc<ConnectionPool>>>>,
+    config: RpcTransportConfig,
+    metrics: Arc<Registry>,
+    /// Fingerprint of this node's SQL extensions.
+    extensions: Arc<std::sync::atomic::AtomicU64>,
+    stats: Arc<std::sync::Mutex<TransportStats>>,
+    #[derivative(Debug = "ignore")]
+    events: broadcast::Sender<TransportEvent>,
+}
 
 impl Connections {
-    fn new() -> Self {
-        Self(Arc::new(Mutex::new(HashMap::new())))
+    fn new(config: RpcTransportConfig, metrics: Arc<Registry>) -> Self {
+        Self {
+            pools: Arc::new(Mutex::new(HashMap::new())),
+            config,
+            metrics,
+            extensions: Arc::new(std::sync::atomic::AtomicU64::new(0)),
+            stats: Arc::new(std::sync::Mutex::new(TransportStats::default())),
+            events: broadcast::channel(TRANSPORT_EVENT_CAPACITY).0,
+        }
     }
 
-    async fn connection<S: ToString>(&self, addr: S) -> Connection {
-        let mut conns = self.0.lock().await;
+    /// Connection to `addr` for sending a sequence paxos or leader election
+    /// message, or `None` if the peer can't be reached, in which case the
+    /// message is dropped.
+    async fn connection<S: ToString>(&self, addr: S) -> Option<Connection> {
         let addr = addr.to_string();
-        let pool = conns
-            .entry(addr.clone())
-            .or_insert_with(ConnectionPool::new);
-        Connection {
-            conn: pool.connection(addr).await,
-            pool: pool.clone(),
+        match self.try_connection(&addr).await {
+            Ok(conn) => Some(conn),
+            Err(_) => {
+                self.metrics.counter(&peer_metric("unreachable_dropped", &addr)).inc();
+                None
+            }
         }
     }
+
+    async fn try_connection<S: ToString>(&self, addr: S) -> Result<Connection, ConnectError> {
+        let addr = addr.to_string();
+        // Only hold the map lock to look up the pool: connecting may be slow,
+        // and must not hold up connections to other peers.
+        let pool = {
+            let mut conns = self.pools.lock().await;
+            match conns.get(&addr) {
+                Some(pool) => pool.clone(),
+                None => {
+                    let pool = ConnectionPool::new(&addr, &self.config, &self.metrics, self.extensions.clone(), self.stats.clone(), self.events.clone())?;
+                    conns.insert(addr.clone(), pool.clone());
+                    pool
+                }
+            }
+        };
+        Ok(Connection {
+            conn: pool.try_connection().await?,
+            pool,
+            broken: false,
+        })
+    }
+}
+
+/// Statistics of the requests a transport sent to peers, since it was
+/// created or they were last reset.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransportStats {
+    /// Requests sent, by message type, such as `AcceptDecide` or
+    /// `HeartbeatRequest`. A request counts as sent once it has a connection,
+    /// whether or not it is then delivered.
+    pub messages_sent: HashMap<String, u64>,
+    /// Encoded size of the requests sent, in bytes.
+    pub bytes_sent: u64,
+    /// Attempts to connect to peers, not counting those the circuit breaker
+    /// of a peer saved.
+    pub connect_attempts: u64,
+    /// Attempts to connect to peers that failed.
+    pub connect_failures: u64,
+}
+
+impl TransportStats {
+    /// Counts `msg` as sent.
+    fn sent<T: prost::Message>(&mut self, msg: &T) {
+        let name = std::any::type_name::<T>().rsplit("::").next().unwrap_or_default();
+        let name = name.strip_suffix("Req").unwrap_or(name);
+        *self.messages_sent.entry(name.to_string()).or_insert(0) += 1;
+        self.bytes_sent += msg.encoded_len() as u64;
+    }
+}
+
+/// Change in the health of the transport's links to its peers.
+///
+/// Peers are identified by their address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransportEvent {
+    /// A connection to the peer was established, after none was.
+    PeerConnected {
+        /// Address of the peer.
+        peer: String,
+    },
+    /// The peer, which was connected, could not be reached anymore.
+    PeerDisconnected {
+        /// Address of the peer.
+        peer: String,
+    },
+    /// An attempt to connect to the peer failed.
+    ConnectFailed {
+        /// Address of the peer.
+        peer: String,
+        /// Why it failed.
+        error: String,
+    },
+    /// Too many attempts to connect to the peer failed in a row, so messages
+    /// to it are dropped for a while; see
+    /// `RpcTransportConfig::connect_failure_threshold`.
+    CircuitOpened {
+        /// Address of the peer.
+        peer: String,
+    },
+}
+
+/// State of the circuit breaker of a connection pool.
+#[derive(Debug, Default)]
+struct CircuitBreaker {
+    /// Attempts to connect that failed in a row.
+    failures: u32,
+    /// Until when no attempt to connect is made, if the breaker is open.
+    open_until: Option<Instant>,
+}
+
+/// Why a connection to a peer could not be had.
+#[derive(Debug, thiserror::Error)]
+enum ConnectError {
+    #[error(transparent)]
+    Transport(#[from] tonic::transport::Error),
+    #[error("Peer unreachable, next connection attempt in {0:?}")]
+    CircuitOpen(Duration),
+    #[error("Invalid peer address {0}: {1}")]
+    InvalidAddress(String, String),
+}
+
+impl From<ConnectError> for crate::Error {
+    fn from(e: ConnectError) -> Self {
+        crate::Error::Transport(e.to_string())
+    }
+}
+
+/// Limits the throughput of the messages passed through it.
+#[derive(Debug)]
+struct RateLimiter {
+    bytes_per_sec: u64,
+    /// When the bytes acquired so far will have been sent.
+    next_free: Mutex<Instant>,
+    /// Bytes passed through the limiter.
+    bytes: Arc<Counter>,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_sec: u64, metrics: &Registry) -> Self {
+        Self {
+            bytes_per_sec: std::cmp::max(1, bytes_per_sec),
+            next_free: Mutex::new(Instant::now()),
+            bytes: metrics.counter("sync_bytes"),
+        }
+    }
+
+    /// Waits until `bytes` more bytes can be sent without exceeding the limit.
+    async fn acquire(&self, bytes: usize) {
+        let start = {
+            let mut next_free = self.next_free.lock().await;
+            let start = std::cmp::max(*next_free, Instant::now());
+            *next_free = start + Duration::from_micros(bytes as u64 * 1_000_000 / self.bytes_per_sec);
+            start
+        };
+        self.bytes.add(bytes as u64);
+        tokio::time::sleep_until(start.into()).await;
+    }
 }
 
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct RpcTransport {
-    /// Node address mapping function.
     #[derivative(Debug = "ignore")]
-    node_addr: Box<NodeAddrFn>,
+    addrs: Arc<NodeAddrs>,
     connections: Connections,
+    metrics: Arc<Registry>,
+    /// Throttles log synchronization messages.
+    sync_limiter: Option<Arc<RateLimiter>>,
+    /// Permits of the sequence paxos messages being sent.
+    send_permits: Option<Arc<Semaphore>>,
+    peer_queue_depth: Option<usize>,
+    /// Send queues of the peers, by id.
+    #[derivative(Debug = "ignore")]
+    peer_queues: std::sync::Mutex<HashMap<u64, Arc<PeerQueue>>>,
+    /// Peers that messages were lost to.
+    #[derivative(Debug = "ignore")]
+    losses: Arc<Losses>,
+    /// Chunked log synchronizations being received, by sender, with the
+    /// entries of the chunks received so far and when the last one arrived.
+    #[derivative(Debug = "ignore")]
+    sync_transfers: std::sync::Mutex<HashMap<u64, (AcceptSyncReq, Instant)>>,
+    /// Members of the current configuration other than this node, once one
+    /// was adopted; messages to other nodes are dropped.
+    peers: std::sync::Mutex<Option<HashSet<u64>>>,
+}
+
+/// Addresses of the nodes, looked up as each message is sent so that
+/// queued messages follow a node that moved.
+struct NodeAddrs {
+    /// Node address mapping function.
+    node_addr: Box<NodeAddrFn>,
+    /// Addresses updated at runtime, by id, in place of those of
+    /// `node_addr`.
+    updated: std::sync::Mutex<HashMap<u64, String>>,
+}
+
+impl NodeAddrs {
+    /// Address of node `id`.
+    fn get(&self, id: u64) -> String {
+        match self.updated.lock().unwrap().get(&id) {
+            Some(addr) => addr.clone(),
+            None => (self.node_addr)(id),
+        }
+    }
 }
 
 impl RpcTransport {
     /// Creates a new RPC transport.
     pub fn new(node_addr: Box<NodeAddrFn>) -> Self {
+        Self::with_config(node_addr, RpcTransportConfig::default())
+    }
+
+    /// Creates a new RPC transport with the given configuration.
+    pub fn with_config(node_addr: Box<NodeAddrFn>, config: RpcTransportConfig) -> Self {
+        let metrics = Registry::new();
+        let sync_limiter = config.sync_bytes_per_sec.map(|rate| Arc::new(RateLimiter::new(rate, &metrics)));
+        let send_permits = config.max_pending_sends.map(|max| Arc::new(Semaphore::new(max)));
         RpcTransport {
-            node_addr,
-            connections: Connections::new(),
+            addrs: Arc::new(NodeAddrs {
+                node_addr,
+                updated: std::sync::Mutex::new(HashMap::new()),
+            }),
+            peer_queue_depth: config.peer_queue_depth,
+            peer_queues: std::sync::Mutex::new(HashMap::new()),
+            losses: Arc::new(Losses::new(&metrics)),
+            sync_transfers: std::sync::Mutex::new(HashMap::new()),
+            peers: std::sync::Mutex::new(None),
+            connections: Connections::new(config, metrics.clone()),
+            metrics,
+            sync_limiter,
+            send_permits,
+        }
+    }
+
+    /// Configuration of this transport.
+    pub fn config(&self) -> &RpcTransportConfig {
+        &self.connections.config
+    }
+
+    /// Metrics of this transport, such as connection pool hit and miss rates per peer.
+    pub fn metrics(&self) -> Arc<Registry> {
+        self.metrics.clone()
+    }
+
+    /// Statistics of the requests this transport sent to peers since it was
+    /// created or they were last reset.
+    pub fn stats_snapshot(&self) -> TransportStats {
+        self.connections.stats.lock().unwrap().clone()
+    }
+
+    /// Resets the statistics of the requests this transport sent to peers.
+    ///
+    /// Only the statistics are reset: the metrics of the transport keep
+    /// counting from where they were.
+    pub fn reset_stats(&self) {
+        *self.connections.stats.lock().unwrap() = TransportStats::default();
+    }
+
+    /// Subscribes to the changes in the health of this transport's links to
+    /// its peers from now on.
+    ///
+    /// Each subscriber buffers the latest events only: one that falls too far
+    /// behind receives `RecvError::Lagged` and misses the oldest events.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<TransportEvent> {
+        self.connections.events.subscribe()
+    }
+
+    /// Adds the chunk `req` of a log synchronization to the transfer it is
+    /// part of, returning the whole synchronization once `req` completes it.
+    ///
+    /// A chunk that doesn't follow the previous one of its transfer discards
+    /// the transfer, and is rejected so that the sender starts over; the
+    /// entries received so far never reach sequence paxos.
+    ///
+    /// Transfers whose next chunk didn't arrive within
+    /// `SYNC_TRANSFER_TIMEOUT`, or of an earlier round than `req`, are
+    /// discarded: their sender is gone or not the leader anymore.
+    fn reassemble_sync(&self, mut req: AcceptSyncReq) -> Result<Option<AcceptSyncReq>, Status> {
+        if req.chunks <= 1 {
+            return Ok(Some(req));
+        }
+        let now = Instant::now();
+        let mut transfers = self.sync_transfers.lock().unwrap();
+        let round = req.n.clone().map(ballot_from_proto);
+        let before = transfers.len();
+        transfers.retain(|_, (transfer, last_at)| {
+            now.saturating_duration_since(*last_at) < SYNC_TRANSFER_TIMEOUT && transfer.n.clone().map(ballot_from_proto) >= round
+        });
+        self.metrics.counter("sync_transfers_expired").add((before - transfers.len()) as u64);
+        if req.chunk > 0 {
+            let mut transfer = match transfers.remove(&req.from).map(|(transfer, _)| transfer) {
+                Some(transfer)
+                    if transfer.chunk + 1 == req.chunk
+                        && transfer.chunks == req.chunks
+                        && transfer.config_id == req.config_id
+                        && transfer.n == req.n
+                        && transfer.sync_idx == req.sync_idx =>
+                {
+                    transfer
+                }
+                _ => {
+                    self.metrics.counter("sync_transfers_discarded").inc();
+                    return Err(Status::aborted(format!(
+                        "chunk {} of {} of a log synchronization from node {} out of order, transfer discarded",
+                        req.chunk + 1,
+                        req.chunks,
+                        req.from
+                    )));
+                }
+            };
+            if let (Some(entries), Some(chunk)) = (sync_entries(&mut transfer), sync_entries(&mut req)) {
+                entries.append(chunk);
+            }
+            transfer.chunk = req.chunk;
+            transfer.decide_idx = req.decide_idx;
+            transfer.stop_sign = req.stop_sign;
+            req = transfer;
+        }
+        if req.chunk + 1 == req.chunks {
+            return Ok(Some(req));
+        }
+        transfers.insert(req.from, (req, now));
+        Ok(None)
+    }
+
+    /// Fetches the status of node `to_id`, with the round trip time of the
+    /// call. Returns `None` if the node could not be reached in time.
+    async fn fetch_status(&self, to_id: u64) -> Option<(StatusReply, Duration)> {
+        let peer = self.peer_addr(to_id);
+        let start = Instant::now();
+        let status = tokio::time::timeout(CLUSTER_METRICS_TIMEOUT, async {
+            let mut client = self.connections.try_connection(peer).await.ok()?;
+            let req = client.request(Void {});
+            let reply = client.conn.status(req).await.ok()?;
+            Some(reply.into_inner())
+        });
+        let status = status.await.ok()??;
+        Some((status, start.elapsed()))
+    }
+
+    /// Fetches the cluster metrics gathered by node `to_id`.
+    async fn fetch_cluster_metrics(&self, to_id: u64) -> Result<ClusterMetricsReply, Status> {
+        let peer = self.peer_addr(to_id);
+        let mut client = self
+            .connections
+            .try_connection(peer)
+            .await
+            .map_err(|e| Status::from(crate::Error::from(e)))?;
+        let req = client.request(Void {});
+        let reply = client.conn.cluster_metrics(req).await?;
+        Ok(reply.into_inner())
+    }
+}
+
+/// How long the status of a peer is waited for when gathering cluster metrics.
+const CLUSTER_METRICS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long after a message to a peer was lost the peer is resynchronized,
+/// so that a peer that is down is not resynchronized on every tick.
+const RESYNC_DELAY: Duration = Duration::from_millis(100);
+
+/// Peers that sequence paxos messages were lost to, waiting to be
+/// resynchronized, and forwarded proposals that were lost, waiting to be
+/// failed.
+///
+/// Sequence paxos assumes reliable links that deliver in order: a lost
+/// accept or decide leaves a gap in the log of its peer that nothing fills
+/// until the peer is resynchronized, so nothing more is sent to it until
+/// then.
+struct Losses {
+    /// Peers, with when the first message to them was lost.
+    peers: std::sync::Mutex<HashMap<u64, Instant>>,
+    /// Ids of the commands of the lost proposals, with the peer they were
+    /// forwarded to.
+    proposals: std::sync::Mutex<Vec<(u64, u64)>>,
+    messages: Arc<Counter>,
+    proposals_dropped: Arc<Counter>,
+}
+
+impl Losses {
+    fn new(metrics: &Registry) -> Self {
+        Self {
+            peers: std::sync::Mutex::new(HashMap::new()),
+            proposals: std::sync::Mutex::new(Vec::new()),
+            messages: metrics.counter("sp_messages_lost"),
+            proposals_dropped: metrics.counter("proposals_dropped"),
+        }
+    }
+
+    /// Records that the proposals of the commands `ids` forwarded to `peer`
+    /// were lost.
+    fn lose_proposals(&self, peer: u64, ids: Vec<u64>) {
+        self.proposals_dropped.inc();
+        self.proposals.lock().unwrap().extend(ids.into_iter().map(|id| (peer, id)));
+    }
+
+    /// Records that `messages` messages to `peer` were lost.
+    fn lose(&self, peer: u64, messages: u64) {
+        self.peers.lock().unwrap().entry(peer).or_insert_with(Instant::now);
+        self.messages.add(messages);
+    }
+
+    fn is_lost(&self, peer: u64) -> bool {
+        self.peers.lock().unwrap().contains_key(&peer)
+    }
+
+    /// Peers lost for long enough to be resynchronized.
+    fn due(&self) -> Vec<u64> {
+        let now = Instant::now();
+        let peers = self.peers.lock().unwrap();
+        peers.iter().filter(|(_, since)| now.duration_since(**since) >= RESYNC_DELAY).map(|(peer, _)| *peer).collect()
+    }
+
+    fn forget(&self, peer: u64) {
+        self.peers.lock().unwrap().remove(&peer);
+    }
+}
+
+/// A sequence paxos message being sent to a peer.
+struct QueuedSend {
+    /// Sends the message, returning whether the peer handled it.
+    send: Pin<Box<dyn Future<Output = bool> + Send>>,
+    /// Ids of the commands of a forwarded proposal, empty for the other
+    /// messages.
+    proposals: Vec<u64>,
+}
+
+impl QueuedSend {
+    /// Sends the message, unless its peer waits to be resynchronized, and
+    /// records its loss if it isn't handled.
+    async fn deliver(self, to_id: u64, losses: &Losses) {
+        // Forwarded proposals are not part of the peer's log.
+        if !self.proposals.is_empty() {
+            if !self.send.await {
+                losses.lose_proposals(to_id, self.proposals);
+            }
+            return;
+        }
+        if losses.is_lost(to_id) || !self.send.await {
+            losses.lose(to_id, 1);
+        }
+    }
+}
+
+/// Bounded queue of the sequence paxos messages to a peer, sent in order by
+/// a task of its own.
+struct PeerQueue {
+    sends: std::sync::Mutex<VecDeque<QueuedSend>>,
+    /// Set once the queue is not used anymore, stopping its task.
+    closed: std::sync::atomic::AtomicBool,
+    notify: Notify,
+    capacity: usize,
+    depth: Arc<Gauge>,
+    dropped: Arc<Counter>,
+}
+
+impl PeerQueue {
+    /// Creates the queue of peer `to_id` and spawns the task sending its
+    /// messages.
+    fn start(to_id: u64, addr: &str, capacity: usize, losses: Arc<Losses>, metrics: &Registry) -> Arc<Self> {
+        let queue = Arc::new(Self {
+            sends: std::sync::Mutex::new(VecDeque::new()),
+            closed: std::sync::atomic::AtomicBool::new(false),
+            notify: Notify::new(),
+            capacity: std::cmp::max(1, capacity),
+            depth: metrics.gauge(&peer_metric("peer_queue_depth", addr)),
+            dropped: metrics.counter(&peer_metric("peer_queue_dropped", addr)),
+        });
+        let sender = queue.clone();
+        tokio::task::spawn(async move {
+            while !sender.closed.load(std::sync::atomic::Ordering::SeqCst) {
+                let next = sender.sends.lock().unwrap().pop_front();
+                match next {
+                    Some(queued) => {
+                        sender.depth.dec();
+                        // In a task of its own, so that a failed send doesn't stop the queue.
+                        let losses = losses.clone();
+                        let _ = tokio::task::spawn(async move { queued.deliver(to_id, &losses).await }).await;
+                    }
+                    None => sender.notify.notified().await,
+                }
+            }
+        });
+        queue
+    }
+
+    /// Queues `send`, handing it back if the queue is full.
+    fn push(&self, send: QueuedSend) -> Result<(), QueuedSend> {
+        let mut sends = self.sends.lock().unwrap();
+        if sends.len() >= self.capacity {
+            return Err(send);
+        }
+        sends.push_back(send);
+        self.depth.inc();
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Drops the queued messages other than forwarded proposals, which the
+    /// resynchronization of the peer supersedes, and returns how many were.
+    fn discard(&self) -> u64 {
+        let mut sends = self.sends.lock().unwrap();
+        let before = sends.len();
+        sends.retain(|queued| !queued.proposals.is_empty());
+        self.depth.set(sends.len() as i64);
+        let discarded = (before - sends.len()) as u64;
+        self.dropped.add(discarded);
+        discarded
+    }
+
+    /// Stops the queue's task, cancelling the messages still queued, and
+    /// returns how many were, with the ids of the commands of the cancelled
+    /// forwarded proposals.
+    fn close(&self) -> (usize, Vec<u64>) {
+        let mut sends = self.sends.lock().unwrap();
+        self.closed.store(true, std::sync::atomic::Ordering::SeqCst);
+        let cancelled = sends.len();
+        let proposals = sends.drain(..).flat_map(|queued| queued.proposals).collect();
+        self.depth.set(0);
+        self.notify.notify_one();
+        (cancelled, proposals)
+    }
+}
+
+/// Permit of a sequence paxos message being sent.
+struct SendPermit {
+    _permit: OwnedSemaphorePermit,
+    in_flight: Arc<Gauge>,
+}
+
+impl SendPermit {
+    fn new(permit: OwnedSemaphorePermit, in_flight: Arc<Gauge>) -> Self {
+        in_flight.inc();
+        Self {
+            _permit: permit,
+            in_flight,
         }
     }
 }
 
+impl Drop for SendPermit {
+    fn drop(&mut self) {
+        self.in_flight.dec();
+    }
+}
+
+/// Request metadata entry carrying the identity of the client, such as the
+/// user name set by an authenticating proxy. It is only trusted when
+/// authentication is disabled.
+pub const CLIENT_METADATA: &str = "client-id";
+
+/// Request metadata entry carrying the bearer token of the client.
+pub const AUTHORIZATION_METADATA: &str = "authorization";
+
+/// Request metadata entry carrying the fingerprint of the sending peer's
+/// SQL extensions. Peers without extensions leave it out.
+pub const EXTENSIONS_METADATA: &str = "chiselstore-extensions";
+
+/// Identity of the client that sent `request`: the name of its
+/// `principal`, or if authentication is disabled the client's own
+/// `client-id`, or empty if unknown.
+fn client_identity<T>(request: &Request<T>, principal: Option<&Principal>) -> String {
+    if let Some(principal) = principal {
+        return principal.name.clone();
+    }
+    request
+        .metadata()
+        .get(CLIENT_METADATA)
+        .and_then(|client| client.to_str().ok())
+        .unwrap_or("")
+        .to_string()
+}
+
 fn ballot_from_proto(b: Ballot) -> omnipaxos_core::ballot_leader_election::Ballot {
     omnipaxos_core::ballot_leader_election::Ballot {
         n: b.n,
@@ -124,6 +1079,35 @@ fn store_command_from_proto(sc: proto::StoreCommand) -> StoreCommand {
     StoreCommand {
         id: sc.id,
         sql: sc.sql,
+        params: sc.params.into_iter().map(value_from_proto).collect(),
+        batch: sc.batch.into_iter().map(store_command_from_proto).collect(),
+        schema_version: sc.schema_version,
+        request_id: sc.request_id,
+        client: sc.client,
+        origin: sc.origin,
+        statements: sc
+            .statements
+            .into_iter()
+            .map(|stmt| CommandStatement {
+                sql: stmt.sql,
+                params: stmt.params.into_iter().map(value_from_proto).collect(),
+            })
+            .collect(),
+        kind: match proto::CommandKind::from_i32(sc.kind) {
+            Some(proto::CommandKind::EnterMaintenance) => CommandKind::EnterMaintenance,
+            Some(proto::CommandKind::ExitMaintenance) => CommandKind::ExitMaintenance,
+            _ => CommandKind::Sql,
+        },
+    }
+}
+
+pub(crate) fn value_from_proto(v: proto::Value) -> sqlite::Value {
+    match v.value {
+        Some(proto::value::Value::Integer(i)) => sqlite::Value::Integer(i),
+        Some(proto::value::Value::Real(f)) => sqlite::Value::Float(f),
+        Some(proto::value::Value::Text(s)) => sqlite::Value::String(s),
+        Some(proto::value::Value::Blob(b)) => sqlite::Value::Binary(b),
+        Some(proto::value::Value::Null(_)) | None => sqlite::Value::Null,
     }
 }
 
@@ -166,6 +1150,66 @@ fn proto_from_store_command(sc: StoreCommand) -> proto::StoreCommand {
     proto::StoreCommand {
         id: sc.id,
         sql: sc.sql,
+        params: sc.params.into_iter().map(proto_from_value).collect(),
+        batch: sc.batch.into_iter().map(proto_from_store_command).collect(),
+        schema_version: sc.schema_version,
+        request_id: sc.request_id,
+        client: sc.client,
+        origin: sc.origin,
+        statements: sc
+            .statements
+            .into_iter()
+            .map(|stmt| proto::CommandStatement {
+                sql: stmt.sql,
+                params: stmt.params.into_iter().map(proto_from_value).collect(),
+            })
+            .collect(),
+        kind: match sc.kind {
+            CommandKind::Sql => proto::CommandKind::Sql,
+            CommandKind::EnterMaintenance => proto::CommandKind::EnterMaintenance,
+            CommandKind::ExitMaintenance => proto::CommandKind::ExitMaintenance,
+        } as i32,
+    }
+}
+
+fn proto_from_value(v: sqlite::Value) -> proto::Value {
+    let value = match v {
+        sqlite::Value::Integer(i) => proto::value::Value::Integer(i),
+        sqlite::Value::Float(f) => proto::value::Value::Real(f),
+        sqlite::Value::String(s) => proto::value::Value::Text(s),
+        sqlite::Value::Binary(b) => proto::value::Value::Blob(b),
+        sqlite::Value::Null => proto::value::Value::Null(true),
+    };
+    proto::Value { value: Some(value) }
+}
+
+impl From<i64> for proto::Value {
+    fn from(i: i64) -> Self {
+        proto_from_value(sqlite::Value::Integer(i))
+    }
+}
+
+impl From<f64> for proto::Value {
+    fn from(f: f64) -> Self {
+        proto_from_value(sqlite::Value::Float(f))
+    }
+}
+
+impl From<&str> for proto::Value {
+    fn from(s: &str) -> Self {
+        proto_from_value(sqlite::Value::String(s.to_string()))
+    }
+}
+
+impl From<String> for proto::Value {
+    fn from(s: String) -> Self {
+        proto_from_value(sqlite::Value::String(s))
+    }
+}
+
+impl From<Vec<u8>> for proto::Value {
+    fn from(b: Vec<u8>) -> Self {
+        proto_from_value(sqlite::Value::Binary(b))
     }
 }
 
@@ -191,6 +1235,55 @@ fn proto_from_sync_item(si: SyncItem<StoreCommand, ()>) -> proto::SyncItem {
     }
 }
 
+/// Splits the log synchronization `req` into chunks of whole entries, each
+/// encoded in at most `max_bytes` unless a single entry is larger, if it
+/// doesn't fit in a single message.
+fn chunk_accept_sync(mut req: AcceptSyncReq, max_bytes: Option<usize>) -> Vec<AcceptSyncReq> {
+    let max_bytes = match max_bytes {
+        Some(max_bytes) if prost::Message::encoded_len(&req) > max_bytes => max_bytes,
+        _ => return vec![req],
+    };
+    let entries = match sync_entries(&mut req) {
+        Some(entries) => std::mem::take(entries),
+        None => return vec![req],
+    };
+    // the chunk fields, and the framing of the entries and of the sync item
+    let overhead = prost::Message::encoded_len(&req) + 32;
+    let mut groups = vec![Vec::new()];
+    let mut size = overhead;
+    for entry in entries {
+        let len = prost::Message::encoded_len(&entry);
+        let len = len + prost::length_delimiter_len(len) + 1;
+        if size + len > max_bytes && !groups.last().unwrap().is_empty() {
+            groups.push(Vec::new());
+            size = overhead;
+        }
+        size += len;
+        groups.last_mut().unwrap().push(entry);
+    }
+    let chunks = groups.len() as u32;
+    groups
+        .into_iter()
+        .enumerate()
+        .map(|(chunk, store_commands)| AcceptSyncReq {
+            sync_item: Some(proto::SyncItem {
+                item: Some(proto::sync_item::Item::Entries(proto::sync_item::Entries { store_commands })),
+            }),
+            chunk: chunk as u32,
+            chunks,
+            ..req.clone()
+        })
+        .collect()
+}
+
+/// Entries of the log synchronization `req`, if it synchronizes entries.
+fn sync_entries(req: &mut AcceptSyncReq) -> Option<&mut Vec<proto::StoreCommand>> {
+    match req.sync_item {
+        Some(proto::SyncItem { item: Some(proto::sync_item::Item::Entries(ref mut entries)) }) => Some(&mut entries.store_commands),
+        _ => None,
+    }
+}
+
 fn proto_from_stopsign(ss: omnipaxos_core::storage::StopSign) -> StopSign {
     let metadata: Vec<u32> = match ss.metadata {
         Some(md) => {
@@ -207,7 +1300,35 @@ fn proto_from_stopsign(ss: omnipaxos_core::storage::StopSign) -> StopSign {
 
 #[async_trait]
 impl StoreTransport for RpcTransport {
-    fn send_sp(&self, to_id: u64, msg: Message<StoreCommand, ()>) {
+    fn send_sp(&self, to_id: u64, config_id: u32, msg: Message<StoreCommand, ()>) -> Result<(), StoreError> {
+        if !self.is_peer(to_id) {
+            self.metrics.counter("removed_peer_dropped").inc();
+            if let PaxosMsg::ProposalForward(_) = msg.msg {
+                return Err(StoreError::ProposalDropped { peer: to_id });
+            }
+            return Ok(());
+        }
+        let proposal = matches!(msg.msg, PaxosMsg::ProposalForward(_));
+        if !proposal && self.losses.is_lost(to_id) {
+            // Superseded by the resynchronization of the peer.
+            self.losses.lose(to_id, 1);
+            return Ok(());
+        }
+        let permit = match self.send_permits {
+            Some(ref permits) => match permits.clone().try_acquire_owned() {
+                Ok(permit) => Some(SendPermit::new(permit, self.metrics.gauge("sp_sends_in_flight"))),
+                Err(_) => {
+                    self.metrics.counter("sp_sends_shed").inc();
+                    if proposal {
+                        self.metrics.counter("proposals_dropped").inc();
+                        return Err(StoreError::ProposalDropped { peer: to_id });
+                    }
+                    self.losses.lose(to_id, 1);
+                    return Ok(());
+                }
+            },
+            None => None,
+        };
         match msg.msg {
             PaxosMsg::Prepare(prepare) => {
                 let from = msg.from;
@@ -221,19 +1342,24 @@ impl StoreTransport for RpcTransport {
                 let req = PrepareReq {
                     from,
                     to,
+                    config_id,
                     n,
                     ld,
                     n_accepted,
                     la,
                 };
 
-                let peer = (self.node_addr)(to_id);
+                let addrs = self.addrs.clone();
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let req = tonic::Request::new(req.clone());
-                    client.conn.prepare(req).await.unwrap();
-                });
+                self.dispatch(to_id, permit, Vec::new(), async move {
+                    let mut client = match pool.connection(addrs.get(to_id)).await {
+                        Some(client) => client,
+                        None => return false,
+                    };
+                    let req = client.request(req.clone());
+                    let result = client.conn.prepare(req).await;
+                    client.finish(result)
+                })
             },
             PaxosMsg::Promise(promise) => {
                 let from = msg.from;
@@ -259,6 +1385,7 @@ impl StoreTransport for RpcTransport {
                 let req = PromiseReq {
                     from,
                     to,
+                    config_id,
                     n,
                     n_accepted,
                     sync_item,
@@ -267,13 +1394,21 @@ impl StoreTransport for RpcTransport {
                     stop_sign,
                 };
 
-                let peer = (self.node_addr)(to_id);
+                let addrs = self.addrs.clone();
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let req = tonic::Request::new(req.clone());
-                    client.conn.promise(req).await.unwrap();
-                });
+                let limiter = self.sync_limiter.clone().filter(|_| req.sync_item.is_some());
+                self.dispatch(to_id, permit, Vec::new(), async move {
+                    if let Some(limiter) = limiter {
+                        limiter.acquire(prost::Message::encoded_len(&req)).await;
+                    }
+                    let mut client = match pool.connection(addrs.get(to_id)).await {
+                        Some(client) => client,
+                        None => return false,
+                    };
+                    let req = client.request(req.clone());
+                    let result = client.conn.promise(req).await;
+                    client.finish(result)
+                })
             },
             PaxosMsg::AcceptSync(accept_sync) => {
                 let from = msg.from;
@@ -293,20 +1428,59 @@ impl StoreTransport for RpcTransport {
                 let req = AcceptSyncReq {
                     from,
                     to,
+                    config_id,
                     n,
                     sync_item,
                     sync_idx,
                     decide_idx,
                     stop_sign,
+                    chunk: 0,
+                    chunks: 1,
                 };
 
-                let peer = (self.node_addr)(to_id);
+                let addrs = self.addrs.clone();
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let req = tonic::Request::new(req.clone());
-                    client.conn.accept_sync(req).await.unwrap();
-                });
+                let limiter = self.sync_limiter.clone();
+                let chunks = chunk_accept_sync(req, self.config().max_sync_message_bytes);
+                let chunks_sent = self.metrics.counter(&peer_metric("sync_chunks_sent", &self.peer_addr(to_id)));
+                self.dispatch(to_id, permit, Vec::new(), async move {
+                    for attempt in 1..=SYNC_TRANSFER_ATTEMPTS {
+                        let peer = addrs.get(to_id);
+                        let mut client = match pool.connection(&peer).await {
+                            Some(client) => client,
+                            None => return false,
+                        };
+                        let mut sent = true;
+                        for chunk in chunks.iter() {
+                            if let Some(ref limiter) = limiter {
+                                limiter.acquire(prost::Message::encoded_len(chunk)).await;
+                            }
+                            let req = client.request(chunk.clone());
+                            let result = client.conn.accept_sync(req).await;
+                            if let Err(ref e) = result {
+                                log(format!(
+                                    "Log synchronization to {} failed at chunk {} of {} (attempt {}): {}",
+                                    peer,
+                                    chunk.chunk + 1,
+                                    chunks.len(),
+                                    attempt,
+                                    e
+                                ));
+                            }
+                            let failed = result.is_err();
+                            client.finish(result);
+                            if failed {
+                                sent = false;
+                                break;
+                            }
+                            chunks_sent.inc();
+                        }
+                        if sent {
+                            return true;
+                        }
+                    }
+                    false
+                })
             },
             PaxosMsg::FirstAccept(first_accept) => {
                 let from = msg.from;
@@ -318,17 +1492,22 @@ impl StoreTransport for RpcTransport {
                 let req = FirstAcceptReq {
                     from,
                     to,
+                    config_id,
                     n,
                     entries,
                 };
 
-                let peer = (self.node_addr)(to_id);
+                let addrs = self.addrs.clone();
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let req = tonic::Request::new(req.clone());
-                    client.conn.first_accept(req).await.unwrap();
-                });
+                self.dispatch(to_id, permit, Vec::new(), async move {
+                    let mut client = match pool.connection(addrs.get(to_id)).await {
+                        Some(client) => client,
+                        None => return false,
+                    };
+                    let req = client.request(req.clone());
+                    let result = client.conn.first_accept(req).await;
+                    client.finish(result)
+                })
             },
             PaxosMsg::AcceptDecide(accept_decide) => {
                 let from = msg.from;
@@ -341,18 +1520,23 @@ impl StoreTransport for RpcTransport {
                 let req = AcceptDecideReq {
                     from,
                     to,
+                    config_id,
                     n,
                     ld,
                     entries,
                 };
 
-                let peer = (self.node_addr)(to_id);
+                let addrs = self.addrs.clone();
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let req = tonic::Request::new(req.clone());
-                    client.conn.accept_decide(req).await.unwrap();
-                });
+                self.dispatch(to_id, permit, Vec::new(), async move {
+                    let mut client = match pool.connection(addrs.get(to_id)).await {
+                        Some(client) => client,
+                        None => return false,
+                    };
+                    let req = client.request(req.clone());
+                    let result = client.conn.accept_decide(req).await;
+                    client.finish(result)
+                })
             },
             PaxosMsg::Accepted(accepted) => {
                 let from = msg.from;
@@ -364,17 +1548,22 @@ impl StoreTransport for RpcTransport {
                 let req = AcceptedReq {
                     from,
                     to,
+                    config_id,
                     n,
                     la,
                 };
 
-                let peer = (self.node_addr)(to_id);
+                let addrs = self.addrs.clone();
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let req = tonic::Request::new(req.clone());
-                    client.conn.accepted(req).await.unwrap();
-                });
+                self.dispatch(to_id, permit, Vec::new(), async move {
+                    let mut client = match pool.connection(addrs.get(to_id)).await {
+                        Some(client) => client,
+                        None => return false,
+                    };
+                    let req = client.request(req.clone());
+                    let result = client.conn.accepted(req).await;
+                    client.finish(result)
+                })
             },
             PaxosMsg::Decide(decide) => {
                 let from = msg.from;
@@ -386,37 +1575,70 @@ impl StoreTransport for RpcTransport {
                 let req = DecideReq {
                     from,
                     to,
+                    config_id,
                     n,
                     ld,
                 };
 
-                let peer = (self.node_addr)(to_id);
+                let addrs = self.addrs.clone();
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let req = tonic::Request::new(req.clone());
-                    client.conn.decide(req).await.unwrap();
-                });
+                self.dispatch(to_id, permit, Vec::new(), async move {
+                    let mut client = match pool.connection(addrs.get(to_id)).await {
+                        Some(client) => client,
+                        None => return false,
+                    };
+                    let req = client.request(req.clone());
+                    let result = client.conn.decide(req).await;
+                    client.finish(result)
+                })
             },
             PaxosMsg::ProposalForward(entries) => {
                 let from = msg.from;
                 let to = msg.to;
 
-                let entries = entries.into_iter().map(|e| proto_from_store_command(e)).collect();
-
-                let req = ProposalForwardReq {
-                    from,
-                    to,
-                    entries,
-                };
-
-                let peer = (self.node_addr)(to_id);
+                let proposals = command_ids(&entries);
+                let batch = self.connections.config.max_forward_batch.unwrap_or(entries.len()).max(1);
+                let batch_ids: Vec<Vec<u64>> = entries.chunks(batch).map(command_ids).collect();
+                let entries: Vec<_> = entries.into_iter().map(|e| proto_from_store_command(e)).collect();
+                let reqs: Vec<(ProposalForwardReq, Vec<u64>)> = entries
+                    .chunks(batch)
+                    .zip(batch_ids)
+                    .map(|(chunk, ids)| {
+                        let req = ProposalForwardReq {
+                            from,
+                            to,
+                            config_id,
+                            entries: chunk.to_vec(),
+                        };
+                        (req, ids)
+                    })
+                    .collect();
+                self.metrics.counter("proposals_forwarded").add(entries.len() as u64);
+                self.metrics.counter("proposal_forward_batches").add(reqs.len() as u64);
+
+                let addrs = self.addrs.clone();
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let req = tonic::Request::new(req.clone());
-                    client.conn.proposal_forward(req).await.unwrap();
-                });
+                let losses = self.losses.clone();
+                self.dispatch(to_id, permit, proposals, async move {
+                    let mut client = match pool.connection(addrs.get(to_id)).await {
+                        Some(client) => client,
+                        None => return false,
+                    };
+                    let mut reqs = reqs.into_iter();
+                    while let Some((req, ids)) = reqs.next() {
+                        let req = client.request(req);
+                        let result = client.conn.proposal_forward(req).await;
+                        if !client.finish(result) {
+                            // Stop at the first lost batch, so that none is
+                            // appended ahead of the proposals it held, and
+                            // fail it along with those that follow.
+                            let unsent = ids.into_iter().chain(reqs.flat_map(|(_, ids)| ids)).collect();
+                            losses.lose_proposals(to_id, unsent);
+                            break;
+                        }
+                    }
+                    true
+                })
             },
             PaxosMsg::Compaction(compaction) => {
                 let from = msg.from;
@@ -436,16 +1658,21 @@ impl StoreTransport for RpcTransport {
                 let req = CompactionReq {
                     from,
                     to,
+                    config_id,
                     compaction: Some(compaction),
                 };
 
-                let peer = (self.node_addr)(to_id);
+                let addrs = self.addrs.clone();
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let req = tonic::Request::new(req.clone());
-                    client.conn.compaction(req).await.unwrap();
-                });
+                self.dispatch(to_id, permit, Vec::new(), async move {
+                    let mut client = match pool.connection(addrs.get(to_id)).await {
+                        Some(client) => client,
+                        None => return false,
+                    };
+                    let req = client.request(req.clone());
+                    let result = client.conn.compaction(req).await;
+                    client.finish(result)
+                })
             },
             PaxosMsg::ForwardCompaction(compaction) => {
                 let from = msg.from;
@@ -465,16 +1692,21 @@ impl StoreTransport for RpcTransport {
                 let req = ForwardCompactionReq {
                     from,
                     to,
+                    config_id,
                     compaction: Some(compaction),
                 };
 
-                let peer = (self.node_addr)(to_id);
+                let addrs = self.addrs.clone();
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let req = tonic::Request::new(req.clone());
-                    client.conn.forward_compaction(req).await.unwrap();
-                });
+                self.dispatch(to_id, permit, Vec::new(), async move {
+                    let mut client = match pool.connection(addrs.get(to_id)).await {
+                        Some(client) => client,
+                        None => return false,
+                    };
+                    let req = client.request(req.clone());
+                    let result = client.conn.forward_compaction(req).await;
+                    client.finish(result)
+                })
             },
             PaxosMsg::AcceptStopSign(accept_stop_sign) => {
                 let from = msg.from;
@@ -486,17 +1718,22 @@ impl StoreTransport for RpcTransport {
                 let req = AcceptStopSignReq {
                     from,
                     to,
+                    config_id,
                     n,
                     ss,
                 };
 
-                let peer = (self.node_addr)(to_id);
+                let addrs = self.addrs.clone();
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let req = tonic::Request::new(req.clone());
-                    client.conn.accept_stop_sign(req).await.unwrap();
-                });
+                self.dispatch(to_id, permit, Vec::new(), async move {
+                    let mut client = match pool.connection(addrs.get(to_id)).await {
+                        Some(client) => client,
+                        None => return false,
+                    };
+                    let req = client.request(req.clone());
+                    let result = client.conn.accept_stop_sign(req).await;
+                    client.finish(result)
+                })
             },
             PaxosMsg::AcceptedStopSign(accepted_stop_sign) => {
                 let from = msg.from;
@@ -507,16 +1744,21 @@ impl StoreTransport for RpcTransport {
                 let req = AcceptedStopSignReq {
                     from,
                     to,
+                    config_id,
                     n,
                 };
 
-                let peer = (self.node_addr)(to_id);
+                let addrs = self.addrs.clone();
                 let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let req = tonic::Request::new(req.clone());
-                    client.conn.accepted_stop_sign(req).await.unwrap();
-                });
+                self.dispatch(to_id, permit, Vec::new(), async move {
+                    let mut client = match pool.connection(addrs.get(to_id)).await {
+                        Some(client) => client,
+                        None => return false,
+                    };
+                    let req = client.request(req.clone());
+                    let result = client.conn.accepted_stop_sign(req).await;
+                    client.finish(result)
+                })
             },
             PaxosMsg::DecideStopSign(decide_stop_sign) => {
                 let from = msg.from;
@@ -527,71 +1769,393 @@ impl StoreTransport for RpcTransport {
                 let req = DecideStopSignReq {
                     from,
                     to,
+                    config_id,
                     n,
                 };
 
-                let peer = (self.node_addr)(to_id);
+                let addrs = self.addrs.clone();
+                let pool = self.connections.clone();
+                self.dispatch(to_id, permit, Vec::new(), async move {
+                    let mut client = match pool.connection(addrs.get(to_id)).await {
+                        Some(client) => client,
+                        None => return false,
+                    };
+                    let req = client.request(req.clone());
+                    let result = client.conn.decide_stop_sign(req).await;
+                    client.finish(result)
+                })
+            },
+            _ => panic!("Missing implementation for send message"),
+        }
+    }
+
+    fn send_ble(&self, to_id: u64, msg: BLEMessage) {
+        if !self.is_peer(to_id) {
+            self.metrics.counter("removed_peer_dropped").inc();
+            return;
+        }
+        match msg.msg {
+            HeartbeatMsg::Request(heartbeat_request) => {
+                let from = msg.from;
+                let to = msg.to;
+
+                let round = heartbeat_request.round;
+
+                let req = HeartbeatRequestReq {
+                    from,
+                    to,
+                    round,
+                };
+
+                let peer = self.peer_addr(to_id);
                 let pool = self.connections.clone();
                 tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let req = tonic::Request::new(req.clone());
-                    client.conn.decide_stop_sign(req).await.unwrap();
+                    let mut client = match pool.connection(peer).await {
+                        Some(client) => client,
+                        None => return,
+                    };
+                    let req = client.request(req.clone());
+                    let result = client.conn.heartbeat_request(req).await;
+                    client.finish(result);
                 });
             },
-            _ => panic!("Missing implementation for send message"),
+            HeartbeatMsg::Reply(heartbeat_reply) => {
+                let from = msg.from;
+                let to = msg.to;
+
+                let round = heartbeat_reply.round;
+                let ballot = Some(proto_from_ballot(heartbeat_reply.ballot));
+                let majority_connected = heartbeat_reply.majority_connected;
+
+                let req = HeartbeatReplyReq {
+                    from,
+                    to,
+                    round,
+                    ballot,
+                    majority_connected,
+                };
+
+                let peer = self.peer_addr(to_id);
+                let pool = self.connections.clone();
+                tokio::task::spawn(async move {
+                    let mut client = match pool.connection(peer).await {
+                        Some(client) => client,
+                        None => return,
+                    };
+                    let req = client.request(req.clone());
+                    let result = client.conn.heartbeat_reply(req).await;
+                    client.finish(result);
+                });
+            },
+        };
+    }
+
+    async fn fetch_decided(&self, to_id: u64, from_idx: u64) -> Option<Vec<StoreCommand>> {
+        let req = LearnerFetchReq {
+            from_idx,
+        };
+
+        let peer = self.peer_addr(to_id);
+        let mut client = self.connections.try_connection(peer).await.ok()?;
+        let req = client.request(req);
+        let reply = client.conn.learner_fetch(req).await.ok()?.into_inner();
+        Some(reply.entries.into_iter().map(|sc| store_command_from_proto(sc)).collect())
+    }
+
+    async fn fetch_snapshot(&self, to_id: u64) -> Option<DatabaseSnapshot> {
+        let peer = self.peer_addr(to_id);
+        let mut client = self.connections.try_connection(peer).await.ok()?;
+        let req = client.request(Void {});
+        let mut chunks = client.conn.fetch_snapshot(req).await.ok()?.into_inner();
+        let mut snapshot = DatabaseSnapshot::default();
+        while let Some(chunk) = chunks.message().await.ok()? {
+            snapshot.configuration_id = chunk.configuration_id;
+            snapshot.decided_idx = chunk.decided_idx;
+            snapshot.data.extend_from_slice(&chunk.data);
+        }
+        Some(snapshot)
+    }
+
+    async fn probe(&self, to_id: u64) -> Option<bool> {
+        Some(self.fetch_status(to_id).await.is_some())
+    }
+
+    async fn remove_member(&self, to_id: u64, node_id: u64) -> Result<(), StoreError> {
+        let peer = self.peer_addr(to_id);
+        let mut client = self
+            .connections
+            .try_connection(&peer)
+            .await
+            .map_err(|e| StoreError::Reconfiguration(format!("leader {} unreachable at {}: {}", to_id, peer, e)))?;
+        let req = client.request(RemoveMemberReq { node_id });
+        let result = client.conn.remove_member(req).await;
+        let reply = result.as_ref().map(|_| ()).map_err(|status| StoreError::Reconfiguration(status.message().to_string()));
+        client.finish(result);
+        reply
+    }
+
+    fn set_extensions(&self, fingerprint: u64) {
+        self.connections.extensions.store(fingerprint, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    fn set_peers(&self, peers: &[u64]) {
+        let peers: HashSet<u64> = peers.iter().copied().collect();
+        // Stop sending first, so that nothing is queued to a closed queue.
+        *self.peers.lock().unwrap() = Some(peers.clone());
+        let removed: Vec<(u64, Arc<PeerQueue>)> = {
+            let mut queues = self.peer_queues.lock().unwrap();
+            let ids: Vec<u64> = queues.keys().filter(|id| !peers.contains(id)).copied().collect();
+            ids.into_iter().filter_map(|id| queues.remove(&id).map(|queue| (id, queue))).collect()
+        };
+        for (id, queue) in removed {
+            let (cancelled, proposals) = queue.close();
+            log(format!("Node {} left the configuration, cancelled {} messages queued to it", id, cancelled));
+            if !proposals.is_empty() {
+                self.losses.lose_proposals(id, proposals);
+            }
+        }
+        let lost: Vec<u64> = self.losses.peers.lock().unwrap().keys().filter(|id| !peers.contains(id)).copied().collect();
+        for id in lost {
+            self.losses.forget(id);
+        }
+    }
+
+    fn take_dropped_proposals(&self) -> Vec<(u64, u64)> {
+        std::mem::take(&mut *self.losses.proposals.lock().unwrap())
+    }
+
+    fn take_lost_peers(&self) -> Vec<u64> {
+        let peers = self.losses.due();
+        for &peer in &peers {
+            // Drop what was queued before the resynchronization, before
+            // sending to the peer again.
+            if let Some(queue) = self.peer_queues.lock().unwrap().get(&peer) {
+                let discarded = queue.discard();
+                self.losses.messages.add(discarded);
+            }
+            self.losses.forget(peer);
+            self.metrics.counter("peer_resyncs").inc();
+        }
+        peers
+    }
+
+    fn update_node_address(&self, node_id: u64, addr: &str) {
+        let old = self.addrs.updated.lock().unwrap().insert(node_id, addr.to_string());
+        let old = old.unwrap_or_else(|| (self.addrs.node_addr)(node_id));
+        if old == addr {
+            return;
+        }
+        self.metrics.counter("node_address_updates").inc();
+        // The messages queued to the node are sent to the new address.
+        // Connections to the old address are stale, and its pool would keep
+        // them alive.
+        let pools = self.connections.pools.clone();
+        tokio::task::spawn(async move {
+            pools.lock().await.remove(&old);
+        });
+    }
+}
+
+impl Drop for RpcTransport {
+    fn drop(&mut self) {
+        // Stop the tasks of the send queues.
+        for queue in self.peer_queues.lock().unwrap().values() {
+            queue.close();
+        }
+    }
+}
+
+impl RpcTransport {
+    /// Address of node `id`.
+    fn peer_addr(&self, id: u64) -> String {
+        self.addrs.get(id)
+    }
+
+    /// Asks node `to_id` to route messages to node `node_id` to `addr`.
+    ///
+    /// The request carries the bearer `token` of the client that asked for
+    /// the update, if any, for node `to_id` to authenticate it.
+    pub(crate) async fn forward_node_address(&self, to_id: u64, node_id: u64, addr: &str, token: Option<&str>) -> Result<(), Status> {
+        let peer = self.peer_addr(to_id);
+        let mut client = self
+            .connections
+            .try_connection(&peer)
+            .await
+            .map_err(|e| Status::unavailable(format!("node {} unreachable at {}: {}", to_id, peer, e)))?;
+        let mut req = client.request(UpdateNodeAddressReq {
+            node_id,
+            addr: addr.to_string(),
+            local: true,
+        });
+        if let Some(token) = token {
+            let token = MetadataValue::from_str(&format!("Bearer {}", token))
+                .map_err(|_| Status::invalid_argument("authentication token is not valid metadata"))?;
+            req.metadata_mut().insert(AUTHORIZATION_METADATA, token);
+        }
+        let result = client.conn.update_node_address(req).await;
+        let reply = result.as_ref().map(|_| ()).map_err(|status| status.clone());
+        client.finish(result);
+        reply
+    }
+
+    /// Returns true unless `id` is not a member of the configuration this
+    /// node adopted last.
+    fn is_peer(&self, id: u64) -> bool {
+        self.peers.lock().unwrap().as_ref().map_or(true, |peers| peers.contains(&id))
+    }
+
+    /// Sends a sequence paxos message to `to_id` with `send`, from the
+    /// peer's queue if send queues are enabled, releasing its send permit
+    /// once the message is sent.
+    ///
+    /// `proposals` are the ids of the commands of a forwarded proposal, and
+    /// empty for the other messages. If the queue is full, a forwarded
+    /// proposal fails with `StoreError::ProposalDropped`, and another
+    /// message is lost, along with those queued before it.
+    fn dispatch<F>(&self, to_id: u64, permit: Option<SendPermit>, proposals: Vec<u64>, send: F) -> Result<(), StoreError>
+    where
+        F: Future<Output = bool> + Send + 'static,
+    {
+        let send = Box::pin(async move {
+            let _permit = permit;
+            send.await
+        });
+        let send = QueuedSend { send, proposals };
+        let depth = match self.peer_queue_depth {
+            Some(depth) => depth,
+            None => {
+                let losses = self.losses.clone();
+                tokio::task::spawn(async move { send.deliver(to_id, &losses).await });
+                return Ok(());
+            }
+        };
+        let queue = self
+            .peer_queues
+            .lock()
+            .unwrap()
+            .entry(to_id)
+            .or_insert_with(|| PeerQueue::start(to_id, &self.peer_addr(to_id), depth, self.losses.clone(), &self.metrics))
+            .clone();
+        let send = match queue.push(send) {
+            Ok(()) => return Ok(()),
+            Err(send) => send,
         };
+        if !send.proposals.is_empty() {
+            self.metrics.counter("proposals_dropped").inc();
+            return Err(StoreError::ProposalDropped { peer: to_id });
+        }
+        queue.dropped.inc();
+        self.losses.lose(to_id, queue.discard() + 1);
+        Ok(())
     }
+}
 
-    fn send_ble(&self, to_id: u64, msg: BLEMessage) {
-        match msg.msg {
-            HeartbeatMsg::Request(heartbeat_request) => {
-                let from = msg.from;
-                let to = msg.to;
+/// Bearer token in the `authorization` metadata of `request`, if any.
+fn bearer_token<T>(request: &Request<T>) -> Option<&str> {
+    request
+        .metadata()
+        .get(AUTHORIZATION_METADATA)
+        .and_then(|token| token.to_str().ok())
+        .map(|token| token.trim_start_matches("Bearer ").trim())
+}
 
-                let round = heartbeat_request.round;
+/// Compares `a` and `b` in time that depends on their lengths alone, so that
+/// timing a comparison against a secret reveals nothing of it but its length.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
 
-                let req = HeartbeatRequestReq {
-                    from,
-                    to,
-                    round,
-                };
+/// Fingerprint of the SQL extensions of the peer that sent `request`, zero
+/// if it has none.
+fn peer_extensions<T>(request: &Request<T>) -> u64 {
+    request
+        .metadata()
+        .get(EXTENSIONS_METADATA)
+        .and_then(|extensions| extensions.to_str().ok())
+        .and_then(|extensions| extensions.parse().ok())
+        .unwrap_or(0)
+}
 
-                let peer = (self.node_addr)(to_id);
-                let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let req = tonic::Request::new(req.clone());
-                    client.conn.heartbeat_request(req).await.unwrap();
-                });
-            },
-            HeartbeatMsg::Reply(heartbeat_reply) => {
-                let from = msg.from;
-                let to = msg.to;
+/// Results of a query as sent to clients.
+fn proto_from_results(results: crate::server::QueryResults) -> QueryResults {
+    // move the values out of the results, large blobs are not copied
+    let rows = results.rows.into_iter().map(|row| QueryRow { values: row.values }).collect();
+    QueryResults {
+        rows,
+        ballot: results.ballot.map(proto_from_ballot),
+        warnings: results.warnings,
+        proposed_at_us: results.proposed_at.map_or(0, micros_since_epoch),
+        committed_at_us: results.committed_at.map_or(0, micros_since_epoch),
+        served_locally: results.served_locally,
+        forwarded_from: results.forwarded_from.unwrap_or(0),
+        log_idx: results.log_idx.unwrap_or(0),
+        acknowledged_by: results.acknowledged_by,
+        configuration_id: results.configuration_id,
+        analysis: results
+            .analysis
+            .into_iter()
+            .map(|analysis| proto::StatementAnalysis {
+                sql: analysis.sql,
+                plan: analysis.plan,
+                rows: analysis.rows,
+                first_row_us: analysis.first_row.map_or(0, |d| d.as_micros() as u64),
+                elapsed_us: analysis.elapsed.as_micros() as u64,
+            })
+            .collect(),
+    }
+}
 
-                let round = heartbeat_reply.round;
-                let ballot = Some(proto_from_ballot(heartbeat_reply.ballot));
-                let majority_connected = heartbeat_reply.majority_connected;
+/// Microseconds from the Unix epoch to `t`.
+fn micros_since_epoch(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0)
+}
 
-                let req = HeartbeatReplyReq {
-                    from,
-                    to,
-                    round,
-                    ballot,
-                    majority_connected,
-                };
+/// Maps a store error to the gRPC status returned to clients, by its kind.
+fn status_from_error(e: StoreError) -> Status {
+    crate::Error::from(e).into()
+}
 
-                let peer = (self.node_addr)(to_id);
-                let pool = self.connections.clone();
-                tokio::task::spawn(async move {
-                    let mut client = pool.connection(peer).await;
-                    let req = tonic::Request::new(req.clone());
-                    client.conn.heartbeat_reply(req).await.unwrap();
-                });
-            },
-        };
+/// Status of the node of `server`.
+fn status_reply(server: &StoreServer<RpcTransport>) -> StatusReply {
+    StatusReply {
+        id: server.get_id(),
+        leader: server.get_current_leader(),
+        decided_idx: server.get_decided_idx(),
+        in_flight: server.get_in_flight(),
+        schema_version: server.schema_version().unwrap_or(0),
+        leader_uptime_ms: server.get_leader_uptime().map_or(0, |uptime| uptime.as_millis() as u64),
+        leader_changes: server.get_recent_leader_changes(),
+        apply_transactions: server.metrics().counter("apply_transactions").get(),
+        consensus_stalled: server.is_consensus_stalled(),
+        wall_time_us: micros_since_epoch(server.wall_time()),
+        quorum_size: server.get_quorum_size() as u64,
+        connected_voters: server.get_connected_voters() as u64,
+        quorum_at_risk: server.is_quorum_at_risk(),
+        peer_connectivity: server.get_peer_connectivity().into_iter().collect(),
+        applied_idx: server.get_applied_idx(),
+        apply_lag: server.get_apply_lag(),
+        maintenance: server.in_maintenance_mode().unwrap_or(false),
+        decided_per_sec: server.get_throughput(),
     }
 }
 
+/// RPCs served by an `RpcService`.
+///
+/// Serving clients and peers on separate listeners isolates client traffic
+/// from consensus traffic, and lets each have its own authentication.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RpcServices {
+    /// Serve both clients and peers.
+    All,
+    /// Serve clients: queries and admin operations.
+    Client,
+    /// Serve peers: consensus messages and learner fetches.
+    Peer,
+}
+
 /// RPC service.
 #[derive(Derivative)]
 #[derivative(Debug)]
@@ -599,43 +2163,444 @@ pub struct RpcService {
     /// The ChiselStore server access via this RPC service.
     #[derivative(Debug = "ignore")]
     pub server: Arc<StoreServer<RpcTransport>>,
+    services: RpcServices,
+    /// Bearer token peers must send, if any.
+    #[derivative(Debug = "ignore")]
+    peer_token: Option<PeerToken>,
 }
 
 impl RpcService {
-    /// Creates a new RPC service.
+    /// Creates a new RPC service, serving both clients and peers.
+    ///
+    /// Peers must send the peer token the server's transport sends, if any
+    /// (see `RpcTransportConfig::peer_token`).
     pub fn new(server: Arc<StoreServer<RpcTransport>>) -> Self {
-        Self { server }
+        let peer_token = server.transport().config().peer_token.clone();
+        Self {
+            server,
+            services: RpcServices::All,
+            peer_token,
+        }
+    }
+
+    /// Creates an RPC service serving only clients.
+    pub fn client(server: Arc<StoreServer<RpcTransport>>) -> Self {
+        Self {
+            server,
+            services: RpcServices::Client,
+            peer_token: None,
+        }
+    }
+
+    /// Creates an RPC service serving only peers, which must send
+    /// `peer_token`, if any, as a bearer token (see
+    /// `RpcTransportConfig::peer_token`).
+    ///
+    /// Node status and cluster metrics are served to both clients and peers.
+    pub fn peer(server: Arc<StoreServer<RpcTransport>>, peer_token: Option<PeerToken>) -> Self {
+        Self {
+            server,
+            services: RpcServices::Peer,
+            peer_token,
+        }
+    }
+
+    /// Fails client RPCs on a peer listener.
+    fn check_client(&self) -> Result<(), tonic::Status> {
+        match self.services {
+            RpcServices::Peer => Err(tonic::Status::unimplemented("client RPCs are not served on this listener")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails peer RPCs on a client listener, and those without the peer
+    /// token.
+    ///
+    /// Returns true if the sender authenticated with the peer token.
+    fn check_peer<T>(&self, request: &Request<T>) -> Result<bool, tonic::Status> {
+        if self.services == RpcServices::Client {
+            return Err(tonic::Status::unimplemented("peer RPCs are not served on this listener"));
+        }
+        self.check_peer_token(request)
+    }
+
+    /// Fails requests without the peer token, if one is required.
+    ///
+    /// Returns true if the sender authenticated with the peer token.
+    fn check_peer_token<T>(&self, request: &Request<T>) -> Result<bool, tonic::Status> {
+        let expected = match self.peer_token {
+            Some(ref token) => token,
+            None => return Ok(false),
+        };
+        match bearer_token(request) {
+            Some(token) if constant_time_eq(token.as_bytes(), expected.token.as_bytes()) => Ok(true),
+            _ => Err(status_from_error(StoreError::Unauthenticated)),
+        }
+    }
+
+    /// Replies to a peer message the server received with `result`.
+    ///
+    /// A message claiming this node's id halts the node only if its sender
+    /// authenticated with the peer token: anyone could claim any id
+    /// otherwise.
+    fn reply_to_peer(&self, authenticated: bool, result: Result<(), StoreError>) -> Result<Response<Void>, tonic::Status> {
+        match result {
+            Ok(()) => Ok(Response::new(Void {})),
+            Err(e @ StoreError::ConflictingNodeId(_)) => {
+                if authenticated {
+                    self.server.halt_on_conflicting_id();
+                }
+                Err(status_from_error(e))
+            }
+            Err(e) => Err(status_from_error(e)),
+        }
+    }
+
+    /// Authenticates the client that sent `request` from its bearer token.
+    ///
+    /// Returns the client's principal, or `None` if authentication is
+    /// disabled.
+    fn principal<T>(&self, request: &Request<T>) -> Result<Option<Principal>, tonic::Status> {
+        self.server.authenticate(bearer_token(request)).map_err(status_from_error)
+    }
+
+    /// Authenticates the client that sent `request` from its bearer token.
+    ///
+    /// Returns the client's role, or `None` if authentication is disabled.
+    fn authenticate<T>(&self, request: &Request<T>) -> Result<Option<Role>, tonic::Status> {
+        Ok(self.principal(request)?.map(|principal| principal.role))
+    }
+
+    /// Authenticates the client that sent `request`, and fails unless it
+    /// has the admin role, if authentication is enabled.
+    fn check_admin<T>(&self, request: &Request<T>) -> Result<(), tonic::Status> {
+        match self.authenticate(request)? {
+            None | Some(Role::Admin) => Ok(()),
+            Some(_) => Err(status_from_error(StoreError::AdminRoleRequired)),
+        }
+    }
+
+    /// Authenticates the client that sent `request`, and fails if it has
+    /// the read-only role.
+    fn check_writer<T>(&self, request: &Request<T>) -> Result<(), tonic::Status> {
+        match self.authenticate(request)? {
+            Some(Role::ReadOnly) => Err(status_from_error(StoreError::ReadOnlyRole)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Authenticates an RPC served to both peers and clients: requests
+    /// with the peer token pass, others must authenticate as a client
+    /// unless this is a peer listener.
+    fn check_peer_or_client<T>(&self, request: &Request<T>) -> Result<(), tonic::Status> {
+        if self.services != RpcServices::Client && self.check_peer_token(request).is_ok() {
+            return Ok(());
+        }
+        if self.services == RpcServices::Peer {
+            return Err(status_from_error(StoreError::Unauthenticated));
+        }
+        self.authenticate(request)?;
+        Ok(())
+    }
+
+    /// Times an RPC into the server's `rpc_latency` histogram of `method`.
+    fn timer(&self, method: &str) -> Timer {
+        Timer::new(self.server.metrics().histogram(&labelled("rpc_latency", "method", method)))
     }
 }
 
 #[tonic::async_trait]
 impl Rpc for RpcService {
+    type ExecuteStreamStream = Pin<Box<dyn Stream<Item = Result<QueryRow, tonic::Status>> + Send + Sync>>;
+    type SubscribeChangesStream = Pin<Box<dyn Stream<Item = Result<proto::ChangeEvent, tonic::Status>> + Send + Sync>>;
+    type FetchSnapshotStream = Pin<Box<dyn Stream<Item = Result<SnapshotChunk, tonic::Status>> + Send + Sync>>;
+
     async fn execute(
         &self,
         request: Request<Query>,
     ) -> Result<Response<QueryResults>, tonic::Status> {
+        let _timer = self.timer("execute");
+        self.check_client()?;
+        let principal = self.principal(&request)?;
+        let role = principal.as_ref().map(|principal| principal.role);
+        let client = client_identity(&request, principal.as_ref());
         let query = request.into_inner();
+        let consistency = match proto::Consistency::from_i32(query.consistency) {
+            Some(proto::Consistency::RelaxedReads) => Consistency::RelaxedReads,
+            Some(proto::Consistency::TableDefault) => Consistency::TableDefault,
+            _ => Consistency::Strong,
+        };
+        let options = QueryOptions {
+            params: query.params.into_iter().map(value_from_proto).collect(),
+            consistency,
+            request_id: query.request_id,
+            client,
+            read_snapshot: query.read_snapshot,
+            role,
+            dry_run: query.dry_run,
+            explain_analyze: query.explain_analyze,
+            min_replicas: query.min_replicas,
+            min_index: query.min_index,
+            min_index_configuration: query.min_index_configuration,
+            unacknowledged: query.unacknowledged,
+        };
         
         let server = self.server.clone();
-        let results = match server.query(query.sql).await {
+        let results = match server.query_with_options(query.sql, options).await {
             Ok(results) => results,
-            Err(e) => return Err(Status::internal(format!("{}", e))),
+            Err(e) => return Err(status_from_error(e)),
         };
 
-        let mut rows = vec![];
-        for row in results.rows {
-            rows.push(QueryRow {
-                values: row.values.clone(),
-            })
+        Ok(Response::new(proto_from_results(results)))
+    }
+
+    async fn execute_stream(
+        &self,
+        request: Request<Query>,
+    ) -> Result<Response<Self::ExecuteStreamStream>, tonic::Status> {
+        let _timer = self.timer("execute_stream");
+        self.check_client()?;
+        let principal = self.principal(&request)?;
+        let role = principal.as_ref().map(|principal| principal.role);
+        let client = client_identity(&request, principal.as_ref());
+        let query = request.into_inner();
+        let consistency = match proto::Consistency::from_i32(query.consistency) {
+            Some(proto::Consistency::RelaxedReads) => Consistency::RelaxedReads,
+            Some(proto::Consistency::TableDefault) => Consistency::TableDefault,
+            _ => Consistency::Strong,
+        };
+        let options = QueryOptions {
+            params: query.params.into_iter().map(value_from_proto).collect(),
+            consistency,
+            request_id: query.request_id,
+            client,
+            read_snapshot: query.read_snapshot,
+            role,
+            dry_run: query.dry_run,
+            explain_analyze: query.explain_analyze,
+            min_replicas: query.min_replicas,
+            min_index: query.min_index,
+            min_index_configuration: query.min_index_configuration,
+            unacknowledged: query.unacknowledged,
+        };
+
+        let server = self.server.clone();
+        let rows = match server.query_stream(query.sql, options).await {
+            Ok(rows) => rows,
+            Err(e) => return Err(status_from_error(e)),
+        };
+        let rows = rows.map(|row| match row {
+            Ok(row) => Ok(QueryRow { values: row.values }),
+            Err(e) => Err(status_from_error(e)),
+        });
+        Ok(Response::new(Box::pin(rows)))
+    }
+
+    async fn status(&self, request: Request<Void>) -> Result<Response<StatusReply>, tonic::Status> {
+        let _timer = self.timer("status");
+        self.check_peer_or_client(&request)?;
+        let server = self.server.clone();
+
+        Ok(Response::new(status_reply(&server)))
+    }
+
+    async fn cluster_metrics(&self, request: Request<Void>) -> Result<Response<ClusterMetricsReply>, tonic::Status> {
+        let _timer = self.timer("cluster_metrics");
+        self.check_peer_or_client(&request)?;
+        let server = self.server.clone();
+        let this_id = server.get_id();
+        let leader = server.get_current_leader();
+        if leader == 0 {
+            return Err(status_from_error(StoreError::NotLeader));
+        }
+        if leader != this_id {
+            // The leader knows how far behind each node is.
+            return Ok(Response::new(server.transport().fetch_cluster_metrics(leader).await?));
+        }
+
+        let own = status_reply(&server);
+        let peers: Vec<u64> = server.get_members().into_iter().filter(|&id| id != this_id).collect();
+        let sent_at_us = micros_since_epoch(server.wall_time()) as i64;
+        let statuses = futures::future::join_all(peers.iter().map(|&id| server.transport().fetch_status(id))).await;
+        let node_metrics = |id: u64, status: Option<(StatusReply, Duration)>| match status {
+            Some((status, rtt)) => {
+                // Staleness is measured on this node's clock only; the node's
+                // own clock is only compared to this one to report skew,
+                // assuming it replied halfway through the round trip.
+                let skew_ms = if id == this_id {
+                    0
+                } else {
+                    (status.wall_time_us as i64 - sent_at_us - rtt.as_micros() as i64 / 2) / 1000
+                };
+                NodeMetrics {
+                    id,
+                    reachable: true,
+                    decided_idx: status.decided_idx,
+                    lag: own.decided_idx.saturating_sub(status.decided_idx),
+                    in_flight: status.in_flight,
+                    apply_transactions: status.apply_transactions,
+                    leader_changes: status.leader_changes,
+                    rtt_ms: rtt.as_millis() as u64,
+                    staleness_ms: server.get_staleness(status.decided_idx).as_millis() as u64,
+                    clock_skew_ms: skew_ms,
+                    clock_skew_exceeded: id != this_id && server.check_clock_skew(id, skew_ms),
+                    throughput: status.decided_per_sec,
+                }
+            }
+            None => NodeMetrics {
+                id,
+                reachable: false,
+                ..Default::default()
+            },
+        };
+        let mut nodes = vec![node_metrics(this_id, Some((own.clone(), Duration::from_millis(0))))];
+        nodes.extend(peers.into_iter().zip(statuses).map(|(id, status)| node_metrics(id, status)));
+
+        Ok(Response::new(ClusterMetricsReply { leader, nodes }))
+    }
+
+    async fn bulk_import(
+        &self,
+        request: Request<tonic::Streaming<proto::ImportRow>>,
+    ) -> Result<Response<proto::ImportSummary>, tonic::Status> {
+        let _timer = self.timer("bulk_import");
+        self.check_client()?;
+        let principal = self.principal(&request)?;
+        let role = principal.as_ref().map(|principal| principal.role);
+        if role == Some(Role::ReadOnly) {
+            return Err(status_from_error(StoreError::ReadOnlyRole));
+        }
+        let options = QueryOptions {
+            client: client_identity(&request, principal.as_ref()),
+            role,
+            ..QueryOptions::default()
+        };
+        // A broken stream ends the import; the client is gone anyway.
+        let rows = request.into_inner().filter_map(|row| {
+            futures::future::ready(row.ok().map(|row| ImportRow {
+                table: row.table,
+                columns: row.columns,
+                values: row.values.into_iter().map(value_from_proto).collect(),
+            }))
+        });
+        let server = self.server.clone();
+        let summary = server.bulk_import(Box::pin(rows), options).await;
+        Ok(Response::new(proto::ImportSummary {
+            rows_imported: summary.rows_imported,
+            rows_failed: summary.rows_failed,
+            batches: summary.batches,
+            errors: summary
+                .errors
+                .into_iter()
+                .map(|e| proto::ImportBatchError {
+                    first_row: e.first_row,
+                    rows: e.rows,
+                    error: e.error,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn multi_read(&self, request: Request<MultiReadReq>) -> Result<Response<MultiReadReply>, tonic::Status> {
+        let _timer = self.timer("multi_read");
+        self.check_client()?;
+        self.authenticate(&request)?;
+        let req = request.into_inner();
+        let consistency = match proto::Consistency::from_i32(req.consistency) {
+            Some(proto::Consistency::RelaxedReads) => Consistency::RelaxedReads,
+            Some(proto::Consistency::TableDefault) => Consistency::TableDefault,
+            _ => Consistency::Strong,
+        };
+        let queries = req
+            .queries
+            .into_iter()
+            .map(|query| (query.sql, query.params.into_iter().map(value_from_proto).collect()))
+            .collect();
+        let server = self.server.clone();
+        let results = match server.multi_read(queries, consistency).await {
+            Ok(results) => results,
+            Err(e) => return Err(status_from_error(e)),
+        };
+
+        Ok(Response::new(MultiReadReply {
+            results: results.into_iter().map(proto_from_results).collect(),
+        }))
+    }
+
+    async fn migrate(&self, request: Request<MigrateReq>) -> Result<Response<QueryResults>, tonic::Status> {
+        let _timer = self.timer("migrate");
+        self.check_client()?;
+        if self.authenticate(&request)? == Some(Role::ReadOnly) {
+            return Err(status_from_error(StoreError::ReadOnlyRole));
+        }
+        let migration = request.into_inner();
+        let server = self.server.clone();
+        let results = match server.migrate(migration.version, migration.sql).await {
+            Ok(results) => results,
+            Err(e) => return Err(status_from_error(e)),
+        };
+
+        Ok(Response::new(proto_from_results(results)))
+    }
+
+    async fn leave_cluster(&self, request: Request<Void>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("leave_cluster");
+        self.check_client()?;
+        self.check_admin(&request)?;
+        let server = self.server.clone();
+        if let Err(e) = server.leave_cluster().await {
+            return Err(status_from_error(e));
+        }
+
+        Ok(Response::new(Void {}))
+    }
+
+    async fn create_read_snapshot(&self, request: Request<Void>) -> Result<Response<ReadSnapshot>, tonic::Status> {
+        let _timer = self.timer("create_read_snapshot");
+        self.check_client()?;
+        let principal = self.principal(&request)?;
+        let owner = client_identity(&request, principal.as_ref());
+        let server = self.server.clone();
+        match server.create_read_snapshot(&owner) {
+            Ok(id) => Ok(Response::new(ReadSnapshot { id })),
+            Err(e) => Err(status_from_error(e)),
+        }
+    }
+
+    async fn release_read_snapshot(&self, request: Request<ReadSnapshot>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("release_read_snapshot");
+        self.check_client()?;
+        let principal = self.principal(&request)?;
+        let owner = client_identity(&request, principal.as_ref());
+        let id = request.into_inner().id;
+        let server = self.server.clone();
+        if let Err(e) = server.release_read_snapshot(id, &owner) {
+            return Err(status_from_error(e));
         }
 
-        Ok(Response::new(QueryResults { rows }))
+        Ok(Response::new(Void {}))
+    }
+
+    async fn no_op(&self, request: Request<Void>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("no_op");
+        self.check_client()?;
+        self.check_writer(&request)?;
+        let server = self.server.clone();
+        if let Err(e) = server.noop().await {
+            return Err(status_from_error(e));
+        }
+
+        Ok(Response::new(Void {}))
     }
 
     async fn prepare(&self, request: Request<PrepareReq>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("prepare");
+        let authenticated = self.check_peer(&request)?;
+        let extensions = peer_extensions(&request);
         let msg = request.into_inner();
         let from = msg.from;
         let to = msg.to;
+        let config_id = msg.config_id;
 
         let n = ballot_from_proto(msg.n.unwrap());
         let n_accepted = ballot_from_proto(msg.n_accepted.unwrap());
@@ -654,15 +2619,17 @@ impl Rpc for RpcService {
         };
 
         let server = self.server.clone();
-        server.recv_sp_msg(msg);
-        
-        Ok(Response::new(Void {}))
+        self.reply_to_peer(authenticated, server.check_extensions(from, extensions).and_then(|_| server.recv_sp_msg(config_id, msg)))
     }
     
     async fn promise(&self, request: Request<PromiseReq>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("promise");
+        let authenticated = self.check_peer(&request)?;
+        let extensions = peer_extensions(&request);
         let msg = request.into_inner();
         let from = msg.from;
         let to = msg.to;
+        let config_id = msg.config_id;
 
         let n = ballot_from_proto(msg.n.unwrap());
         let n_accepted = ballot_from_proto(msg.n_accepted.unwrap());
@@ -696,15 +2663,20 @@ impl Rpc for RpcService {
         };
 
         let server = self.server.clone();
-        server.recv_sp_msg(msg);
-        
-        Ok(Response::new(Void {}))
+        self.reply_to_peer(authenticated, server.check_extensions(from, extensions).and_then(|_| server.recv_sp_msg(config_id, msg)))
     }
     
     async fn accept_sync(&self, request: Request<AcceptSyncReq>) -> Result<Response<Void>, tonic::Status> {
-        let msg = request.into_inner();
+        let _timer = self.timer("accept_sync");
+        let authenticated = self.check_peer(&request)?;
+        let extensions = peer_extensions(&request);
+        let msg = match self.server.transport().reassemble_sync(request.into_inner())? {
+            Some(msg) => msg,
+            None => return Ok(Response::new(Void {})),
+        };
         let from = msg.from;
         let to = msg.to;
+        let config_id = msg.config_id;
 
         let n = ballot_from_proto(msg.n.unwrap());
         
@@ -733,15 +2705,17 @@ impl Rpc for RpcService {
         };
 
         let server = self.server.clone();
-        server.recv_sp_msg(msg);
-        
-        Ok(Response::new(Void {}))
+        self.reply_to_peer(authenticated, server.check_extensions(from, extensions).and_then(|_| server.recv_sp_msg(config_id, msg)))
     }
     
     async fn first_accept(&self, request: Request<FirstAcceptReq>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("first_accept");
+        let authenticated = self.check_peer(&request)?;
+        let extensions = peer_extensions(&request);
         let msg = request.into_inner();
         let from = msg.from;
         let to = msg.to;
+        let config_id = msg.config_id;
 
         let n = ballot_from_proto(msg.n.unwrap());
         let entries = msg.entries.into_iter().map(|sc| store_command_from_proto(sc)).collect();
@@ -758,15 +2732,17 @@ impl Rpc for RpcService {
         };
 
         let server = self.server.clone();
-        server.recv_sp_msg(msg);
-        
-        Ok(Response::new(Void {}))
+        self.reply_to_peer(authenticated, server.check_extensions(from, extensions).and_then(|_| server.recv_sp_msg(config_id, msg)))
     }
     
     async fn accept_decide(&self, request: Request<AcceptDecideReq>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("accept_decide");
+        let authenticated = self.check_peer(&request)?;
+        let extensions = peer_extensions(&request);
         let msg = request.into_inner();
         let from = msg.from;
         let to = msg.to;
+        let config_id = msg.config_id;
 
         let n = ballot_from_proto(msg.n.unwrap());
         let ld = msg.ld;
@@ -785,15 +2761,17 @@ impl Rpc for RpcService {
         };
 
         let server = self.server.clone();
-        server.recv_sp_msg(msg);
-        
-        Ok(Response::new(Void {}))
+        self.reply_to_peer(authenticated, server.check_extensions(from, extensions).and_then(|_| server.recv_sp_msg(config_id, msg)))
     }
     
     async fn accepted(&self, request: Request<AcceptedReq>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("accepted");
+        let authenticated = self.check_peer(&request)?;
+        let extensions = peer_extensions(&request);
         let msg = request.into_inner();
         let from = msg.from;
         let to = msg.to;
+        let config_id = msg.config_id;
 
         let n = ballot_from_proto(msg.n.unwrap());
         let la = msg.la;
@@ -810,15 +2788,17 @@ impl Rpc for RpcService {
         };
 
         let server = self.server.clone();
-        server.recv_sp_msg(msg);
-        
-        Ok(Response::new(Void {}))
+        self.reply_to_peer(authenticated, server.check_extensions(from, extensions).and_then(|_| server.recv_sp_msg(config_id, msg)))
     }
     
     async fn decide(&self, request: Request<DecideReq>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("decide");
+        let authenticated = self.check_peer(&request)?;
+        let extensions = peer_extensions(&request);
         let msg = request.into_inner();
         let from = msg.from;
         let to = msg.to;
+        let config_id = msg.config_id;
 
         let n = ballot_from_proto(msg.n.unwrap());
         let ld = msg.ld;
@@ -835,15 +2815,17 @@ impl Rpc for RpcService {
         };
 
         let server = self.server.clone();
-        server.recv_sp_msg(msg);
-        
-        Ok(Response::new(Void {}))
+        self.reply_to_peer(authenticated, server.check_extensions(from, extensions).and_then(|_| server.recv_sp_msg(config_id, msg)))
     }
     
     async fn proposal_forward(&self, request: Request<ProposalForwardReq>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("proposal_forward");
+        let authenticated = self.check_peer(&request)?;
+        let extensions = peer_extensions(&request);
         let msg = request.into_inner();
         let from = msg.from;
         let to = msg.to;
+        let config_id = msg.config_id;
 
         let entries = msg.entries.into_iter().map(|sc| store_command_from_proto(sc)).collect();
 
@@ -854,15 +2836,17 @@ impl Rpc for RpcService {
         };
 
         let server = self.server.clone();
-        server.recv_sp_msg(msg);
-        
-        Ok(Response::new(Void {}))
+        self.reply_to_peer(authenticated, server.check_extensions(from, extensions).and_then(|_| server.recv_sp_msg(config_id, msg)))
     }
     
     async fn compaction(&self, request: Request<CompactionReq>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("compaction");
+        let authenticated = self.check_peer(&request)?;
+        let extensions = peer_extensions(&request);
         let msg = request.into_inner();
         let from = msg.from;
         let to = msg.to;
+        let config_id = msg.config_id;
 
         let compaction = match msg.compaction.unwrap() {
             proto::compaction_req::Compaction::Trim(trim) => {
@@ -880,15 +2864,17 @@ impl Rpc for RpcService {
         };
 
         let server = self.server.clone();
-        server.recv_sp_msg(msg);
-        
-        Ok(Response::new(Void {}))
+        self.reply_to_peer(authenticated, server.check_extensions(from, extensions).and_then(|_| server.recv_sp_msg(config_id, msg)))
     }
     
     async fn forward_compaction(&self, request: Request<ForwardCompactionReq>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("forward_compaction");
+        let authenticated = self.check_peer(&request)?;
+        let extensions = peer_extensions(&request);
         let msg = request.into_inner();
         let from = msg.from;
         let to = msg.to;
+        let config_id = msg.config_id;
 
         let compaction = match msg.compaction.unwrap() {
             proto::forward_compaction_req::Compaction::Trim(trim) => {
@@ -906,15 +2892,17 @@ impl Rpc for RpcService {
         };
 
         let server = self.server.clone();
-        server.recv_sp_msg(msg);
-        
-        Ok(Response::new(Void {}))
+        self.reply_to_peer(authenticated, server.check_extensions(from, extensions).and_then(|_| server.recv_sp_msg(config_id, msg)))
     }
     
     async fn accept_stop_sign(&self, request: Request<AcceptStopSignReq>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("accept_stop_sign");
+        let authenticated = self.check_peer(&request)?;
+        let extensions = peer_extensions(&request);
         let msg = request.into_inner();
         let from = msg.from;
         let to = msg.to;
+        let config_id = msg.config_id;
 
         let n = ballot_from_proto(msg.n.unwrap());
         let ss = stopsign_from_proto(msg.ss.unwrap());
@@ -931,15 +2919,17 @@ impl Rpc for RpcService {
         };
 
         let server = self.server.clone();
-        server.recv_sp_msg(msg);
-        
-        Ok(Response::new(Void {}))
+        self.reply_to_peer(authenticated, server.check_extensions(from, extensions).and_then(|_| server.recv_sp_msg(config_id, msg)))
     }
     
     async fn accepted_stop_sign(&self, request: Request<AcceptedStopSignReq>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("accepted_stop_sign");
+        let authenticated = self.check_peer(&request)?;
+        let extensions = peer_extensions(&request);
         let msg = request.into_inner();
         let from = msg.from;
         let to = msg.to;
+        let config_id = msg.config_id;
 
         let n = ballot_from_proto(msg.n.unwrap());
 
@@ -954,15 +2944,17 @@ impl Rpc for RpcService {
         };
 
         let server = self.server.clone();
-        server.recv_sp_msg(msg);
-        
-        Ok(Response::new(Void {}))
+        self.reply_to_peer(authenticated, server.check_extensions(from, extensions).and_then(|_| server.recv_sp_msg(config_id, msg)))
     }
     
     async fn decide_stop_sign(&self, request: Request<DecideStopSignReq>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("decide_stop_sign");
+        let authenticated = self.check_peer(&request)?;
+        let extensions = peer_extensions(&request);
         let msg = request.into_inner();
         let from = msg.from;
         let to = msg.to;
+        let config_id = msg.config_id;
 
         let n = ballot_from_proto(msg.n.unwrap());
 
@@ -977,12 +2969,13 @@ impl Rpc for RpcService {
         };
 
         let server = self.server.clone();
-        server.recv_sp_msg(msg);
-        
-        Ok(Response::new(Void {}))
+        self.reply_to_peer(authenticated, server.check_extensions(from, extensions).and_then(|_| server.recv_sp_msg(config_id, msg)))
     }
 
     async fn heartbeat_request(&self, request: Request<HeartbeatRequestReq>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("heartbeat_request");
+        let authenticated = self.check_peer(&request)?;
+        let extensions = peer_extensions(&request);
         let msg = request.into_inner();
         let from = msg.from;
         let to = msg.to;
@@ -1000,12 +2993,13 @@ impl Rpc for RpcService {
         };
 
         let server = self.server.clone();
-        server.recv_ble_msg(msg);
-        
-        Ok(Response::new(Void {}))
+        self.reply_to_peer(authenticated, server.check_extensions(from, extensions).and_then(|_| server.recv_ble_msg(msg)))
     }
 
     async fn heartbeat_reply(&self, request: Request<HeartbeatReplyReq>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("heartbeat_reply");
+        let authenticated = self.check_peer(&request)?;
+        let extensions = peer_extensions(&request);
         let msg = request.into_inner();
         let from = msg.from;
         let to = msg.to;
@@ -1027,8 +3021,229 @@ impl Rpc for RpcService {
         };
 
         let server = self.server.clone();
-        server.recv_ble_msg(msg);
-        
+        self.reply_to_peer(authenticated, server.check_extensions(from, extensions).and_then(|_| server.recv_ble_msg(msg)))
+    }
+
+    async fn learner_fetch(&self, request: Request<LearnerFetchReq>) -> Result<Response<LearnerFetchReply>, tonic::Status> {
+        let _timer = self.timer("learner_fetch");
+        self.check_peer(&request)?;
+        let msg = request.into_inner();
+
+        let server = self.server.clone();
+        let entries = server.get_decided_entries(msg.from_idx);
+        let entries = entries.into_iter().map(|e| proto_from_store_command(e)).collect();
+
+        Ok(Response::new(LearnerFetchReply { entries }))
+    }
+
+    async fn fetch_snapshot(
+        &self,
+        request: Request<Void>,
+    ) -> Result<Response<Self::FetchSnapshotStream>, tonic::Status> {
+        let _timer = self.timer("fetch_snapshot");
+        self.check_peer(&request)?;
+
+        let server = self.server.clone();
+        let snapshot = match server.database_snapshot().await {
+            Ok(snapshot) => snapshot,
+            Err(e) => return Err(status_from_error(e)),
+        };
+        let chunks: Vec<Result<SnapshotChunk, tonic::Status>> = snapshot
+            .data
+            .chunks(SNAPSHOT_CHUNK_BYTES)
+            .map(|data| Ok(SnapshotChunk {
+                configuration_id: snapshot.configuration_id,
+                decided_idx: snapshot.decided_idx,
+                data: data.to_vec(),
+            }))
+            .collect();
+        Ok(Response::new(Box::pin(futures::stream::iter(chunks))))
+    }
+
+    async fn remove_member(&self, request: Request<RemoveMemberReq>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("remove_member");
+        self.check_peer(&request)?;
+        let msg = request.into_inner();
+
+        let server = self.server.clone();
+        if let Err(e) = server.remove_member(msg.node_id) {
+            return Err(status_from_error(e));
+        }
+
+        Ok(Response::new(Void {}))
+    }
+
+    async fn dump_log(&self, request: Request<DumpLogReq>) -> Result<Response<DumpLogReply>, tonic::Status> {
+        let _timer = self.timer("dump_log");
+        self.check_client()?;
+        self.check_admin(&request)?;
+        let msg = request.into_inner();
+
+        let server = self.server.clone();
+        let entries = match server.dump_log(msg.from, msg.to) {
+            Ok(entries) => entries,
+            Err(e) => return Err(status_from_error(e)),
+        };
+        let entries = entries.into_iter().map(|e| proto_from_store_command(e)).collect();
+
+        Ok(Response::new(DumpLogReply { entries }))
+    }
+
+    async fn table_checksum(&self, request: Request<TableChecksumReq>) -> Result<Response<TableChecksumReply>, tonic::Status> {
+        let _timer = self.timer("table_checksum");
+        self.check_client()?;
+        self.check_admin(&request)?;
+        let msg = request.into_inner();
+
+        let server = self.server.clone();
+        let checksum = match server.table_checksum(&msg.table) {
+            Ok(checksum) => checksum,
+            Err(e) => return Err(status_from_error(e)),
+        };
+
+        Ok(Response::new(TableChecksumReply {
+            decided_idx: checksum.decided_idx,
+            rows: checksum.rows,
+            checksum: checksum.checksum,
+        }))
+    }
+
+    async fn storage_stats(&self, request: Request<Void>) -> Result<Response<proto::StorageStatsReply>, tonic::Status> {
+        let _timer = self.timer("storage_stats");
+        self.check_client()?;
+        self.authenticate(&request)?;
+
+        let server = self.server.clone();
+        let stats = match server.storage_stats().await {
+            Ok(stats) => stats,
+            Err(e) => return Err(status_from_error(e)),
+        };
+
+        Ok(Response::new(proto::StorageStatsReply {
+            decided_idx: stats.decided_idx,
+            page_count: stats.page_count,
+            page_size: stats.page_size,
+            freelist_count: stats.freelist_count,
+            tables: stats
+                .tables
+                .into_iter()
+                .map(|table| proto::TableStats {
+                    name: table.name,
+                    rows: table.rows,
+                    pages: table.pages,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn subscribe_changes(
+        &self,
+        request: Request<Void>,
+    ) -> Result<Response<Self::SubscribeChangesStream>, tonic::Status> {
+        let _timer = self.timer("subscribe_changes");
+        self.check_client()?;
+        // the stream carries the statements and parameters of every write
+        self.check_admin(&request)?;
+        let server = self.server.clone();
+        let changes = match server.subscribe_changes() {
+            Ok(changes) => changes,
+            Err(e) => return Err(status_from_error(e)),
+        };
+        let changes = changes.map(|event| match event {
+            Ok(ChangeEvent::Write(change)) => Ok(proto::ChangeEvent {
+                event: Some(proto::change_event::Event::Write(Change {
+                    configuration_id: change.configuration_id,
+                    log_idx: change.log_idx,
+                    client: change.client,
+                    sql: change.sql,
+                    params: change.params.into_iter().map(proto_from_value).collect(),
+                })),
+            }),
+            Ok(ChangeEvent::Gap { missed }) => Ok(proto::ChangeEvent {
+                event: Some(proto::change_event::Event::Gap(missed)),
+            }),
+            Err(e) => Err(status_from_error(e)),
+        });
+        Ok(Response::new(Box::pin(changes)))
+    }
+
+    async fn update_node_address(&self, request: Request<UpdateNodeAddressReq>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("update_node_address");
+        self.check_client()?;
+        self.check_admin(&request)?;
+        let token = bearer_token(&request).map(str::to_string);
+        let msg = request.into_inner();
+
+        let server = self.server.clone();
+        if let Err(e) = server.update_node_address(msg.node_id, &msg.addr) {
+            return Err(status_from_error(e));
+        }
+        if !msg.local {
+            // The moved node itself doesn't send to its own address.
+            let members = server.get_members();
+            let others = members.into_iter().filter(|&id| id != server.get_id() && id != msg.node_id);
+            for id in others {
+                server.transport().forward_node_address(id, msg.node_id, &msg.addr, token.as_deref()).await?;
+            }
+        }
+
+        Ok(Response::new(Void {}))
+    }
+
+    async fn set_maintenance_mode(&self, request: Request<MaintenanceModeReq>) -> Result<Response<Void>, tonic::Status> {
+        let _timer = self.timer("set_maintenance_mode");
+        self.check_client()?;
+        self.check_admin(&request)?;
+        let msg = request.into_inner();
+
+        let server = self.server.clone();
+        if let Err(e) = server.set_maintenance_mode(msg.enabled).await {
+            return Err(status_from_error(e));
+        }
+
         Ok(Response::new(Void {}))
     }
+
+    async fn apply_errors(&self, request: Request<Void>) -> Result<Response<ApplyErrorsReply>, tonic::Status> {
+        let _timer = self.timer("apply_errors");
+        self.check_client()?;
+        self.check_admin(&request)?;
+        let server = self.server.clone();
+        let errors = match server.apply_errors() {
+            Ok(errors) => errors,
+            Err(e) => return Err(status_from_error(e)),
+        };
+        let errors = errors
+            .into_iter()
+            .map(|e| proto::ApplyError {
+                log_idx: e.log_idx,
+                command_id: e.command_id,
+                request_id: e.request_id,
+                sql: e.sql,
+                error: e.error,
+            })
+            .collect();
+
+        Ok(Response::new(ApplyErrorsReply { errors }))
+    }
+
+    async fn epochs(&self, request: Request<Void>) -> Result<Response<EpochsReply>, tonic::Status> {
+        let _timer = self.timer("epochs");
+        self.check_client()?;
+        self.authenticate(&request)?;
+        let server = self.server.clone();
+        let epochs = match server.epochs() {
+            Ok(epochs) => epochs,
+            Err(e) => return Err(status_from_error(e)),
+        };
+        let epochs = epochs
+            .into_iter()
+            .map(|e| proto::Epoch {
+                ballot: Some(proto_from_ballot(e.ballot)),
+                first_idx: e.first_idx,
+            })
+            .collect();
+
+        Ok(Response::new(EpochsReply { epochs }))
+    }
 }
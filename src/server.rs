@@ -1,20 +1,35 @@
 //! ChiselStore server module.
 
+use crate::audit::AuditLog;
+use crate::changes::{ChangeFeed, ChangeOverflowPolicy, ChangeSubscription};
+use crate::clock::{Clock, SystemClock};
 use crate::errors::StoreError;
+use crate::import::{quote_identifier, ImportBatch, ImportRow, ImportSummary};
+use crate::metrics::{labelled, Counter, Gauge, Histogram, Registry, Timer};
+use crate::sql;
+use crate::util::log::log;
 use async_notify::Notify;
 use async_trait::async_trait;
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, Duration, Instant};
 use derivative::Derivative;
-use sqlite::{Connection, OpenFlags};
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicU64, Ordering};
+use futures::{Stream, StreamExt};
+use sqlite::{Connection, OpenFlags, State, Value};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, watch, Semaphore};
 use omnipaxos_core::{
     ballot_leader_election::{BLEConfig, BallotLeaderElection, Ballot},
-    ballot_leader_election::messages::BLEMessage,
+    ballot_leader_election::messages::{BLEMessage, HeartbeatMsg},
     sequence_paxos::{SequencePaxos, SequencePaxosConfig},
     storage::{Storage, Snapshot, StopSignEntry},
-    messages::Message,
+    messages::{Message, PaxosMsg},
+    util::{LogEntry, SyncItem},
 };
 
 /// ChiselStore transport layer.
@@ -23,9 +38,73 @@ use omnipaxos_core::{
 /// to the ChiselStore server.
 #[async_trait]
 pub trait StoreTransport {
-    /// Send a store command message `msg` to `to_id` node.
-    fn send_sp(&self, to_id: u64, msg: Message<StoreCommand, ()>);
+    /// Send a store command message `msg` of configuration `config_id` to
+    /// `to_id` node.
+    ///
+    /// Sequence paxos expects the messages to a node to be delivered in
+    /// order: a message that is lost must be reported by `take_lost_peers`,
+    /// so that the node is resynchronized. Client proposals forwarded to the
+    /// leader are not part of the log: if one is dropped, fails with
+    /// `StoreError::ProposalDropped` so that its commands fail.
+    fn send_sp(&self, to_id: u64, config_id: u32, msg: Message<StoreCommand, ()>) -> Result<(), StoreError>;
+    /// Send a ballot leader election message `msg` to `to_id` node.
     fn send_ble(&self, to_id: u64, msg: BLEMessage);
+    /// Fetch the decided log entries starting at `from_idx` from `to_id` node.
+    ///
+    /// Used by learners to follow the log. Returns `None` if the node could
+    /// not be reached, as does the default, so that only transports that
+    /// implement it can serve learners.
+    async fn fetch_decided(&self, _to_id: u64, _from_idx: u64) -> Option<Vec<StoreCommand>> {
+        None
+    }
+    /// Fetch a copy of the database of `to_id` node.
+    ///
+    /// Used by followers whose log is behind the trimmed log of the leader.
+    /// Returns `None` if the node could not be reached, as does the default.
+    async fn fetch_snapshot(&self, _to_id: u64) -> Option<DatabaseSnapshot> {
+        None
+    }
+    /// Check whether `to_id` node is reachable, waiting for it for a
+    /// bounded time.
+    ///
+    /// Returns `None` if the transport can't probe nodes, as does the
+    /// default, in which case a node counts as reachable while it replies to
+    /// this node's heartbeats.
+    async fn probe(&self, _to_id: u64) -> Option<bool> {
+        None
+    }
+    /// Ask `to_id` node, the leader, to remove node `node_id`, this node,
+    /// from the cluster.
+    async fn remove_member(&self, _to_id: u64, _node_id: u64) -> Result<(), StoreError> {
+        Err(StoreError::Reconfiguration(String::from("the transport can't forward to the leader")))
+    }
+    /// Called when this node adopts a new configuration, with the other
+    /// members of it.
+    ///
+    /// Nothing is sent to nodes that are not among them anymore, so the
+    /// transport may cancel the messages still queued to them and drop
+    /// those that follow, instead of failing to deliver them.
+    fn set_peers(&self, _peers: &[u64]) {}
+    /// Called when the server starts, with the fingerprint of its SQL
+    /// extensions.
+    ///
+    /// The transport should send it along with every message, so that the
+    /// receiver can check it with [`StoreServer::check_extensions`].
+    fn set_extensions(&self, _fingerprint: u64) {}
+    /// Called when node `node_id` moved to address `addr`, to route the
+    /// messages to it there from now on.
+    fn update_node_address(&self, _node_id: u64, _addr: &str) {}
+    /// Nodes that sequence paxos messages were lost to since they were last
+    /// taken, to be resynchronized as if they had reconnected.
+    fn take_lost_peers(&self) -> Vec<u64> {
+        Vec::new()
+    }
+    /// Commands of the forwarded proposals that were lost after `send_sp`
+    /// accepted them, since they were last taken, each with the node it was
+    /// forwarded to.
+    fn take_dropped_proposals(&self) -> Vec<(u64, u64)> {
+        Vec::new()
+    }
 }
 
 /// Store command.
@@ -37,6 +116,1119 @@ pub struct StoreCommand {
     pub id: u64,
     /// The SQL statement of this command.
     pub sql: String,
+    /// Values bound to the `?` placeholders of the SQL statement, in order.
+    pub params: Vec<Value>,
+    /// Commands coalesced into this log entry by the write buffer.
+    ///
+    /// When non-empty, `sql` is ignored and the batched commands are applied
+    /// in order, each reporting its own result.
+    pub batch: Vec<StoreCommand>,
+    /// Schema version this command migrates the database to, or zero if the
+    /// command is not a migration.
+    pub schema_version: u64,
+    /// Client-chosen id of the request that proposed this command, or zero.
+    ///
+    /// A command whose request id was applied recently is not applied again,
+    /// so clients can safely retry proposals.
+    pub request_id: u64,
+    /// Identity of the client that proposed this command, for auditing, or
+    /// empty if unknown.
+    pub client: String,
+    /// Node the command was submitted to, which forwarded it to the leader
+    /// if it was another node, or zero if unknown.
+    pub origin: u64,
+    /// The statements of `sql`, each with the values bound to its
+    /// placeholders, for commands in the structured `CommandFormat`.
+    ///
+    /// When non-empty, the command is applied from its statements, and
+    /// `sql` and `params` are empty so that the SQL isn't carried twice: see
+    /// [`StoreCommand::sql_text`].
+    pub statements: Vec<CommandStatement>,
+    /// What the command does when applied.
+    pub kind: CommandKind,
+}
+
+/// What a command does when applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandKind {
+    /// Executes its SQL.
+    Sql,
+    /// Puts the cluster in maintenance mode; its SQL is ignored.
+    EnterMaintenance,
+    /// Takes the cluster out of maintenance mode; its SQL is ignored.
+    ExitMaintenance,
+}
+
+/// A statement of a command in the structured `CommandFormat`.
+#[derive(Clone, Debug)]
+pub struct CommandStatement {
+    /// The SQL of the statement, a single statement.
+    pub sql: String,
+    /// Values bound to the `?` placeholders of the statement, in order.
+    pub params: Vec<Value>,
+}
+
+impl CommandStatement {
+    /// Splits `sql` into its statements, each with the values of `params`
+    /// bound to its placeholders.
+    fn split(sql: &str, params: Vec<Value>) -> Vec<CommandStatement> {
+        let mut params = params.into_iter();
+        sql::statements(sql)
+            .into_iter()
+            .map(|stmt| CommandStatement {
+                sql: stmt.to_string(),
+                params: params.by_ref().take(sql::placeholders(stmt)).collect(),
+            })
+            .collect()
+    }
+}
+
+impl StoreCommand {
+    /// Values bound to the placeholders of the command, in order, whatever
+    /// its format.
+    pub fn bound_params(&self) -> Vec<Value> {
+        if self.statements.is_empty() {
+            return self.params.clone();
+        }
+        self.statements.iter().flat_map(|stmt| stmt.params.iter().cloned()).collect()
+    }
+
+    /// The SQL of the command, whatever its format: that of its statements,
+    /// one per line, if it is structured.
+    pub fn sql_text(&self) -> Cow<'_, str> {
+        if self.statements.is_empty() {
+            return Cow::Borrowed(&self.sql);
+        }
+        // A statement may end in a line comment, so each ends its line.
+        Cow::Owned(self.statements.iter().map(|stmt| format!("{};\n", stmt.sql)).collect())
+    }
+
+    /// Approximate size of the command in the log, in bytes: that of its
+    /// SQL and parameters.
+    pub(crate) fn size(&self) -> usize {
+        fn params_size(params: &[Value]) -> usize {
+            params
+                .iter()
+                .map(|param| match param {
+                    Value::Binary(bytes) => bytes.len(),
+                    Value::String(s) => s.len(),
+                    _ => 8,
+                })
+                .sum()
+        }
+        self.sql.len()
+            + params_size(&self.params)
+            + self.batch.iter().map(|cmd| cmd.size()).sum::<usize>()
+            + self.statements.iter().map(|stmt| stmt.sql.len() + params_size(&stmt.params)).sum::<usize>()
+    }
+}
+
+/// How the SQL of commands is stored in the log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandFormat {
+    /// As the SQL text and parameters submitted, split into statements and
+    /// matched with their parameters every time the command is applied.
+    Sql,
+    /// Split into statements once, when proposed, each with its own
+    /// parameters, so that applying a command only prepares and runs them,
+    /// and a statement can never be bound another's parameters.
+    ///
+    /// Nodes of a cluster may use different formats: every node applies
+    /// commands in either format.
+    Structured,
+}
+
+/// Store server configuration.
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub struct StoreServerConfig {
+    /// How long a write may wait to be coalesced with other writes into a
+    /// single log entry. Zero disables the write buffer.
+    pub write_buffer_linger: Duration,
+    /// Maximum number of writes coalesced into a single log entry.
+    pub write_buffer_max_batch: usize,
+    /// How long after this node becomes leader writes skip the write buffer.
+    ///
+    /// Writes buffered during the election are proposed at once, with the
+    /// first accept of the new round, and so are the writes that follow,
+    /// instead of lingering. This trades batching for latency right after a
+    /// leader change. Zero always lingers.
+    pub leader_warmup: Duration,
+    /// How long SQLite waits on a locked database before reporting `SQLITE_BUSY`.
+    pub busy_timeout: Duration,
+    /// How the SQL of the commands this node proposes is stored in the log.
+    pub command_format: CommandFormat,
+    /// Journal mode of the database.
+    pub journal_mode: JournalMode,
+    /// Where SQLite keeps temporary tables and indices, including those of
+    /// large sorts and joins.
+    pub temp_store: TempStore,
+    /// Directory of the temporary files of SQLite, when they are kept in
+    /// files; `None` uses SQLite's default, such as `/tmp`.
+    ///
+    /// Large sorts and joins can fill up a small `/tmp`, failing queries.
+    /// SQLite has a single temporary directory per process, so it is set
+    /// once when the server starts and applies to every database of the
+    /// process.
+    pub temp_store_directory: Option<PathBuf>,
+    /// How many times a command that hit `SQLITE_BUSY` is retried, with
+    /// exponential backoff, before the error is reported. The commands
+    /// decided after it wait for it.
+    pub busy_retries: u32,
+    /// Run this node as a non-voting learner.
+    ///
+    /// A learner follows the decided log of its peers and serves reads, but
+    /// never votes or becomes leader. Its peers must not list it as a peer,
+    /// so it never counts toward quorum.
+    pub learner: bool,
+    /// Serve reads on this learner from snapshots, for analytical queries.
+    ///
+    /// Each read runs in its own read transaction on a dedicated connection,
+    /// so it sees a consistent snapshot of the database and long scans don't
+    /// hold up applying the log. Only takes effect on learners.
+    pub analytics: bool,
+    /// What to do when a decided command fails to apply because of a node
+    /// fault, such as an I/O error or a persistently locked database.
+    pub apply_error_policy: ApplyErrorPolicy,
+    /// Maximum number of decided commands applied in a single SQLite
+    /// transaction. One applies every command in its own transaction.
+    ///
+    /// Each command still runs in its own savepoint and reports its own
+    /// results. Commands that control transactions themselves, such as
+    /// `transaction()` or migrations, are always applied on their own.
+    pub apply_batch_size: usize,
+    /// Key the database is encrypted at rest with, using SQLCipher.
+    ///
+    /// Requires the `sqlcipher` feature, which links SQLCipher in place of
+    /// SQLite. The server fails to start if SQLCipher is not linked, or if
+    /// the key doesn't match the database.
+    #[derivative(Debug = "ignore")]
+    pub encryption_key: Option<String>,
+    /// Clock driving the server's timeouts: heartbeats and elections, the
+    /// write buffer linger, leader change checks and learner fetches.
+    pub clock: Arc<dyn Clock>,
+    /// Enable admin operations, such as dumping the log.
+    ///
+    /// These expose the raw contents of the log, so they are disabled by default.
+    pub admin: bool,
+    /// Ids of all the nodes in the cluster, including this one.
+    ///
+    /// Required: the server refuses to start without it, or unless its own
+    /// id and all of its peers are members.
+    pub membership: Vec<u64>,
+    /// Maximum number of rows a streaming query reads ahead of its consumer.
+    ///
+    /// Once the buffer is full, SQLite is not stepped again until the
+    /// consumer catches up.
+    pub stream_buffer_rows: usize,
+    /// Maximum number of rows of a bulk import inserted by one log entry.
+    ///
+    /// The rows of a batch are applied in one transaction, so a batch is
+    /// imported entirely or not at all. Larger batches make fewer round
+    /// trips through consensus, but larger log entries.
+    pub import_batch_rows: usize,
+    /// Capacity of the queue of sequence paxos messages received from each
+    /// peer. Zero disables the queues.
+    ///
+    /// When enabled, received messages are queued and handled by a task per
+    /// peer, in order, so that a burst of messages doesn't hold up the RPC
+    /// handlers. Messages that arrive when a queue is full are rejected, so
+    /// that the peer resynchronizes, and counted in the `inbound_dropped`
+    /// metric of the peer. The tasks stop when the node halts.
+    pub inbound_queue_capacity: usize,
+    /// Maximum number of queries executed at once by this node. Zero
+    /// disables the limit.
+    ///
+    /// Queries over the limit wait for one to finish. The time queries wait
+    /// is measured by the `query_wait` histogram, apart from the time they
+    /// take to execute, measured by `query_execution`.
+    pub max_concurrent_queries: usize,
+    /// How often a replicated command waiting to be decided checks whether
+    /// leadership changed. Zero disables the check.
+    ///
+    /// A command proposed under a leader that lost leadership may never be
+    /// decided, so when the leader changes the wait fails with
+    /// `StoreError::LeaderChanged` instead of hanging.
+    pub leader_change_check_interval: Duration,
+    /// How long a replicated command may wait to be decided before it fails
+    /// with `StoreError::CommitTimeout`, e.g. while a quorum is lost.
+    /// `None` waits until it is decided, however long that takes.
+    ///
+    /// A command that timed out may still be decided later.
+    pub commit_timeout: Option<Duration>,
+    /// How long commands may wait without the decided index advancing before
+    /// consensus is reported as stalled. Zero disables the detection.
+    ///
+    /// Consensus stalls when, for example, a quorum is lost. Commands then
+    /// wait until it is back; a stall is reported by `Status`, the
+    /// `consensus_stalled` metric and in the log, instead of passing silently.
+    pub stall_window: Duration,
+    /// Maximum difference tolerated between the wall clock of a node and
+    /// the leader's.
+    ///
+    /// The leader estimates the skew of every node when gathering cluster
+    /// metrics; a node beyond the tolerance is reported by `ClusterMetrics`,
+    /// the `clock_skew_ms` metric and in the log. Skew doesn't affect
+    /// correctness; see the [`clock`](crate::clock) module.
+    pub max_clock_skew: Duration,
+    /// Number of most recently active requests remembered for deduplicating
+    /// retried proposals. Zero disables deduplication.
+    ///
+    /// A retry of a remembered request gets the rows it was applied with
+    /// instead of being applied again. Its size is the `dedup_requests` metric.
+    pub dedup_window: usize,
+    /// Number of log entries after which a request that was not retried is
+    /// evicted from the dedup window. Zero only evicts when the window is full.
+    ///
+    /// Evicting a request weakens its at-most-once guarantee: a retry that
+    /// arrives after the request was evicted is applied again. Each retry
+    /// within the horizon counts as activity and keeps the request. A
+    /// request last active in an earlier configuration counts as active
+    /// when the current one started, whose log starts over.
+    pub dedup_idle_horizon: u64,
+    /// Maximum number of rows a query returns. Zero doesn't limit results.
+    ///
+    /// A safety cap against accidentally huge results, which are buffered in
+    /// full on the server. Streaming queries are not limited.
+    pub max_result_rows: usize,
+    /// What to do with results over `max_result_rows`.
+    pub result_limit_policy: ResultLimitPolicy,
+    /// Maximum number of rows a decided command may return when applied,
+    /// such as the rows of an `INSERT ... RETURNING` or of a strong read.
+    /// Zero doesn't limit them.
+    ///
+    /// The rows are buffered while the command is applied, before
+    /// `max_result_rows` is checked. A command over the limit fails with
+    /// `StoreError::TooManyApplyRows` and its writes are rolled back, so every
+    /// node must have the same limit.
+    pub max_apply_rows: usize,
+    /// Number of decided entries after which the leader snapshots the
+    /// database and trims the log. Zero disables it.
+    ///
+    /// The snapshot is a copy of the database written to `node<id>.snapshot.db`
+    /// from a read transaction, so it doesn't hold up applying the log.
+    /// A follower behind the trimmed index, e.g. one restarted without its
+    /// database, catches up from a copy of the leader's database, counted
+    /// by the `snapshots_restored` metric. Learners that are behind the
+    /// trimmed index can no longer catch up.
+    pub snapshot_interval_entries: u64,
+    /// How often the leader snapshots the database and trims the log, if
+    /// any entries were decided since the last snapshot. Zero disables it.
+    pub snapshot_interval: Duration,
+    /// Size of the log, in bytes, beyond which the leader snapshots the
+    /// database and trims the log. Zero disables it.
+    ///
+    /// The size is that of the SQL and parameters of the entries not trimmed
+    /// yet, reported by the `log_bytes` gauge. The log is kept in memory, so
+    /// this bounds the memory it takes rather than disk usage: the database
+    /// is what ChiselStore persists.
+    pub snapshot_log_bytes: u64,
+    /// Minimum time between snapshots triggered by `snapshot_log_bytes`, so
+    /// that a log that stays over the threshold, e.g. because of a few huge
+    /// entries, isn't snapshotted over and over.
+    pub snapshot_min_interval: Duration,
+    /// Number of snapshots kept on disk. Zero keeps only the latest one, in
+    /// `node<id>.snapshot.db`.
+    ///
+    /// Otherwise each snapshot is written to `node<id>.snapshot.<idx>.db`,
+    /// where `idx` is the decided index it covers, and older snapshots
+    /// beyond the latest ones are deleted, unless they are pinned with
+    /// [`StoreServer::pin_snapshot`] or a follower hasn't accepted the log
+    /// up to their index yet.
+    pub snapshot_retention: usize,
+    /// What to do with sequence paxos messages for a configuration this node
+    /// has not adopted yet.
+    pub future_config_policy: FutureConfigPolicy,
+    /// What to do when the schema of the database doesn't match the schema
+    /// this node last applied, checked when the node starts.
+    ///
+    /// The schema is recorded in the database, in the transaction of every
+    /// command changing it.
+    pub schema_mismatch_policy: SchemaMismatchPolicy,
+    /// File this node appends every committed write to, with the time it was
+    /// applied, its log index, the client and the SQL. `None` disables it.
+    pub audit_log: Option<PathBuf>,
+    /// Replace the literals of the SQL in the audit log and the SQL trace
+    /// log with `?`, so that they don't hold the data written.
+    pub audit_redact_sql: bool,
+    /// File this node traces every command it applies to, reads included,
+    /// with the values of its parameters bound in place of the placeholders,
+    /// for debugging. `None` disables it.
+    ///
+    /// The bound values are the data written, so they are redacted with
+    /// `audit_redact_sql`.
+    pub sql_trace_log: Option<PathBuf>,
+    /// Maximum number of committed writes buffered for each change feed
+    /// subscriber that hasn't received them yet.
+    pub change_buffer_capacity: usize,
+    /// What to do with a change feed subscriber whose buffer is full.
+    ///
+    /// Committed writes are published without waiting on subscribers, so a
+    /// slow subscriber never slows down applying the log.
+    pub change_overflow_policy: ChangeOverflowPolicy,
+    /// How long a read snapshot is kept without being queried before it is
+    /// released, e.g. because its client went away.
+    pub read_snapshot_timeout: Duration,
+    /// Bearer tokens accepted from clients and the principal each
+    /// authenticates. Empty disables authentication.
+    #[derivative(Debug = "ignore")]
+    pub auth_tokens: HashMap<String, Principal>,
+    /// SQL extensions set up on every connection, by name and version. See
+    /// [`StoreServerConfig::register_extension`].
+    #[derivative(Debug = "ignore")]
+    pub extensions: Vec<(String, String, Arc<SqlExtension>)>,
+    /// Consistency of queries run with `Consistency::TableDefault`, by the
+    /// name of the tables they touch, lowercased.
+    ///
+    /// Since writes are always replicated, this only decides how reads are
+    /// served, e.g. strong for configuration tables and relaxed for
+    /// high-volume tables that tolerate stale reads. The tables are found
+    /// from the names following `FROM`, `JOIN`, `INTO` and `UPDATE`, so
+    /// those read through a view have the consistency of the view.
+    pub table_consistency: HashMap<String, Consistency>,
+}
+
+/// Setup of a SQL extension on a connection, such as registering
+/// application-defined functions or collations, or loading an extension.
+pub type SqlExtension = dyn Fn(&Connection) -> Result<(), sqlite::Error> + Send + Sync;
+
+/// Privileges granted to an authenticated client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Only read-only statements may be executed.
+    ReadOnly,
+    /// Any statement may be executed.
+    ReadWrite,
+    /// Any statement may be executed, and admin operations performed.
+    Admin,
+}
+
+/// Client that a bearer token authenticates.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Principal {
+    /// Name of the client, recorded as the client identity of its writes,
+    /// e.g. in the audit log.
+    pub name: String,
+    /// Privileges granted to the client.
+    pub role: Role,
+}
+
+impl Principal {
+    /// Creates the principal `name` with the role `role`.
+    pub fn new<S: Into<String>>(name: S, role: Role) -> Self {
+        Self { name: name.into(), role }
+    }
+}
+
+/// Policy for commands that fail to apply because of a node fault.
+///
+/// Errors that SQLite reports identically on every node, like constraint
+/// violations, are not node faults: they are always returned to the client
+/// and the node continues applying the log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApplyErrorPolicy {
+    /// Stop the node, so that it cannot diverge from the rest of the cluster.
+    Halt,
+    /// Log the error, report it to the client and continue applying the log.
+    ///
+    /// The node may diverge from the rest of the cluster.
+    Skip,
+}
+
+/// Journal mode of the database, the `journal_mode` pragma.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalMode {
+    /// Write-ahead log. Streaming queries keep a read transaction open for
+    /// as long as their consumer takes, which doesn't block the writes
+    /// applying the log.
+    Wal,
+    /// Rollback journal, deleted when a transaction commits. Readers block
+    /// writers, so a slow streaming query holds up applying the log.
+    Delete,
+    /// Rollback journal, truncated when a transaction commits.
+    Truncate,
+    /// Rollback journal, kept with its header zeroed when a transaction
+    /// commits.
+    Persist,
+}
+
+/// Where SQLite keeps temporary tables and indices, the `temp_store` pragma.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TempStore {
+    /// As SQLite was built to, in files unless configured otherwise.
+    Default,
+    /// In files, in the `temp_store_directory`.
+    File,
+    /// In memory.
+    Memory,
+}
+
+/// Policy for query results over the maximum number of rows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResultLimitPolicy {
+    /// Fail a read with `StoreError::TooManyRows`.
+    ///
+    /// A write has been committed by then, so rather than failing it, its
+    /// results are truncated as with `Truncate`.
+    Error,
+    /// Return the first rows, with a warning that the results were truncated.
+    Truncate,
+}
+
+/// Policy for messages of a configuration newer than this node's, received
+/// while a reconfiguration is in progress.
+///
+/// Such messages must not be handled by the current configuration, whose
+/// membership differs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FutureConfigPolicy {
+    /// Keep the messages and handle them once the node adopts the
+    /// configuration, which saves the sender from resynchronizing the node.
+    /// Messages over the buffer's capacity are rejected.
+    Buffer,
+    /// Reject the messages with `StoreError::UnknownConfiguration`, so that
+    /// the sender resynchronizes the node.
+    Reject,
+}
+
+/// What to do when the schema of the database a node starts from doesn't
+/// match the schema the node last applied, e.g. because the database was
+/// modified behind its back or restored from a backup of another version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchemaMismatchPolicy {
+    /// Log the mismatch and start all the same. The node may diverge from
+    /// the rest of the cluster.
+    Ignore,
+    /// Fail to start with `StoreError::SchemaMismatch`.
+    Refuse,
+    /// Discard the database, to be rebuilt from the log as the node catches
+    /// up with its peers. Fails to start like `Refuse` once the log was
+    /// trimmed, as it no longer holds every command.
+    Rebuild,
+}
+
+/// Maximum number of messages buffered for future configurations; later
+/// ones are rejected.
+const FUTURE_CONFIG_BUFFER_CAPACITY: usize = 1024;
+
+impl Default for StoreServerConfig {
+    fn default() -> Self {
+        Self {
+            write_buffer_linger: Duration::from_millis(0),
+            write_buffer_max_batch: 64,
+            leader_warmup: Duration::from_millis(0),
+            busy_timeout: Duration::from_millis(5000),
+            command_format: CommandFormat::Sql,
+            journal_mode: JournalMode::Wal,
+            temp_store: TempStore::Default,
+            temp_store_directory: None,
+            busy_retries: 5,
+            learner: false,
+            analytics: false,
+            apply_error_policy: ApplyErrorPolicy::Halt,
+            apply_batch_size: 1,
+            encryption_key: None,
+            clock: Arc::new(SystemClock),
+            admin: false,
+            membership: Vec::new(),
+            stream_buffer_rows: 64,
+            import_batch_rows: 1000,
+            inbound_queue_capacity: 0,
+            max_concurrent_queries: 0,
+            leader_change_check_interval: Duration::from_millis(0),
+            commit_timeout: Some(Duration::from_secs(30)),
+            stall_window: Duration::from_secs(10),
+            max_clock_skew: Duration::from_millis(500),
+            dedup_window: 1024,
+            dedup_idle_horizon: 0,
+            max_result_rows: 0,
+            result_limit_policy: ResultLimitPolicy::Error,
+            max_apply_rows: 0,
+            snapshot_interval_entries: 0,
+            snapshot_interval: Duration::from_millis(0),
+            snapshot_log_bytes: 0,
+            snapshot_min_interval: Duration::from_secs(10),
+            snapshot_retention: 0,
+            future_config_policy: FutureConfigPolicy::Buffer,
+            schema_mismatch_policy: SchemaMismatchPolicy::Ignore,
+            audit_log: None,
+            audit_redact_sql: false,
+            sql_trace_log: None,
+            change_buffer_capacity: 1024,
+            change_overflow_policy: ChangeOverflowPolicy::DropOldest,
+            read_snapshot_timeout: Duration::from_secs(60),
+            auth_tokens: HashMap::new(),
+            extensions: Vec::new(),
+            table_consistency: HashMap::new(),
+        }
+    }
+}
+
+impl StoreServerConfig {
+    /// Registers version `version` of the SQL extension `name`, set up by
+    /// `init` on every connection the server opens.
+    ///
+    /// Commands are applied on every node, so every node must register the
+    /// same extensions, behaving identically. Nodes send the names and
+    /// versions of their extensions with every message and ignore peers
+    /// with other ones.
+    pub fn register_extension<F>(&mut self, name: &str, version: &str, init: F)
+    where
+        F: Fn(&Connection) -> Result<(), sqlite::Error> + Send + Sync + 'static,
+    {
+        self.extensions.retain(|(n, _, _)| n != name);
+        self.extensions.push((name.to_string(), version.to_string(), Arc::new(init)));
+    }
+
+    /// Fingerprint of the names and versions of the registered extensions,
+    /// zero if none.
+    fn extensions_fingerprint(&self) -> u64 {
+        if self.extensions.is_empty() {
+            return 0;
+        }
+        let mut extensions: Vec<(&str, &str)> = self.extensions.iter().map(|(name, version, _)| (name.as_str(), version.as_str())).collect();
+        extensions.sort_unstable();
+        extensions.iter().fold(FNV_OFFSET_BASIS, |hash, (name, version)| {
+            let hash = fnv1a(fnv1a(hash, name.as_bytes()), &[0]);
+            fnv1a(fnv1a(hash, version.as_bytes()), &[0])
+        })
+    }
+
+    fn connection_options(&self) -> ConnectionOptions {
+        ConnectionOptions {
+            busy_timeout: self.busy_timeout,
+            journal_mode: self.journal_mode,
+            temp_store: self.temp_store,
+            encryption_key: self.encryption_key.clone(),
+            extensions: self.extensions.iter().map(|(_, _, init)| init.clone()).collect(),
+        }
+    }
+}
+
+/// Options for opening SQLite connections.
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+struct ConnectionOptions {
+    /// SQLite busy timeout.
+    busy_timeout: Duration,
+    journal_mode: JournalMode,
+    temp_store: TempStore,
+    #[derivative(Debug = "ignore")]
+    encryption_key: Option<String>,
+    /// SQL extensions set up on every connection.
+    #[derivative(Debug = "ignore")]
+    extensions: Vec<Arc<SqlExtension>>,
+}
+
+/// Buffer of writes waiting to be proposed as a single log entry.
+#[derive(Debug)]
+struct WriteBuffer {
+    pending: Vec<StoreCommand>,
+    /// When the oldest pending write was buffered.
+    oldest: Option<Instant>,
+    /// When the buffer was last flushed.
+    last_flush: Instant,
+}
+
+impl WriteBuffer {
+    fn new(now: Instant) -> Self {
+        Self {
+            pending: Vec::new(),
+            oldest: None,
+            last_flush: now,
+        }
+    }
+
+    fn push(&mut self, cmd: StoreCommand, now: Instant) {
+        if self.pending.is_empty() {
+            self.oldest = Some(now);
+        }
+        self.pending.push(cmd);
+    }
+
+    fn take(&mut self, now: Instant) -> Vec<StoreCommand> {
+        self.oldest = None;
+        self.last_flush = now;
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Takes the pending writes if the oldest one has lingered long enough.
+    fn take_expired(&mut self, linger: Duration, now: Instant) -> Vec<StoreCommand> {
+        match self.oldest {
+            Some(oldest) if now.saturating_duration_since(oldest) >= linger => self.take(now),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// A decided command that failed to apply on this node.
+#[derive(Clone, Debug)]
+pub struct ApplyError {
+    /// Index of the command's entry in the log.
+    pub log_idx: u64,
+    /// Id of the command.
+    pub command_id: u64,
+    /// Client-chosen id of the request the command was submitted by, zero
+    /// if unset.
+    pub request_id: u64,
+    /// SQL statement of the command.
+    pub sql: String,
+    /// Why the command failed.
+    pub error: String,
+}
+
+/// Maximum number of apply errors remembered; older ones are forgotten.
+const APPLY_ERROR_LOG_CAPACITY: usize = 1024;
+
+/// A leadership epoch: the ballot entries were decided under from a log index on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Epoch {
+    /// Ballot of the leader.
+    pub ballot: Ballot,
+    /// Index of the first entry this node saw decided under the ballot.
+    pub first_idx: u64,
+}
+
+/// Checksum of a table's rows, including their rowids.
+///
+/// SQLite assigns the rowids of inserted rows from the current state of the
+/// table, so nodes only agree on them as long as their states never diverged.
+/// Comparing checksums taken at the same decided index detects nodes that
+/// did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TableChecksum {
+    /// Decided index of the node when the checksum was taken.
+    pub decided_idx: u64,
+    /// Number of rows in the table.
+    pub rows: u64,
+    /// FNV-1a hash of the rowids and values of the rows, in rowid order.
+    pub checksum: u64,
+}
+
+/// Copy of a node's database, for catching up a node whose log is behind
+/// the trimmed log of the others.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DatabaseSnapshot {
+    /// Configuration whose log the copy covers.
+    pub configuration_id: u32,
+    /// Length of the decided log of the configuration the copy holds.
+    pub decided_idx: u64,
+    /// Contents of the database file.
+    pub data: Vec<u8>,
+}
+
+/// Storage statistics of a node's database, for capacity planning.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    /// Decided index of the node when the statistics were taken.
+    pub decided_idx: u64,
+    /// Number of pages in the database file.
+    pub page_count: u64,
+    /// Size of a page, in bytes.
+    pub page_size: u64,
+    /// Number of unused pages, which are reused before the file grows.
+    pub freelist_count: u64,
+    /// Statistics of each table, by name.
+    pub tables: Vec<TableStats>,
+}
+
+/// Storage statistics of a table.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TableStats {
+    /// Name of the table.
+    pub name: String,
+    /// Number of rows in the table.
+    pub rows: u64,
+    /// Pages used by the table, not counting its indexes, or `None` if
+    /// SQLite was built without the `dbstat` virtual table.
+    pub pages: Option<u64>,
+}
+
+/// Maximum number of epochs remembered; older ones are forgotten.
+const EPOCH_HISTORY_CAPACITY: usize = 256;
+
+/// Maximum number of read snapshots open at once.
+const READ_SNAPSHOT_LIMIT: usize = 64;
+
+/// A read transaction kept open across queries, so that they all see the
+/// database as it was when the snapshot was created.
+#[derive(Derivative)]
+#[derivative(Debug)]
+struct ReadSnapshot {
+    #[derivative(Debug = "ignore")]
+    conn: Arc<Mutex<Connection>>,
+    /// Identity of the client that created the snapshot, the only one
+    /// that may use it.
+    owner: String,
+    /// When the snapshot was last queried.
+    last_used: Instant,
+}
+
+/// Progress of automatic snapshots.
+#[derive(Debug)]
+struct SnapshotState {
+    /// Decided index covered by the last snapshot.
+    last_idx: u64,
+    /// When the last snapshot was taken.
+    last_at: Instant,
+    /// Whether a snapshot is being taken.
+    running: bool,
+    /// Number of pins of each retained snapshot, by index.
+    pins: HashMap<u64, usize>,
+}
+
+impl SnapshotState {
+    /// Returns true if a snapshot is due with `decided_idx` entries decided
+    /// and a log of `log_bytes` bytes.
+    fn is_due(&self, config: &StoreServerConfig, decided_idx: u64, log_bytes: u64, now: Instant) -> bool {
+        if self.running || decided_idx <= self.last_idx {
+            return false;
+        }
+        let entries = config.snapshot_interval_entries;
+        let interval = config.snapshot_interval;
+        let max_bytes = config.snapshot_log_bytes;
+        let since_last = now.saturating_duration_since(self.last_at);
+        (entries > 0 && decided_idx - self.last_idx >= entries)
+            || (!interval.is_zero() && since_last >= interval)
+            || (max_bytes > 0 && log_bytes >= max_bytes && since_last >= config.snapshot_min_interval)
+    }
+}
+
+/// Keeps a retained snapshot from being deleted, e.g. while it is being
+/// sent to a follower. The snapshot is unpinned when this is dropped.
+#[derive(Debug)]
+pub struct SnapshotPin {
+    idx: u64,
+    snapshot_state: Arc<Mutex<SnapshotState>>,
+}
+
+impl SnapshotPin {
+    /// Decided index covered by the pinned snapshot.
+    pub fn idx(&self) -> u64 {
+        self.idx
+    }
+
+    /// Path of the pinned snapshot, relative to the working directory.
+    pub fn path(&self, this_id: u64) -> String {
+        snapshot_path(this_id, Some(self.idx))
+    }
+}
+
+impl Drop for SnapshotPin {
+    fn drop(&mut self) {
+        let mut snapshot_state = self.snapshot_state.lock().unwrap();
+        if let Some(pins) = snapshot_state.pins.get_mut(&self.idx) {
+            *pins -= 1;
+            if *pins == 0 {
+                snapshot_state.pins.remove(&self.idx);
+            }
+        }
+    }
+}
+
+/// Progress of consensus, for detecting stalls.
+#[derive(Debug)]
+struct ConsensusProgress {
+    decided_idx: u64,
+    /// Since when commands have been waiting without the decided index
+    /// advancing.
+    since: Instant,
+    stalled: bool,
+}
+
+/// When the decided index advanced on this node, on its own wall clock.
+///
+/// Comparing another node's decided index against it tells how long that
+/// node has been missing writes, without relying on the other node's clock.
+#[derive(Debug)]
+struct DecideTimes {
+    /// Decided index reached and when, oldest first.
+    decides: VecDeque<(u64, SystemTime)>,
+}
+
+/// Maximum number of decided index advances remembered for measuring
+/// staleness; staleness beyond the oldest one is underestimated.
+const DECIDE_TIMES_CAPACITY: usize = 1024;
+
+/// Period over which the throughput of a node is measured.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(10);
+
+impl DecideTimes {
+    fn new() -> Self {
+        Self { decides: VecDeque::new() }
+    }
+
+    fn record(&mut self, decided_idx: u64, now: SystemTime) {
+        if self.decides.back().map_or(false, |&(idx, _)| idx >= decided_idx) {
+            return;
+        }
+        if self.decides.len() >= DECIDE_TIMES_CAPACITY {
+            self.decides.pop_front();
+        }
+        self.decides.push_back((decided_idx, now));
+    }
+
+    /// How long ago the first write beyond `decided_idx` was decided, zero if
+    /// there is none.
+    fn staleness(&self, decided_idx: u64, now: SystemTime) -> Duration {
+        self.decides
+            .iter()
+            .find(|&&(idx, _)| idx > decided_idx)
+            .map_or(Duration::from_millis(0), |&(_, at)| now.duration_since(at).unwrap_or_default())
+    }
+
+    /// Entries decided per second over the `window` before `now`.
+    fn throughput(&self, now: SystemTime, window: Duration) -> f64 {
+        let latest = match self.decides.back() {
+            Some(&(idx, _)) => idx,
+            None => return 0.0,
+        };
+        let since = now.checked_sub(window).unwrap_or(UNIX_EPOCH);
+        // Decided index at the start of the window. Without an earlier
+        // advance, the window starts from the beginning of the log, unless
+        // the oldest advances were forgotten.
+        let base = match self.decides.iter().rev().find(|&&(_, at)| at <= since) {
+            Some(&(idx, _)) => idx,
+            None if self.decides.len() < DECIDE_TIMES_CAPACITY => 0,
+            None => self.decides.front().map_or(0, |&(idx, _)| idx),
+        };
+        latest.saturating_sub(base) as f64 / window.as_secs_f64()
+    }
+}
+
+/// Acceptances reported to this node, as leader, in the latest round.
+#[derive(Debug, Default)]
+struct Acceptances {
+    /// Round the acceptances were made in.
+    ballot: Ballot,
+    /// Length of the log each peer accepted in `ballot`.
+    la: HashMap<u64, u64>,
+}
+
+/// Leader changes seen by this node.
+#[derive(Debug)]
+struct LeaderHistory {
+    /// When the current leader was elected.
+    since: Option<Instant>,
+    /// When each recent leader change happened, oldest first.
+    changes: VecDeque<Instant>,
+}
+
+impl LeaderHistory {
+    fn new() -> Self {
+        Self {
+            since: None,
+            changes: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, now: Instant) {
+        self.since = Some(now);
+        self.changes.push_back(now);
+        self.prune(now);
+    }
+
+    /// Forgets the changes older than `LEADER_CHANGE_WINDOW`.
+    fn prune(&mut self, now: Instant) {
+        while let Some(&oldest) = self.changes.front() {
+            if now.saturating_duration_since(oldest) <= LEADER_CHANGE_WINDOW {
+                break;
+            }
+            self.changes.pop_front();
+        }
+    }
+}
+
+/// Table of the dedup window. It is part of the database, so that requests
+/// are recorded in the same transaction as their commands and snapshots
+/// carry the window.
+const DEDUP_TABLE: &str = "chiselstore_dedup";
+
+/// Results of the most recently active requests, for deduplication.
+///
+/// Requests are keyed by client and request id, and only recorded once
+/// applied. They are evicted in least recently active order, by log
+/// position rather than time, so every node evicts the same requests at the
+/// same point. The log of each configuration starts over at index zero, so
+/// positions are qualified by their configuration.
+#[derive(Debug)]
+struct DedupWindow {
+    capacity: usize,
+    /// Log entries after which an idle request is evicted, or zero.
+    idle_horizon: u64,
+    /// Number of requests in the window.
+    size: Arc<Gauge>,
+}
+
+impl DedupWindow {
+    fn new(capacity: usize, idle_horizon: u64, size: Arc<Gauge>) -> Self {
+        Self {
+            capacity,
+            idle_horizon,
+            size,
+        }
+    }
+
+    /// Creates the table of the window in the database, if it is missing.
+    fn open(&self, conn: &Connection) -> Result<(), StoreError> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        conn.execute(format!(
+            "CREATE TABLE IF NOT EXISTS {table} (client TEXT NOT NULL, request_id INTEGER NOT NULL, log_idx INTEGER NOT NULL, \
+             last_active INTEGER NOT NULL, rows BLOB NOT NULL, configuration_id INTEGER NOT NULL DEFAULT 0, \
+             last_configuration INTEGER NOT NULL DEFAULT 0, PRIMARY KEY (client, request_id))",
+            table = DEDUP_TABLE
+        ))?;
+        // Tables created before positions were qualified by configuration
+        // get the columns, with the requests in configuration zero.
+        if conn.prepare(format!("SELECT last_configuration FROM {}", DEDUP_TABLE)).is_err() {
+            conn.execute(format!(
+                "ALTER TABLE {table} ADD COLUMN configuration_id INTEGER NOT NULL DEFAULT 0; \
+                 ALTER TABLE {table} ADD COLUMN last_configuration INTEGER NOT NULL DEFAULT 0",
+                table = DEDUP_TABLE
+            ))?;
+        }
+        conn.execute(format!(
+            "DROP INDEX IF EXISTS {table}_last_active; \
+             CREATE INDEX IF NOT EXISTS {table}_activity ON {table} (last_configuration, last_active)",
+            table = DEDUP_TABLE
+        ))?;
+        self.update_size(conn)
+    }
+
+    /// Looks up the request of `cmd`, decided at log position `at`, after
+    /// evicting the requests idle for longer than the horizon.
+    ///
+    /// Returns the log position and result rows the request was applied
+    /// with, if it is in the window, counting the retry as activity.
+    fn lookup(&self, conn: &Connection, at: LogPosition, cmd: &StoreCommand) -> Result<Option<(LogPosition, Vec<QueryRow>)>, StoreError> {
+        if self.capacity == 0 {
+            return Ok(None);
+        }
+        self.evict_idle(conn, at)?;
+        if cmd.request_id == 0 {
+            return Ok(None);
+        }
+        let mut stmt = conn.prepare(format!("SELECT configuration_id, log_idx, rows FROM {} WHERE client = ? AND request_id = ?", DEDUP_TABLE))?;
+        stmt.bind(1, cmd.client.as_str())?;
+        stmt.bind(2, cmd.request_id as i64)?;
+        if let State::Done = stmt.next()? {
+            return Ok(None);
+        }
+        let applied_at = LogPosition {
+            configuration_id: stmt.read::<i64>(0)? as u32,
+            idx: stmt.read::<i64>(1)? as u64,
+        };
+        let rows = decode_rows(&stmt.read::<Vec<u8>>(2)?)?;
+        drop(stmt);
+        let mut stmt = conn.prepare(format!("UPDATE {} SET last_configuration = ?, last_active = ? WHERE client = ? AND request_id = ?", DEDUP_TABLE))?;
+        stmt.bind(1, at.configuration_id as i64)?;
+        stmt.bind(2, at.idx as i64)?;
+        stmt.bind(3, cmd.client.as_str())?;
+        stmt.bind(4, cmd.request_id as i64)?;
+        stmt.next()?;
+        Ok(Some((applied_at, rows)))
+    }
+
+    /// Records the request of `cmd`, applied at log position `at` with
+    /// `results`, evicting the least recently active requests beyond the
+    /// capacity.
+    fn record(&self, conn: &Connection, at: LogPosition, cmd: &StoreCommand, results: &QueryResults) -> Result<(), StoreError> {
+        if self.capacity == 0 || cmd.request_id == 0 {
+            return Ok(());
+        }
+        let mut stmt = conn.prepare(format!(
+            "INSERT OR REPLACE INTO {} (client, request_id, configuration_id, log_idx, last_configuration, last_active, rows) VALUES(?, ?, ?, ?, ?, ?, ?)",
+            DEDUP_TABLE
+        ))?;
+        stmt.bind(1, cmd.client.as_str())?;
+        stmt.bind(2, cmd.request_id as i64)?;
+        stmt.bind(3, at.configuration_id as i64)?;
+        stmt.bind(4, at.idx as i64)?;
+        stmt.bind(5, at.configuration_id as i64)?;
+        stmt.bind(6, at.idx as i64)?;
+        stmt.bind(7, &encode_rows(&results.rows)[..])?;
+        stmt.next()?;
+        drop(stmt);
+        conn.execute(format!(
+            "DELETE FROM {table} WHERE rowid IN (SELECT rowid FROM {table} ORDER BY last_configuration DESC, last_active DESC, rowid DESC LIMIT -1 OFFSET {capacity})",
+            table = DEDUP_TABLE,
+            capacity = self.capacity
+        ))?;
+        self.update_size(conn)
+    }
+
+    /// Evicts the requests that were not active in the last `idle_horizon`
+    /// log entries before position `at`.
+    ///
+    /// The length of the logs of earlier configurations is not known, so a
+    /// request last active in one counts as active when the configuration
+    /// of `at` started.
+    fn evict_idle(&self, conn: &Connection, at: LogPosition) -> Result<(), StoreError> {
+        if self.idle_horizon == 0 || at.idx <= self.idle_horizon {
+            // No request can have been idle that long yet.
+            return Ok(());
+        }
+        let horizon = at.idx - self.idle_horizon;
+        let idle = format!("last_configuration < {} OR (last_configuration = {} AND last_active < {})", at.configuration_id, at.configuration_id, horizon);
+        // Checked first, so that commands without idle requests don't write.
+        let mut stmt = conn.prepare(format!("SELECT 1 FROM {} WHERE {} LIMIT 1", DEDUP_TABLE, idle))?;
+        if let State::Done = stmt.next()? {
+            return Ok(());
+        }
+        drop(stmt);
+        conn.execute(format!("DELETE FROM {} WHERE {}", DEDUP_TABLE, idle))?;
+        self.update_size(conn)
+    }
+
+    fn update_size(&self, conn: &Connection) -> Result<(), StoreError> {
+        let mut stmt = conn.prepare(format!("SELECT count(*) FROM {}", DEDUP_TABLE))?;
+        stmt.next()?;
+        self.size.set(stmt.read::<i64>(0)?);
+        Ok(())
+    }
+}
+
+/// Encodes result rows for the dedup window: each row is its number of
+/// values followed by the values, each prefixed by its length.
+fn encode_rows(rows: &[QueryRow]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for row in rows.iter() {
+        buf.extend_from_slice(&(row.values.len() as u32).to_le_bytes());
+        for value in row.values.iter() {
+            buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            buf.extend_from_slice(value.as_bytes());
+        }
+    }
+    buf
+}
+
+/// Decodes result rows encoded by `encode_rows`.
+fn decode_rows(mut buf: &[u8]) -> Result<Vec<QueryRow>, StoreError> {
+    fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8], StoreError> {
+        if buf.len() < n {
+            return Err(StoreError::Dedup(String::from("truncated result rows")));
+        }
+        let (head, rest) = buf.split_at(n);
+        *buf = rest;
+        Ok(head)
+    }
+    fn take_len(buf: &mut &[u8]) -> Result<usize, StoreError> {
+        let bytes = take(buf, 4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+    let mut rows = Vec::new();
+    while !buf.is_empty() {
+        let mut row = QueryRow::new();
+        for _ in 0..take_len(&mut buf)? {
+            let len = take_len(&mut buf)?;
+            let value = std::str::from_utf8(take(&mut buf, len)?).map_err(|e| StoreError::Dedup(e.to_string()))?;
+            row.values.push(value.to_string());
+        }
+        rows.push(row);
+    }
+    Ok(rows)
 }
 
 // Used for handling async queries
@@ -62,6 +1254,17 @@ impl QueryResultsHolder {
     pub fn remove_result(&mut self, id: &u64) -> Option<Result<QueryResults, StoreError>> {
         self.results.remove(id)
     }
+
+    /// Number of commands waiting for their results.
+    fn pending(&self) -> usize {
+        self.query_completion_notifiers.len()
+    }
+
+    /// Forget about a query whose caller stopped waiting for it.
+    fn remove(&mut self, id: &u64) {
+        self.query_completion_notifiers.remove(id);
+        self.results.remove(id);
+    }
     
     fn default() -> Self {
         Self {
@@ -71,12 +1274,72 @@ impl QueryResultsHolder {
     }
 }
 
+/// Cleans up after a replicated query when its caller stops waiting, e.g.
+/// because the client disconnected. The command itself is still decided and
+/// applied; only its results are discarded.
+struct PendingQuery<'a> {
+    query_results_holder: &'a Mutex<QueryResultsHolder>,
+    id: u64,
+}
+
+impl Drop for PendingQuery<'_> {
+    fn drop(&mut self) {
+        self.query_results_holder.lock().unwrap().remove(&self.id);
+    }
+}
+
 /// Store configuration.
 #[derive(Debug)]
 struct StoreConfig {
+    /// Id of the configuration whose log the store holds.
+    configuration_id: u32,
     /// Connection pool size.
     conn_pool_size: usize,
+    /// Options for opening connections.
+    connection: ConnectionOptions,
+    /// Retries of commands that hit `SQLITE_BUSY`.
+    busy_retries: u32,
+    /// Maximum number of rows a command returns when applied.
+    max_apply_rows: usize,
+    /// Policy for commands that fail to apply because of a node fault.
+    apply_error_policy: ApplyErrorPolicy,
+    /// Maximum number of commands applied in a single transaction.
+    apply_batch_size: usize,
+    /// SQLite transactions used to apply commands.
+    apply_transactions: Arc<Counter>,
+    /// Commands applied successfully.
+    commands_applied: Arc<Counter>,
+    /// Duration of the SQLite transactions applying commands, from running
+    /// their SQL to committing it.
+    apply_latency: Arc<Histogram>,
+    /// Duration of committing the SQLite transactions applying commands,
+    /// which is when SQLite syncs them to disk.
+    commit_latency: Arc<Histogram>,
+    /// Length of the log applied so far.
+    applied_idx: Arc<Gauge>,
+    /// Configuration and length of the log applied so far, for waiting on.
+    applied: Arc<watch::Sender<(u32, u64)>>,
+    /// Decided commands put off because the database was locked.
+    pending: Arc<Mutex<PendingApplies>>,
+    /// Decided entries that are not applied yet.
+    apply_lag: Arc<Gauge>,
+    /// Size of the entries in the log, in bytes.
+    log_bytes: Arc<Gauge>,
+    /// Request ids of recently applied commands.
+    dedup: Arc<DedupWindow>,
+    /// Commands that failed to apply.
+    apply_errors: Arc<Mutex<VecDeque<ApplyError>>>,
+    /// Leadership epochs.
+    epochs: Arc<Mutex<VecDeque<Epoch>>>,
+    /// Audit log of committed writes, and SQL trace log.
+    audit: Option<Arc<Mutex<AuditLog>>>,
+    /// Change feed of committed writes.
+    changes: Arc<ChangeFeed>,
+    /// Clock that proposals and decides are timestamped with.
+    clock: Arc<dyn Clock>,
     query_results_holder: Arc<Mutex<QueryResultsHolder>>,
+    /// Halt flag of the server.
+    halt: Arc<Mutex<bool>>,
 }
 
 #[derive(Clone)]
@@ -106,33 +1369,61 @@ where
 
     /// SQLite
     this_id: u64,
+    configuration_id: u32,
     #[derivative(Debug = "ignore")]
     conn_pool: Vec<Arc<Mutex<Connection>>>,
     conn_idx: usize,
+    busy_retries: u32,
+    max_apply_rows: usize,
+    apply_error_policy: ApplyErrorPolicy,
+    apply_batch_size: usize,
+    apply_transactions: Arc<Counter>,
+    commands_applied: Arc<Counter>,
+    apply_latency: Arc<Histogram>,
+    commit_latency: Arc<Histogram>,
+    applied_idx: Arc<Gauge>,
+    applied: Arc<watch::Sender<(u32, u64)>>,
+    pending: Arc<Mutex<PendingApplies>>,
+    apply_lag: Arc<Gauge>,
+    /// Size of the entries in `log`, in bytes.
+    log_bytes: Arc<Gauge>,
+    #[derivative(Debug = "ignore")]
+    dedup: Arc<DedupWindow>,
+    /// Commands that failed to apply, oldest first.
+    apply_errors: Arc<Mutex<VecDeque<ApplyError>>>,
+    /// Leadership epochs, oldest first.
+    epochs: Arc<Mutex<VecDeque<Epoch>>>,
+    /// Audit log of committed writes, and SQL trace log.
+    audit: Option<Arc<Mutex<AuditLog>>>,
+    /// Change feed of committed writes.
+    changes: Arc<ChangeFeed>,
+    clock: Arc<dyn Clock>,
+    /// When this node, as leader, appended the entries at each log index
+    /// that is not decided yet.
+    proposed_at: HashMap<u64, SystemTime>,
+    /// When the entries being applied were decided.
+    decided_at: SystemTime,
+    /// Set when a command failed to apply and the node halted.
+    faulted: bool,
     query_results_holder: Arc<Mutex<QueryResultsHolder>>,
+    halt: Arc<Mutex<bool>>,
 }
 
 impl <S> SQLiteStore<S>
 where
     S: Snapshot<StoreCommand>
 {
-    pub fn new(this_id: u64, config: StoreConfig) -> Self {
+    pub fn new(this_id: u64, config: StoreConfig) -> Result<Self, StoreError> {
         let mut conn_pool = vec![];
         let conn_pool_size = config.conn_pool_size;
         for _ in 0..conn_pool_size {
-            // FIXME: Let's use the 'memdb' VFS of SQLite, which allows concurrent threads
-            // accessing the same in-memory database.
-            let flags = OpenFlags::new()
-                .set_read_write()
-                .set_create()
-                .set_no_mutex();
-            let mut conn =
-                Connection::open_with_flags(format!("node{}.db", this_id), flags).unwrap();
-            conn.set_busy_timeout(5000).unwrap();
+            let conn = open_connection(this_id, &config.connection)?;
             conn_pool.push(Arc::new(Mutex::new(conn)));
         }
         let conn_idx = 0;
-        SQLiteStore {
+        // The log of a new configuration starts out empty.
+        config.log_bytes.set(0);
+        Ok(SQLiteStore {
             log: Vec::new(),
             n_prom: Ballot::default(),
             acc_round: Ballot::default(),
@@ -143,11 +1434,35 @@ where
             stopsign: None,
 
             this_id,
+            configuration_id: config.configuration_id,
             conn_pool,
             conn_idx,
+            busy_retries: config.busy_retries,
+            max_apply_rows: config.max_apply_rows,
+            apply_error_policy: config.apply_error_policy,
+            apply_batch_size: std::cmp::max(1, config.apply_batch_size),
+            apply_transactions: config.apply_transactions,
+            commands_applied: config.commands_applied,
+            apply_latency: config.apply_latency,
+            commit_latency: config.commit_latency,
+            applied_idx: config.applied_idx,
+            applied: config.applied,
+            pending: config.pending,
+            apply_lag: config.apply_lag,
+            log_bytes: config.log_bytes,
+            dedup: config.dedup,
+            apply_errors: config.apply_errors,
+            epochs: config.epochs,
+            audit: config.audit,
+            changes: config.changes,
+            clock: config.clock,
+            proposed_at: HashMap::new(),
+            decided_at: UNIX_EPOCH,
+            faulted: false,
 
             query_results_holder: config.query_results_holder,
-        }
+            halt: config.halt,
+        })
     }
 
     pub fn get_connection(&mut self) -> Arc<Mutex<Connection>> {
@@ -156,334 +1471,4017 @@ where
         self.conn_idx += 1;
         conn.clone()
     }
-}
 
-fn query(conn: Arc<Mutex<Connection>>, sql: String) -> Result<QueryResults, StoreError> {
-    let conn = conn.lock().unwrap();
-    let mut rows = vec![];
-    conn.iterate(sql, |pairs| {
-        let mut row = QueryRow::new();
-        for &(_, value) in pairs.iter() {
-            row.values.push(value.unwrap().to_string());
+    /// Applies decided log entries, the first of which is at `from_idx`,
+    /// after the commands put off before them.
+    fn apply_entries(&mut self, from_idx: u64, entries: Vec<StoreCommand>) {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            for (e, idx) in entries.into_iter().zip(from_idx..) {
+                if pending.restored.0 == self.configuration_id && idx < pending.restored.1 {
+                    continue;
+                }
+                let proposed_at = self.proposed_at.get(&idx).copied();
+                let cmds = if e.batch.is_empty() { vec![e] } else { e.batch };
+                pending.commands.extend(cmds.into_iter().map(|cmd| Decided {
+                    configuration_id: self.configuration_id,
+                    idx,
+                    cmd,
+                    ballot: self.acc_round,
+                    proposed_at,
+                    decided_at: self.decided_at,
+                }));
+            }
         }
-        rows.push(row);
-        true
-    })?;
-    Ok(QueryResults { rows })
-}
-
-impl<S> Storage<StoreCommand, S> for SQLiteStore<S>
-where
-    S: Snapshot<StoreCommand>,
-{
-    fn append_entry(&mut self, entry: StoreCommand) -> u64 {
-        self.log.push(entry);
-        self.get_log_len()
+        self.apply_pending();
     }
 
-    fn append_entries(&mut self, entries: Vec<StoreCommand>) -> u64 {
-        let mut e = entries;
-        self.log.append(&mut e);
-        self.get_log_len()
-    }
+    /// Applies the pending commands in order, until one finds the database
+    /// locked, unless they are put off.
+    fn apply_pending(&mut self) {
+        let mut cmds = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.retry_at.map_or(false, |at| at > self.clock.now()) {
+                return;
+            }
+            pending.retry_at = None;
+            std::mem::take(&mut pending.commands)
+        };
+        if cmds.is_empty() {
+            self.set_applied(self.ld);
+        }
+        while !cmds.is_empty() {
+            if self.faulted {
+                // The node is halting, don't apply anything after the failed command.
+                return;
+            }
+            let n = cmds
+                .iter()
+                .take(self.apply_batch_size)
+                .take_while(|d| d.cmd.schema_version == 0 && !sql::controls_transaction(&d.cmd.sql_text()))
+                .count();
+            let batch: Vec<Decided> = cmds.drain(..std::cmp::max(n, 1)).collect();
+            let done = if n > 1 {
+                self.apply_batched(&batch)
+            } else {
+                self.apply_command(&batch[0]) as usize
+            };
+            if done < batch.len() && !self.faulted {
+                // Put the rest off until the database is unlocked.
+                for d in batch.into_iter().skip(done).rev() {
+                    cmds.push_front(d);
+                }
+                self.set_applied_before(cmds.front());
+                let mut pending = self.pending.lock().unwrap();
+                cmds.extend(pending.commands.drain(..));
+                pending.commands = cmds;
+                return;
+            }
+            if !self.faulted {
+                // The entry of the next command, if any, may be partly applied.
+                self.set_applied_before(cmds.front());
+            }
+        }
+    }
+
+    /// Records that the log is applied up to the entry of the `next` command
+    /// to apply, or up to the decided index if there is none.
+    ///
+    /// Commands put off by a previous configuration don't move the applied
+    /// index of this one.
+    fn set_applied_before(&self, next: Option<&Decided>) {
+        match next {
+            Some(d) if d.configuration_id != self.configuration_id => {}
+            next => self.set_applied(next.map_or(self.ld, |d| d.idx)),
+        }
+    }
+
+    /// Measures the size of the log again, after entries were removed.
+    fn update_log_bytes(&self) {
+        self.log_bytes.set(self.log.iter().map(|e| e.size() as i64).sum());
+    }
+
+    /// Records that the log is applied up to `applied_idx` entries.
+    fn set_applied(&self, applied_idx: u64) {
+        self.applied_idx.set(applied_idx as i64);
+        self.apply_lag.set(self.ld.saturating_sub(applied_idx) as i64);
+        let _ = self.applied.send((self.configuration_id, applied_idx));
+    }
+
+    /// Returns the results of applying `cmd` at log index `idx`, auditing it
+    /// if it was applied, or recording why it failed to.
+    ///
+    /// A retry of a request in the dedup window gets the rows the request
+    /// was applied with.
+    fn finish(&self, d: &Decided, applied: Result<Applied, StoreError>) -> Result<QueryResults, StoreError> {
+        let cmd = &d.cmd;
+        match applied {
+            Ok(Applied::Results(results)) => {
+                self.audit(d);
+                Ok(self.decided_under(d, results))
+            }
+            Ok(Applied::Duplicate(applied_at, rows)) => {
+                let results = QueryResults {
+                    rows,
+                    ballot: None,
+                    warnings: vec![format!(
+                        "duplicate of request {} applied at log index {} of configuration {}, not applied again",
+                        cmd.request_id, applied_at.idx, applied_at.configuration_id
+                    )],
+                    proposed_at: None,
+                    committed_at: None,
+                    served_locally: false,
+                    forwarded_from: None,
+                    analysis: Vec::new(),
+                    log_idx: None,
+                    acknowledged_by: 0,
+                    configuration_id: 0,
+                };
+                Ok(self.decided_under(d, results))
+            }
+            Err(e) => {
+                self.record_apply_error(d.idx, cmd, &e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Applies commands in a single transaction, each in its own savepoint,
+    /// returning how many were applied.
+    ///
+    /// If the transaction fails as a whole, e.g. on a node fault, nothing is
+    /// applied and the commands are applied one at a time instead.
+    fn apply_batched(&mut self, cmds: &[Decided]) -> usize {
+        let conn = self.get_connection();
+        self.apply_transactions.inc();
+        let timer = Timer::new(self.apply_latency.clone());
+        let batch: Vec<(LogPosition, &StoreCommand)> = cmds.iter().map(|d| (d.position(), &d.cmd)).collect();
+        let applied = apply_in_transaction(conn, &batch, &self.dedup, self.max_apply_rows, &self.commit_latency);
+        drop(timer);
+        match applied {
+            Ok(results) => {
+                let results: Vec<_> = cmds.iter().zip(results).map(|(d, r)| self.finish(d, r)).collect();
+                let mut query_results_holder = self.query_results_holder.lock().unwrap();
+                for (d, results) in cmds.iter().zip(results) {
+                    query_results_holder.push_result(d.cmd.id, results);
+                }
+                cmds.len()
+            }
+            Err(_) => {
+                for (i, d) in cmds.iter().enumerate() {
+                    if self.faulted || !self.apply_command(d) {
+                        return i;
+                    }
+                }
+                cmds.len()
+            }
+        }
+    }
+
+    /// Applies a command, returning false if it is put off because the
+    /// database is locked.
+    fn apply_command(&mut self, d: &Decided) -> bool {
+        let conn = self.get_connection();
+        self.apply_transactions.inc();
+        if d.cmd.schema_version != 0 {
+            return self.apply_migration(conn, d);
+        }
+        let timer = Timer::new(self.apply_latency.clone());
+        let results = apply(conn, d.position(), &d.cmd, &self.dedup, self.max_apply_rows, &self.commit_latency);
+        drop(timer);
+        if self.put_off(&results) {
+            return false;
+        }
+        let cmd = &d.cmd;
+        let results = self.finish(d, results);
+        if let Err(ref e) = results {
+            if is_node_fault(e) {
+                match self.apply_error_policy {
+                    ApplyErrorPolicy::Halt => {
+                        log(format!("Node {} halting: failed to apply command {}: {}", self.this_id, command_label(cmd), e));
+                        self.faulted = true;
+                        *self.halt.lock().unwrap() = true;
+                    }
+                    ApplyErrorPolicy::Skip => {
+                        log(format!("Node {} skipping command {}: {}", self.this_id, command_label(cmd), e));
+                    }
+                }
+            }
+        }
+
+        let mut query_results_holder = self.query_results_holder.lock().unwrap();
+        query_results_holder.push_result(cmd.id, results);
+        true
+    }
+
+    /// Returns true if the command that was applied with `results` found the
+    /// database locked and is to be retried, after a backoff that doubles
+    /// with every retry, instead of failing.
+    ///
+    /// The backoff runs on the clock of the server, which proposes a no-op
+    /// when it is over so that the next decide applies the command again.
+    /// Sleeping here would hold up the sequence paxos lock.
+    fn put_off(&self, results: &Result<Applied, StoreError>) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        match results {
+            Err(e) if is_busy(e) && pending.busy < self.busy_retries => {
+                let backoff = BUSY_BACKOFF_MS << std::cmp::min(pending.busy, 16);
+                pending.busy += 1;
+                pending.retry_at = Some(self.clock.now() + Duration::from_millis(backoff));
+                true
+            }
+            _ => {
+                pending.busy = 0;
+                false
+            }
+        }
+    }
+
+    /// Records that entries from `first_idx` on were decided under the
+    /// accepted round, if it starts a new epoch.
+    fn record_epoch(&self, first_idx: u64) {
+        let mut epochs = self.epochs.lock().unwrap();
+        if epochs.back().map_or(false, |epoch| epoch.ballot == self.acc_round) {
+            return;
+        }
+        if epochs.len() >= EPOCH_HISTORY_CAPACITY {
+            epochs.pop_front();
+        }
+        epochs.push_back(Epoch {
+            ballot: self.acc_round,
+            first_idx,
+        });
+    }
+
+    /// Records a committed write in the audit and SQL trace logs, if enabled,
+    /// and publishes it to the change feed.
+    fn audit(&self, d: &Decided) {
+        let cmd = &d.cmd;
+        self.commands_applied.inc();
+        trace_applied(self.this_id, d.idx, cmd);
+        if let Some(ref audit) = self.audit {
+            if let Err(e) = audit.lock().unwrap().record(d.idx, cmd) {
+                log(format!("Node {} failed to audit command {}: {}", self.this_id, command_label(cmd), e));
+            }
+        }
+        self.changes.publish(d.configuration_id, d.idx, cmd);
+    }
+
+    /// Records a command that failed to apply, for auditing.
+    fn record_apply_error(&self, idx: u64, cmd: &StoreCommand, e: &StoreError) {
+        record_apply_error(&self.apply_errors, idx, cmd, e);
+    }
+
+    /// Tags results with the ballot of the decide that committed the command,
+    /// which is the round this node accepted entries in.
+    ///
+    /// On the leader, the results are also tagged with when the entry of the
+    /// command was proposed and decided. If the command was submitted to
+    /// another node than the leader, the results record that it was forwarded
+    /// from there.
+    fn decided_under(&self, d: &Decided, results: QueryResults) -> QueryResults {
+        let forwarded = d.cmd.origin != 0 && d.cmd.origin != d.ballot.pid;
+        QueryResults {
+            ballot: Some(d.ballot),
+            proposed_at: d.proposed_at,
+            committed_at: d.proposed_at.map(|_| d.decided_at),
+            served_locally: false,
+            forwarded_from: if forwarded { Some(d.cmd.origin) } else { None },
+            log_idx: Some(d.idx),
+            configuration_id: d.configuration_id,
+            ..results
+        }
+    }
+
+    /// Records when entries appended from log index `from_idx` on were
+    /// proposed, if this node is the leader proposing them.
+    fn record_proposed(&mut self, from_idx: u64, n: usize) {
+        if self.acc_round.pid != self.this_id {
+            return;
+        }
+        let now = self.clock.wall_time();
+        for idx in from_idx..from_idx + n as u64 {
+            self.proposed_at.entry(idx).or_insert(now);
+        }
+    }
+
+    /// Applies a migration, halting the node if it fails.
+    ///
+    /// A failed migration would leave the node on a schema the rest of the
+    /// cluster may not have, so it halts regardless of the apply error policy.
+    /// Migrations to a version that is not newer than the current one are
+    /// rejected on every node alike, so they don't halt.
+    fn apply_migration(&mut self, conn: Arc<Mutex<Connection>>, d: &Decided) -> bool {
+        let cmd = &d.cmd;
+        let results = migrate(conn, d.position(), cmd, &self.dedup, self.max_apply_rows, &self.commit_latency);
+        if self.put_off(&results) {
+            return false;
+        }
+        let results = self.finish(d, results);
+        match results {
+            Err(StoreError::StaleSchemaVersion { .. }) | Ok(_) => {}
+            Err(ref e) => {
+                log(format!("Node {} halting: failed to migrate to schema version {}: {}", self.this_id, cmd.schema_version, e));
+                self.faulted = true;
+                *self.halt.lock().unwrap() = true;
+            }
+        }
+
+        let mut query_results_holder = self.query_results_holder.lock().unwrap();
+        query_results_holder.push_result(cmd.id, results);
+        true
+    }
+}
+
+fn open_connection(this_id: u64, options: &ConnectionOptions) -> Result<Connection, StoreError> {
+    open_database(this_id, &format!("node{}.db", this_id), options, options.encryption_key.as_deref())
+}
+
+/// Opens the database at `path` with the connection options, unlocking it
+/// with `key` if it is encrypted.
+fn open_database(this_id: u64, path: &str, options: &ConnectionOptions, key: Option<&str>) -> Result<Connection, StoreError> {
+    // FIXME: Let's use the 'memdb' VFS of SQLite, which allows concurrent threads
+    // accessing the same in-memory database.
+    let flags = OpenFlags::new()
+        .set_read_write()
+        .set_create()
+        .set_no_mutex();
+    let mut conn = Connection::open_with_flags(path, flags)?;
+    conn.set_busy_timeout(options.busy_timeout.as_millis() as usize)?;
+    if let Some(key) = key {
+        unlock(&conn, this_id, key)?;
+    }
+    let journal_mode = match options.journal_mode {
+        JournalMode::Wal => "WAL",
+        JournalMode::Delete => "DELETE",
+        JournalMode::Truncate => "TRUNCATE",
+        JournalMode::Persist => "PERSIST",
+    };
+    conn.execute(format!("PRAGMA journal_mode = {}", journal_mode))?;
+    let temp_store = match options.temp_store {
+        TempStore::Default => 0,
+        TempStore::File => 1,
+        TempStore::Memory => 2,
+    };
+    conn.execute(format!("PRAGMA temp_store = {}", temp_store))?;
+    for init in &options.extensions {
+        init(&conn)?;
+    }
+    Ok(conn)
+}
+
+/// Table of what this node records about its database: the fingerprint of
+/// the schema it applied, and whether its log was ever trimmed. It is part
+/// of the database, so that the schema is recorded in the same transaction
+/// as the command changing it.
+const STATE_TABLE: &str = "chiselstore_state";
+
+/// Creates the state table in the database, if it is missing.
+fn open_state(conn: &Connection) -> Result<(), StoreError> {
+    conn.execute(format!("CREATE TABLE IF NOT EXISTS {} (name TEXT PRIMARY KEY, value INTEGER NOT NULL)", STATE_TABLE))?;
+    Ok(())
+}
+
+/// Reads `name` from the state table, if it was recorded.
+fn read_state(conn: &Connection, name: &str) -> Result<Option<i64>, StoreError> {
+    // The table is missing until the node first starts on the database.
+    let mut stmt = conn.prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?")?;
+    stmt.bind(1, STATE_TABLE)?;
+    if let State::Done = stmt.next()? {
+        return Ok(None);
+    }
+    let mut stmt = conn.prepare(format!("SELECT value FROM {} WHERE name = ?", STATE_TABLE))?;
+    stmt.bind(1, name)?;
+    match stmt.next()? {
+        State::Row => Ok(Some(stmt.read::<i64>(0)?)),
+        State::Done => Ok(None),
+    }
+}
+
+/// Records `value` as `name` in the state table.
+fn write_state(conn: &Connection, name: &str, value: i64) -> Result<(), StoreError> {
+    let mut stmt = conn.prepare(format!("INSERT OR REPLACE INTO {} VALUES(?, ?)", STATE_TABLE))?;
+    stmt.bind(1, name)?;
+    stmt.bind(2, value)?;
+    stmt.next()?;
+    Ok(())
+}
+
+/// Fingerprint of the schema of the database: its schema version and the
+/// SQL of its schema objects.
+fn schema_fingerprint(conn: &Connection) -> Result<u64, StoreError> {
+    let mut hash = fnv1a(FNV_OFFSET_BASIS, &pragma(conn, "user_version")?.to_le_bytes());
+    let mut stmt = conn.prepare("SELECT type, name, sql FROM sqlite_master WHERE name NOT LIKE 'sqlite_%' AND name NOT LIKE 'chiselstore_%' ORDER BY type, name")?;
+    while let State::Row = stmt.next()? {
+        for i in 0..3 {
+            let value = stmt.read::<Option<String>>(i)?.unwrap_or_default();
+            hash = fnv1a(fnv1a(hash, value.as_bytes()), &[0]);
+        }
+    }
+    Ok(hash)
+}
+
+/// Records the schema of the database as the one this node applied, if
+/// `cmd` changed it.
+fn record_schema(conn: &Connection, cmd: &StoreCommand) -> Result<(), StoreError> {
+    if cmd.schema_version == 0 && !sql::changes_schema(&cmd.sql_text()) {
+        return Ok(());
+    }
+    write_state(conn, "schema", schema_fingerprint(conn)? as i64)
+}
+
+/// Checks that the schema of the database is the one this node last
+/// applied, if it recorded one.
+fn verify_schema(conn: &Connection) -> Result<(), StoreError> {
+    let expected = match read_state(conn, "schema")? {
+        Some(expected) => expected as u64,
+        None => return Ok(()),
+    };
+    let found = schema_fingerprint(conn)?;
+    if found != expected {
+        return Err(StoreError::SchemaMismatch { expected, found });
+    }
+    Ok(())
+}
+
+/// Returns true if this node ever trimmed its log, so that the log no
+/// longer holds every command applied to the database.
+fn log_trimmed(conn: &Connection) -> Result<bool, StoreError> {
+    Ok(read_state(conn, "log_trimmed")?.is_some())
+}
+
+/// Deletes the database of this node.
+fn discard_database(this_id: u64) -> Result<(), StoreError> {
+    let db = format!("node{}.db", this_id);
+    for path in [format!("{}-wal", db), format!("{}-shm", db), db] {
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(StoreError::Schema(e.to_string())),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Path of the file recording the node addresses updated at runtime.
+fn node_addrs_path(this_id: u64) -> String {
+    format!("node{}.addrs", this_id)
+}
+
+/// Node addresses this node recorded, by node id.
+fn recorded_node_addresses(this_id: u64) -> Result<BTreeMap<u64, String>, StoreError> {
+    let recorded = match std::fs::read_to_string(node_addrs_path(this_id)) {
+        Ok(recorded) => recorded,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(StoreError::NodeAddress(e.to_string())),
+    };
+    recorded
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (id, addr) = line.split_once(' ').ok_or_else(|| StoreError::NodeAddress(format!("malformed line: {}", line)))?;
+            let id = id.parse().map_err(|e: std::num::ParseIntError| StoreError::NodeAddress(e.to_string()))?;
+            Ok((id, addr.trim().to_string()))
+        })
+        .collect()
+}
+
+/// Records that the messages to node `node_id` are routed to `addr`, so
+/// that the routing survives restarts.
+fn record_node_address(this_id: u64, node_id: u64, addr: &str) -> Result<(), StoreError> {
+    let mut addrs = recorded_node_addresses(this_id)?;
+    addrs.insert(node_id, addr.to_string());
+    let recorded: String = addrs.iter().map(|(id, addr)| format!("{} {}\n", id, addr)).collect();
+    let path = node_addrs_path(this_id);
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, recorded).map_err(|e| StoreError::NodeAddress(e.to_string()))?;
+    std::fs::rename(&tmp, &path).map_err(|e| StoreError::NodeAddress(e.to_string()))
+}
+
+/// Path of the snapshot covering the decided index `idx`, or of the only
+/// snapshot if snapshots are not retained.
+fn snapshot_path(this_id: u64, idx: Option<u64>) -> String {
+    match idx {
+        Some(idx) => format!("node{}.snapshot.{}.db", this_id, idx),
+        None => format!("node{}.snapshot.db", this_id),
+    }
+}
+
+/// Writes a consistent copy of the database to `path`.
+///
+/// The copy is read in a single read transaction on its own connection, so
+/// commands keep being applied while it is written.
+fn snapshot_database(this_id: u64, options: &ConnectionOptions, path: &str) -> Result<(), StoreError> {
+    let conn = open_connection(this_id, options)?;
+    let tmp = format!("node{}.snapshot.db.tmp", this_id);
+    // VACUUM INTO refuses to overwrite a file left by a failed snapshot.
+    let _ = std::fs::remove_file(&tmp);
+    conn.execute(format!("VACUUM INTO '{}'", tmp))?;
+    std::fs::rename(&tmp, path).map_err(|e| StoreError::Snapshot(e.to_string()))
+}
+
+/// Copies the database attached to `conn` as `source`, in the read
+/// transaction started by `StoreServer::start_copy`, and reads the copy
+/// from `path`.
+fn finish_copy(conn: Connection, mut copy: DatabaseSnapshot, path: &str) -> Result<DatabaseSnapshot, StoreError> {
+    let copied = copy_database(&conn, "source");
+    match copied {
+        Ok(()) => conn.execute("COMMIT")?,
+        Err(_) => conn.execute("ROLLBACK")?,
+    }
+    copied?;
+    drop(conn);
+    copy.data = std::fs::read(path).map_err(|e| StoreError::Snapshot(e.to_string()))?;
+    Ok(copy)
+}
+
+/// Attaches the database at `path` as `schema`, unlocking it with `key`,
+/// if given, where the empty key means it is not encrypted.
+fn attach_database(conn: &Connection, path: &str, schema: &str, key: Option<&str>) -> Result<(), StoreError> {
+    let key = match key {
+        Some(key) => format!(" KEY '{}'", key.replace('\'', "''")),
+        None => String::new(),
+    };
+    conn.execute(format!("ATTACH DATABASE '{}' AS {}{}", path.replace('\'', "''"), schema, key))?;
+    Ok(())
+}
+
+/// Replaces the contents of the main database of `conn` with those of the
+/// attached database `source`, in the transaction of the caller.
+///
+/// Shadow tables, which SQLite creates along with their virtual table,
+/// are emptied and refilled rather than created.
+fn copy_database(conn: &Connection, source: &str) -> Result<(), StoreError> {
+    let mut stmt = conn.prepare("SELECT type, name FROM main.sqlite_master WHERE type IN ('table', 'view') AND name NOT LIKE 'sqlite_%' ORDER BY type = 'table', sql NOT LIKE 'CREATE VIRTUAL%'")?;
+    let mut dropped = Vec::new();
+    while let State::Row = stmt.next()? {
+        dropped.push((stmt.read::<String>(0)?, stmt.read::<String>(1)?));
+    }
+    drop(stmt);
+    // Views first, then virtual tables, which drop their shadow tables.
+    for (kind, name) in dropped {
+        conn.execute(format!("DROP {} IF EXISTS main.{}", kind.to_ascii_uppercase(), quote_identifier(&name)))?;
+    }
+
+    let mut stmt = conn.prepare(format!("SELECT type, name, sql FROM {}.sqlite_master WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_%' ORDER BY rowid", source))?;
+    let mut objects = Vec::new();
+    while let State::Row = stmt.next()? {
+        objects.push((stmt.read::<String>(0)?, stmt.read::<String>(1)?, stmt.read::<String>(2)?));
+    }
+    drop(stmt);
+    for (_, name, sql) in objects.iter().filter(|(kind, _, _)| kind == "table") {
+        let table = quote_identifier(name);
+        if table_exists(conn, "main", name)? {
+            conn.execute(format!("DELETE FROM main.{}", table))?;
+        } else {
+            conn.execute(sql)?;
+        }
+        let upper = sql.to_ascii_uppercase();
+        if upper.starts_with("CREATE VIRTUAL") {
+            // Its rows are held by its shadow tables.
+            continue;
+        }
+        let mut stmt = conn.prepare(format!("PRAGMA {}.table_info({})", source, table))?;
+        let mut columns = Vec::new();
+        if !upper.contains("WITHOUT ROWID") {
+            columns.push(String::from("rowid"));
+        }
+        while let State::Row = stmt.next()? {
+            columns.push(quote_identifier(&stmt.read::<String>(1)?));
+        }
+        drop(stmt);
+        let columns = columns.join(", ");
+        conn.execute(format!("INSERT INTO main.{} ({}) SELECT {} FROM {}.{}", table, columns, columns, source, table))?;
+    }
+    if table_exists(conn, source, "sqlite_sequence")? {
+        conn.execute(format!("DELETE FROM main.sqlite_sequence; INSERT INTO main.sqlite_sequence SELECT * FROM {}.sqlite_sequence", source))?;
+    }
+    for (_, _, sql) in objects.iter().filter(|(kind, _, _)| kind != "table") {
+        conn.execute(sql)?;
+    }
+    for name in ["user_version", "application_id"] {
+        let value = pragma(conn, &format!("{}.{}", source, name))?;
+        conn.execute(format!("PRAGMA main.{} = {}", name, value as i64))?;
+    }
+    Ok(())
+}
+
+/// Returns true if the `schema` database has a table called `name`.
+fn table_exists(conn: &Connection, schema: &str, name: &str) -> Result<bool, StoreError> {
+    let mut stmt = conn.prepare(format!("SELECT 1 FROM {}.sqlite_master WHERE type = 'table' AND name = ?", schema))?;
+    stmt.bind(1, name)?;
+    Ok(matches!(stmt.next()?, State::Row))
+}
+
+/// Replaces the database of this node with `data`, a copy of the database
+/// of another node, in a single transaction.
+fn restore_snapshot(this_id: u64, options: &ConnectionOptions, data: &[u8]) -> Result<(), StoreError> {
+    let path = format!("node{}.snapshot.recv.db", this_id);
+    std::fs::write(&path, data).map_err(|e| StoreError::Snapshot(e.to_string()))?;
+    let restored = install_snapshot(this_id, options, &path);
+    let _ = std::fs::remove_file(&path);
+    restored
+}
+
+fn install_snapshot(this_id: u64, options: &ConnectionOptions, path: &str) -> Result<(), StoreError> {
+    let conn = open_connection(this_id, options)?;
+    // Copies are sent unencrypted, like log entries.
+    attach_database(&conn, path, "snapshot", options.encryption_key.as_ref().map(|_| ""))?;
+    conn.execute("BEGIN IMMEDIATE")?;
+    let copied = copy_database(&conn, "snapshot");
+    match copied {
+        Ok(()) => conn.execute("COMMIT")?,
+        Err(_) => conn.execute("ROLLBACK")?,
+    }
+    conn.execute("DETACH DATABASE snapshot")?;
+    copied
+}
+
+/// Deletes the retained snapshots older than the latest `retention` ones,
+/// except pinned snapshots and those past `followers_idx`, the log every
+/// follower accepted, which a lagging follower may still need. Returns the
+/// number of snapshots deleted.
+fn prune_snapshots(this_id: u64, retention: usize, followers_idx: u64, snapshot_state: &Mutex<SnapshotState>) -> Result<u64, StoreError> {
+    let prefix = format!("node{}.snapshot.", this_id);
+    let mut retained: Vec<u64> = std::fs::read_dir(".")
+        .map_err(|e| StoreError::Snapshot(e.to_string()))?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            name.strip_prefix(&prefix)?.strip_suffix(".db")?.parse().ok()
+        })
+        .collect();
+    retained.sort_unstable_by(|a, b| b.cmp(a));
+    // Pins are taken under the same lock, so a snapshot can't be pinned
+    // while it is being deleted.
+    let snapshot_state = snapshot_state.lock().unwrap();
+    let mut pruned = 0;
+    for idx in retained.into_iter().skip(retention) {
+        if snapshot_state.pins.contains_key(&idx) || idx > followers_idx {
+            continue;
+        }
+        std::fs::remove_file(snapshot_path(this_id, Some(idx))).map_err(|e| StoreError::Snapshot(e.to_string()))?;
+        pruned += 1;
+    }
+    Ok(pruned)
+}
+
+/// Unlocks a database encrypted with SQLCipher.
+///
+/// Fails unless SQLCipher is linked, which reports its version, so that a
+/// key is never silently ignored by plain SQLite.
+#[cfg(feature = "sqlcipher")]
+fn unlock(conn: &Connection, this_id: u64, key: &str) -> Result<(), StoreError> {
+    let mut stmt = conn.prepare("PRAGMA cipher_version")?;
+    match stmt.next()? {
+        State::Row if !stmt.read::<String>(0)?.is_empty() => {}
+        _ => return Err(StoreError::EncryptionUnsupported),
+    }
+    drop(stmt);
+    conn.execute(format!("PRAGMA key = '{}'", key.replace('\'', "''")))?;
+    // The key is only checked when the database is first read.
+    conn.execute("SELECT count(*) FROM sqlite_master")
+        .map_err(|_| StoreError::WrongEncryptionKey(this_id))
+}
+
+#[cfg(not(feature = "sqlcipher"))]
+fn unlock(_conn: &Connection, _this_id: u64, _key: &str) -> Result<(), StoreError> {
+    Err(StoreError::EncryptionUnsupported)
+}
+
+fn query(conn: Arc<Mutex<Connection>>, sql: &str, params: &[Value]) -> Result<QueryResults, StoreError> {
+    query_limited(conn, sql, params, 0)
+}
+
+/// Executes `sql`, failing with `StoreError::TooManyApplyRows` as soon as it
+/// returns more than `max_rows` rows, so that they are never all buffered.
+/// Zero doesn't limit the rows.
+///
+/// A statement stopped part-way may have written already, so the caller
+/// must roll it back.
+fn query_limited(conn: Arc<Mutex<Connection>>, sql: &str, params: &[Value], max_rows: usize) -> Result<QueryResults, StoreError> {
+    if !params.is_empty() {
+        return query_with_params(conn, sql, params, max_rows);
+    }
+    let conn = conn.lock().unwrap();
+    let mut rows = vec![];
+    let mut overflow = false;
+    let result = conn.iterate(sql, |pairs| {
+        if max_rows > 0 && rows.len() >= max_rows {
+            overflow = true;
+            return false;
+        }
+        let mut row = QueryRow::new();
+        for &(_, value) in pairs.iter() {
+            row.values.push(value.unwrap_or("NULL").to_string());
+        }
+        rows.push(row);
+        true
+    });
+    if overflow {
+        return Err(StoreError::TooManyApplyRows { limit: max_rows });
+    }
+    result?;
+    Ok(QueryResults {
+        rows,
+        ballot: None,
+        warnings: Vec::new(),
+        proposed_at: None,
+        committed_at: None,
+        served_locally: true,
+        forwarded_from: None,
+        analysis: Vec::new(),
+        log_idx: None,
+        acknowledged_by: 0,
+        configuration_id: 0,
+    })
+}
+
+/// Executes a read-only transaction, which sees a consistent snapshot of
+/// the database.
+///
+/// If it fails part-way, the transaction is rolled back so that it isn't
+/// left open on the connection.
+fn read_transaction(conn: Arc<Mutex<Connection>>, sql: &str, params: &[Value]) -> Result<QueryResults, StoreError> {
+    let results = query(conn.clone(), sql, params);
+    if results.is_err() {
+        // No transaction may be active, in which case this fails harmlessly.
+        let _ = conn.lock().unwrap().execute("ROLLBACK");
+    }
+    results
+}
+
+/// Executes the statements in `sql` one by one, binding `params` to their
+/// placeholders in order.
+fn query_with_params(conn: Arc<Mutex<Connection>>, sql: &str, params: &[Value], max_rows: usize) -> Result<QueryResults, StoreError> {
+    let mut params = params;
+    let statements = sql::statements(sql).into_iter().map(|stmt| {
+        let (stmt_params, rest) = params.split_at(sql::placeholders(stmt).min(params.len()));
+        params = rest;
+        (stmt, stmt_params)
+    });
+    query_statements(conn, statements, max_rows)
+}
+
+/// Executes the SQL of `cmd`, from its statements if it is structured.
+fn query_command(conn: Arc<Mutex<Connection>>, cmd: &StoreCommand, max_rows: usize) -> Result<QueryResults, StoreError> {
+    let maintenance = match cmd.kind {
+        CommandKind::Sql => None,
+        CommandKind::EnterMaintenance => Some(MAINTENANCE_APPLICATION_ID),
+        CommandKind::ExitMaintenance => Some(0),
+    };
+    if let Some(id) = maintenance {
+        return query_limited(conn, &format!("PRAGMA application_id = {}", id), &[], max_rows);
+    }
+    // Checked when applying too, so that a command proposed by a node that
+    // didn't check fails on every node.
+    let text = cmd.sql_text();
+    check_application_id(&text)?;
+    // Checked when applying, rather than only when proposing, so that the
+    // writes decided after entering maintenance mode fail on every node.
+    if cmd.schema_version == 0
+        && !sql::is_read_only(&text)
+        && !sql::is_read_only_transaction(&text)
+        && in_maintenance_mode(&conn.lock().unwrap())?
+    {
+        return Err(StoreError::MaintenanceMode);
+    }
+    if cmd.statements.is_empty() {
+        return query_limited(conn, &cmd.sql, &cmd.params, max_rows);
+    }
+    let statements = cmd.statements.iter().map(|stmt| (stmt.sql.as_str(), stmt.params.as_slice()));
+    query_statements(conn, statements, max_rows)
+}
+
+/// Executes single statements one by one, each with the values bound to its
+/// placeholders.
+fn query_statements<'a, I>(conn: Arc<Mutex<Connection>>, statements: I, max_rows: usize) -> Result<QueryResults, StoreError>
+where
+    I: IntoIterator<Item = (&'a str, &'a [Value])>,
+{
+    let conn = conn.lock().unwrap();
+    let mut rows = vec![];
+    for (stmt, params) in statements {
+        let mut statement = conn.prepare(stmt)?;
+        for (i, param) in params.iter().enumerate() {
+            statement.bind(i + 1, param)?;
+        }
+        while let State::Row = statement.next()? {
+            if max_rows > 0 && rows.len() >= max_rows {
+                return Err(StoreError::TooManyApplyRows { limit: max_rows });
+            }
+            let mut row = QueryRow::new();
+            for i in 0..statement.column_count() {
+                row.values.push(read_text(&statement, i)?);
+            }
+            rows.push(row);
+        }
+    }
+    Ok(QueryResults {
+        rows,
+        ballot: None,
+        warnings: Vec::new(),
+        proposed_at: None,
+        committed_at: None,
+        served_locally: true,
+        forwarded_from: None,
+        analysis: Vec::new(),
+        log_idx: None,
+        acknowledged_by: 0,
+        configuration_id: 0,
+    })
+}
+
+/// Reads column `i` of the current row of `statement` as text.
+///
+/// Floats are rendered by SQLite, as for queries without parameters, so
+/// that results don't depend on whether parameters were bound: `1.0` reads
+/// as `1.0` and `0.1 * 3` as `0.3`, rather than `1` and
+/// `0.30000000000000004` as Rust formats them.
+fn read_text(statement: &sqlite::Statement<'_>, i: usize) -> Result<String, sqlite::Error> {
+    Ok(match statement.read::<Value>(i)? {
+        // reuses the buffer of blobs that are valid UTF-8
+        Value::Binary(b) => String::from_utf8(b).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned()),
+        Value::Float(_) => statement.read::<String>(i)?,
+        Value::Integer(i) => i.to_string(),
+        Value::String(s) => s,
+        Value::Null => String::from("NULL"),
+    })
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Folds `bytes` into the FNV-1a hash `hash`.
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// Ids of the commands in `entries`, including those of batches.
+pub(crate) fn command_ids(entries: &[StoreCommand]) -> Vec<u64> {
+    entries
+        .iter()
+        .flat_map(|e| if e.batch.is_empty() { std::slice::from_ref(e) } else { &e.batch[..] })
+        .map(|cmd| cmd.id)
+        .collect()
+}
+
+/// Checks that `params` has a value for every placeholder in `sql`.
+fn check_params(sql: &str, params: &[Value]) -> Result<(), StoreError> {
+    let expected: usize = sql::statements(sql).iter().map(|stmt| sql::placeholders(stmt)).sum();
+    if expected != params.len() {
+        return Err(StoreError::ParameterCount { expected, got: params.len() });
+    }
+    Ok(())
+}
+
+/// SQLite result code for a locked database file.
+const SQLITE_BUSY: isize = 5;
+/// SQLite result code for a locked table.
+const SQLITE_LOCKED: isize = 6;
+/// Initial backoff before retrying a command that hit `SQLITE_BUSY`.
+const BUSY_BACKOFF_MS: u64 = 10;
+
+fn is_busy(err: &StoreError) -> bool {
+    match err {
+        StoreError::SQLiteError(e) => matches!(e.code, Some(SQLITE_BUSY) | Some(SQLITE_LOCKED)),
+        _ => false,
+    }
+}
+
+/// Logs that `cmd`, of a request with an id, was applied at log index
+/// `idx`, so that a write can be traced to every node that applied it.
+fn trace_applied(this_id: u64, idx: u64, cmd: &StoreCommand) {
+    if cmd.request_id != 0 {
+        log(format!("Node {} applied command {} at log index {}", this_id, command_label(cmd), idx));
+    }
+}
+
+/// Identifies `cmd` in log messages, with the id of its request if set, so
+/// that a write can be traced across nodes.
+fn command_label(cmd: &StoreCommand) -> String {
+    if cmd.request_id == 0 {
+        cmd.id.to_string()
+    } else {
+        format!("{} (request {})", cmd.id, cmd.request_id)
+    }
+}
+
+/// Records that `cmd` failed to apply at log index `idx` with `e`, forgetting
+/// the oldest failure once `apply_errors` is full.
+fn record_apply_error(apply_errors: &Mutex<VecDeque<ApplyError>>, idx: u64, cmd: &StoreCommand, e: &StoreError) {
+    let mut apply_errors = apply_errors.lock().unwrap();
+    if apply_errors.len() >= APPLY_ERROR_LOG_CAPACITY {
+        apply_errors.pop_front();
+    }
+    apply_errors.push_back(ApplyError {
+        log_idx: idx,
+        command_id: cmd.id,
+        request_id: cmd.request_id,
+        sql: cmd.sql_text().into_owned(),
+        error: e.to_string(),
+    });
+}
+
+/// Returns true if `err` is specific to this node rather than to the command,
+/// i.e. other nodes may well apply the same command successfully.
+fn is_node_fault(err: &StoreError) -> bool {
+    match err {
+        StoreError::SQLiteError(e) => match e.code {
+            // SQLITE_BUSY, SQLITE_LOCKED, SQLITE_NOMEM, SQLITE_READONLY, SQLITE_IOERR,
+            // SQLITE_CORRUPT, SQLITE_FULL, SQLITE_CANTOPEN, SQLITE_NOTADB
+            Some(code) => matches!(code & 0xff, 5 | 6 | 7 | 8 | 10 | 11 | 13 | 14 | 26),
+            None => false,
+        },
+        StoreError::Dedup(_) => true,
+        _ => false,
+    }
+}
+
+/// `application_id` of the database while the cluster is in maintenance
+/// mode, zero otherwise.
+///
+/// The mode is kept in the database, so that it is set by a committed
+/// command like the data, survives restarts and is carried by snapshots.
+/// Only the maintenance commands set it: client SQL may not touch the
+/// `application_id`.
+const MAINTENANCE_APPLICATION_ID: u64 = 0x4d41_494e;
+
+/// Returns true if the database is in maintenance mode.
+fn in_maintenance_mode(conn: &Connection) -> Result<bool, StoreError> {
+    Ok(pragma(conn, "application_id")? == MAINTENANCE_APPLICATION_ID)
+}
+
+/// Rejects `sql` if it reads or sets the `application_id` of the database,
+/// which holds the maintenance mode.
+fn check_application_id(sql: &str) -> Result<(), StoreError> {
+    if sql::is_pragma(sql, "application_id") {
+        return Err(StoreError::StatementRejected(String::from("the application_id is reserved for maintenance mode")));
+    }
+    Ok(())
+}
+
+/// A decided command, with the decide that committed it.
+#[derive(Clone, Debug)]
+struct Decided {
+    /// Configuration whose log the command was decided in.
+    configuration_id: u32,
+    /// Log index of the entry of the command.
+    idx: u64,
+    cmd: StoreCommand,
+    /// Round the command was decided in.
+    ballot: Ballot,
+    /// When this node, as leader, proposed the command.
+    proposed_at: Option<SystemTime>,
+    /// When the command was decided.
+    decided_at: SystemTime,
+}
+
+impl Decided {
+    fn position(&self) -> LogPosition {
+        LogPosition {
+            configuration_id: self.configuration_id,
+            idx: self.idx,
+        }
+    }
+}
+
+/// Position of a log entry: the configuration whose log holds it, and its
+/// index in that log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct LogPosition {
+    configuration_id: u32,
+    idx: u64,
+}
+
+/// Decided commands that are not applied yet, because one of them found
+/// the database locked.
+///
+/// Shared by the stores of every configuration, so that commands put off
+/// by a configuration that stopped are applied by the next one.
+#[derive(Debug, Default)]
+struct PendingApplies {
+    /// Commands to apply, oldest first.
+    commands: VecDeque<Decided>,
+    /// Times in a row the first command found the database locked.
+    busy: u32,
+    /// When to apply the commands again, while they are put off.
+    retry_at: Option<Instant>,
+    /// Configuration and length of its decided log held by a database
+    /// restored from a snapshot, whose entries are not applied again.
+    restored: (u32, u64),
+}
+
+/// Outcome of applying a decided command.
+enum Applied {
+    /// The command was applied, with these results.
+    Results(QueryResults),
+    /// The command retries a request in the dedup window, which was applied
+    /// at this log position with these result rows.
+    Duplicate(LogPosition, Vec<QueryRow>),
+}
+
+/// Applies a decided command, decided at log position `at`, to the local
+/// SQLite instance, unless it retries a request in the dedup window.
+fn apply(conn: Arc<Mutex<Connection>>, at: LogPosition, cmd: &StoreCommand, dedup: &DedupWindow, max_rows: usize, commit_latency: &Arc<Histogram>) -> Result<Applied, StoreError> {
+    if let Some((applied_at, rows)) = dedup.lookup(&conn.lock().unwrap(), at, cmd)? {
+        return Ok(Applied::Duplicate(applied_at, rows));
+    }
+    execute_decided(conn, at, cmd, dedup, max_rows, commit_latency).map(Applied::Results)
+}
+
+/// Executes the SQL of `cmd`, decided at log position `at`, recording its
+/// request in the dedup window.
+///
+/// A command may span a whole transaction (including `SAVEPOINT` and
+/// `ROLLBACK TO` statements). If it fails part-way, SQLite leaves the
+/// transaction open on the connection, so it is rolled back here to make
+/// every node end up in the same state regardless of where it failed.
+///
+/// The request, and the schema if the command changed it, are recorded in
+/// the same transaction as the command, unless the command controls
+/// transactions itself, in which case they are recorded right after it
+/// committed.
+///
+/// Commands that fail because the database is locked are rolled back like
+/// any other failure; it is up to the caller to retry them.
+///
+/// Commands returning more than `max_rows` rows, such as large `RETURNING`
+/// clauses, fail and are rolled back; zero doesn't limit the rows.
+///
+/// Committing an autocommitted statement is timed in `commit_latency`; a
+/// command controlling transactions commits as part of its SQL.
+fn execute_decided(conn: Arc<Mutex<Connection>>, at: LogPosition, cmd: &StoreCommand, dedup: &DedupWindow, max_rows: usize, commit_latency: &Arc<Histogram>) -> Result<QueryResults, StoreError> {
+    let execute = || {
+        let results = query_command(conn.clone(), cmd, max_rows)?;
+        let conn = conn.lock().unwrap();
+        dedup.record(&conn, at, cmd, &results)?;
+        record_schema(&conn, cmd)?;
+        Ok(results)
+    };
+    let results = if !sql::controls_transaction(&cmd.sql_text()) {
+        // An autocommitted statement stopped part-way keeps what it
+        // wrote, so run it in a savepoint to roll that back.
+        in_savepoint(conn.clone(), &execute, commit_latency)
+    } else {
+        execute()
+    };
+    if results.is_err() {
+        // No transaction may be active, in which case this fails harmlessly.
+        let _ = conn.lock().unwrap().execute("ROLLBACK");
+    }
+    results
+}
+
+/// Runs `f` in a savepoint, rolled back if `f` fails.
+///
+/// Outside a transaction, releasing the savepoint commits it, which is timed
+/// in `commit_latency`.
+fn in_savepoint<F>(conn: Arc<Mutex<Connection>>, f: F, commit_latency: &Arc<Histogram>) -> Result<QueryResults, StoreError>
+where
+    F: FnOnce() -> Result<QueryResults, StoreError>,
+{
+    conn.lock().unwrap().execute("SAVEPOINT apply_rows")?;
+    let results = f();
+    let conn = conn.lock().unwrap();
+    match results {
+        Ok(_) => {
+            let _timer = Timer::new(commit_latency.clone());
+            conn.execute("RELEASE apply_rows")?
+        }
+        Err(_) => conn.execute("ROLLBACK TO apply_rows; RELEASE apply_rows")?,
+    }
+    results
+}
+
+/// Schema version of the database, kept in SQLite's `user_version`.
+fn schema_version(conn: Arc<Mutex<Connection>>) -> Result<u64, StoreError> {
+    let conn = conn.lock().unwrap();
+    let mut stmt = conn.prepare("PRAGMA user_version")?;
+    stmt.next()?;
+    Ok(stmt.read::<i64>(0)? as u64)
+}
+
+/// Value of the integer `pragma` of the database.
+fn pragma(conn: &Connection, pragma: &str) -> Result<u64, StoreError> {
+    let mut stmt = conn.prepare(format!("PRAGMA {}", pragma))?;
+    stmt.next()?;
+    Ok(stmt.read::<i64>(0)? as u64)
+}
+
+/// Reads the storage statistics of the database, except the decided index.
+fn read_storage_stats(conn: &Connection) -> Result<StorageStats, StoreError> {
+    let mut names = Vec::new();
+    let mut stmt = conn.prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' AND name NOT LIKE 'chiselstore_%' ORDER BY name")?;
+    while let State::Row = stmt.next()? {
+        names.push(stmt.read::<String>(0)?);
+    }
+    // dbstat is only available if SQLite was built with it.
+    let mut pages = HashMap::new();
+    if let Ok(mut stmt) = conn.prepare("SELECT name, COUNT(*) FROM dbstat GROUP BY name") {
+        while let State::Row = stmt.next()? {
+            pages.insert(stmt.read::<String>(0)?, stmt.read::<i64>(1)? as u64);
+        }
+    }
+    let mut tables = Vec::with_capacity(names.len());
+    for name in names {
+        let mut stmt = conn.prepare(format!("SELECT COUNT(*) FROM {}", quote_identifier(&name)))?;
+        stmt.next()?;
+        let rows = stmt.read::<i64>(0)? as u64;
+        let pages = if pages.is_empty() { None } else { Some(pages.get(&name).copied().unwrap_or(0)) };
+        tables.push(TableStats { name, rows, pages });
+    }
+    Ok(StorageStats {
+        decided_idx: 0,
+        page_count: pragma(conn, "page_count")?,
+        page_size: pragma(conn, "page_size")?,
+        freelist_count: pragma(conn, "freelist_count")?,
+        tables,
+    })
+}
+
+/// Applies a decided migration to the local SQLite instance.
+///
+/// The DDL and the schema version bump run in one transaction, with the
+/// schema they result in recorded, so a failed migration is rolled back as
+/// a whole.
+fn migrate(conn: Arc<Mutex<Connection>>, at: LogPosition, cmd: &StoreCommand, dedup: &DedupWindow, max_rows: usize, commit_latency: &Arc<Histogram>) -> Result<Applied, StoreError> {
+    if let Some((applied_at, rows)) = dedup.lookup(&conn.lock().unwrap(), at, cmd)? {
+        return Ok(Applied::Duplicate(applied_at, rows));
+    }
+    let current = schema_version(conn.clone())?;
+    if cmd.schema_version <= current {
+        return Err(StoreError::StaleSchemaVersion {
+            current,
+            requested: cmd.schema_version,
+        });
+    }
+    let migration = StoreCommand {
+        sql: format!("{};PRAGMA user_version = {};", cmd.sql.trim_end_matches(';'), cmd.schema_version),
+        statements: Vec::new(),
+        kind: CommandKind::Sql,
+        ..cmd.clone()
+    };
+    execute_decided(conn, at, &migration, dedup, max_rows, commit_latency).map(Applied::Results)
+}
+
+/// Applies decided commands in one transaction, rolling back each failed
+/// command to its savepoint.
+///
+/// Returns the results of every command, or an error if the transaction as a
+/// whole failed and was rolled back.
+fn apply_in_transaction(
+    conn: Arc<Mutex<Connection>>,
+    cmds: &[(LogPosition, &StoreCommand)],
+    dedup: &DedupWindow,
+    max_rows: usize,
+    commit_latency: &Arc<Histogram>,
+) -> Result<Vec<Result<Applied, StoreError>>, StoreError> {
+    conn.lock().unwrap().execute("BEGIN")?;
+    let mut results = Vec::with_capacity(cmds.len());
+    for &(at, cmd) in cmds {
+        let savepoint = conn.lock().unwrap().execute("SAVEPOINT apply_command");
+        let result = savepoint.map_err(StoreError::from).and_then(|_| {
+            // Looked up in the transaction, which sees the requests of the
+            // commands before.
+            if let Some((applied_at, rows)) = dedup.lookup(&conn.lock().unwrap(), at, cmd)? {
+                return Ok(Applied::Duplicate(applied_at, rows));
+            }
+            let results = query_command(conn.clone(), cmd, max_rows)?;
+            let conn = conn.lock().unwrap();
+            dedup.record(&conn, at, cmd, &results)?;
+            record_schema(&conn, cmd)?;
+            Ok(Applied::Results(results))
+        });
+        let conn = conn.lock().unwrap();
+        match result {
+            Err(ref e) if is_node_fault(e) => {
+                let _ = conn.execute("ROLLBACK");
+                return Err(result.unwrap_err());
+            }
+            Err(_) => conn.execute("ROLLBACK TO apply_command; RELEASE apply_command")?,
+            Ok(_) => conn.execute("RELEASE apply_command")?,
+        }
+        results.push(result);
+    }
+    let timer = Timer::new(commit_latency.clone());
+    let commit = conn.lock().unwrap().execute("COMMIT");
+    drop(timer);
+    if let Err(e) = commit {
+        let _ = conn.lock().unwrap().execute("ROLLBACK");
+        return Err(e.into());
+    }
+    Ok(results)
+}
+
+impl<S> Storage<StoreCommand, S> for SQLiteStore<S>
+where
+    S: Snapshot<StoreCommand>,
+{
+    fn append_entry(&mut self, entry: StoreCommand) -> u64 {
+        self.record_proposed(self.trimmed_idx + self.log.len() as u64, 1);
+        self.log_bytes.add(entry.size() as i64);
+        self.log.push(entry);
+        self.get_log_len()
+    }
+
+    fn append_entries(&mut self, entries: Vec<StoreCommand>) -> u64 {
+        self.record_proposed(self.trimmed_idx + self.log.len() as u64, entries.len());
+        self.log_bytes.add(entries.iter().map(|e| e.size() as i64).sum());
+        let mut e = entries;
+        self.log.append(&mut e);
+        self.get_log_len()
+    }
+
+    fn append_on_prefix(&mut self, from_idx: u64, entries: Vec<StoreCommand>) -> u64 {
+        self.log.truncate(from_idx as usize);
+        self.update_log_bytes();
+        self.proposed_at.retain(|&idx, _| idx < from_idx);
+        self.append_entries(entries)
+    }
+
+    fn set_promise(&mut self, n_prom: Ballot) {
+        self.n_prom = n_prom;
+    }
+
+    fn set_decided_idx(&mut self, ld: u64) {
+        
+        let old_ld = self.ld;
+        let new_ld = ld;
+
+        self.ld = ld;
+
+        
+        // the in-memory log starts at the trimmed index
+        let offset = self.trimmed_idx;
+
+        let ss = self.get_stopsign();
+        if self.log.len() + (offset as usize) < (new_ld as usize) && !ss.is_none() { // stop sign decided, do nothing
+            self.set_applied(new_ld);
+            return;
+        }
+
+        if new_ld > old_ld {
+            self.record_epoch(old_ld);
+        }
+
+        // commit decided transactions to DB; the entries before the trimmed
+        // index are in the snapshot the log was synchronized with
+        let from_ld = std::cmp::max(old_ld, offset);
+        let queries_to_run = match self.log.get(((from_ld - offset) as usize)..((new_ld.max(from_ld) - offset) as usize)) {
+            Some(entries) => entries.to_vec(),
+            None => Vec::new(),
+        };
+        
+        self.decided_at = self.clock.wall_time();
+        self.apply_lag.set(new_ld.saturating_sub(self.applied_idx.get() as u64) as i64);
+        self.apply_entries(from_ld, queries_to_run);
+        self.proposed_at.retain(|&idx, _| idx >= new_ld);
+    }
+
+    fn get_decided_idx(&self) -> u64 {
+        self.ld
+    }
+
+    fn set_accepted_round(&mut self, na: Ballot) {
+        if na != self.acc_round {
+            // Entries of the previous round are proposed again by the new leader.
+            self.proposed_at.clear();
+        }
+        self.acc_round = na;
+    }
+
+    fn get_accepted_round(&self) -> Ballot {
+        self.acc_round
+    }
+
+    fn get_entries(&self, from: u64, to: u64) -> &[StoreCommand] {
+        self.log.get(from as usize..to as usize).unwrap_or(&[])
+    }
+
+    fn get_log_len(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn get_suffix(&self, from: u64) -> &[StoreCommand] {
+        match self.log.get(from as usize..) {
+            Some(s) => s,
+            None => &[],
+        }
+    }
+
+    fn get_promise(&self) -> Ballot {
+        self.n_prom
+    }
+
+    fn set_stopsign(&mut self, s: StopSignEntry) {
+        self.stopsign = Some(s);
+    }
+    
+    fn get_stopsign(&self) -> Option<StopSignEntry> {
+        self.stopsign.clone()
+    }
+    
+    // TEMP: Snapshot impl
+    fn trim(&mut self, trimmed_idx: u64) {
+        // A follower synchronized with a snapshot trims past its log.
+        self.log.drain(0..std::cmp::min(trimmed_idx as usize, self.log.len()));
+        self.update_log_bytes();
+        // A database discarded from now on can't be rebuilt from the log.
+        if let Err(e) = write_state(&self.conn_pool[0].lock().unwrap(), "log_trimmed", 1) {
+            log(format!("Node {} failed to record that its log was trimmed: {}", self.this_id, e));
+        }
+    }
+
+    fn set_compacted_idx(&mut self, trimmed_idx: u64) {
+        self.trimmed_idx = trimmed_idx;
+    }
+
+    fn get_compacted_idx(&self) -> u64 {
+        self.trimmed_idx
+    }
+
+    fn set_snapshot(&mut self, snapshot: S) {
+        self.snapshot = Some(snapshot);
+    }
+
+    fn get_snapshot(&self) -> Option<S> {
+        self.snapshot.clone()
+    }
+}
+
+type StoreSequencePaxos = SequencePaxos<StoreCommand, (), SQLiteStore<()>>;
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct StoreServer<T: StoreTransport + Send + Sync> {
+    this_id: u64,
+    config: StoreServerConfig,
+    next_cmd_id: AtomicU64,
+    write_buffer: Mutex<WriteBuffer>,
+    #[derivative(Debug = "ignore")]
+    sequence_paxos: Arc<Mutex<StoreSequencePaxos>>,
+    #[derivative(Debug = "ignore")]
+    ballot_leader_election: Arc<Mutex<BallotLeaderElection>>,
+    transport: T,
+    query_results_holder: Arc<Mutex<QueryResultsHolder>>,
+    halt: Arc<Mutex<bool>>,
+    /// Peers of this node.
+    peers: Vec<u64>,
+    /// Nodes in the current configuration, including this one.
+    members: Mutex<Vec<u64>>,
+    /// Id of the current configuration.
+    configuration_id: AtomicU32,
+    /// Messages received for configurations not adopted yet, by configuration id.
+    #[derivative(Debug = "ignore")]
+    future_msgs: Mutex<Vec<(u32, Message<StoreCommand, ()>)>>,
+    /// Connection for queries served locally, such as reads on a learner.
+    #[derivative(Debug = "ignore")]
+    local_conn: Arc<Mutex<Connection>>,
+    /// Length of the log applied by this learner.
+    learner_applied_idx: AtomicU64,
+    /// Configuration and length of the log applied so far, published
+    /// whenever either advances.
+    #[derivative(Debug = "ignore")]
+    applied: Arc<watch::Sender<(u32, u64)>>,
+    /// Kept so that publishing to `applied` never fails for lack of
+    /// receivers; reads waiting for an index clone it.
+    #[derivative(Debug = "ignore")]
+    applied_rx: watch::Receiver<(u32, u64)>,
+    /// Decided commands put off because the database was locked.
+    pending_applies: Arc<Mutex<PendingApplies>>,
+    /// Request ids of recently applied commands.
+    dedup: Arc<DedupWindow>,
+    /// Commands that failed to apply.
+    apply_errors: Arc<Mutex<VecDeque<ApplyError>>>,
+    /// Leadership epochs seen by this node.
+    epochs: Arc<Mutex<VecDeque<Epoch>>>,
+    /// Audit log of committed writes, and SQL trace log.
+    audit: Option<Arc<Mutex<AuditLog>>>,
+    /// Change feed of committed writes.
+    changes: Arc<ChangeFeed>,
+    /// Leader changes seen by this node.
+    leader_history: Mutex<LeaderHistory>,
+    /// Leader of the current ballot, zero if unknown, refreshed whenever
+    /// sequence paxos handles a message or leader, so that reading it
+    /// doesn't contend for the sequence paxos lock.
+    current_leader: Arc<AtomicU64>,
+    /// Length of the log each peer last reported accepting to this node,
+    /// as its leader.
+    accepted: Mutex<Acceptances>,
+    /// Notified whenever a peer reports accepting more of the log.
+    #[derivative(Debug = "ignore")]
+    accepted_changed: watch::Sender<()>,
+    /// Kept so that notifying `accepted_changed` never fails for lack of
+    /// receivers; writes waiting for acceptances clone it.
+    #[derivative(Debug = "ignore")]
+    accepted_rx: watch::Receiver<()>,
+    /// When each peer last replied to a heartbeat of this node.
+    heartbeat_replies: Mutex<HashMap<u64, Instant>>,
+    /// Progress of consensus, for detecting stalls.
+    progress: Mutex<ConsensusProgress>,
+    /// When the decided index advanced, for measuring staleness.
+    decide_times: Mutex<DecideTimes>,
+    /// Progress of automatic snapshots.
+    snapshot_state: Arc<Mutex<SnapshotState>>,
+    /// Open read snapshots, by id.
+    read_snapshots: Mutex<HashMap<u64, ReadSnapshot>>,
+    next_read_snapshot_id: AtomicU64,
+    metrics: Arc<Registry>,
+    #[derivative(Debug = "ignore")]
+    row_transform: Mutex<Option<Arc<RowTransform>>>,
+    #[derivative(Debug = "ignore")]
+    sql_validator: Mutex<Option<Arc<SqlValidator>>>,
+    #[derivative(Debug = "ignore")]
+    priority_hook: Mutex<Option<Arc<PriorityHook>>>,
+    /// Synchronization with a snapshot this node is catching up from.
+    #[derivative(Debug = "ignore")]
+    catch_up: Mutex<Option<CatchUp>>,
+    /// Queues of sequence paxos messages received from each peer.
+    #[derivative(Debug = "ignore")]
+    inbound: Mutex<HashMap<u64, mpsc::Sender<Message<StoreCommand, ()>>>>,
+    /// Permits of the queries being executed, if their number is limited.
+    query_permits: Option<Semaphore>,
+}
+
+/// Synchronization of this node's log with a snapshot, held back until the
+/// database is restored from a copy of the leader's.
+struct CatchUp {
+    /// Leader that sent the synchronization.
+    from: u64,
+    /// Length of the decided log the snapshot covers.
+    sync_idx: u64,
+    /// The synchronization, followed by the messages of the leader received
+    /// since.
+    msgs: Vec<Message<StoreCommand, ()>>,
+}
+
+/// Transformation applied to every row returned to a client.
+///
+/// Called with the SQL statement that produced the row, it may modify or
+/// clear the row's values, e.g. to mask personal data.
+pub type RowTransform = dyn Fn(&str, &mut QueryRow) + Send + Sync;
+
+/// Source of this node's leader election priority, such as a function of
+/// its CPU or query load.
+pub type PriorityHook = dyn Fn() -> u64 + Send + Sync;
+
+/// Validation of SQL before it is executed.
+///
+/// Called with each statement, it returns why the statement is rejected,
+/// if it is.
+pub type SqlValidator = dyn Fn(&str) -> Result<(), String> + Send + Sync;
+
+/// Returns a validator that only allows statements of the given kinds,
+/// identified by their leading keyword, such as `SELECT` or `INSERT`.
+pub fn statement_allowlist<S: AsRef<str>>(kinds: &[S]) -> impl Fn(&str) -> Result<(), String> + Send + Sync + 'static {
+    let kinds: Vec<String> = kinds.iter().map(|kind| kind.as_ref().to_ascii_uppercase()).collect();
+    move |stmt: &str| {
+        let kind = sql::keyword(stmt);
+        if kinds.contains(&kind) {
+            Ok(())
+        } else {
+            Err(format!("{} statements are not allowed", kind))
+        }
+    }
+}
+
+/// Query row.
+#[derive(Debug)]
+pub struct QueryRow {
+    /// Column values of the row.
+    pub values: Vec<String>,
+}
+
+impl QueryRow {
+    fn new() -> Self {
+        QueryRow { values: Vec::new() }
+    }
+}
+
+/// Rows of a streaming query, read from SQLite as they are consumed.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct QueryStream {
+    sql: String,
+    rows: mpsc::Receiver<Result<QueryRow, StoreError>>,
+    buffered: Arc<Gauge>,
+    #[derivative(Debug = "ignore")]
+    transform: Option<Arc<RowTransform>>,
+}
+
+impl Stream for QueryStream {
+    type Item = Result<QueryRow, StoreError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut row = self.rows.poll_recv(cx);
+        if let Poll::Ready(Some(ref mut row)) = row {
+            self.buffered.dec();
+            if let (Ok(row), Some(transform)) = (row, &self.transform) {
+                transform(&self.sql, row);
+            }
+        }
+        row
+    }
+}
+
+/// Steps through the statements in `sql` on a dedicated connection, sending
+/// each row to `rows` and blocking while it is full.
+///
+/// Stops early if the receiving end is dropped.
+fn stream_rows(
+    this_id: u64,
+    options: &ConnectionOptions,
+    sql: &str,
+    params: &[Value],
+    rows: mpsc::Sender<Result<QueryRow, StoreError>>,
+    produced: Arc<Counter>,
+    buffered: Arc<Gauge>,
+) -> Result<(), StoreError> {
+    let conn = open_connection(this_id, options)?;
+    let mut params = params.iter();
+    for stmt in sql::statements(sql) {
+        let mut statement = conn.prepare(stmt)?;
+        for i in 1..=sql::placeholders(stmt) {
+            if let Some(param) = params.next() {
+                statement.bind(i, param)?;
+            }
+        }
+        while let State::Row = statement.next()? {
+            let mut row = QueryRow::new();
+            for i in 0..statement.column_count() {
+                row.values.push(read_text(&statement, i)?);
+            }
+            produced.inc();
+            buffered.inc();
+            if rows.blocking_send(Ok(row)).is_err() {
+                buffered.dec();
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Consistency level of a query.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Consistency {
+    /// The query is replicated through the log, so it observes every write
+    /// decided before it (linearizability).
+    Strong,
+    /// Read-only queries are executed on the local node, which may not have
+    /// applied the latest writes yet. Writes are always replicated.
+    RelaxedReads,
+    /// The consistency configured for the tables the query touches in
+    /// `StoreServerConfig::table_consistency`: the strongest of them, and
+    /// strong if any of them has none.
+    TableDefault,
+}
+
+impl Default for Consistency {
+    fn default() -> Self {
+        Consistency::Strong
+    }
+}
+
+/// Query options.
+#[derive(Clone, Debug, Default)]
+pub struct QueryOptions {
+    /// Values bound to the `?` placeholders of the statement, in order.
+    pub params: Vec<Value>,
+    /// Consistency level of the query.
+    pub consistency: Consistency,
+    /// Client-chosen id of the request, for deduplicating retried writes.
+    /// Zero if the request should not be deduplicated.
+    pub request_id: u64,
+    /// Identity of the client, recorded in the audit log with its writes.
+    pub client: String,
+    /// Id of the read snapshot to run the query against, or zero.
+    ///
+    /// Only read-only statements can run against a read snapshot.
+    pub read_snapshot: u64,
+    /// Role of the authenticated client, or `None` if it is not restricted.
+    ///
+    /// Must come from [`StoreServer::authenticate`], never from the client.
+    pub role: Option<Role>,
+    /// Validate a write without committing it: it runs on the leader in a
+    /// transaction that is rolled back, and is never proposed. Read-only
+    /// statements run as usual.
+    ///
+    /// A dry run is not linearizable: it sees the leader's database as
+    /// applied so far, and a write that passed may still fail, or fail
+    /// differently, once proposed.
+    pub dry_run: bool,
+    /// Report the query plan and execution statistics of each statement in
+    /// the `analysis` of the results. Only read-only statements can be
+    /// analyzed.
+    ///
+    /// The query really runs, against this node's database, so it costs as
+    /// much as the query itself, and more for statements returning many rows.
+    pub explain_analyze: bool,
+    /// Members that must have accepted a write before it returns, counting
+    /// the leader; a quorum always has once it is decided. Zero or a quorum
+    /// return as soon as the write is decided.
+    ///
+    /// Waiting for more than a quorum is bounded by the `commit_timeout`,
+    /// after which the write returns with a warning and the members that
+    /// accepted it so far in `acknowledged_by`: it is committed all the
+    /// same. Only the leader learns of acceptances beyond a quorum, so other
+    /// nodes reject such writes with `StoreError::NotLeader`.
+    pub min_replicas: u64,
+    /// Length of the log this node must have applied before serving a read,
+    /// so that the read sees the write at index `min_index - 1`, wherever it
+    /// was made. Zero doesn't wait.
+    ///
+    /// Waiting is bounded by the `commit_timeout`, after which the read fails
+    /// with `StoreError::IndexNotReached`.
+    pub min_index: u64,
+    /// Configuration whose log `min_index` indexes, as reported in the
+    /// `configuration_id` of the write's results. Log indices restart with
+    /// every configuration, so a node in a later configuration has applied
+    /// the write already. Zero means this node's current configuration.
+    pub min_index_configuration: u32,
+    /// Submit a write without waiting for it to commit: an unacknowledged
+    /// write with dry-run results. Unsafe for strict durability: a write
+    /// that succeeded may never be applied.
+    ///
+    /// The returned results are those of a dry run of the write on the
+    /// leader, while the write itself is replicated in the background. They
+    /// may differ from the results the write commits with, as other writes
+    /// may be applied before it. Reads see the write once it is committed
+    /// and applied, like any other. A write that is not decided within the
+    /// `commit_timeout`, or fails when applied, is only logged and counted
+    /// in the `unacknowledged_failures` metric. A write whose commit timed
+    /// out may still be decided later, though.
+    ///
+    /// Only the leader accepts unacknowledged writes; other nodes reject
+    /// them with `StoreError::NotLeader`.
+    pub unacknowledged: bool,
+}
+
+/// Query results.
+#[derive(Debug)]
+pub struct QueryResults {
+    /// Query result rows.
+    pub rows: Vec<QueryRow>,
+    /// Ballot of the leader under which the command was decided, if it was
+    /// replicated through the log.
+    pub ballot: Option<Ballot>,
+    /// Caveats of the query, which succeeded nonetheless.
+    ///
+    /// For example, the rows of a write over `max_result_rows` are
+    /// truncated.
+    pub warnings: Vec<String>,
+    /// When the leader proposed the command, set on writes whose results
+    /// come from the leader. Only the leader's timestamps are reported, so
+    /// that they are comparable with each other.
+    pub proposed_at: Option<SystemTime>,
+    /// When the leader saw the command decided, set along with `proposed_at`.
+    pub committed_at: Option<SystemTime>,
+    /// Whether the query was served from this node's database, with eventual
+    /// consistency, rather than through the replicated log.
+    pub served_locally: bool,
+    /// Node that forwarded the command to the leader that proposed it, or
+    /// `None` if the command was submitted to the leader or not replicated.
+    ///
+    /// A forwarded command takes an extra hop before it is proposed.
+    pub forwarded_from: Option<u64>,
+    /// Execution statistics of each statement, if the query was run with
+    /// `explain_analyze`.
+    pub analysis: Vec<StatementAnalysis>,
+    /// Index of the command's entry in the log, if it was replicated
+    /// through the log.
+    pub log_idx: Option<u64>,
+    /// Members known to have accepted the command's entry when the results
+    /// were returned, counting this node; zero if it was not replicated.
+    ///
+    /// A decided entry was accepted by at least a quorum, and only the
+    /// leader learns of the acceptances beyond that, so other nodes report a
+    /// quorum.
+    pub acknowledged_by: u64,
+    /// Configuration whose log `log_idx` indexes, zero if the command was
+    /// not replicated through the log.
+    pub configuration_id: u32,
+}
+
+/// Execution statistics of a statement run with `explain_analyze`.
+#[derive(Clone, Debug)]
+pub struct StatementAnalysis {
+    /// The statement.
+    pub sql: String,
+    /// Steps of the query plan SQLite chose, as reported by `EXPLAIN QUERY
+    /// PLAN`, e.g. `SCAN t` or `SEARCH t USING INTEGER PRIMARY KEY (rowid=?)`.
+    pub plan: Vec<String>,
+    /// Rows the statement returned.
+    ///
+    /// SQLite only counts the rows each step of the plan scanned when built
+    /// with `SQLITE_ENABLE_STMT_SCANSTATUS`, so they are not reported.
+    pub rows: u64,
+    /// Time until the statement returned its first row, or `None` if it
+    /// returned none.
+    pub first_row: Option<Duration>,
+    /// Time to run the statement to completion.
+    pub elapsed: Duration,
+}
+
+const HEARTBEAT_TIMEOUT: u64 = 10; // ticks until timeout
+const MESSAGE_LOOP_TIMEOUT_MS: u64 = 1; // How often to check for new outgoing messages
+const BLE_LOOP_TIMEOUT_MS: u64 = 100; // How often to check to do a BLE tick
+const LEARNER_LOOP_TIMEOUT_MS: u64 = 10; // How often a learner fetches decided entries
+const VOTER_CONNECTED_WINDOW: Duration = Duration::from_millis(3 * HEARTBEAT_TIMEOUT * BLE_LOOP_TIMEOUT_MS); // How long a peer counts as connected after its last heartbeat reply
+const LEADER_CHANGE_WINDOW: Duration = Duration::from_secs(60); // How long leader changes count as recent
+impl<T: StoreTransport + Send + Sync> StoreServer<T> {
+    /// Start a new server as part of a ChiselStore cluster made of this
+    /// node and its peers.
+    pub fn start(this_id: u64, peers: Vec<u64>, transport: T) -> Result<Self, StoreError> {
+        let config = StoreServerConfig {
+            membership: std::iter::once(this_id).chain(peers.iter().copied()).collect(),
+            ..Default::default()
+        };
+        Self::start_with_config(this_id, peers, transport, config)
+    }
+
+    /// Start a new server with the given configuration as part of a ChiselStore cluster.
+    pub fn start_with_config(
+        this_id: u64,
+        peers: Vec<u64>,
+        transport: T,
+        config: StoreServerConfig,
+    ) -> Result<Self, StoreError> {
+        for (i, &peer) in peers.iter().enumerate() {
+            if peer == this_id || peers[..i].contains(&peer) {
+                return Err(StoreError::DuplicateNodeId(peer));
+            }
+        }
+        if config.membership.is_empty() {
+            return Err(StoreError::MembershipRequired);
+        }
+        if !config.membership.contains(&this_id) {
+            return Err(StoreError::NotAMember(this_id));
+        }
+        if let Some(&peer) = peers.iter().find(|&&p| !config.membership.contains(&p)) {
+            return Err(StoreError::NotAMember(peer));
+        }
+
+        transport.set_extensions(config.extensions_fingerprint());
+
+        // routing updated at runtime, before the node restarted
+        for (node_id, addr) in recorded_node_addresses(this_id)? {
+            transport.update_node_address(node_id, &addr);
+        }
+
+        // sequence paxos
+        let configuration_id = 1;
+
+        let mut sp_config = SequencePaxosConfig::default();
+        sp_config.set_configuration_id(configuration_id);
+        sp_config.set_pid(this_id);
+        sp_config.set_peers(peers.to_vec());
+
+        let query_results_holder = Arc::new(Mutex::new(QueryResultsHolder::default()));
+        let halt = Arc::new(Mutex::new(false));
+
+        // Opened first, so that a database that can't be opened fails startup.
+        let mut local_conn = open_connection(this_id, &config.connection_options())?;
+        match verify_schema(&local_conn) {
+            Ok(()) => {}
+            Err(e @ StoreError::SchemaMismatch { .. }) => match config.schema_mismatch_policy {
+                SchemaMismatchPolicy::Ignore => log(format!("Node {} starting despite a schema mismatch: {}", this_id, e)),
+                SchemaMismatchPolicy::Refuse => return Err(e),
+                SchemaMismatchPolicy::Rebuild if log_trimmed(&local_conn)? => {
+                    log(format!("Node {} can't rebuild its database from a trimmed log: {}", this_id, e));
+                    return Err(e);
+                }
+                SchemaMismatchPolicy::Rebuild => {
+                    log(format!("Node {} discarding its database: {}", this_id, e));
+                    drop(local_conn);
+                    discard_database(this_id)?;
+                    local_conn = open_connection(this_id, &config.connection_options())?;
+                }
+            },
+            Err(e) => return Err(e),
+        }
+        open_state(&local_conn)?;
+        if let Some(ref dir) = config.temp_store_directory {
+            // Global to the process, so it is set on this connection alone.
+            let dir = dir.to_string_lossy().replace('\'', "''");
+            local_conn.execute(format!("PRAGMA temp_store_directory = '{}'", dir))?;
+        }
+        let local_conn = Arc::new(Mutex::new(local_conn));
+
+        let metrics = Registry::new();
+        let dedup = Arc::new(DedupWindow::new(config.dedup_window, config.dedup_idle_horizon, metrics.gauge("dedup_requests")));
+        dedup.open(&local_conn.lock().unwrap())?;
+        let apply_errors = Arc::new(Mutex::new(VecDeque::new()));
+        let epochs = Arc::new(Mutex::new(VecDeque::new()));
+        let audit = AuditLog::open(config.audit_log.as_deref(), config.sql_trace_log.as_deref(), config.audit_redact_sql, config.clock.clone())?
+            .map(|audit| Arc::new(Mutex::new(audit)));
+        let changes = Arc::new(ChangeFeed::new(config.change_buffer_capacity, config.change_overflow_policy, &metrics));
+        let (applied, applied_rx) = watch::channel((configuration_id, 0));
+        let (accepted_changed, accepted_rx) = watch::channel(());
+        let applied = Arc::new(applied);
+        let pending_applies = Arc::new(Mutex::new(PendingApplies::default()));
+        let shared = SharedState {
+            query_results_holder: query_results_holder.clone(),
+            halt: halt.clone(),
+            dedup: dedup.clone(),
+            apply_errors: apply_errors.clone(),
+            epochs: epochs.clone(),
+            audit: audit.clone(),
+            changes: changes.clone(),
+            applied: applied.clone(),
+            pending: pending_applies.clone(),
+            metrics: metrics.clone(),
+        };
+        let sequence_paxos = Arc::new(Mutex::new(new_sequence_paxos(configuration_id, this_id, peers.clone(), shared, &config, None)?));
+
+        // ballot leader election
+        let mut ble_config = BLEConfig::default();
+        ble_config.set_pid(this_id);
+        ble_config.set_peers(peers.clone());
+        ble_config.set_hb_delay(HEARTBEAT_TIMEOUT);
+
+        let ballot_leader_election = Arc::new(Mutex::new(BallotLeaderElection::with(ble_config)));
+
+        let mut members = peers.clone();
+        members.push(this_id);
+
+        let snapshot_state = Arc::new(Mutex::new(SnapshotState {
+            last_idx: 0,
+            last_at: config.clock.now(),
+            running: false,
+            pins: HashMap::new(),
+        }));
+
+        let query_permits = match config.max_concurrent_queries {
+            0 => None,
+            max => Some(Semaphore::new(max)),
+        };
+
+        let started_at = config.clock.now();
+        Ok(StoreServer {
+            this_id,
+            write_buffer: Mutex::new(WriteBuffer::new(started_at)),
+            config,
+            next_cmd_id: AtomicU64::new(0),
+            sequence_paxos,
+            ballot_leader_election,
+            transport,
+            query_results_holder,
+            halt,
+            peers,
+            members: Mutex::new(members),
+            configuration_id: AtomicU32::new(configuration_id),
+            future_msgs: Mutex::new(Vec::new()),
+            local_conn,
+            learner_applied_idx: AtomicU64::new(0),
+            applied,
+            applied_rx,
+            pending_applies,
+            dedup,
+            apply_errors,
+            epochs,
+            audit,
+            changes,
+            leader_history: Mutex::new(LeaderHistory::new()),
+            current_leader: Arc::new(AtomicU64::new(0)),
+            accepted: Mutex::new(Acceptances::default()),
+            accepted_changed,
+            accepted_rx,
+            heartbeat_replies: Mutex::new(HashMap::new()),
+            progress: Mutex::new(ConsensusProgress {
+                decided_idx: 0,
+                since: started_at,
+                stalled: false,
+            }),
+            decide_times: Mutex::new(DecideTimes::new()),
+            snapshot_state,
+            read_snapshots: Mutex::new(HashMap::new()),
+            next_read_snapshot_id: AtomicU64::new(1),
+            metrics,
+            row_transform: Mutex::new(None),
+            sql_validator: Mutex::new(None),
+            priority_hook: Mutex::new(None),
+            catch_up: Mutex::new(None),
+            inbound: Mutex::new(HashMap::new()),
+            query_permits,
+        })
+    }
+
+    /// Returns the state that the store of a new configuration shares with
+    /// this server.
+    fn shared_state(&self) -> SharedState {
+        SharedState {
+            query_results_holder: self.query_results_holder.clone(),
+            halt: self.halt.clone(),
+            dedup: self.dedup.clone(),
+            apply_errors: self.apply_errors.clone(),
+            epochs: self.epochs.clone(),
+            audit: self.audit.clone(),
+            changes: self.changes.clone(),
+            applied: self.applied.clone(),
+            pending: self.pending_applies.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    /// Run the blocking event loop.
+    pub async fn run_message_loop(&self) {
+        if self.config.learner {
+            return self.run_learner_loop().await;
+        }
+        loop {
+            sleep(Duration::from_millis(MESSAGE_LOOP_TIMEOUT_MS)).await;
+
+            if *self.halt.lock().unwrap() {
+                break
+            }
+
+            self.catch_up_from_snapshot().await;
+
+            let now = self.config.clock.now();
+            self.expire_read_snapshots(now);
+            let linger = self.write_buffer_linger(now);
+            let batch = self.write_buffer.lock().unwrap().take_expired(linger, now);
+
+            let mut sequence_paxos = self.sequence_paxos.lock().unwrap();
+            let mut ballot_leader_election = self.ballot_leader_election.lock().unwrap();
+
+            // propose buffered writes
+            self.append_batch(&mut sequence_paxos, batch);
+
+            // fail the commands of the lost forwarded proposals
+            let dropped = self.transport.take_dropped_proposals();
+            if !dropped.is_empty() {
+                let mut query_results_holder = self.query_results_holder.lock().unwrap();
+                for (peer, id) in dropped {
+                    query_results_holder.push_result(id, Err(StoreError::ProposalDropped { peer }));
+                }
+            }
+
+            // resynchronize the nodes whose logs may have gaps
+            for peer in self.transport.take_lost_peers() {
+                sequence_paxos.reconnected(peer);
+            }
+
+            // send outgoing messages
+
+            let configuration_id = self.configuration_id.load(Ordering::SeqCst);
+            for out_msg in sequence_paxos.get_outgoing_msgs() {
+                let receiver = out_msg.to;
+                let proposed = match out_msg.msg {
+                    PaxosMsg::ProposalForward(ref entries) => command_ids(entries),
+                    _ => Vec::new(),
+                };
+                if let Err(e) = self.transport.send_sp(receiver, configuration_id, out_msg) {
+                    log(format!("Node {} failed to forward proposals to node {}: {}", self.this_id, receiver, e));
+                    let mut query_results_holder = self.query_results_holder.lock().unwrap();
+                    for id in proposed {
+                        query_results_holder.push_result(id, Err(StoreError::ProposalDropped { peer: receiver }));
+                    }
+                }
+            }
+
+            for out_msg in ballot_leader_election.get_outgoing_msgs() {
+                let receiver = out_msg.to;
+                self.transport.send_ble(receiver, out_msg);
+            }
+
+            self.current_leader.store(sequence_paxos.get_current_leader(), Ordering::SeqCst);
+            if sequence_paxos.get_current_leader() == self.this_id {
+                self.maybe_snapshot(sequence_paxos.get_decided_idx(), now);
+            }
+            self.retry_pending_applies(&mut sequence_paxos, now);
+            self.check_progress(sequence_paxos.get_decided_idx(), now);
+            self.decide_times.lock().unwrap().record(sequence_paxos.get_decided_idx(), self.config.clock.wall_time());
+
+            // check if node has stopped
+            if sequence_paxos.stopped() {
+                let final_entry = sequence_paxos.read(sequence_paxos.get_decided_idx());
+                
+                if final_entry.is_some() {
+                    let final_entry = final_entry.unwrap();
+                    match final_entry {
+                        LogEntry::StopSign(ss) => {
+                            *self.members.lock().unwrap() = ss.nodes.clone();
+                            if !ss.nodes.contains(&self.this_id) { // node not part of new configuration
+                                return;
+                            }
+                            
+                            let configuration_id = ss.config_id;
+                            
+                            let mut nodes = ss.nodes;
+                            let this_idx = nodes.iter().position(|&n| n == self.this_id).unwrap();
+                            nodes.remove(this_idx);
+                            
+                            let peers = nodes;
+                            self.transport.set_peers(&peers);
+
+                            *sequence_paxos = match new_sequence_paxos(configuration_id, self.this_id, peers, self.shared_state(), &self.config, ballot_leader_election.get_leader()) {
+                                Ok(sequence_paxos) => sequence_paxos,
+                                Err(e) => {
+                                    log(format!("Node {} halting: failed to adopt configuration {}: {}", self.this_id, configuration_id, e));
+                                    *self.halt.lock().unwrap() = true;
+                                    return;
+                                }
+                            };
+
+                            // handle the messages that arrived before the configuration was adopted
+                            let mut future_msgs = self.future_msgs.lock().unwrap();
+                            self.configuration_id.store(configuration_id, Ordering::SeqCst);
+                            let _ = self.applied.send((configuration_id, 0));
+                            // the log of the new configuration starts over
+                            *self.accepted.lock().unwrap() = Acceptances::default();
+                            for (config_id, msg) in std::mem::take(&mut *future_msgs) {
+                                if config_id == configuration_id {
+                                    sequence_paxos.handle(msg);
+                                } else if config_id > configuration_id {
+                                    future_msgs.push((config_id, msg));
+                                }
+                            }
+                            self.current_leader.store(sequence_paxos.get_current_leader(), Ordering::SeqCst);
+                        },
+                        _ => panic!("Unexpected log entry"),
+                    };
+                }
+            };
+        }
+        // Closing the inbound queues stops their tasks.
+        self.inbound.lock().unwrap().clear();
+    }
+    
+    /// Proposes a no-op once the commands put off because the database was
+    /// locked are due to be retried, so that its decide applies them.
+    fn retry_pending_applies(&self, sequence_paxos: &mut StoreSequencePaxos, now: Instant) {
+        {
+            let mut pending = self.pending_applies.lock().unwrap();
+            match pending.retry_at {
+                Some(at) if at <= now => pending.retry_at = None,
+                _ => return,
+            }
+        }
+        let cmd = StoreCommand {
+            id: self.next_cmd_id.fetch_add(1, Ordering::SeqCst),
+            sql: String::new(),
+            params: Vec::new(),
+            batch: Vec::new(),
+            schema_version: 0,
+            request_id: 0,
+            client: String::new(),
+            origin: self.this_id,
+            statements: Vec::new(),
+            kind: CommandKind::Sql,
+        };
+        if let Err(e) = sequence_paxos.append(cmd) {
+            log(format!("Node {} failed to propose a no-op to retry its applies: {:?}", self.this_id, e));
+            self.pending_applies.lock().unwrap().retry_at = Some(now + Duration::from_millis(BUSY_BACKOFF_MS));
+        }
+    }
+
+    /// Snapshot the database and trim the log in the background, if a
+    /// snapshot is due.
+    fn maybe_snapshot(&self, decided_idx: u64, now: Instant) {
+        if !self.pending_applies.lock().unwrap().commands.is_empty() {
+            // The database doesn't hold every decided entry yet.
+            return;
+        }
+        {
+            let mut snapshot_state = self.snapshot_state.lock().unwrap();
+            let log_bytes = self.metrics.gauge("log_bytes").get() as u64;
+            if !snapshot_state.is_due(&self.config, decided_idx, log_bytes, now) {
+                return;
+            }
+            snapshot_state.running = true;
+        }
+        let this_id = self.this_id;
+        let options = self.config.connection_options();
+        let sequence_paxos = self.sequence_paxos.clone();
+        let snapshot_state = self.snapshot_state.clone();
+        let snapshots = self.metrics.counter("snapshots");
+        let pruned = self.metrics.counter("snapshots_pruned");
+        let clock = self.config.clock.clone();
+        let retention = self.config.snapshot_retention;
+        let followers_idx = self.followers_accepted_idx();
+        tokio::task::spawn(async move {
+            let path = snapshot_path(this_id, if retention > 0 { Some(decided_idx) } else { None });
+            let snapshot = tokio::task::spawn_blocking(move || snapshot_database(this_id, &options, &path)).await;
+            let mut trimmed = false;
+            match snapshot {
+                Ok(Ok(())) => {
+                    // Every entry up to decided_idx was applied before the snapshot was taken.
+                    match sequence_paxos.lock().unwrap().trim(Some(decided_idx)) {
+                        Ok(_) => trimmed = true,
+                        Err(e) => log(format!("Node {} could not trim the log to {}: {:?}", this_id, decided_idx, e)),
+                    }
+                }
+                Ok(Err(e)) => log(format!("Node {} failed to snapshot the database: {}", this_id, e)),
+                Err(e) => log(format!("Node {} snapshot task failed: {}", this_id, e)),
+            }
+            {
+                let mut snapshot_state = snapshot_state.lock().unwrap();
+                snapshot_state.running = false;
+                if trimmed {
+                    snapshots.inc();
+                    snapshot_state.last_idx = decided_idx;
+                    snapshot_state.last_at = clock.now();
+                }
+            }
+            if retention > 0 {
+                let prune = tokio::task::spawn_blocking(move || prune_snapshots(this_id, retention, followers_idx, &snapshot_state)).await;
+                match prune {
+                    Ok(Ok(n)) => pruned.add(n),
+                    Ok(Err(e)) => log(format!("Node {} failed to prune snapshots: {}", this_id, e)),
+                    Err(e) => log(format!("Node {} snapshot pruning task failed: {}", this_id, e)),
+                }
+            }
+        });
+    }
+
+    /// Follow the decided log of the peers, applying it locally.
+    async fn run_learner_loop(&self) {
+        let mut next_peer = 0;
+        loop {
+            self.config.clock.sleep(Duration::from_millis(LEARNER_LOOP_TIMEOUT_MS)).await;
+
+            if *self.halt.lock().unwrap() {
+                break
+            }
+
+            self.expire_read_snapshots(self.config.clock.now());
+
+            if self.peers.is_empty() {
+                continue;
+            }
+            let peer = self.peers[next_peer % self.peers.len()];
+            let from_idx = self.learner_applied_idx.load(Ordering::SeqCst);
+            let entries = match self.transport.fetch_decided(peer, from_idx).await {
+                Some(entries) => entries,
+                None => {
+                    // try another peer next time
+                    next_peer += 1;
+                    continue;
+                }
+            };
+
+            for entry in entries.iter() {
+                let idx = self.learner_applied_idx.load(Ordering::SeqCst);
+                let cmds = if entry.batch.is_empty() { std::slice::from_ref(entry) } else { &entry.batch[..] };
+                for cmd in cmds.iter() {
+                    // Nobody waits for the results, but failures are
+                    // recorded and node faults handled as on voters.
+                    match self.apply_fetched(idx, cmd).await {
+                        Ok(Applied::Results(_)) => {}
+                        Ok(Applied::Duplicate(..)) => continue,
+                        Err(e) => {
+                            record_apply_error(&self.apply_errors, idx, cmd, &e);
+                            if is_node_fault(&e) {
+                                match self.config.apply_error_policy {
+                                    ApplyErrorPolicy::Halt => {
+                                        log(format!("Node {} halting: failed to apply command {}: {}", self.this_id, command_label(cmd), e));
+                                        *self.halt.lock().unwrap() = true;
+                                        return;
+                                    }
+                                    ApplyErrorPolicy::Skip => {
+                                        log(format!("Node {} skipping command {}: {}", self.this_id, command_label(cmd), e));
+                                    }
+                                }
+                            }
+                            continue;
+                        }
+                    }
+                    self.metrics.counter("commands_applied").inc();
+                    trace_applied(self.this_id, idx, cmd);
+                    if let Some(ref audit) = self.audit {
+                        if let Err(e) = audit.lock().unwrap().record(idx, cmd) {
+                            log(format!("Node {} failed to audit command {}: {}", self.this_id, command_label(cmd), e));
+                        }
+                    }
+                    self.changes.publish(self.configuration_id.load(Ordering::SeqCst), idx, cmd);
+                }
+                let applied_idx = self.learner_applied_idx.fetch_add(1, Ordering::SeqCst) + 1;
+                let _ = self.applied.send((self.configuration_id.load(Ordering::SeqCst), applied_idx));
+            }
+        }
+    }
+
+    /// Applies a command fetched by this learner, retrying it with
+    /// exponential backoff while the database is locked.
+    async fn apply_fetched(&self, idx: u64, cmd: &StoreCommand) -> Result<Applied, StoreError> {
+        let commit_latency = self.metrics.histogram("commit_latency");
+        let at = LogPosition {
+            configuration_id: self.configuration_id.load(Ordering::SeqCst),
+            idx,
+        };
+        let mut retries = 0;
+        loop {
+            let results = match cmd.schema_version {
+                0 => apply(self.local_conn.clone(), at, cmd, &self.dedup, self.config.max_apply_rows, &commit_latency),
+                _ => migrate(self.local_conn.clone(), at, cmd, &self.dedup, self.config.max_apply_rows, &commit_latency),
+            };
+            match results {
+                Err(ref e) if is_busy(e) && retries < self.config.busy_retries => {
+                    self.config.clock.sleep(Duration::from_millis(BUSY_BACKOFF_MS << std::cmp::min(retries, 16))).await;
+                    retries += 1;
+                }
+                _ => return results,
+            }
+        }
+    }
+
+    /// Run the blocking event loop.
+    pub async fn run_ble_loop(&self) {
+        if self.config.learner {
+            // Learners never take part in leader election.
+            return;
+        }
+        loop {
+            self.config.clock.sleep(Duration::from_millis(BLE_LOOP_TIMEOUT_MS)).await;
+
+            if *self.halt.lock().unwrap() {
+                break
+            }
+
+            let priority = self.priority_hook.lock().unwrap().clone().map(|hook| hook());
+
+            let mut sequence_paxos = self.sequence_paxos.lock().unwrap();
+            let mut ballot_leader_election = self.ballot_leader_election.lock().unwrap();
+
+            if let Some(priority) = priority {
+                ballot_leader_election.set_priority(priority);
+            }
+            if let Some(leader) = ballot_leader_election.tick() {
+                // a new leader is elected, pass it to SequencePaxos.
+                sequence_paxos.handle_leader(leader);
+                self.current_leader.store(sequence_paxos.get_current_leader(), Ordering::SeqCst);
+                self.leader_history.lock().unwrap().record(self.config.clock.now());
+                self.metrics.counter("leader_changes").inc();
+            }
+        }
+    }
+
+    /// Execute a SQL statement on the ChiselStore cluster.
+    pub async fn query<S: AsRef<str>>(
+        &self,
+        stmt: S,
+    ) -> Result<QueryResults, StoreError> {
+        self.query_with_params(stmt, Vec::new()).await
+    }
+
+    /// Execute a SQL statement with `params` bound to its `?` placeholders
+    /// on the ChiselStore cluster.
+    ///
+    /// The statement may consist of several statements separated by `;`, in
+    /// which case the parameters are bound to their placeholders in order.
+    pub async fn query_with_params<S: AsRef<str>>(
+        &self,
+        stmt: S,
+        params: Vec<Value>,
+    ) -> Result<QueryResults, StoreError> {
+        let options = QueryOptions {
+            params,
+            ..QueryOptions::default()
+        };
+        self.query_with_options(stmt, options).await
+    }
+
+    /// Execute a SQL statement with the given options on the ChiselStore cluster.
+    pub async fn query_with_options<S: AsRef<str>>(
+        &self,
+        stmt: S,
+        options: QueryOptions,
+    ) -> Result<QueryResults, StoreError> {
+        let wait = Timer::new(self.metrics.histogram("query_wait"));
+        let _permit = match self.query_permits {
+            Some(ref permits) => Some(permits.acquire().await.expect("query permits are never closed")),
+            None => None,
+        };
+        drop(wait);
+        let _timer = Timer::new(self.metrics.histogram("query_execution"));
+        self.execute_query(stmt.as_ref(), options).await
+    }
 
-    fn append_on_prefix(&mut self, from_idx: u64, entries: Vec<StoreCommand>) -> u64 {
-        self.log.truncate(from_idx as usize);
-        self.append_entries(entries)
+    /// Executes `stmt` with `options`, once it may.
+    async fn execute_query(&self, stmt: &str, options: QueryOptions) -> Result<QueryResults, StoreError> {
+        let params = options.params;
+        check_params(stmt, &params)?;
+        self.validate(stmt)?;
+        check_application_id(stmt)?;
+        let consistency = self.resolve_consistency(stmt, options.consistency);
+        let read_only = sql::is_read_only(stmt);
+        let read_only_transaction = sql::is_read_only_transaction(stmt);
+        if options.role == Some(Role::ReadOnly) && !read_only && !read_only_transaction {
+            return Err(StoreError::ReadOnlyRole);
+        }
+        if !read_only && !options.dry_run {
+            if !read_only_transaction && self.in_maintenance_mode()? {
+                return Err(StoreError::MaintenanceMode);
+            }
+            self.check_min_replicas(options.min_replicas)?;
+        }
+        if read_only || read_only_transaction {
+            self.wait_for_index(options.min_index, options.min_index_configuration).await?;
+        }
+        let results = if options.read_snapshot != 0 {
+            if !read_only {
+                return Err(StoreError::NotReadOnly);
+            }
+            query(self.read_snapshot(options.read_snapshot, &options.client)?, stmt, &params)?
+        } else if options.explain_analyze {
+            if !read_only {
+                return Err(StoreError::StatementRejected(String::from("only read-only statements can be analyzed")));
+            }
+            self.explain_analyze(stmt, &params)?
+        } else if options.dry_run && !read_only {
+            self.dry_run(stmt, &params)?
+        } else if options.unacknowledged && !read_only {
+            self.submit_unacknowledged(stmt, params, options.request_id, options.client)?
+        } else if self.config.learner {
+            if read_only_transaction {
+                read_transaction(self.local_conn.clone(), stmt, &params)?
+            } else if !read_only {
+                return Err(StoreError::Learner);
+            } else if self.config.analytics {
+                self.snapshot_query(stmt, params).await?
+            } else {
+                query(self.local_conn.clone(), stmt, &params)?
+            }
+        } else if read_only && consistency == Consistency::RelaxedReads {
+            query(self.local_conn.clone(), stmt, &params)?
+        } else if read_only && self.get_current_leader() == 0 {
+            // Without a leader the read waits for one to be elected, rather
+            // than being served from local state, which may be stale.
+            self.replicate_command(stmt.to_string(), params, 0, options.request_id, options.client)
+                .await
+                .map_err(|e| self.strong_read_error(e))?
+        } else if read_only_transaction && (consistency == Consistency::RelaxedReads || self.is_leader()) {
+            // A strong read first commits a no-op, so that this node is known
+            // to still be the leader and to have applied every earlier write.
+            if consistency != Consistency::RelaxedReads {
+                self.noop().await?;
+            }
+            read_transaction(self.local_conn.clone(), stmt, &params)?
+        } else {
+            let results = self.replicate_command(stmt.to_string(), params, 0, options.request_id, options.client).await?;
+            self.wait_acknowledged(results, options.min_replicas).await?
+        };
+        self.finish_results(stmt, results)
     }
 
-    fn set_promise(&mut self, n_prom: Ballot) {
-        self.n_prom = n_prom;
+    /// Resolves `Consistency::TableDefault` to the consistency configured for
+    /// the tables `stmt` touches.
+    fn resolve_consistency(&self, stmt: &str, consistency: Consistency) -> Consistency {
+        if consistency != Consistency::TableDefault {
+            return consistency;
+        }
+        // Tables that can't be told for certain may include a strong one.
+        let relaxed = match sql::tables(stmt) {
+            Some(tables) => {
+                !tables.is_empty()
+                    && tables
+                        .iter()
+                        .all(|table| self.config.table_consistency.get(table) == Some(&Consistency::RelaxedReads))
+            }
+            None => false,
+        };
+        if relaxed {
+            Consistency::RelaxedReads
+        } else {
+            Consistency::Strong
+        }
     }
 
-    fn set_decided_idx(&mut self, ld: u64) {
-        
-        let old_ld = self.ld;
-        let new_ld = ld;
+    /// Applies the result limit and the row transformation to the results
+    /// of `stmt`.
+    fn finish_results(&self, stmt: &str, mut results: QueryResults) -> Result<QueryResults, StoreError> {
+        let limit = self.config.max_result_rows;
+        if limit > 0 && results.rows.len() > limit {
+            let committed = sql::writes(stmt) && !sql::is_read_only_transaction(stmt);
+            match self.config.result_limit_policy {
+                ResultLimitPolicy::Error if !committed => return Err(StoreError::TooManyRows { limit }),
+                _ => {
+                    results.rows.truncate(limit);
+                    results.warnings.push(format!("results truncated to {} rows", limit));
+                }
+            }
+        }
+        if let Some(transform) = self.row_transform() {
+            for row in results.rows.iter_mut() {
+                transform(stmt, row);
+            }
+        }
+        Ok(results)
+    }
 
-        self.ld = ld;
+    /// Execute the read-only statements of `queries`, each with its
+    /// parameters, against a single snapshot of the database, so that they
+    /// all see the same writes.
+    ///
+    /// With strong consistency, the snapshot reflects at least every write
+    /// decided before the call; with relaxed reads, or on a learner, it is
+    /// only as recent as this node is.
+    pub async fn multi_read<S: AsRef<str>>(
+        &self,
+        queries: Vec<(S, Vec<Value>)>,
+        consistency: Consistency,
+    ) -> Result<Vec<QueryResults>, StoreError> {
+        for (stmt, params) in queries.iter() {
+            let stmt = stmt.as_ref();
+            check_params(stmt, params)?;
+            self.validate(stmt)?;
+            if !sql::is_read_only(stmt) {
+                return Err(StoreError::NotReadOnly);
+            }
+        }
+        let stmts: Vec<&str> = queries.iter().map(|(stmt, _)| stmt.as_ref()).collect();
+        let consistency = self.resolve_consistency(&stmts.join(";"), consistency);
+        if !self.config.learner && consistency != Consistency::RelaxedReads {
+            // Once the noop is applied here, so is every write decided before it.
+            self.noop().await.map_err(|e| self.strong_read_error(e))?;
+        }
+        // A connection of its own, whose read transaction doesn't hold up
+        // other queries.
+        let conn = Arc::new(Mutex::new(open_connection(self.this_id, &self.config.connection_options())?));
+        conn.lock().unwrap().execute("BEGIN")?;
+        let results: Result<Vec<QueryResults>, StoreError> =
+            queries.iter().map(|(stmt, params)| query(conn.clone(), stmt.as_ref(), params)).collect();
+        let _ = conn.lock().unwrap().execute("COMMIT");
+        results?
+            .into_iter()
+            .zip(queries.iter())
+            .map(|(results, (stmt, _))| self.finish_results(stmt.as_ref(), results))
+            .collect()
+    }
 
-        
-        let ss = self.get_stopsign();
-        if self.log.len() < (new_ld as usize) && !ss.is_none() { // stop sign decided, do nothing
+    /// Error of a strong read that went through the log: one that timed out
+    /// without a leader being elected fails with `StoreError::NotLeader`
+    /// instead, so that clients retry it.
+    fn strong_read_error(&self, e: StoreError) -> StoreError {
+        match e {
+            StoreError::CommitTimeout { .. } if self.get_current_leader() == 0 => StoreError::NotLeader,
+            e => e,
+        }
+    }
+
+    /// Runs the write `stmt` in a transaction that is rolled back, on the
+    /// leader, reporting whether it would succeed.
+    fn dry_run(&self, stmt: &str, params: &[Value]) -> Result<QueryResults, StoreError> {
+        if self.config.learner {
+            return Err(StoreError::Learner);
+        }
+        if !self.is_leader() {
+            return Err(StoreError::NotLeader);
+        }
+        if sql::controls_transaction(stmt) {
+            // The statement could commit the transaction the dry run relies on
+            // rolling back.
+            return Err(StoreError::StatementRejected(String::from("dry runs can't control transactions")));
+        }
+        let mut results = self.rolled_back(stmt, params)?;
+        results.warnings.push(String::from("dry run: the write was rolled back, and may not succeed when committed"));
+        Ok(results)
+    }
+
+    /// Runs `stmt` in a transaction that is rolled back.
+    fn rolled_back(&self, stmt: &str, params: &[Value]) -> Result<QueryResults, StoreError> {
+        // A connection of its own, so that no other query sees the write.
+        let conn = Arc::new(Mutex::new(open_connection(self.this_id, &self.config.connection_options())?));
+        conn.lock().unwrap().execute("BEGIN")?;
+        let results = query(conn.clone(), stmt, params);
+        let _ = conn.lock().unwrap().execute("ROLLBACK");
+        results
+    }
+
+    /// Submits the write `stmt` without waiting for it to commit, returning
+    /// the results of a dry run of it.
+    fn submit_unacknowledged(&self, stmt: &str, params: Vec<Value>, request_id: u64, client: String) -> Result<QueryResults, StoreError> {
+        if !self.is_leader() {
+            return Err(StoreError::NotLeader);
+        }
+        if sql::controls_transaction(stmt) {
+            return Err(StoreError::StatementRejected(String::from("unacknowledged writes can't control transactions")));
+        }
+        let mut results = self.rolled_back(stmt, &params)?;
+        results.warnings.push(String::from(
+            "unacknowledged: the results are a dry run, the write is not committed yet and may fail to, or commit with other results",
+        ));
+        self.metrics.counter("unacknowledged_writes").inc();
+
+        let (notify, id) = self.submit_command(stmt.to_string(), params, 0, request_id, client);
+        let query_results_holder = self.query_results_holder.clone();
+        let failures = self.metrics.counter("unacknowledged_failures");
+        let clock = self.config.clock.clone();
+        let timeout = self.config.commit_timeout;
+        let this_id = self.this_id;
+        tokio::task::spawn(async move {
+            let decided = async {
+                notify.notified().await;
+                true
+            };
+            let decided = match timeout {
+                Some(timeout) => tokio::select! {
+                    decided = decided => decided,
+                    _ = clock.sleep(timeout) => false,
+                },
+                None => decided.await,
+            };
+            let result = {
+                let mut query_results_holder = query_results_holder.lock().unwrap();
+                let result = query_results_holder.remove_result(&id);
+                query_results_holder.remove(&id);
+                result
+            };
+            let error = match result {
+                Some(Ok(_)) => return,
+                Some(Err(e)) => e.to_string(),
+                None if decided => String::from("no results"),
+                None => format!("not decided within {:?}", timeout.unwrap_or_default()),
+            };
+            failures.inc();
+            log(format!("Node {} failed to commit unacknowledged write {}: {}", this_id, id, error));
+        });
+        Ok(results)
+    }
+
+    /// Runs the read-only `stmt` against this node's database, timing each
+    /// of its statements.
+    fn explain_analyze(&self, stmt: &str, params: &[Value]) -> Result<QueryResults, StoreError> {
+        // A connection of its own, so that its cost is its own.
+        let conn = open_connection(self.this_id, &self.config.connection_options())?;
+        let mut rows = Vec::new();
+        let mut analysis = Vec::new();
+        let mut params = params.iter();
+        for stmt in sql::statements(stmt) {
+            let stmt_params: Vec<&Value> = params.by_ref().take(sql::placeholders(stmt)).collect();
+            let mut plan = Vec::new();
+            let mut explain = conn.prepare(format!("EXPLAIN QUERY PLAN {}", stmt))?;
+            for (i, param) in stmt_params.iter().enumerate() {
+                explain.bind(i + 1, *param)?;
+            }
+            while let State::Row = explain.next()? {
+                // id, parent, notused, detail
+                plan.push(explain.read::<String>(3)?);
+            }
+            let start = Instant::now();
+            let mut statement = conn.prepare(stmt)?;
+            for (i, param) in stmt_params.iter().enumerate() {
+                statement.bind(i + 1, *param)?;
+            }
+            let mut first_row = None;
+            let mut count = 0;
+            while let State::Row = statement.next()? {
+                first_row.get_or_insert_with(|| start.elapsed());
+                count += 1;
+                let mut row = QueryRow::new();
+                for i in 0..statement.column_count() {
+                    row.values.push(read_text(&statement, i)?);
+                }
+                rows.push(row);
+            }
+            analysis.push(StatementAnalysis {
+                sql: stmt.to_string(),
+                plan,
+                rows: count,
+                first_row,
+                elapsed: start.elapsed(),
+            });
+        }
+        Ok(QueryResults {
+            rows,
+            ballot: None,
+            warnings: vec![String::from("explain analyze: the query ran against this node's database, which may be stale")],
+            proposed_at: None,
+            committed_at: None,
+            served_locally: true,
+            forwarded_from: None,
+            analysis,
+            log_idx: None,
+            acknowledged_by: 0,
+            configuration_id: 0,
+        })
+    }
+
+    /// Returns the principal authenticated by the bearer `token`, or `None`
+    /// if authentication is disabled.
+    pub fn authenticate(&self, token: Option<&str>) -> Result<Option<Principal>, StoreError> {
+        if self.config.auth_tokens.is_empty() {
+            return Ok(None);
+        }
+        match token.and_then(|token| self.config.auth_tokens.get(token)) {
+            Some(principal) => Ok(Some(principal.clone())),
+            None => Err(StoreError::Unauthenticated),
+        }
+    }
+
+    /// Creates a read snapshot of this node's database and returns its id.
+    ///
+    /// Queries with the id in their `read_snapshot` option all see the
+    /// database as it is now, while writes keep being applied, until the
+    /// snapshot is released with [`StoreServer::release_read_snapshot`] or
+    /// goes unused for the `read_snapshot_timeout`. The snapshot holds the
+    /// writes this node applied, so it is only as recent as the node is.
+    ///
+    /// Only the client identified as `owner` may query or release the
+    /// snapshot.
+    pub fn create_read_snapshot(&self, owner: &str) -> Result<u64, StoreError> {
+        let mut read_snapshots = self.read_snapshots.lock().unwrap();
+        if read_snapshots.len() >= READ_SNAPSHOT_LIMIT {
+            return Err(StoreError::TooManyReadSnapshots { limit: READ_SNAPSHOT_LIMIT });
+        }
+        let conn = open_connection(self.this_id, &self.config.connection_options())?;
+        conn.execute("BEGIN")?;
+        // The read transaction only takes its snapshot when it first reads.
+        conn.execute("SELECT count(*) FROM sqlite_master")?;
+        let id = self.next_read_snapshot_id.fetch_add(1, Ordering::SeqCst);
+        read_snapshots.insert(id, ReadSnapshot {
+            conn: Arc::new(Mutex::new(conn)),
+            owner: owner.to_string(),
+            last_used: self.config.clock.now(),
+        });
+        self.metrics.gauge("read_snapshots").set(read_snapshots.len() as i64);
+        Ok(id)
+    }
+
+    /// Releases the read snapshot `id` of the client `owner`.
+    pub fn release_read_snapshot(&self, id: u64, owner: &str) -> Result<(), StoreError> {
+        let mut read_snapshots = self.read_snapshots.lock().unwrap();
+        match read_snapshots.get(&id) {
+            Some(read_snapshot) if read_snapshot.owner != owner => return Err(StoreError::ReadSnapshotNotOwned(id)),
+            Some(_) => {}
+            None => return Err(StoreError::UnknownReadSnapshot(id)),
+        }
+        let read_snapshot = read_snapshots.remove(&id).unwrap();
+        self.metrics.gauge("read_snapshots").set(read_snapshots.len() as i64);
+        drop(read_snapshots);
+        let _ = read_snapshot.conn.lock().unwrap().execute("COMMIT");
+        Ok(())
+    }
+
+    /// Returns the connection of the read snapshot `id` of the client
+    /// `owner`, marking it used.
+    fn read_snapshot(&self, id: u64, owner: &str) -> Result<Arc<Mutex<Connection>>, StoreError> {
+        let mut read_snapshots = self.read_snapshots.lock().unwrap();
+        let read_snapshot = read_snapshots.get_mut(&id).ok_or(StoreError::UnknownReadSnapshot(id))?;
+        if read_snapshot.owner != owner {
+            return Err(StoreError::ReadSnapshotNotOwned(id));
+        }
+        read_snapshot.last_used = self.config.clock.now();
+        Ok(read_snapshot.conn.clone())
+    }
+
+    /// Releases the read snapshots that went unused for the timeout.
+    fn expire_read_snapshots(&self, now: Instant) {
+        let timeout = self.config.read_snapshot_timeout;
+        let mut read_snapshots = self.read_snapshots.lock().unwrap();
+        let before = read_snapshots.len();
+        // Closing the connection ends its read transaction.
+        read_snapshots.retain(|_, read_snapshot| now.saturating_duration_since(read_snapshot.last_used) < timeout);
+        if read_snapshots.len() != before {
+            self.metrics.gauge("read_snapshots").set(read_snapshots.len() as i64);
+        }
+    }
+
+    /// Execute a read-only SQL statement on a snapshot of the database.
+    async fn snapshot_query(&self, stmt: &str, params: Vec<Value>) -> Result<QueryResults, StoreError> {
+        let this_id = self.this_id;
+        let options = self.config.connection_options();
+        let stmt = stmt.to_string();
+        let results = tokio::task::spawn_blocking(move || {
+            let conn = Arc::new(Mutex::new(open_connection(this_id, &options)?));
+            conn.lock().unwrap().execute("BEGIN")?;
+            let results = query(conn.clone(), &stmt, &params);
+            let _ = conn.lock().unwrap().execute("COMMIT");
+            results
+        });
+        results.await.map_err(|e| StoreError::QueryTask(e.to_string()))?
+    }
+
+    /// Register a transformation applied to every row returned by queries.
+    ///
+    /// The transformation only affects the rows returned to the client, never
+    /// the stored data. It must be deterministic, so that the same query
+    /// returns the same rows on every node. It replaces any previously
+    /// registered transformation.
+    pub fn set_row_transform<F>(&self, transform: F)
+    where
+        F: Fn(&str, &mut QueryRow) + Send + Sync + 'static,
+    {
+        *self.row_transform.lock().unwrap() = Some(Arc::new(transform));
+    }
+
+    /// Remove the registered row transformation.
+    pub fn clear_row_transform(&self) {
+        *self.row_transform.lock().unwrap() = None;
+    }
+
+    fn row_transform(&self) -> Option<Arc<RowTransform>> {
+        self.row_transform.lock().unwrap().clone()
+    }
+
+    /// Set this node's leader election priority.
+    ///
+    /// The priority is carried in this node's ballot, so among candidates in
+    /// the same round, the one with the highest priority is elected. It only
+    /// takes effect at the next election: it never deposes a leader.
+    pub fn set_priority(&self, priority: u64) {
+        self.ballot_leader_election.lock().unwrap().set_priority(priority);
+    }
+
+    /// Register a hook that updates this node's leader election priority
+    /// before every election round, e.g. to bias elections toward less loaded
+    /// nodes. It replaces any previously registered hook.
+    pub fn set_priority_hook<F>(&self, hook: F)
+    where
+        F: Fn() -> u64 + Send + Sync + 'static,
+    {
+        *self.priority_hook.lock().unwrap() = Some(Arc::new(hook));
+    }
+
+    /// Remove the registered priority hook, keeping the last priority it set.
+    pub fn clear_priority_hook(&self) {
+        *self.priority_hook.lock().unwrap() = None;
+    }
+
+    /// Register a validator that every statement must pass before it is
+    /// executed, e.g. to keep unsupported or dangerous SQL out of the log.
+    ///
+    /// Validation runs on the node the query is sent to, before anything is
+    /// proposed. It replaces any previously registered validator.
+    pub fn set_sql_validator<F>(&self, validator: F)
+    where
+        F: Fn(&str) -> Result<(), String> + Send + Sync + 'static,
+    {
+        *self.sql_validator.lock().unwrap() = Some(Arc::new(validator));
+    }
+
+    /// Remove the registered SQL validator.
+    pub fn clear_sql_validator(&self) {
+        *self.sql_validator.lock().unwrap() = None;
+    }
+
+    /// Checks every statement in `sql` against the registered validator.
+    fn validate(&self, sql: &str) -> Result<(), StoreError> {
+        let validator = match self.sql_validator.lock().unwrap().clone() {
+            Some(validator) => validator,
+            None => return Ok(()),
+        };
+        for stmt in sql::statements(sql) {
+            validator(stmt).map_err(StoreError::StatementRejected)?;
+        }
+        Ok(())
+    }
+
+    /// Execute a read-only SQL statement, streaming its rows.
+    ///
+    /// Rows are read from SQLite as the stream is consumed: at most
+    /// `stream_buffer_rows` rows are read ahead, so a slow consumer holds
+    /// back the query instead of making the server buffer its whole result.
+    /// A strong query first waits for a no-op to be decided, so it observes
+    /// every write committed before it started.
+    pub async fn query_stream<S: AsRef<str>>(
+        &self,
+        stmt: S,
+        options: QueryOptions,
+    ) -> Result<QueryStream, StoreError> {
+        let stmt = stmt.as_ref().to_string();
+        let params = options.params;
+        check_params(&stmt, &params)?;
+        self.validate(&stmt)?;
+        if !sql::is_read_only(&stmt) {
+            return Err(StoreError::NotReadOnly);
+        }
+        if options.read_snapshot != 0 {
+            return Err(StoreError::StreamedReadSnapshot);
+        }
+        self.wait_for_index(options.min_index, options.min_index_configuration).await?;
+        if self.resolve_consistency(&stmt, options.consistency) == Consistency::Strong && !self.config.learner {
+            self.noop().await?;
+        }
+        let (sender, receiver) = mpsc::channel(std::cmp::max(1, self.config.stream_buffer_rows));
+        let produced = self.metrics.counter("stream_rows");
+        let buffered = self.metrics.gauge("stream_buffered_rows");
+        let this_id = self.this_id;
+        let options = self.config.connection_options();
+        let stream = QueryStream {
+            sql: stmt.clone(),
+            rows: receiver,
+            buffered: buffered.clone(),
+            transform: self.row_transform(),
+        };
+        tokio::task::spawn_blocking(move || {
+            let errors = sender.clone();
+            if let Err(e) = stream_rows(this_id, &options, &stmt, &params, sender, produced, buffered) {
+                let _ = errors.blocking_send(Err(e));
+            }
+        });
+        Ok(stream)
+    }
+
+    /// Propose a no-op command and wait for it to be decided.
+    ///
+    /// Unlike reading the node status, this exercises the whole commit path,
+    /// so it can be used to probe that consensus is making progress.
+    pub async fn noop(&self) -> Result<(), StoreError> {
+        if self.config.learner {
+            return Err(StoreError::Learner);
+        }
+        self.replicate(String::new(), Vec::new()).await?;
+        Ok(())
+    }
+
+    /// Migrate the database schema to `version` by executing `ddl`.
+    ///
+    /// The migration is replicated through the log like any other write, so
+    /// every node applies it at the same log position. The DDL runs in a
+    /// transaction together with the schema version bump, and a node that
+    /// fails to apply it halts instead of serving a half-migrated schema.
+    /// `version` must be newer than the current schema version.
+    pub async fn migrate<S: AsRef<str>>(&self, version: u64, ddl: S) -> Result<QueryResults, StoreError> {
+        if self.config.learner {
+            return Err(StoreError::Learner);
+        }
+        let current = self.schema_version()?;
+        if version <= current {
+            return Err(StoreError::StaleSchemaVersion {
+                current,
+                requested: version,
+            });
+        }
+        self.replicate_command(ddl.as_ref().to_string(), Vec::new(), version, 0, String::new()).await
+    }
+
+    /// Schema version of this node's database, as set by the last migration it applied.
+    pub fn schema_version(&self) -> Result<u64, StoreError> {
+        schema_version(self.local_conn.clone())
+    }
+
+    /// Puts the cluster in maintenance mode, or takes it out of it.
+    ///
+    /// In maintenance mode, e.g. during an investigation, writes are rejected
+    /// with `StoreError::MaintenanceMode` while reads are still served.
+    /// Migrations are still applied. The mode is set by a committed command,
+    /// so every node enters it at the same index of the log: the writes
+    /// decided after it fail on every node. Requires admin operations to be
+    /// enabled.
+    pub async fn set_maintenance_mode(&self, enabled: bool) -> Result<(), StoreError> {
+        if !self.config.admin {
+            return Err(StoreError::AdminDisabled);
+        }
+        let cmd = StoreCommand {
+            id: self.next_cmd_id.fetch_add(1, Ordering::SeqCst),
+            sql: String::new(),
+            params: Vec::new(),
+            batch: Vec::new(),
+            schema_version: 0,
+            request_id: 0,
+            client: String::new(),
+            origin: self.this_id,
+            statements: Vec::new(),
+            kind: if enabled { CommandKind::EnterMaintenance } else { CommandKind::ExitMaintenance },
+        };
+        let (notify, id) = self.submit(cmd);
+        self.wait_results(&notify, id).await?;
+        Ok(())
+    }
+
+    /// Returns true if the cluster is in maintenance mode, as far as this
+    /// node applied the log.
+    pub fn in_maintenance_mode(&self) -> Result<bool, StoreError> {
+        in_maintenance_mode(&self.local_conn.lock().unwrap())
+    }
+
+    /// Replicate a SQL statement through the log and wait for its results.
+    async fn replicate(&self, sql: String, params: Vec<Value>) -> Result<QueryResults, StoreError> {
+        self.replicate_command(sql, params, 0, 0, String::new()).await
+    }
+
+    async fn replicate_command(&self, sql: String, params: Vec<Value>, schema_version: u64, request_id: u64, client: String) -> Result<QueryResults, StoreError> {
+        let (notify, id) = self.submit_command(sql, params, schema_version, request_id, client);
+        self.wait_results(&notify, id).await
+    }
+
+    /// Waits for the results of the submitted command `id`.
+    async fn wait_results(&self, notify: &Notify, id: u64) -> Result<QueryResults, StoreError> {
+        // wait for append (and decide) to finish in background
+        let _pending = PendingQuery {
+            query_results_holder: &self.query_results_holder,
+            id,
+        };
+        self.wait_decided(notify).await?;
+        let results = self.query_results_holder.lock().unwrap().remove_result(&id);
+        results.unwrap()
+    }
+
+    /// Proposes a command, returning its id and the notifier of its results.
+    fn submit_command(&self, sql: String, params: Vec<Value>, schema_version: u64, request_id: u64, client: String) -> (Arc<Notify>, u64) {
+        let id = self.next_cmd_id.fetch_add(1, Ordering::SeqCst);
+        // Migrations wrap their SQL in a transaction when applied.
+        let (sql, params, statements) = if self.config.command_format == CommandFormat::Structured && schema_version == 0 {
+            (String::new(), Vec::new(), CommandStatement::split(&sql, params))
+        } else {
+            (sql, params, Vec::new())
+        };
+        let cmd = StoreCommand {
+            id: id,
+            sql,
+            params,
+            batch: Vec::new(),
+            schema_version,
+            request_id,
+            client,
+            origin: self.this_id,
+            statements,
+            kind: CommandKind::Sql,
+        };
+        self.submit(cmd)
+    }
+
+    /// Proposes `cmd`, returning its id and the notifier of its results.
+    fn submit(&self, cmd: StoreCommand) -> (Arc<Notify>, u64) {
+        let id = cmd.id;
+        let notify = Arc::new(Notify::new());
+
+        let mut query_results_holder = self.query_results_holder.lock().unwrap();
+        query_results_holder.insert_notifier(id, notify.clone());
+        drop(query_results_holder);
+
+        self.propose(cmd);
+
+        (notify, id)
+    }
+
+    /// Waits until at least `min_replicas` members accepted the entry of the
+    /// replicated command of `results`, reporting how many had.
+    async fn wait_acknowledged(&self, mut results: QueryResults, min_replicas: u64) -> Result<QueryResults, StoreError> {
+        let idx = match results.log_idx {
+            Some(idx) => idx,
+            None => return Ok(results),
+        };
+        let ballot = results.ballot;
+        let mut changed = self.accepted_rx.clone();
+        let wait = async {
+            loop {
+                let acknowledged = self.get_acknowledged(idx, ballot);
+                // The sender lives as long as the server does.
+                if acknowledged >= min_replicas || changed.changed().await.is_err() {
+                    return acknowledged;
+                }
+            }
+        };
+        results.acknowledged_by = match self.config.commit_timeout {
+            Some(timeout) => tokio::select! {
+                acknowledged = wait => acknowledged,
+                _ = self.config.clock.sleep(timeout) => {
+                    // The write is committed all the same, so it succeeds.
+                    let acknowledged = self.get_acknowledged(idx, ballot);
+                    results.warnings.push(format!(
+                        "accepted by {} of the {} members required within the commit timeout",
+                        acknowledged, min_replicas
+                    ));
+                    acknowledged
+                }
+            },
+            None => wait.await,
+        };
+        Ok(results)
+    }
+
+    /// Waits until this node has applied the log of configuration
+    /// `configuration` up to `min_index` entries, or moved on to a later
+    /// configuration. Configuration zero is the current one.
+    async fn wait_for_index(&self, min_index: u64, configuration: u32) -> Result<(), StoreError> {
+        let reached = |&(current, applied_idx): &(u32, u64)| match configuration {
+            0 => applied_idx >= min_index,
+            _ => current > configuration || (current == configuration && applied_idx >= min_index),
+        };
+        let mut applied = self.applied_rx.clone();
+        if reached(&*applied.borrow()) {
+            return Ok(());
+        }
+        let wait = async {
+            // The sender lives as long as the server does.
+            while applied.changed().await.is_ok() {
+                if reached(&*applied.borrow()) {
+                    break;
+                }
+            }
+        };
+        match self.config.commit_timeout {
+            Some(timeout) => tokio::select! {
+                _ = wait => Ok(()),
+                _ = self.config.clock.sleep(timeout) => Err(StoreError::IndexNotReached {
+                    min_index,
+                    applied_idx: self.get_applied_idx(),
+                }),
+            },
+            None => {
+                wait.await;
+                Ok(())
+            }
+        }
+    }
+
+    /// Checks that a write requiring `min_replicas` members to accept it can
+    /// be served by this node, before it is proposed.
+    fn check_min_replicas(&self, min_replicas: u64) -> Result<(), StoreError> {
+        if min_replicas <= self.get_quorum_size() as u64 {
+            return Ok(());
+        }
+        let members = self.members.lock().unwrap().len() as u64;
+        if min_replicas > members {
+            return Err(StoreError::TooManyReplicasRequired { required: min_replicas, members });
+        }
+        if !self.is_leader() {
+            return Err(StoreError::NotLeader);
+        }
+        Ok(())
+    }
+
+    /// Returns how many members are known to have accepted the log entry
+    /// at `idx`, decided in round `ballot`, counting this node.
+    ///
+    /// Only acceptances of current members in that round count: those of
+    /// another round may be of other entries at the same index.
+    fn get_acknowledged(&self, idx: u64, ballot: Option<Ballot>) -> u64 {
+        let quorum = self.get_quorum_size() as u64;
+        if !self.is_leader() {
+            return quorum;
+        }
+        let accepted = self.accepted.lock().unwrap();
+        if Some(accepted.ballot) != ballot {
+            return quorum;
+        }
+        let members = self.members.lock().unwrap();
+        let peers = accepted.la.iter().filter(|&(peer, &la)| la > idx && members.contains(peer)).count() as u64;
+        (peers + 1).max(quorum)
+    }
+
+    /// Returns the length of the log every other member accepted in the
+    /// current round. A member that accepted nothing yet counts as zero.
+    fn followers_accepted_idx(&self) -> u64 {
+        let accepted = self.accepted.lock().unwrap();
+        let members = self.members.lock().unwrap();
+        members
+            .iter()
+            .filter(|&&peer| peer != self.this_id)
+            .map(|peer| accepted.la.get(peer).copied().unwrap_or(0))
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Records that `peer` accepted the log up to `la` in round `n`. The
+    /// acceptances of earlier rounds are forgotten once a later one is.
+    fn record_accepted(&self, peer: u64, n: Ballot, la: u64) {
+        let mut accepted = self.accepted.lock().unwrap();
+        if n > accepted.ballot {
+            accepted.ballot = n;
+            accepted.la.clear();
+        }
+        if n == accepted.ballot {
+            let accepted_la = accepted.la.entry(peer).or_insert(0);
+            *accepted_la = (*accepted_la).max(la);
+        }
+        drop(accepted);
+        let _ = self.accepted_changed.send(());
+    }
+
+    /// Wait for a proposed command to be decided, failing if the leader
+    /// changes or the commit timeout expires.
+    async fn wait_decided(&self, notify: &Notify) -> Result<(), StoreError> {
+        let timeout = match self.config.commit_timeout {
+            Some(timeout) => timeout,
+            None => return self.wait_decided_unbounded(notify).await,
+        };
+        tokio::select! {
+            result = self.wait_decided_unbounded(notify) => result,
+            _ = self.config.clock.sleep(timeout) => {
+                self.metrics.counter("commit_timeouts").inc();
+                Err(StoreError::CommitTimeout {
+                    timeout,
+                    decided_idx: self.get_decided_idx(),
+                    still_leader: self.is_leader(),
+                })
+            }
+        }
+    }
+
+    /// Wait for a proposed command to be decided, failing if the leader changes.
+    async fn wait_decided_unbounded(&self, notify: &Notify) -> Result<(), StoreError> {
+        let interval = self.config.leader_change_check_interval;
+        let leader = self.get_current_leader();
+        if interval.is_zero() || leader == 0 {
+            notify.notified().await;
+            return Ok(());
+        }
+        let notified = notify.notified();
+        tokio::pin!(notified);
+        loop {
+            tokio::select! {
+                _ = &mut notified => return Ok(()),
+                _ = self.config.clock.sleep(interval) => {
+                    let current = self.get_current_leader();
+                    if current != 0 && current != leader {
+                        return Err(StoreError::LeaderChanged { leader: current });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Propose a command, coalescing it with other writes if the write buffer is enabled.
+    fn propose(&self, cmd: StoreCommand) {
+        let now = self.config.clock.now();
+        let linger = self.write_buffer_linger(now);
+        let batch = if self.config.write_buffer_linger.is_zero() {
+            vec![cmd]
+        } else if linger.is_zero() {
+            let mut batch = self.write_buffer.lock().unwrap().take(now);
+            batch.push(cmd);
+            batch
+        } else {
+            let mut write_buffer = self.write_buffer.lock().unwrap();
+            if write_buffer.pending.is_empty() && now.saturating_duration_since(write_buffer.last_flush) >= linger {
+                // Low load: there is nothing to coalesce with, so don't make the write wait.
+                write_buffer.push(cmd, now);
+                write_buffer.take(now)
+            } else {
+                write_buffer.push(cmd, now);
+                if write_buffer.pending.len() >= self.config.write_buffer_max_batch {
+                    write_buffer.take(now)
+                } else {
+                    Vec::new()
+                }
+            }
+        };
+        let mut sequence_paxos = self.sequence_paxos.lock().unwrap();
+        self.append_batch(&mut sequence_paxos, batch);
+    }
+
+    /// How long writes linger in the write buffer, which is not at all while
+    /// this node warms up as leader.
+    fn write_buffer_linger(&self, now: Instant) -> Duration {
+        let linger = self.config.write_buffer_linger;
+        if linger.is_zero() || self.config.leader_warmup.is_zero() || !self.is_leader() {
+            return linger;
+        }
+        match self.leader_history.lock().unwrap().since {
+            Some(since) if now.saturating_duration_since(since) < self.config.leader_warmup => Duration::from_millis(0),
+            _ => linger,
+        }
+    }
+
+    /// Append commands to the log, as a single entry if there are more than one.
+    ///
+    /// If sequence paxos refuses the entry, e.g. while the configuration is
+    /// being stopped, its commands fail with `StoreError::AppendFailed`.
+    fn append_batch(&self, sequence_paxos: &mut StoreSequencePaxos, mut batch: Vec<StoreCommand>) {
+        let cmd = match batch.len() {
+            0 => return,
+            1 => batch.pop().unwrap(),
+            _ => StoreCommand {
+                id: self.next_cmd_id.fetch_add(1, Ordering::SeqCst),
+                sql: String::new(),
+                params: Vec::new(),
+                batch,
+                schema_version: 0,
+                request_id: 0,
+                client: String::new(),
+                origin: self.this_id,
+                statements: Vec::new(),
+                kind: CommandKind::Sql,
+            },
+        };
+        let ids = command_ids(std::slice::from_ref(&cmd));
+        if let Err(e) = sequence_paxos.append(cmd) {
+            log(format!("Node {} failed to append {} commands: {:?}", self.this_id, ids.len(), e));
+            let mut query_results_holder = self.query_results_holder.lock().unwrap();
+            for id in ids {
+                query_results_holder.push_result(id, Err(StoreError::AppendFailed(format!("{:?}", e))));
+            }
+        }
+    }
+
+    /// Execute a list of SQL statements as a single transaction.
+    ///
+    /// The statements are wrapped in `BEGIN`/`COMMIT` and replicated as one
+    /// log entry, so `SAVEPOINT`, `RELEASE` and `ROLLBACK TO` can be used to
+    /// partially roll back the transaction before it commits.
+    ///
+    /// A transaction of read-only statements is not replicated when this
+    /// node is the leader, a learner or the reads are relaxed: it runs on a
+    /// snapshot of the local database instead. A strong one on the leader
+    /// still waits for a no-op to be committed first.
+    pub async fn transaction<S: AsRef<str>>(
+        &self,
+        stmts: &[S],
+    ) -> Result<QueryResults, StoreError> {
+        let mut sql = String::from("BEGIN;");
+        for stmt in stmts {
+            sql.push_str(stmt.as_ref().trim_end_matches(';'));
+            sql.push(';');
+        }
+        sql.push_str("COMMIT;");
+        self.query(sql).await
+    }
+
+    /// Import the rows of `rows` in batches of up to `import_batch_rows`.
+    ///
+    /// Each batch is replicated as one log entry and applied in one
+    /// transaction, so it is imported entirely or not at all; a batch also
+    /// ends where a row names another table or columns. A failed batch is
+    /// reported in the summary and the import carries on with the next one.
+    pub async fn bulk_import<R>(&self, mut rows: R, options: QueryOptions) -> ImportSummary
+    where
+        R: Stream<Item = ImportRow> + Unpin,
+    {
+        let batch_rows = std::cmp::max(1, self.config.import_batch_rows);
+        let mut summary = ImportSummary::default();
+        let mut batch = ImportBatch::default();
+        while let Some(row) = rows.next().await {
+            if batch.retargets(&row) || batch.rows.len() >= batch_rows {
+                let full = std::mem::take(&mut batch);
+                batch.table = full.table.clone();
+                batch.columns = full.columns.clone();
+                self.import_batch(full, &options, &mut summary).await;
+            }
+            if !row.table.is_empty() {
+                batch.table = row.table;
+                batch.columns = row.columns;
+            }
+            batch.rows.push(row.values);
+        }
+        self.import_batch(batch, &options, &mut summary).await;
+        summary
+    }
+
+    async fn import_batch(&self, batch: ImportBatch, options: &QueryOptions, summary: &mut ImportSummary) {
+        let rows = batch.rows.len() as u64;
+        if rows == 0 {
             return;
         }
+        let result = match batch.into_sql() {
+            Ok((sql, params)) => {
+                let options = QueryOptions {
+                    params,
+                    ..options.clone()
+                };
+                self.query_with_options(sql, options).await.map(|_| ())
+            }
+            Err(e) => Err(e),
+        };
+        summary.record(rows, result);
+    }
+
+    /// Receive a sequence paxos message of configuration `config_id` from
+    /// the ChiselStore cluster.
+    ///
+    /// A `config_id` of zero is not checked, for senders that don't know
+    /// their configuration. Messages of previous configurations are rejected
+    /// and messages of future ones handled according to the
+    /// `future_config_policy`.
+    pub fn recv_sp_msg(&self, config_id: u32, msg: Message<StoreCommand, ()>) -> Result<(), StoreError> {
+        self.check_addressee(msg.to)?;
+        self.check_sender(msg.from)?;
+        let current = self.configuration_id.load(Ordering::SeqCst);
+        if config_id != 0 && config_id < current {
+            self.metrics.counter("stale_config_rejected").inc();
+            return Err(StoreError::UnknownConfiguration { config_id, current });
+        }
+        if config_id > current {
+            return self.buffer_future_msg(config_id, current, msg);
+        }
+        if let PaxosMsg::Accepted(ref accepted) = msg.msg {
+            self.record_accepted(msg.from, accepted.n, accepted.la);
+        }
+        let msg = match self.hold_for_catch_up(msg)? {
+            Some(msg) => msg,
+            None => return Ok(()),
+        };
+        if self.config.inbound_queue_capacity > 0 {
+            return self.enqueue_sp_msg(msg);
+        }
+        let mut sequence_paxos = self.sequence_paxos.lock().unwrap();
+        sequence_paxos.handle(msg);
+        self.current_leader.store(sequence_paxos.get_current_leader(), Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Holds `msg` back until this node catches up from a snapshot, if it
+    /// is a synchronization with a snapshot, or any message of its sender
+    /// while catching up. Returns the message otherwise.
+    ///
+    /// The log of the snapshot was trimmed, so the database is restored
+    /// from a copy of the sender's before the log is synchronized.
+    fn hold_for_catch_up(&self, msg: Message<StoreCommand, ()>) -> Result<Option<Message<StoreCommand, ()>>, StoreError> {
+        let mut catch_up = self.catch_up.lock().unwrap();
+        let sync_idx = match msg.msg {
+            PaxosMsg::AcceptSync(ref sync) if matches!(sync.sync_item, SyncItem::Snapshot(_)) => Some(sync.sync_idx),
+            _ => None,
+        };
+        if let Some(held) = catch_up.as_mut() {
+            if held.from == msg.from {
+                held.msgs.push(msg);
+                return Ok(None);
+            }
+            if sync_idx.is_some() {
+                // Another leader synchronizes this node again once it
+                // finds the message lost.
+                return Err(StoreError::InboundDropped { peer: msg.from });
+            }
+            return Ok(Some(msg));
+        }
+        match sync_idx {
+            Some(sync_idx) => {
+                *catch_up = Some(CatchUp { from: msg.from, sync_idx, msgs: vec![msg] });
+                Ok(None)
+            }
+            None => Ok(Some(msg)),
+        }
+    }
+
+    /// Restores the database from a copy of the leader's, if this node's log
+    /// was synchronized with a snapshot, then handles the synchronization
+    /// and the messages held back since.
+    ///
+    /// If the copy can't be fetched or restored, the messages are dropped
+    /// and the leader is asked to synchronize this node again.
+    async fn catch_up_from_snapshot(&self) {
+        let (from, sync_idx) = match *self.catch_up.lock().unwrap() {
+            Some(ref catch_up) => (catch_up.from, catch_up.sync_idx),
+            None => return,
+        };
+        let configuration_id = self.configuration_id.load(Ordering::SeqCst);
+        let restored = match self.transport.fetch_snapshot(from).await {
+            Some(snapshot) if snapshot.configuration_id != configuration_id || snapshot.decided_idx < sync_idx => {
+                Err(StoreError::Snapshot(format!("node {} sent a copy of configuration {} up to {}, not {} up to {}", from, snapshot.configuration_id, snapshot.decided_idx, configuration_id, sync_idx)))
+            }
+            Some(snapshot) => {
+                let this_id = self.this_id;
+                let options = self.config.connection_options();
+                let decided_idx = snapshot.decided_idx;
+                tokio::task::spawn_blocking(move || restore_snapshot(this_id, &options, &snapshot.data))
+                    .await
+                    .map_err(|e| StoreError::Snapshot(e.to_string()))
+                    .and_then(|restored| restored)
+                    .map(|_| decided_idx)
+            }
+            None => Err(StoreError::Snapshot(format!("node {} could not be reached", from))),
+        };
+        let mut catch_up = self.catch_up.lock().unwrap();
+        let msgs = catch_up.take().map_or(Vec::new(), |catch_up| catch_up.msgs);
+        let mut sequence_paxos = self.sequence_paxos.lock().unwrap();
+        match restored {
+            Ok(decided_idx) => {
+                self.pending_applies.lock().unwrap().restored = (configuration_id, decided_idx);
+                self.metrics.counter("snapshots_restored").inc();
+                log(format!("Node {} restored its database from node {} up to {}", self.this_id, from, decided_idx));
+                for msg in msgs {
+                    sequence_paxos.handle(msg);
+                }
+            }
+            Err(e) => {
+                log(format!("Node {} failed to catch up from a snapshot: {}", self.this_id, e));
+                sequence_paxos.reconnected(from);
+            }
+        }
+        self.current_leader.store(sequence_paxos.get_current_leader(), Ordering::SeqCst);
+    }
+
+    /// Keep a message of a configuration this node has not adopted yet, or
+    /// reject it, according to the `future_config_policy`.
+    fn buffer_future_msg(&self, config_id: u32, current: u32, msg: Message<StoreCommand, ()>) -> Result<(), StoreError> {
+        if self.config.future_config_policy == FutureConfigPolicy::Reject {
+            self.metrics.counter("future_config_rejected").inc();
+            return Err(StoreError::UnknownConfiguration { config_id, current });
+        }
+        let mut future_msgs = self.future_msgs.lock().unwrap();
+        if config_id <= self.configuration_id.load(Ordering::SeqCst) {
+            // adopted in the meantime
+            drop(future_msgs);
+            return self.recv_sp_msg(config_id, msg);
+        }
+        if future_msgs.len() >= FUTURE_CONFIG_BUFFER_CAPACITY {
+            self.metrics.counter("future_config_rejected").inc();
+            return Err(StoreError::UnknownConfiguration { config_id, current });
+        }
+        future_msgs.push((config_id, msg));
+        self.metrics.counter("future_config_buffered").inc();
+        Ok(())
+    }
+
+    /// Queue a sequence paxos message to be handled by the task of its sender.
+    ///
+    /// Fails if the queue is full, or closed because the node halted, so
+    /// that the sender finds out the message was lost and resynchronizes.
+    fn enqueue_sp_msg(&self, msg: Message<StoreCommand, ()>) -> Result<(), StoreError> {
+        let from = msg.from;
+        if *self.halt.lock().unwrap() {
+            return Err(StoreError::InboundDropped { peer: from });
+        }
+        let mut inbound = self.inbound.lock().unwrap();
+        let queue = inbound.entry(from).or_insert_with(|| {
+            let (sender, mut receiver) = mpsc::channel(self.config.inbound_queue_capacity);
+            let sequence_paxos = self.sequence_paxos.clone();
+            let current_leader = self.current_leader.clone();
+            let halt = self.halt.clone();
+            let depth = self.metrics.gauge(&labelled("inbound_queue_depth", "peer", &from.to_string()));
+            tokio::task::spawn(async move {
+                while let Some(msg) = receiver.recv().await {
+                    depth.dec();
+                    if *halt.lock().unwrap() {
+                        break;
+                    }
+                    let mut sequence_paxos = sequence_paxos.lock().unwrap();
+                    sequence_paxos.handle(msg);
+                    current_leader.store(sequence_paxos.get_current_leader(), Ordering::SeqCst);
+                }
+            });
+            sender
+        });
+        let depth = self.metrics.gauge(&labelled("inbound_queue_depth", "peer", &from.to_string()));
+        depth.inc();
+        if queue.try_send(msg).is_err() {
+            depth.dec();
+            self.metrics.counter(&labelled("inbound_dropped", "peer", &from.to_string())).inc();
+            return Err(StoreError::InboundDropped { peer: from });
+        }
+        Ok(())
+    }
+    
+    /// Rejects the messages of node `from` if `extensions`, the fingerprint
+    /// of its SQL extensions, differs from this node's.
+    ///
+    /// A node with other extensions would apply commands differently, so it
+    /// can't take part in electing a leader or replicating the log with
+    /// this node.
+    pub fn check_extensions(&self, from: u64, extensions: u64) -> Result<(), StoreError> {
+        let ours = self.config.extensions_fingerprint();
+        if extensions != ours {
+            self.metrics.counter("extension_mismatch").inc();
+            return Err(StoreError::ExtensionMismatch { from, ours, theirs: extensions });
+        }
+        Ok(())
+    }
 
-        // commit decided transactions to DB
-        let queries_to_run = self.log[(old_ld as usize)..(new_ld as usize)].to_vec();
-        
-        for q in queries_to_run.iter() {
-            let conn = self.get_connection();
-            let results = query(conn, q.sql.clone());
+    /// Receive a ballot leader election message from the ChiselStore cluster.
+    pub fn recv_ble_msg(&self, msg: BLEMessage) -> Result<(), StoreError> {
+        self.check_addressee(msg.to)?;
+        self.check_sender(msg.from)?;
+        if let HeartbeatMsg::Reply(_) = msg.msg {
+            self.heartbeat_replies.lock().unwrap().insert(msg.from, self.config.clock.now());
+        }
+        let mut ballot_leader_election = self.ballot_leader_election.lock().unwrap();
+        ballot_leader_election.handle(msg);
+        Ok(())
+    }
 
-            let mut query_results_holder = self.query_results_holder.lock().unwrap();
-            query_results_holder.push_result(q.id, results);
+    /// Rejects messages claiming to be from this node.
+    ///
+    /// Nodes never message themselves, so such a message means either that
+    /// two nodes are configured with the same id, or that the sender is not
+    /// who it claims to be. The transport knows which, from how the sender
+    /// authenticated, and halts the node in the first case with
+    /// [`StoreServer::halt_on_conflicting_id`].
+    fn check_sender(&self, from: u64) -> Result<(), StoreError> {
+        if from == self.this_id {
+            log(format!("Node {} rejecting a message claiming its id", self.this_id));
+            return Err(StoreError::ConflictingNodeId(self.this_id));
         }
+        Ok(())
     }
 
-    fn get_decided_idx(&self) -> u64 {
-        self.ld
+    /// Halts this node because an authenticated peer claims its id.
+    ///
+    /// Two nodes configured with the same id silently break consensus, so
+    /// neither may take part in it.
+    pub fn halt_on_conflicting_id(&self) {
+        log(format!(
+            "Node {} halting: received a message from another node with the same id, check the cluster configuration",
+            self.this_id
+        ));
+        *self.halt.lock().unwrap() = true;
     }
 
-    fn set_accepted_round(&mut self, na: Ballot) {
-        self.acc_round = na;
+    /// Rejects messages that are not addressed to this node.
+    fn check_addressee(&self, to: u64) -> Result<(), StoreError> {
+        if to != self.this_id {
+            log(format!("Node {} rejecting message addressed to node {}", self.this_id, to));
+            return Err(StoreError::Misaddressed { to, this_id: self.this_id });
+        }
+        Ok(())
     }
 
-    fn get_accepted_round(&self) -> Ballot {
-        self.acc_round
+    /// Used to shutdown replica
+    pub fn set_halt(&self, new_halt: bool) {
+        let mut halt = self.halt.lock().unwrap();
+        *halt = new_halt;
     }
 
-    fn get_entries(&self, from: u64, to: u64) -> &[StoreCommand] {
-        self.log.get(from as usize..to as usize).unwrap_or(&[])
+    /// Returns true if the server has been halted, either by `set_halt` or
+    /// because a command failed to apply.
+    pub fn is_halted(&self) -> bool {
+        *self.halt.lock().unwrap()
     }
 
-    fn get_log_len(&self) -> u64 {
-        self.log.len() as u64
+    /// Leader of the current ballot, zero if unknown.
+    pub fn get_current_leader(&self) -> u64 {
+        self.current_leader.load(Ordering::SeqCst)
     }
 
-    fn get_suffix(&self, from: u64) -> &[StoreCommand] {
-        match self.log.get(from as usize..) {
-            Some(s) => s,
-            None => &[],
-        }
+    fn is_leader(&self) -> bool {
+        self.get_current_leader() == self.this_id
     }
 
-    fn get_promise(&self) -> Ballot {
-        self.n_prom
+    pub fn get_id(&self) -> u64 {
+        self.this_id
     }
 
-    fn set_stopsign(&mut self, s: StopSignEntry) {
-        self.stopsign = Some(s);
+    /// Metrics of this server.
+    pub fn metrics(&self) -> Arc<Registry> {
+        self.metrics.clone()
     }
-    
-    fn get_stopsign(&self) -> Option<StopSignEntry> {
-        self.stopsign.clone()
+
+    /// The transport this server sends messages with.
+    pub fn transport(&self) -> &T {
+        &self.transport
     }
-    
-    // TEMP: Snapshot impl
-    fn trim(&mut self, trimmed_idx: u64) {
-        self.log.drain(0..trimmed_idx as usize);
+
+    /// Returns the nodes of the current configuration, including this one.
+    pub fn get_members(&self) -> Vec<u64> {
+        self.members.lock().unwrap().clone()
     }
 
-    fn set_compacted_idx(&mut self, trimmed_idx: u64) {
-        self.trimmed_idx = trimmed_idx;
+    /// Returns the length of the decided log on this node.
+    pub fn get_decided_idx(&self) -> u64 {
+        if self.config.learner {
+            return self.learner_applied_idx.load(Ordering::SeqCst);
+        }
+        let sequence_paxos = self.sequence_paxos.lock().unwrap();
+        sequence_paxos.get_decided_idx()
     }
 
-    fn get_compacted_idx(&self) -> u64 {
-        self.trimmed_idx
+    /// Returns the number of members that must be connected for the
+    /// cluster to make progress: a majority of the members.
+    ///
+    /// Every member counts equally, and the same majority is used for both
+    /// phases of sequence paxos: the OmniPaxos version ChiselStore is built
+    /// on has no flexible or weighted quorums.
+    pub fn get_quorum_size(&self) -> usize {
+        self.members.lock().unwrap().len() / 2 + 1
     }
 
-    fn set_snapshot(&mut self, snapshot: S) {
-        self.snapshot = Some(snapshot);
+    /// Returns the number of members this node is connected to, counting
+    /// itself if it is a member.
+    ///
+    /// A peer counts as connected while it replies to this node's
+    /// heartbeats.
+    pub fn get_connected_voters(&self) -> usize {
+        let member = self.members.lock().unwrap().contains(&self.this_id);
+        let connected = self.get_peer_connectivity().into_iter().filter(|&(_, connected)| connected).count();
+        connected + member as usize
     }
 
-    fn get_snapshot(&self) -> Option<S> {
-        self.snapshot.clone()
+    /// Returns whether this node is connected to each of the other members,
+    /// by id, in order of ids.
+    ///
+    /// A peer counts as connected while it replies to this node's
+    /// heartbeats, so in a partition, each node sees the peers on its side.
+    pub fn get_peer_connectivity(&self) -> Vec<(u64, bool)> {
+        let mut members = self.members.lock().unwrap().clone();
+        members.sort_unstable();
+        let now = self.config.clock.now();
+        let heartbeat_replies = self.heartbeat_replies.lock().unwrap();
+        members
+            .into_iter()
+            .filter(|&id| id != self.this_id)
+            .map(|id| {
+                let connected = heartbeat_replies
+                    .get(&id)
+                    .map_or(false, |&at| now.saturating_duration_since(at) <= VOTER_CONNECTED_WINDOW);
+                (id, connected)
+            })
+            .collect()
     }
-}
 
-#[derive(Derivative)]
-#[derivative(Debug)]
-pub struct StoreServer<T: StoreTransport + Send + Sync> {
-    this_id: u64,
-    next_cmd_id: AtomicU64,
-    #[derivative(Debug = "ignore")]
-    sequence_paxos: Arc<Mutex<SequencePaxos<StoreCommand, (), SQLiteStore<()>>>>,
-    #[derivative(Debug = "ignore")]
-    ballot_leader_election: Arc<Mutex<BallotLeaderElection>>,
-    transport: T,
-    query_results_holder: Arc<Mutex<QueryResultsHolder>>,
-    halt: Arc<Mutex<bool>>,
-}
+    /// Returns true if this node is connected to fewer members than a
+    /// quorum, so that the cluster is at risk of losing, or has lost,
+    /// availability.
+    pub fn is_quorum_at_risk(&self) -> bool {
+        self.get_connected_voters() < self.get_quorum_size()
+    }
 
-/// Query row.
-#[derive(Debug)]
-pub struct QueryRow {
-    /// Column values of the row.
-    pub values: Vec<String>,
-}
+    /// Returns how long the current leader has held leadership, or `None`
+    /// if no leader was elected yet.
+    pub fn get_leader_uptime(&self) -> Option<Duration> {
+        let since = self.leader_history.lock().unwrap().since?;
+        Some(self.config.clock.now().saturating_duration_since(since))
+    }
 
-impl QueryRow {
-    fn new() -> Self {
-        QueryRow { values: Vec::new() }
+    /// Returns true if commands are waiting on this node without the decided
+    /// index advancing for longer than `stall_window`, e.g. because a quorum
+    /// was lost.
+    pub fn is_consensus_stalled(&self) -> bool {
+        self.progress.lock().unwrap().stalled
     }
-}
 
-/// Query results.
-#[derive(Debug)]
-pub struct QueryResults {
-    /// Query result rows.
-    pub rows: Vec<QueryRow>,
-}
+    /// Updates whether consensus is stalled, given the decided index.
+    fn check_progress(&self, decided_idx: u64, now: Instant) {
+        if self.config.stall_window.is_zero() {
+            return;
+        }
+        let pending = self.query_results_holder.lock().unwrap().pending();
+        let mut progress = self.progress.lock().unwrap();
+        let stalled = if decided_idx != progress.decided_idx || pending == 0 {
+            progress.decided_idx = decided_idx;
+            progress.since = now;
+            false
+        } else {
+            now.saturating_duration_since(progress.since) >= self.config.stall_window
+        };
+        if stalled == progress.stalled {
+            return;
+        }
+        progress.stalled = stalled;
+        self.metrics.gauge("consensus_stalled").set(stalled as i64);
+        if stalled {
+            log(format!(
+                "Node {}: consensus stalled, {} commands waiting and nothing decided for {:?}",
+                self.this_id, pending, self.config.stall_window
+            ));
+        } else {
+            log(format!("Node {}: consensus resumed at index {}", self.this_id, decided_idx));
+        }
+    }
 
-const HEARTBEAT_TIMEOUT: u64 = 10; // ticks until timeout
-const MESSAGE_LOOP_TIMEOUT_MS: u64 = 1; // How often to check for new outgoing messages
-const BLE_LOOP_TIMEOUT_MS: u64 = 100; // How often to check to do a BLE tick
-impl<T: StoreTransport + Send + Sync> StoreServer<T> {
-    /// Start a new server as part of a ChiselStore cluster.
-    pub fn start(this_id: u64, peers: Vec<u64>, transport: T) -> Result<Self, StoreError> {
-        // sequence paxos
-        let configuration_id = 1;
+    /// Returns the current wall time on this node's clock.
+    pub fn wall_time(&self) -> SystemTime {
+        self.config.clock.wall_time()
+    }
 
-        let mut sp_config = SequencePaxosConfig::default();
-        sp_config.set_configuration_id(configuration_id);
-        sp_config.set_pid(this_id);
-        sp_config.set_peers(peers.to_vec());
+    /// Returns how long a node whose decided index is `decided_idx` has been
+    /// missing writes that are decided on this node: how long ago, on this
+    /// node's clock, the oldest of them was decided. Zero if it misses none.
+    ///
+    /// Measured on the leader, this is how stale a follower is, regardless
+    /// of the follower's clock.
+    pub fn get_staleness(&self, decided_idx: u64) -> Duration {
+        self.decide_times.lock().unwrap().staleness(decided_idx, self.config.clock.wall_time())
+    }
 
-        let query_results_holder = Arc::new(Mutex::new(QueryResultsHolder::default()));
+    /// Returns the log entries this node decided per second, over the last
+    /// ten seconds.
+    pub fn get_throughput(&self) -> f64 {
+        self.decide_times.lock().unwrap().throughput(self.config.clock.wall_time(), THROUGHPUT_WINDOW)
+    }
 
-        let sequence_paxos = Arc::new(Mutex::new(new_sequence_paxos(configuration_id, this_id, peers.clone(), query_results_holder.clone(), None)));
+    /// Records the estimated skew of the wall clock of node `id` relative to
+    /// this node's, in milliseconds, positive if it is ahead. Returns true if
+    /// it exceeds `max_clock_skew`.
+    pub fn check_clock_skew(&self, id: u64, skew_ms: i64) -> bool {
+        self.metrics.gauge(&labelled("clock_skew_ms", "peer", &id.to_string())).set(skew_ms);
+        let exceeded = skew_ms.unsigned_abs() as u128 > self.config.max_clock_skew.as_millis();
+        if exceeded {
+            log(format!(
+                "Node {}: clock of node {} is off by {}ms, more than the {:?} tolerated",
+                self.this_id, id, skew_ms, self.config.max_clock_skew
+            ));
+        }
+        exceeded
+    }
 
-        // ballot leader election
-        let mut ble_config = BLEConfig::default();
-        ble_config.set_pid(this_id);
-        ble_config.set_peers(peers);
-        ble_config.set_hb_delay(HEARTBEAT_TIMEOUT);
+    /// Returns the number of leader changes this node saw in the last minute.
+    ///
+    /// Frequent changes mean leadership is flapping, e.g. because of an
+    /// unreliable network or overloaded nodes.
+    pub fn get_recent_leader_changes(&self) -> u64 {
+        let mut leader_history = self.leader_history.lock().unwrap();
+        leader_history.prune(self.config.clock.now());
+        leader_history.changes.len() as u64
+    }
 
-        let ballot_leader_election = Arc::new(Mutex::new(BallotLeaderElection::with(ble_config)));
-        
-        Ok(StoreServer {
-            this_id,
-            next_cmd_id: AtomicU64::new(0),
-            sequence_paxos,
-            ballot_leader_election,
-            transport,
-            query_results_holder,
-            halt: Arc::new(Mutex::new(false)),
+    /// Pins the retained snapshot covering the decided index `idx`, so that
+    /// it is not deleted until the returned pin is dropped.
+    pub fn pin_snapshot(&self, idx: u64) -> Result<SnapshotPin, StoreError> {
+        let mut snapshot_state = self.snapshot_state.lock().unwrap();
+        if !std::path::Path::new(&snapshot_path(self.this_id, Some(idx))).exists() {
+            return Err(StoreError::Snapshot(format!("no snapshot at index {}", idx)));
+        }
+        *snapshot_state.pins.entry(idx).or_insert(0) += 1;
+        Ok(SnapshotPin {
+            idx,
+            snapshot_state: self.snapshot_state.clone(),
         })
     }
 
-    /// Run the blocking event loop.
-    pub async fn run_message_loop(&self) {
-        loop {
-            sleep(Duration::from_millis(MESSAGE_LOOP_TIMEOUT_MS)).await;
+    /// Returns the length of the log this node has applied to its database.
+    ///
+    /// Decided entries are applied in order, as soon as they are decided;
+    /// they are only read without taking the log's lock, so this can be
+    /// observed while entries are being applied.
+    pub fn get_applied_idx(&self) -> u64 {
+        if self.config.learner {
+            return self.learner_applied_idx.load(Ordering::SeqCst);
+        }
+        self.metrics.gauge("applied_idx").get() as u64
+    }
 
-            if *self.halt.lock().unwrap() {
-                break
-            }
+    /// Returns how many decided entries this node has not applied yet, also
+    /// reported by the `apply_lag` gauge.
+    ///
+    /// A gap that keeps growing means applying is slower than deciding, e.g.
+    /// because of a slow disk or contention on the database, and the node
+    /// falls further and further behind.
+    pub fn get_apply_lag(&self) -> u64 {
+        if self.config.learner {
+            return 0;
+        }
+        self.metrics.gauge("apply_lag").get() as u64
+    }
 
-            let mut sequence_paxos = self.sequence_paxos.lock().unwrap();
-            let mut ballot_leader_election = self.ballot_leader_election.lock().unwrap();
+    /// Returns the index up to which this node's log was trimmed.
+    pub fn get_compacted_idx(&self) -> u64 {
+        let sequence_paxos = self.sequence_paxos.lock().unwrap();
+        sequence_paxos.get_compacted_idx()
+    }
 
-            // send outgoing messages
+    /// Returns the number of entries in this node's log that are accepted
+    /// but not decided yet.
+    ///
+    /// On the leader, these are the proposals waiting for a quorum; a growing
+    /// count means followers are not keeping up.
+    pub fn get_in_flight(&self) -> u64 {
+        if self.config.learner {
+            return 0;
+        }
+        let sequence_paxos = self.sequence_paxos.lock().unwrap();
+        let mut idx = sequence_paxos.get_decided_idx();
+        while let Some(LogEntry::Undecided(_)) = sequence_paxos.read(idx) {
+            idx += 1;
+        }
+        idx - sequence_paxos.get_decided_idx()
+    }
 
-            for out_msg in sequence_paxos.get_outgoing_msgs() {
-                let receiver = out_msg.to;
-                self.transport.send_sp(receiver, out_msg);
+    /// Returns the decided log entries starting at `from_idx`.
+    pub fn get_decided_entries(&self, from_idx: u64) -> Vec<StoreCommand> {
+        let sequence_paxos = self.sequence_paxos.lock().unwrap();
+        let decided_idx = sequence_paxos.get_decided_idx();
+        let mut entries = Vec::new();
+        for idx in from_idx..decided_idx {
+            match sequence_paxos.read(idx) {
+                Some(LogEntry::Decided(cmd)) => entries.push(cmd.clone()),
+                _ => break,
             }
+        }
+        entries
+    }
 
-            for out_msg in ballot_leader_election.get_outgoing_msgs() {
-                let receiver = out_msg.to;
-                self.transport.send_ble(receiver, out_msg);
+    /// Returns a copy of the database, for a follower whose log is behind
+    /// the trimmed log to catch up from.
+    ///
+    /// The copy is read in a single read transaction, started once every
+    /// decided entry is applied, so that it holds exactly the decided log.
+    pub async fn database_snapshot(&self) -> Result<DatabaseSnapshot, StoreError> {
+        if self.config.learner {
+            return Err(StoreError::Snapshot(String::from("learners don't serve snapshots")));
+        }
+        static NEXT_COPY: AtomicU64 = AtomicU64::new(0);
+        let path = format!("node{}.snapshot.send.{}.db", self.this_id, NEXT_COPY.fetch_add(1, Ordering::SeqCst));
+        let _ = std::fs::remove_file(&path);
+        let options = self.config.connection_options();
+        // Copies are sent unencrypted, like log entries.
+        let conn = open_database(self.this_id, &path, &options, None)?;
+        let copy = self.start_copy(&conn, &options);
+        let copy = match copy {
+            Ok(copy) => {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || finish_copy(conn, copy, &path))
+                    .await
+                    .map_err(|e| StoreError::Snapshot(e.to_string()))
+                    .and_then(|copy| copy)
             }
+            Err(e) => Err(e),
+        };
+        let _ = std::fs::remove_file(&path);
+        if copy.is_ok() {
+            self.metrics.counter("snapshots_served").inc();
+        }
+        copy
+    }
 
-            // check if node has stopped
-            if sequence_paxos.stopped() {
-                let final_entry = sequence_paxos.read(sequence_paxos.get_decided_idx());
-                
-                if final_entry.is_some() {
-                    let final_entry = final_entry.unwrap();
-                    match final_entry {
-                        omnipaxos_core::util::LogEntry::StopSign(ss) => {
-                            if !ss.nodes.contains(&self.this_id) { // node not part of new configuration
-                                return;
-                            }
-                            
-                            let configuration_id = ss.config_id;
-                            
-                            let mut nodes = ss.nodes;
-                            let this_idx = nodes.iter().position(|&n| n == self.this_id).unwrap();
-                            nodes.remove(this_idx);
-                            
-                            let peers = nodes;
-
-                            let query_results_holder = self.query_results_holder.clone();
-                            
-                            *sequence_paxos = new_sequence_paxos(configuration_id, self.this_id, peers, query_results_holder, ballot_leader_election.get_leader());
-                        },
-                        _ => panic!("Unexpected log entry"),
-                    };
-                }
-            };
+    /// Starts the read transaction of a copy of the database to the main
+    /// database of `conn`, returning the decided log it holds.
+    fn start_copy(&self, conn: &Connection, options: &ConnectionOptions) -> Result<DatabaseSnapshot, StoreError> {
+        conn.execute("PRAGMA journal_mode = DELETE")?;
+        attach_database(conn, &format!("node{}.db", self.this_id), "source", options.encryption_key.as_deref())?;
+        conn.execute("BEGIN")?;
+        // Commands are applied under the sequence paxos lock.
+        let sequence_paxos = self.sequence_paxos.lock().unwrap();
+        if !self.pending_applies.lock().unwrap().commands.is_empty() {
+            return Err(StoreError::Snapshot(String::from("the database doesn't hold every decided entry yet")));
         }
+        // Reading starts the read transaction.
+        conn.execute("SELECT count(*) FROM source.sqlite_master")?;
+        Ok(DatabaseSnapshot {
+            configuration_id: self.configuration_id.load(Ordering::SeqCst),
+            decided_idx: sequence_paxos.get_decided_idx(),
+            data: Vec::new(),
+        })
     }
-    
-    /// Run the blocking event loop.
-    pub async fn run_ble_loop(&self) {
-        loop {
-            sleep(Duration::from_millis(BLE_LOOP_TIMEOUT_MS)).await;
 
-            if *self.halt.lock().unwrap() {
-                break
+    /// Remove this node from the cluster, for decommissioning it.
+    ///
+    /// Has the leader propose a configuration of the other members and waits
+    /// for it to be decided, after which this node halts. Fails without
+    /// proposing anything if a majority of the remaining members can't be
+    /// reached, since the cluster could then make no progress without this
+    /// node. The members are probed concurrently, each for a bounded time,
+    /// with [`StoreTransport::probe`].
+    ///
+    /// Waiting is bounded by the `commit_timeout`, after which this fails
+    /// with `StoreError::Reconfiguration`. The configuration may still be
+    /// decided later.
+    pub async fn leave_cluster(&self) -> Result<(), StoreError> {
+        if self.config.learner {
+            return Err(StoreError::Learner);
+        }
+        let connectivity = self.get_peer_connectivity();
+        let probes = connectivity.iter().map(|&(id, connected)| async move {
+            self.transport.probe(id).await.unwrap_or(connected)
+        });
+        let reachable = futures::future::join_all(probes).await.into_iter().filter(|&reachable| reachable).count();
+        if reachable <= connectivity.len() / 2 {
+            return Err(StoreError::WouldLoseQuorum);
+        }
+        match self.get_current_leader() {
+            0 => return Err(StoreError::NotLeader),
+            leader if leader == self.this_id => self.remove_member(self.this_id)?,
+            leader => self.transport.remove_member(leader, self.this_id).await?,
+        }
+        let stopped = async {
+            while !self.sequence_paxos.lock().unwrap().stopped() {
+                self.config.clock.sleep(Duration::from_millis(MESSAGE_LOOP_TIMEOUT_MS)).await;
             }
+        };
+        match self.config.commit_timeout {
+            Some(timeout) => tokio::select! {
+                _ = stopped => {}
+                _ = self.config.clock.sleep(timeout) => {
+                    return Err(StoreError::Reconfiguration(format!("leaving the cluster was not decided within {:?}", timeout)));
+                }
+            },
+            None => stopped.await,
+        }
+        log(format!("Node {} left the cluster", self.this_id));
+        self.set_halt(true);
+        Ok(())
+    }
 
-            let mut sequence_paxos = self.sequence_paxos.lock().unwrap();
-            let mut ballot_leader_election = self.ballot_leader_election.lock().unwrap();
+    /// Remove node `node_id` from the cluster, as the leader, by proposing a
+    /// configuration of the other members.
+    pub fn remove_member(&self, node_id: u64) -> Result<(), StoreError> {
+        if self.get_current_leader() != self.this_id {
+            return Err(StoreError::NotLeader);
+        }
+        let remaining: Vec<u64> = self.members.lock().unwrap().iter().copied().filter(|&id| id != node_id).collect();
+        self.reconfigure(remaining).map_err(|e| StoreError::Reconfiguration(format!("{:?}", e)))
+    }
 
-            if let Some(leader) = ballot_leader_election.tick() {
-                // a new leader is elected, pass it to SequencePaxos.
-                sequence_paxos.handle_leader(leader);
+    /// Returns the entries of this node's log in the index range `from..to`,
+    /// whether decided or not, for comparing logs across nodes.
+    ///
+    /// Requires admin operations to be enabled.
+    pub fn dump_log(&self, from: u64, to: u64) -> Result<Vec<StoreCommand>, StoreError> {
+        if !self.config.admin {
+            return Err(StoreError::AdminDisabled);
+        }
+        let sequence_paxos = self.sequence_paxos.lock().unwrap();
+        let mut entries = Vec::new();
+        for idx in from..to {
+            match sequence_paxos.read(idx) {
+                Some(LogEntry::Decided(cmd)) | Some(LogEntry::Undecided(cmd)) => entries.push(cmd.clone()),
+                _ => break,
             }
         }
+        Ok(entries)
     }
 
-    /// Execute a SQL statement on the ChiselStore cluster.
-    pub async fn query<S: AsRef<str>>(
-        &self,
-        stmt: S,
-    ) -> Result<QueryResults, StoreError> {
-        let results = {
-            let (notify, id) = {
-                let id = self.next_cmd_id.fetch_add(1, Ordering::SeqCst);
-                let cmd = StoreCommand {
-                    id: id,
-                    sql: stmt.as_ref().to_string(),
-                };
-                
-                let notify = Arc::new(Notify::new());
-
-                let mut query_results_holder = self.query_results_holder.lock().unwrap();
-                query_results_holder.insert_notifier(id, notify.clone());
-                
-                let mut sequence_paxos = self.sequence_paxos.lock().unwrap();
-                sequence_paxos.append(cmd).expect("Failed to append");
-                
-                (notify, id)
-            };
-
-            // wait for append (and decide) to finish in background
-            notify.notified().await;
-            let results = self.query_results_holder.lock().unwrap().remove_result(&id).unwrap();
-            results?
+    /// Returns a checksum of the rows of `table` and their rowids, for
+    /// checking that nodes assigned the same rowids to inserted rows.
+    ///
+    /// Requires admin operations to be enabled.
+    pub fn table_checksum(&self, table: &str) -> Result<TableChecksum, StoreError> {
+        if !self.config.admin {
+            return Err(StoreError::AdminDisabled);
+        }
+        let sql = format!("SELECT rowid, * FROM {} ORDER BY rowid", quote_identifier(table));
+        let conn = open_connection(self.this_id, &self.config.connection_options())?;
+        // Holding sequence paxos keeps commands from being applied while the
+        // read transaction takes its snapshot; the scan reads the snapshot.
+        let sequence_paxos = self.sequence_paxos.lock().unwrap();
+        let decided_idx = if self.config.learner {
+            self.learner_applied_idx.load(Ordering::SeqCst)
+        } else {
+            sequence_paxos.get_decided_idx()
         };
-        Ok(results)
+        conn.execute("BEGIN")?;
+        conn.execute("SELECT count(*) FROM sqlite_master")?;
+        drop(sequence_paxos);
+        let mut statement = conn.prepare(&sql)?;
+        let mut rows = 0;
+        let mut checksum = FNV_OFFSET_BASIS;
+        while let State::Row = statement.next()? {
+            for i in 0..statement.column_count() {
+                let value = statement.read::<Value>(i)?;
+                // The type keeps NULL apart from the text 'NULL'.
+                let kind = match value {
+                    Value::Null => 0,
+                    Value::Integer(_) => 1,
+                    Value::Float(_) => 2,
+                    Value::String(_) => 3,
+                    Value::Binary(_) => 4,
+                };
+                checksum = fnv1a(checksum, &[kind]);
+                checksum = fnv1a(checksum, read_text(&statement, i)?.as_bytes());
+                checksum = fnv1a(checksum, &[0]);
+            }
+            checksum = fnv1a(checksum, &[0xff]);
+            rows += 1;
+        }
+        drop(statement);
+        let _ = conn.execute("COMMIT");
+        Ok(TableChecksum { decided_idx, rows, checksum })
     }
 
-    /// Receive a sequence paxos message from the ChiselStore cluster.
-    pub fn recv_sp_msg(&self, msg: Message<StoreCommand, ()>) {
-        let mut sequence_paxos = self.sequence_paxos.lock().unwrap();
-        sequence_paxos.handle(msg);
+    /// Returns storage statistics of this node's database.
+    ///
+    /// The statistics are a consistent read: unless this node is a learner,
+    /// they reflect at least every write decided before the call, and they
+    /// are all taken from the same snapshot of the database.
+    pub async fn storage_stats(&self) -> Result<StorageStats, StoreError> {
+        if !self.config.learner {
+            self.noop().await?;
+        }
+        let decided_idx = self.get_decided_idx();
+        let conn = self.local_conn.lock().unwrap();
+        conn.execute("BEGIN")?;
+        let stats = read_storage_stats(&conn);
+        let _ = conn.execute("COMMIT");
+        Ok(StorageStats { decided_idx, ..stats? })
     }
-    
-    /// Receive a ballot leader election message from the ChiselStore cluster.
-    pub fn recv_ble_msg(&self, msg: BLEMessage) {
-        let mut ballot_leader_election = self.ballot_leader_election.lock().unwrap();
-        ballot_leader_election.handle(msg);
+
+    /// Returns the most recent decided commands that failed to apply on this
+    /// node, oldest first, for auditing divergence between nodes.
+    ///
+    /// Requires admin operations to be enabled.
+    pub fn apply_errors(&self) -> Result<Vec<ApplyError>, StoreError> {
+        if !self.config.admin {
+            return Err(StoreError::AdminDisabled);
+        }
+        Ok(self.apply_errors.lock().unwrap().iter().cloned().collect())
     }
 
-    /// Used to shutdown replica
-    pub fn set_halt(&self, new_halt: bool) {
-        let mut halt = self.halt.lock().unwrap();
-        *halt = new_halt;
+    /// Returns the leadership epochs this node saw entries decided in, oldest
+    /// first, for mapping log ranges to the leaders that decided them.
+    ///
+    /// Requires admin operations to be enabled.
+    pub fn epochs(&self) -> Result<Vec<Epoch>, StoreError> {
+        if !self.config.admin {
+            return Err(StoreError::AdminDisabled);
+        }
+        Ok(self.epochs.lock().unwrap().iter().copied().collect())
     }
 
-    pub fn get_current_leader(&self) -> u64 {
-        let sequence_paxos = self.sequence_paxos.lock().unwrap();
-        sequence_paxos.get_current_leader()
+    /// Subscribes to the writes committed on this node from now on, in log
+    /// order, for change data capture.
+    ///
+    /// Each subscriber buffers up to `change_buffer_capacity` writes; one that
+    /// falls further behind is handled according to `change_overflow_policy`.
+    ///
+    /// Requires admin operations to be enabled.
+    pub fn subscribe_changes(&self) -> Result<ChangeSubscription, StoreError> {
+        if !self.config.admin {
+            return Err(StoreError::AdminDisabled);
+        }
+        Ok(self.changes.subscribe())
     }
 
-    pub fn get_id(&self) -> u64 {
-        self.this_id
+    /// Routes the messages of this node to node `node_id` to `addr` from now
+    /// on, e.g. after the node was rescheduled to another host with the same
+    /// id, without reconfiguring the cluster.
+    ///
+    /// Only this node's routing is updated: every other member must be
+    /// updated too. The routing is recorded, and survives restarts. Requires
+    /// admin operations to be enabled.
+    pub fn update_node_address(&self, node_id: u64, addr: &str) -> Result<(), StoreError> {
+        if !self.config.admin {
+            return Err(StoreError::AdminDisabled);
+        }
+        record_node_address(self.this_id, node_id, addr)?;
+        log(format!("Node {} routing messages to node {} to {}", self.this_id, node_id, addr));
+        self.transport.update_node_address(node_id, addr);
+        Ok(())
     }
 
     /// Reconfigure Omnipaxos cluster. Should be called from leader
@@ -495,7 +5493,23 @@ impl<T: StoreTransport + Send + Sync> StoreServer<T> {
     }
 }
 
-fn new_sequence_paxos(configuration_id: u32, pid: u64, peers: Vec<u64>, query_results_holder: Arc<Mutex<QueryResultsHolder>>, skip_prepare_use_leader: Option<Ballot>) -> SequencePaxos<StoreCommand, (), SQLiteStore<()>> {
+/// State of the server that the store of every configuration shares.
+#[derive(Clone)]
+struct SharedState {
+    query_results_holder: Arc<Mutex<QueryResultsHolder>>,
+    halt: Arc<Mutex<bool>>,
+    dedup: Arc<DedupWindow>,
+    apply_errors: Arc<Mutex<VecDeque<ApplyError>>>,
+    epochs: Arc<Mutex<VecDeque<Epoch>>>,
+    audit: Option<Arc<Mutex<AuditLog>>>,
+    changes: Arc<ChangeFeed>,
+    applied: Arc<watch::Sender<(u32, u64)>>,
+    pending: Arc<Mutex<PendingApplies>>,
+    metrics: Arc<Registry>,
+}
+
+fn new_sequence_paxos(configuration_id: u32, pid: u64, peers: Vec<u64>, shared: SharedState, config: &StoreServerConfig, skip_prepare_use_leader: Option<Ballot>) -> Result<StoreSequencePaxos, StoreError> {
+    let SharedState { query_results_holder, halt, dedup, apply_errors, epochs, audit, changes, applied, pending, metrics } = shared;
     let mut sp_config = SequencePaxosConfig::default();
     sp_config.set_configuration_id(configuration_id);
     sp_config.set_pid(pid);
@@ -504,8 +5518,33 @@ fn new_sequence_paxos(configuration_id: u32, pid: u64, peers: Vec<u64>, query_re
         sp_config.set_skip_prepare_use_leader(b);
     }
 
-    let store_config = StoreConfig { conn_pool_size: 20, query_results_holder };
-    let sqlite_store = SQLiteStore::new(pid, store_config);
+    let store_config = StoreConfig {
+        configuration_id,
+        conn_pool_size: 20,
+        connection: config.connection_options(),
+        busy_retries: config.busy_retries,
+        max_apply_rows: config.max_apply_rows,
+        apply_error_policy: config.apply_error_policy,
+        apply_batch_size: config.apply_batch_size,
+        apply_transactions: metrics.counter("apply_transactions"),
+        commands_applied: metrics.counter("commands_applied"),
+        apply_latency: metrics.histogram("apply_latency"),
+        commit_latency: metrics.histogram("commit_latency"),
+        applied_idx: metrics.gauge("applied_idx"),
+        applied,
+        pending,
+        apply_lag: metrics.gauge("apply_lag"),
+        log_bytes: metrics.gauge("log_bytes"),
+        dedup,
+        apply_errors,
+        epochs,
+        audit,
+        changes,
+        clock: config.clock.clone(),
+        query_results_holder,
+        halt,
+    };
+    let sqlite_store = SQLiteStore::new(pid, store_config)?;
     
-    SequencePaxos::with(sp_config, sqlite_store)
+    Ok(SequencePaxos::with(sp_config, sqlite_store))
 }
@@ -0,0 +1,506 @@
+//! SQL statement inspection.
+//!
+//! ChiselStore does not parse SQL; these helpers only split statements and
+//! look at their leading keyword, which is enough to tell reads from writes,
+//! or at their tokens, to tell which tables they touch.
+
+use sqlite::Value;
+
+/// Splits `sql` into its statements, skipping empty ones and those that
+/// are only comments.
+///
+/// Semicolons inside quoted literals and identifiers, and inside comments,
+/// do not end a statement.
+pub(crate) fn statements(sql: &str) -> Vec<&str> {
+    let mut stmts = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut chars = sql.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let next = chars.peek().map(|&(_, next)| next);
+        match (quote, c) {
+            // a block comment, until `*/`
+            (None, '/') if next == Some('*') => {
+                chars.next();
+                quote = Some('*');
+            }
+            (Some('*'), '*') if next == Some('/') => {
+                chars.next();
+                quote = None;
+            }
+            (Some('*'), _) => {}
+            // a line comment, until the end of the line
+            (None, '-') if next == Some('-') => quote = Some('\n'),
+            (None, '\'') | (None, '"') | (None, '`') => quote = Some(c),
+            (None, '[') => quote = Some(']'),
+            (Some(q), c) if q == c => quote = None,
+            (None, ';') => {
+                stmts.push(&sql[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    stmts.push(&sql[start..]);
+    stmts.into_iter().map(|s| s.trim()).filter(|s| !skip_comments(s).is_empty()).collect()
+}
+
+/// Number of parameters of `stmt`, which are bound by their index from 1
+/// up to it.
+///
+/// As in SQLite, that is the largest index of its placeholders: `?` takes
+/// the index after the largest so far, `?NNN` index NNN, and `:name`,
+/// `@name` and `$name` the index of the first placeholder with that name,
+/// or the one after the largest so far.
+pub(crate) fn placeholders(stmt: &str) -> usize {
+    parameters(stmt).iter().map(|param| param.idx).max().unwrap_or(0)
+}
+
+/// Returns `sql` with the values of `params` in place of its placeholders,
+/// as SQL literals, so that it can be logged as it was executed.
+///
+/// The parameters of each statement follow those of the statements before,
+/// as they are bound. Placeholders without a value are kept.
+pub(crate) fn bind(sql: &str, params: &[Value]) -> String {
+    let mut bound = String::with_capacity(sql.len());
+    let mut end = 0;
+    for param in parameters(sql) {
+        bound.push_str(&sql[end..param.start]);
+        match params.get(param.idx - 1) {
+            Some(value) => bound.push_str(&literal(value)),
+            None => bound.push_str(&sql[param.start..param.end]),
+        }
+        end = param.end;
+    }
+    bound.push_str(&sql[end..]);
+    bound
+}
+
+/// A parameter placeholder in SQL.
+struct Parameter {
+    /// Byte range of the placeholder.
+    start: usize,
+    end: usize,
+    /// Index the parameter is bound by, counting those of the statements
+    /// before.
+    idx: usize,
+}
+
+/// Parameter placeholders of `sql`, skipping those in quoted literals,
+/// identifiers and comments.
+fn parameters(sql: &str) -> Vec<Parameter> {
+    let mut params = Vec::new();
+    // parameters of the statements before, and of this one
+    let mut offset = 0;
+    let mut count = 0;
+    let mut names: Vec<(&str, usize)> = Vec::new();
+    let mut chars = sql.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let next = chars.peek().map(|&(_, next)| next);
+        match c {
+            '-' if next == Some('-') => {
+                for (_, c) in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if next == Some('*') => {
+                chars.next();
+                let mut prev = ' ';
+                for (_, c) in chars.by_ref() {
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+            }
+            '\'' | '"' | '`' | '[' => {
+                // a doubled quote inside quotes is skipped as two
+                let close = if c == '[' { ']' } else { c };
+                for (_, c) in chars.by_ref() {
+                    if c == close {
+                        break;
+                    }
+                }
+            }
+            ';' => {
+                offset += count;
+                count = 0;
+                names.clear();
+            }
+            '?' => {
+                let mut end = i + 1;
+                while let Some((j, _)) = chars.next_if(|&(_, c)| c.is_ascii_digit()) {
+                    end = j + 1;
+                }
+                let idx = match sql[i + 1..end].parse::<usize>() {
+                    Ok(idx) if idx > 0 => idx,
+                    _ => count + 1,
+                };
+                count = count.max(idx);
+                params.push(Parameter { start: i, end, idx: offset + idx });
+            }
+            ':' | '@' | '$' if next.map_or(false, |c| c.is_alphanumeric() || c == '_') => {
+                let mut end = i + 1;
+                while let Some((j, c)) = chars.next_if(|&(_, c)| c.is_alphanumeric() || c == '_') {
+                    end = j + c.len_utf8();
+                }
+                let name = &sql[i..end];
+                let idx = match names.iter().find(|&&(n, _)| n == name) {
+                    Some(&(_, idx)) => idx,
+                    None => {
+                        count += 1;
+                        names.push((name, count));
+                        count
+                    }
+                };
+                params.push(Parameter { start: i, end, idx: offset + idx });
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                // identifiers may contain `$`
+                while chars.next_if(|&(_, c)| c.is_alphanumeric() || c == '_' || c == '$').is_some() {}
+            }
+            _ => {}
+        }
+    }
+    params
+}
+
+/// `value` as a SQL literal.
+///
+/// SQLite has no literals for infinite floats, which are written as a number
+/// too large to represent, nor for NaN, which it stores as NULL.
+fn literal(value: &Value) -> String {
+    match value {
+        Value::Null => String::from("NULL"),
+        Value::Integer(i) => i.to_string(),
+        Value::Float(f) if f.is_nan() => String::from("NULL"),
+        Value::Float(f) if f.is_infinite() => String::from(if *f > 0.0 { "9e999" } else { "-9e999" }),
+        Value::Float(f) => format!("{:?}", f),
+        Value::String(s) => format!("'{}'", s.replace('\'', "''")),
+        Value::Binary(bytes) => format!("X'{}'", bytes.iter().map(|b| format!("{:02X}", b)).collect::<String>()),
+    }
+}
+
+/// `stmt` without its leading whitespace and comments.
+fn skip_comments(mut stmt: &str) -> &str {
+    loop {
+        stmt = stmt.trim_start();
+        if let Some(rest) = stmt.strip_prefix("--") {
+            stmt = rest.split_once('\n').map_or("", |(_, rest)| rest);
+        } else if let Some(rest) = stmt.strip_prefix("/*") {
+            stmt = rest.split_once("*/").map_or("", |(_, rest)| rest);
+        } else {
+            return stmt;
+        }
+    }
+}
+
+/// Leading keyword of `stmt`, uppercased, after any comments.
+pub(crate) fn keyword(stmt: &str) -> String {
+    skip_comments(stmt)
+        .split_whitespace()
+        .next()
+        .unwrap_or("")
+        .trim_start_matches('(')
+        .to_ascii_uppercase()
+}
+
+/// Returns true if any statement in `sql` creates, alters or drops a
+/// schema object.
+pub(crate) fn changes_schema(sql: &str) -> bool {
+    statements(sql)
+        .iter()
+        .any(|stmt| matches!(keyword(stmt).as_str(), "CREATE" | "ALTER" | "DROP"))
+}
+
+/// Returns true if any statement in `sql` controls transactions or can't
+/// run inside one.
+pub(crate) fn controls_transaction(sql: &str) -> bool {
+    statements(sql).iter().any(|stmt| {
+        matches!(
+            keyword(stmt).as_str(),
+            "BEGIN" | "COMMIT" | "END" | "ROLLBACK" | "SAVEPOINT" | "RELEASE" | "PRAGMA" | "VACUUM" | "ATTACH" | "DETACH"
+        )
+    })
+}
+
+/// Returns true if any statement in `sql` is a `PRAGMA` naming `pragma`,
+/// which may be qualified by a schema, e.g. `PRAGMA main.application_id`.
+pub(crate) fn is_pragma(sql: &str, pragma: &str) -> bool {
+    let pragma = pragma.to_ascii_lowercase();
+    statements(sql)
+        .iter()
+        .any(|stmt| keyword(stmt) == "PRAGMA" && stmt.to_ascii_lowercase().contains(&pragma))
+}
+
+/// Returns true if every statement in `sql` only reads from the database.
+pub(crate) fn is_read_only(sql: &str) -> bool {
+    statements(sql)
+        .iter()
+        .all(|stmt| matches!(keyword(stmt).as_str(), "SELECT" | "VALUES" | "EXPLAIN"))
+}
+
+/// Returns true if any statement in `sql` writes to the database.
+///
+/// Unlike [`is_read_only`], this looks past a leading `WITH` clause, to the
+/// statement it is the common table expressions of.
+pub(crate) fn writes(sql: &str) -> bool {
+    statements(sql).iter().any(|stmt| {
+        if keyword(stmt) != "WITH" {
+            return !is_read_only(stmt);
+        }
+        let tokens = tokens(stmt);
+        tokens.iter().enumerate().any(|(i, token)| {
+            // `replace(...)` is a function
+            let keyword = matches!(token, Token::Word(word) if matches!(word.to_ascii_uppercase().as_str(), "INSERT" | "UPDATE" | "DELETE" | "REPLACE"));
+            keyword && tokens.get(i + 1) != Some(&Token::Punct('('))
+        })
+    })
+}
+
+/// Returns true if `sql` is a single transaction whose statements only read
+/// from the database, such as `BEGIN; SELECT ...; SELECT ...; COMMIT;`.
+pub(crate) fn is_read_only_transaction(sql: &str) -> bool {
+    let stmts = statements(sql);
+    match (stmts.first(), stmts.last()) {
+        (Some(first), Some(last)) if stmts.len() > 2 => {
+            keyword(first) == "BEGIN"
+                && matches!(keyword(last).as_str(), "COMMIT" | "END")
+                && stmts[1..stmts.len() - 1].iter().all(|stmt| is_read_only(stmt))
+        }
+        _ => false,
+    }
+}
+
+/// Tables `sql` reads from or writes to, lowercased and unquoted, or
+/// `None` if a statement names them in a way this can't tell for certain.
+///
+/// Only the names following `FROM`, `JOIN`, `INTO` and `UPDATE`, and the
+/// further ones in a comma separated list, are found; tables named in
+/// subqueries are found as well, but not those accessed through views or
+/// triggers.
+pub(crate) fn tables(sql: &str) -> Option<Vec<String>> {
+    let mut tables: Vec<String> = Vec::new();
+    for stmt in statements(sql) {
+        tables_of(&tokens(stmt), &mut tables)?;
+    }
+    Some(tables)
+}
+
+/// Adds the tables named in `tokens` to `tables`, as in [`tables`].
+fn tables_of(tokens: &[Token], tables: &mut Vec<String>) -> Option<()> {
+    let mut i = 0;
+    while i < tokens.len() {
+        let keyword = match tokens[i] {
+            Token::Word(word) => word.to_ascii_uppercase(),
+            _ => String::new(),
+        };
+        i += 1;
+        if !matches!(keyword.as_str(), "FROM" | "JOIN" | "INTO" | "UPDATE") {
+            continue;
+        }
+        // UPDATE OR REPLACE t
+        if keyword == "UPDATE" && is_word(tokens.get(i), "OR") {
+            i += 2;
+        }
+        loop {
+            if tokens.get(i) == Some(&Token::Punct('(')) {
+                // a subquery, whose tables are found in turn
+                let close = i + closing_paren(&tokens[i..])?;
+                tables_of(&tokens[i + 1..close], tables)?;
+                i = close + 1;
+            } else {
+                let (name, next) = table_name(tokens, i)?;
+                i = next;
+                if !tables.contains(&name) {
+                    tables.push(name);
+                }
+            }
+            // the table of an INTO or UPDATE is followed by its columns
+            if matches!(keyword.as_str(), "INTO" | "UPDATE") {
+                break;
+            }
+            // a table-valued function, whose arguments may name anything
+            if tokens.get(i) == Some(&Token::Punct('(')) {
+                return None;
+            }
+            if is_word(tokens.get(i), "AS") {
+                i += 2;
+            } else if matches!(tokens.get(i), Some(Token::Word(word)) if !ends_table(word)) || matches!(tokens.get(i), Some(Token::Quoted(_))) {
+                i += 1;
+            }
+            if tokens.get(i) != Some(&Token::Punct(',')) {
+                break;
+            }
+            i += 1;
+        }
+    }
+    Some(())
+}
+
+/// Name of the table, possibly qualified by a schema, starting at
+/// `tokens[i]`, and the index of the token following it.
+fn table_name(tokens: &[Token], i: usize) -> Option<(String, usize)> {
+    let name = |token: Option<&Token>| match token {
+        Some(Token::Word(name)) | Some(Token::Quoted(name)) => Some(name.to_ascii_lowercase()),
+        _ => None,
+    };
+    let first = name(tokens.get(i))?;
+    if tokens.get(i + 1) == Some(&Token::Punct('.')) {
+        return Some((name(tokens.get(i + 2))?, i + 3));
+    }
+    Some((first, i + 1))
+}
+
+/// Index of the parenthesis closing the one `tokens` starts with.
+fn closing_paren(tokens: &[Token]) -> Option<usize> {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Punct('(') => depth += 1,
+            Token::Punct(')') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Returns true if `word` ends the table list of a `FROM` or `JOIN`,
+/// rather than being the alias of the last table.
+fn ends_table(word: &str) -> bool {
+    matches!(
+        word.to_ascii_uppercase().as_str(),
+        "WHERE" | "GROUP" | "HAVING" | "WINDOW" | "ORDER" | "LIMIT" | "UNION" | "INTERSECT" | "EXCEPT"
+            | "JOIN" | "INNER" | "LEFT" | "RIGHT" | "FULL" | "CROSS" | "NATURAL" | "OUTER" | "ON" | "USING"
+            | "INDEXED" | "NOT" | "RETURNING" | "SET" | "VALUES" | "SELECT" | "DEFAULT"
+    )
+}
+
+/// Returns true if `token` is the unquoted word `word`.
+fn is_word(token: Option<&Token>, word: &str) -> bool {
+    matches!(token, Some(Token::Word(w)) if w.eq_ignore_ascii_case(word))
+}
+
+/// Token of a statement, as far as [`tables`] tells them apart.
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    /// An unquoted keyword, identifier or number.
+    Word(&'a str),
+    /// A quoted identifier, without its quotes.
+    Quoted(&'a str),
+    /// A string literal.
+    Literal,
+    /// Any other character, e.g. `(`, `,` or `.`.
+    Punct(char),
+}
+
+/// Splits `stmt` into tokens, skipping whitespace and comments.
+fn tokens(stmt: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut chars = stmt.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let next = chars.peek().map(|&(_, next)| next);
+        match c {
+            c if c.is_whitespace() => {}
+            '-' if next == Some('-') => {
+                for (_, c) in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if next == Some('*') => {
+                chars.next();
+                let mut prev = ' ';
+                for (_, c) in chars.by_ref() {
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+            }
+            '\'' => {
+                // a quote inside a literal is doubled
+                while let Some((_, c)) = chars.next() {
+                    if c == '\'' && chars.next_if(|&(_, next)| next == '\'').is_none() {
+                        break;
+                    }
+                }
+                tokens.push(Token::Literal);
+            }
+            '"' | '`' | '[' => {
+                let close = if c == '[' { ']' } else { c };
+                let start = i + 1;
+                let mut end = stmt.len();
+                for (j, c) in chars.by_ref() {
+                    if c == close {
+                        end = j;
+                        break;
+                    }
+                }
+                tokens.push(Token::Quoted(&stmt[start..end]));
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut end = i + c.len_utf8();
+                while let Some((j, c)) = chars.next_if(|&(_, c)| c.is_alphanumeric() || c == '_' || c == '$') {
+                    end = j + c.len_utf8();
+                }
+                tokens.push(Token::Word(&stmt[i..end]));
+            }
+            c => tokens.push(Token::Punct(c)),
+        }
+    }
+    tokens
+}
+
+/// Replaces the string and numeric literals of `sql` with `?`, so that it
+/// can be logged without the data it writes.
+pub(crate) fn redact(sql: &str) -> String {
+    let mut redacted = String::with_capacity(sql.len());
+    let mut quote = None;
+    let mut prev = ' ';
+    let mut in_number = false;
+    for c in sql.chars() {
+        match (quote, c) {
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {}
+            (Some(q), c) => {
+                if q == c {
+                    quote = None;
+                }
+                redacted.push(c);
+            }
+            (None, '\'') => {
+                quote = Some('\'');
+                in_number = false;
+                redacted.push('?');
+            }
+            (None, '"') | (None, '`') => {
+                quote = Some(c);
+                redacted.push(c);
+            }
+            (None, '[') => {
+                quote = Some(']');
+                redacted.push(c);
+            }
+            (None, c) if in_number && (c.is_ascii_alphanumeric() || c == '.') => {}
+            (None, c) if c.is_ascii_digit() && !(prev.is_alphanumeric() || prev == '_') => {
+                in_number = true;
+                redacted.push('?');
+            }
+            (None, c) => {
+                in_number = false;
+                redacted.push(c);
+            }
+        }
+        prev = c;
+    }
+    redacted
+}
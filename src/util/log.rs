@@ -4,6 +4,7 @@ use sloggers::Build;
 use sloggers::terminal::{TerminalLoggerBuilder, Destination};
 use sloggers::types::Severity;
 
+/// Log `s` to stderr.
 pub fn log(s: String) {
     let mut builder = TerminalLoggerBuilder::new();
     builder.level(Severity::Debug);
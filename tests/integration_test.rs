@@ -1,10 +1,9 @@
 use chiselstore::rpc::proto::rpc_server::RpcServer;
 use chiselstore::{
-    rpc::{RpcService, RpcTransport},
-    StoreServer,
+    rpc::{PeerToken, RpcServerConfig, RpcService, RpcTransport, RpcTransportConfig},
+    CommandKind, StoreServer, StoreServerConfig,
 };
 use std::sync::Arc;
-use tonic::transport::Server;
 
 extern crate futures;
 use crate::futures::FutureExt;
@@ -13,7 +12,7 @@ pub mod proto {
   tonic::include_proto!("proto");
 }
 use proto::rpc_client::RpcClient;
-use proto::{Query};
+use proto::{Query, Void};
 use tokio::sync::oneshot;
 
 use slog::info;
@@ -76,13 +75,89 @@ impl Replica {
     pub fn reconfigure(&self, new_cluster: Vec<u64>) {
         self.store_server.reconfigure(new_cluster);
     }
+
+    /// Stops serving RPCs, cutting the node off from messages of its peers.
+    pub async fn disconnect(&mut self) {
+        let (shutdown_sender, _) = oneshot::channel::<()>();
+        let _ = std::mem::replace(&mut self.shutdown_sender, shutdown_sender).send(());
+        let rpc_handle = tokio::task::spawn(async { Ok(()) });
+        std::mem::replace(&mut self.rpc_handle, rpc_handle).await.unwrap().unwrap();
+    }
+
+    /// Serves RPCs again after `disconnect`.
+    pub fn reconnect(&mut self) {
+        let (shutdown_sender, rpc_handle) = serve_rpc(
+            self.get_id(),
+            RpcService::new(self.store_server.clone()),
+            RpcServerConfig::default(),
+        );
+        self.shutdown_sender = shutdown_sender;
+        self.rpc_handle = rpc_handle;
+    }
 }
 
-async fn start_replica(id: u64, peers: Vec<u64>) -> Replica {
+/// Serves `rpc` on the address of node `id`, until the returned sender is used.
+fn serve_rpc(
+    id: u64,
+    rpc: RpcService,
+    server_config: RpcServerConfig,
+) -> (oneshot::Sender<()>, tokio::task::JoinHandle<Result<(), tonic::transport::Error>>) {
     let (host, port) = node_authority(id);
     let rpc_listen_addr = format!("{}:{}", host, port).parse().unwrap();
-    let transport = RpcTransport::new(Box::new(node_rpc_addr));
-    let server = StoreServer::start(id, peers, transport).unwrap();
+    let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+    let rpc_handle = tokio::task::spawn(async move {
+        log(format!("RPC listening to {} ...", rpc_listen_addr).to_string());
+        let ret = server_config.builder()
+            .add_service(RpcServer::new(rpc))
+            .serve_with_shutdown(rpc_listen_addr, shutdown_receiver.map(drop))
+            .await;
+        log("RPC Server shutting down...".to_string());
+        ret
+    });
+    (shutdown_sender, rpc_handle)
+}
+
+async fn start_replica(id: u64, peers: Vec<u64>) -> Replica {
+    start_replica_with_config(id, peers, StoreServerConfig::default()).await
+}
+
+async fn start_replica_with_config(id: u64, peers: Vec<u64>, config: StoreServerConfig) -> Replica {
+    start_replica_with_transport_config(id, peers, config, RpcTransportConfig::default()).await
+}
+
+async fn start_replica_with_transport_config(
+    id: u64,
+    peers: Vec<u64>,
+    config: StoreServerConfig,
+    transport_config: RpcTransportConfig,
+) -> Replica {
+    start_replica_with_server_config(id, peers, config, transport_config, RpcServerConfig::default()).await
+}
+
+async fn start_replica_with_server_config(
+    id: u64,
+    peers: Vec<u64>,
+    config: StoreServerConfig,
+    transport_config: RpcTransportConfig,
+    server_config: RpcServerConfig,
+) -> Replica {
+    start_replica_with_service(id, peers, config, transport_config, server_config, RpcService::new).await
+}
+
+async fn start_replica_with_service(
+    id: u64,
+    peers: Vec<u64>,
+    config: StoreServerConfig,
+    transport_config: RpcTransportConfig,
+    server_config: RpcServerConfig,
+    service: impl FnOnce(Arc<StoreServer<RpcTransport>>) -> RpcService,
+) -> Replica {
+    let mut config = config;
+    if config.membership.is_empty() {
+        config.membership = std::iter::once(id).chain(peers.iter().copied()).collect();
+    }
+    let transport = RpcTransport::with_config(Box::new(node_rpc_addr), transport_config);
+    let server = StoreServer::start_with_config(id, peers, transport, config).unwrap();
     let server = Arc::new(server);
     let (halt_sender, halt_receiver) = oneshot::channel::<()>();
     let store_handles = {
@@ -107,20 +182,7 @@ async fn start_replica(id: u64, peers: Vec<u64>) -> Replica {
         });
         (message_loop, ble_loop)
     };
-    let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
-    let rpc_handle = {
-        let server = server.clone();
-        let rpc = RpcService::new(server);
-        tokio::task::spawn(async move {
-            log(format!("RPC listening to {} ...", rpc_listen_addr).to_string());
-            let ret = Server::builder()
-                .add_service(RpcServer::new(rpc))
-                .serve_with_shutdown(rpc_listen_addr, shutdown_receiver.map(drop))
-                .await;
-            log("RPC Server shutting down...".to_string());
-            ret
-        })
-    };
+    let (shutdown_sender, rpc_handle) = serve_rpc(id, service(server.clone()), server_config);
 
     return Replica {
         store_server: server.clone(),
@@ -133,6 +195,10 @@ async fn start_replica(id: u64, peers: Vec<u64>) -> Replica {
 }
 
 async fn setup_replicas(num_replicas: u64) -> Vec<Replica> {
+    setup_replicas_with_config(num_replicas, StoreServerConfig::default()).await
+}
+
+async fn setup_replicas_with_config(num_replicas: u64, config: StoreServerConfig) -> Vec<Replica> {
     let mut replicas: Vec<Replica> = Vec::new();
     for id in 1..(num_replicas+1) {
         let mut peers: Vec<u64> = (1..num_replicas+1).collect();
@@ -140,7 +206,7 @@ async fn setup_replicas(num_replicas: u64) -> Vec<Replica> {
 
         log(format!("setup pid: {} peers: {:?}", id, peers).to_string());
 
-        replicas.push(start_replica(id, peers).await);
+        replicas.push(start_replica_with_config(id, peers, config.clone()).await);
     }
 
     return replicas
@@ -161,6 +227,7 @@ async fn query(replica_id: u64, sql: String) -> Result<String, Box<dyn Error>> {
     // create request
     let query = tonic::Request::new(Query {
         sql: sql,
+        ..Default::default()
     });
 
     // execute request
@@ -379,4 +446,4623 @@ async fn follower_crashes() {
     }).await.unwrap();
     
     shutdown_replicas(replicas).await;
-}
\ No newline at end of file
+}
+#[tokio::test(flavor = "multi_thread")]
+async fn transaction_savepoint() {
+    let replicas = setup_replicas(2).await;
+    let server = &replicas[0].store_server;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_savepoint (id integer PRIMARY KEY)")).await.unwrap();
+
+    // roll back to a savepoint and commit the rest of the transaction
+    server.transaction(&[
+        "INSERT INTO test_savepoint VALUES(1)",
+        "SAVEPOINT sp",
+        "INSERT INTO test_savepoint VALUES(2)",
+        "ROLLBACK TO sp",
+        "INSERT INTO test_savepoint VALUES(3)",
+    ]).await.unwrap();
+
+    // a transaction failing part-way is rolled back as a whole, and doesn't
+    // leave a transaction open that the next one would start inside of
+    assert!(server.transaction(&[
+        "INSERT INTO test_savepoint VALUES(4)",
+        "SAVEPOINT sp",
+        "INSERT INTO test_savepoint VALUES(1)",
+    ]).await.is_err());
+    server.transaction(&["INSERT INTO test_savepoint VALUES(5)"]).await.unwrap();
+
+    let decided_idx = server.get_decided_idx();
+    for replica in replicas.iter() {
+        while replica.store_server.get_applied_idx() < decided_idx {
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+    }
+    let x = query(1, String::from("SELECT group_concat(id) FROM (SELECT id FROM test_savepoint ORDER BY id)")).await.unwrap();
+    let y = query(2, String::from("SELECT group_concat(id) FROM (SELECT id FROM test_savepoint ORDER BY id)")).await.unwrap();
+    assert_eq!(x, "1,3,5");
+    assert_eq!(x, y);
+
+    query(1, String::from("DROP TABLE test_savepoint")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn write_buffer_coalesces_writes() {
+    let mut config = StoreServerConfig::default();
+    config.write_buffer_linger = tokio::time::Duration::from_millis(5);
+    config.write_buffer_max_batch = 32;
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_write_buffer (id integer PRIMARY KEY)")).await.unwrap();
+    let decided_before = replicas[0].store_server.get_decided_idx();
+
+    // flood the cluster with writes
+    let num_writes = 200;
+    let start = std::time::Instant::now();
+    let mut writes = Vec::new();
+    for i in 0..num_writes {
+        writes.push(tokio::task::spawn(async move {
+            query(1, format!("INSERT INTO test_write_buffer VALUES({})", i)).await.unwrap();
+        }));
+    }
+    for w in writes {
+        w.await.unwrap();
+    }
+    log(format!("{} writes committed in {:?}", num_writes, start.elapsed()));
+
+    // the writes were coalesced into fewer log entries
+    let decided_after = replicas[0].store_server.get_decided_idx();
+    assert!(decided_after - decided_before < num_writes);
+
+    let res = query(2, String::from("SELECT COUNT(*) FROM test_write_buffer")).await.unwrap();
+    assert!(res == num_writes.to_string());
+
+    query(1, String::from("DROP TABLE test_write_buffer")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connection_pool_metrics() {
+    let replicas = setup_replicas(2).await;
+
+    query(1, String::from("SELECT 1+1;")).await.unwrap();
+    // give the leader election some rounds of heartbeats
+    tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
+
+    // the first connection to a peer misses the empty pool, later ones reuse it
+    let metrics = replicas[0].store_server.transport().metrics();
+    let peer = node_rpc_addr(2);
+    let misses = metrics.counter(&chiselstore::metrics::peer_metric("connection_pool_misses", &peer)).get();
+    let hits = metrics.counter(&chiselstore::metrics::peer_metric("connection_pool_hits", &peer)).get();
+    let created = metrics.counter(&chiselstore::metrics::peer_metric("connection_pool_created", &peer)).get();
+    assert!(misses >= 1);
+    assert!(hits >= 1);
+    assert!(created == misses);
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn database_locked_retries() {
+    let mut config = StoreServerConfig::default();
+    config.busy_timeout = tokio::time::Duration::from_millis(50);
+    config.busy_retries = 10;
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_busy (id integer PRIMARY KEY)")).await.unwrap();
+
+    // hold an exclusive lock on replica 1's database for longer than the busy timeout
+    let locker = std::thread::spawn(|| {
+        let conn = sqlite::open("node1.db").unwrap();
+        conn.execute("BEGIN EXCLUSIVE").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        conn.execute("COMMIT").unwrap();
+    });
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+    query(1, String::from("INSERT INTO test_busy VALUES(1)")).await.unwrap();
+    locker.join().unwrap();
+
+    let x = query(1, String::from("SELECT COUNT(*) FROM test_busy")).await.unwrap();
+    let y = query(2, String::from("SELECT COUNT(*) FROM test_busy")).await.unwrap();
+    assert!(x == "1");
+    assert!(x == y);
+
+    query(1, String::from("DROP TABLE test_busy")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn learner_serves_reads() {
+    // two voters, and a learner following them
+    let mut replicas = setup_replicas(2).await;
+    let mut config = StoreServerConfig::default();
+    config.learner = true;
+    config.admin = true;
+    replicas.push(start_replica_with_config(3, vec![1, 2], config).await);
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_learner (id integer PRIMARY KEY)")).await.unwrap();
+    query(1, String::from("INSERT INTO test_learner VALUES(1)")).await.unwrap();
+
+    // the learner catches up with the decided log
+    let decided_idx = replicas[0].store_server.get_decided_idx();
+    while replicas[2].store_server.get_decided_idx() < decided_idx {
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    let res = query(3, String::from("SELECT id FROM test_learner WHERE id = 1")).await.unwrap();
+    assert!(res == "1");
+
+    // but never takes part in leader election
+    assert!(!replicas[2].is_leader());
+    assert!(replicas[0].get_current_leader() != 3);
+    assert!(replicas[1].get_current_leader() != 3);
+
+    // commands failing to apply are recorded, as on voters
+    assert!(query(1, String::from("INSERT INTO test_learner VALUES(1)")).await.is_err());
+    let decided_idx = replicas[0].store_server.get_decided_idx();
+    while replicas[2].store_server.get_decided_idx() < decided_idx {
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    let errors = replicas[2].store_server.apply_errors().unwrap();
+    assert!(errors.iter().any(|e| e.sql == "INSERT INTO test_learner VALUES(1)"));
+
+    query(1, String::from("DROP TABLE test_learner")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn noop_advances_decided_idx() {
+    let replicas = setup_replicas(2).await;
+
+    query(1, String::from("SELECT 1+1;")).await.unwrap();
+    let decided_before = replicas[0].store_server.get_decided_idx();
+
+    // NoOp returns once the no-op command is decided
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    client.no_op(tonic::Request::new(Void {})).await.unwrap();
+
+    let decided_after = replicas[0].store_server.get_decided_idx();
+    assert!(decided_after > decided_before);
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn apply_error_halts_node() {
+    let mut config = StoreServerConfig::default();
+    config.busy_timeout = tokio::time::Duration::from_millis(10);
+    config.busy_retries = 0;
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_apply_error (id integer PRIMARY KEY)")).await.unwrap();
+
+    // keep replica 1's database locked so that applying the next write fails there
+    let (unlock_sender, unlock_receiver) = std::sync::mpsc::channel::<()>();
+    let locker = std::thread::spawn(move || {
+        let conn = sqlite::open("node1.db").unwrap();
+        conn.execute("BEGIN EXCLUSIVE").unwrap();
+        unlock_receiver.recv().unwrap();
+        conn.execute("COMMIT").unwrap();
+    });
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+    let write = tokio::task::spawn(async {
+        let mut client = RpcClient::connect(node_rpc_addr(2)).await.unwrap();
+        let _ = client.execute(tonic::Request::new(Query {
+            sql: String::from("INSERT OR REPLACE INTO test_apply_error VALUES(1)"),
+            ..Default::default()
+        })).await;
+    });
+
+    // the default policy halts the node instead of skipping the command
+    while !replicas[0].store_server.is_halted() {
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    assert!(!replicas[1].store_server.is_halted());
+
+    unlock_sender.send(()).unwrap();
+    locker.join().unwrap();
+    write.abort();
+
+    shutdown_replicas(replicas).await;
+
+    // replica 1 is halted, so drop the table directly
+    for db in ["node1.db", "node2.db"] {
+        sqlite::open(db).unwrap().execute("DROP TABLE IF EXISTS test_apply_error").unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn batch_upsert() {
+    use chiselstore::client::Client;
+    use chiselstore::rpc::proto::Value;
+
+    let replicas = setup_replicas(2).await;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_upsert (id integer PRIMARY KEY, value text NOT NULL)")).await.unwrap();
+
+    let mut client = Client::connect(node_rpc_addr(1)).await.unwrap();
+    let rows = |ids: std::ops::Range<i64>, value: &str| -> Vec<Vec<Value>> {
+        ids.map(|id| vec![Value::from(id), Value::from(value)]).collect()
+    };
+
+    // 500 existing rows, then 1000 upserted rows of which half collide
+    client.batch_upsert("test_upsert", &["id", "value"], &["id"], rows(0..500, "old")).await.unwrap();
+    client.batch_upsert("test_upsert", &["id", "value"], &["id"], rows(250..1250, "new")).await.unwrap();
+
+    for replica_id in [1, 2] {
+        let total = query(replica_id, String::from("SELECT COUNT(*) FROM test_upsert")).await.unwrap();
+        let old = query(replica_id, String::from("SELECT COUNT(*) FROM test_upsert WHERE value = 'old'")).await.unwrap();
+        let new = query(replica_id, String::from("SELECT COUNT(*) FROM test_upsert WHERE value = 'new'")).await.unwrap();
+        assert!(total == "1250");
+        assert!(old == "250");
+        assert!(new == "1000");
+    }
+
+    // names are quoted, so they may be keywords
+    query(1, String::from("CREATE TABLE IF NOT EXISTS \"test upsert\" (\"order\" integer PRIMARY KEY, \"group\" text)")).await.unwrap();
+    client.batch_upsert("test upsert", &["order", "group"], &["order"], rows(0..2, "a")).await.unwrap();
+    client.batch_upsert("test upsert", &["order", "group"], &["order"], rows(1..3, "b")).await.unwrap();
+    let groups = query(1, String::from("SELECT group_concat(\"group\", '') FROM (SELECT \"group\" FROM \"test upsert\" ORDER BY \"order\")")).await.unwrap();
+    assert!(groups == "abb");
+
+    query(1, String::from("DROP TABLE test_upsert")).await.unwrap();
+    query(1, String::from("DROP TABLE \"test upsert\"")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn node_not_in_membership_fails_startup() {
+    let mut config = StoreServerConfig::default();
+    config.membership = vec![1, 2, 3];
+    let transport = RpcTransport::new(Box::new(node_rpc_addr));
+    let err = StoreServer::start_with_config(4, vec![1, 2], transport, config).unwrap_err();
+    assert!(format!("{}", err) == "Node id 4 is not in the cluster membership");
+
+    // the membership must be configured
+    let transport = RpcTransport::new(Box::new(node_rpc_addr));
+    let err = StoreServer::start_with_config(1, vec![2], transport, StoreServerConfig::default()).unwrap_err();
+    assert!(matches!(err, chiselstore::StoreError::MembershipRequired));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pipelined_writes() {
+    use chiselstore::client::Client;
+
+    let replicas = setup_replicas(2).await;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_pipeline (id integer PRIMARY KEY)")).await.unwrap();
+
+    // submit all writes before awaiting any of them
+    let client = Client::connect(node_rpc_addr(1)).await.unwrap();
+    let mut pending = Vec::new();
+    for i in 0..100 {
+        pending.push(client.submit(format!("INSERT INTO test_pipeline VALUES({})", i), Vec::new()));
+    }
+    for p in pending {
+        p.await.unwrap();
+    }
+
+    let res = query(2, String::from("SELECT COUNT(*) FROM test_pipeline")).await.unwrap();
+    assert!(res == "100");
+
+    query(1, String::from("DROP TABLE test_pipeline")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn relaxed_reads_prefer_nearest_node() {
+    use chiselstore::client::ClusterClient;
+    use chiselstore::rpc::proto::Consistency;
+    use std::time::Duration;
+
+    let replicas = setup_replicas(3).await;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_nearest (id integer PRIMARY KEY)")).await.unwrap();
+    query(1, String::from("INSERT INTO test_nearest VALUES(1)")).await.unwrap();
+
+    let addrs: Vec<String> = (1..4).map(node_rpc_addr).collect();
+    let mut client = ClusterClient::connect(addrs.clone()).await.unwrap();
+    client.refresh().await;
+    client.max_staleness = 0;
+    client.refresh_interval = Duration::from_secs(3600);
+
+    // simulate node 2 being the closest
+    client.record_latency(&addrs[0], Duration::from_millis(50), 10);
+    client.record_latency(&addrs[1], Duration::from_millis(1), 10);
+    client.record_latency(&addrs[2], Duration::from_millis(20), 10);
+    assert!(client.nearest_node() == Some(addrs[1].as_str()));
+
+    let res = client.execute("SELECT COUNT(*) FROM test_nearest", Vec::new(), Consistency::RelaxedReads).await.unwrap();
+    assert!(res.rows[0].values[0] == "1");
+
+    // the closest node falls behind, so reads go to the next closest one
+    client.record_latency(&addrs[1], Duration::from_millis(1), 5);
+    assert!(client.nearest_node() == Some(addrs[2].as_str()));
+
+    query(1, String::from("DROP TABLE test_nearest")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cluster_client_tolerates_unreachable_nodes() {
+    use chiselstore::client::ClusterClient;
+    use chiselstore::rpc::proto::Consistency;
+
+    let replicas = setup_replicas(2).await;
+    query(1, String::from("SELECT 1+1;")).await.unwrap();
+
+    // node 3 is not running
+    let addrs: Vec<String> = vec![node_rpc_addr(3), node_rpc_addr(1), node_rpc_addr(2)];
+    let mut client = ClusterClient::connect(addrs.clone()).await.unwrap();
+    client.refresh().await;
+    assert!(client.nearest_node().is_some());
+    assert!(client.nearest_node() != Some(addrs[0].as_str()));
+
+    let res = client.execute("SELECT 1+1", Vec::new(), Consistency::Strong).await.unwrap();
+    assert!(res.rows[0].values[0] == "2");
+    let res = client.execute("SELECT 1+1", Vec::new(), Consistency::RelaxedReads).await.unwrap();
+    assert!(res.rows[0].values[0] == "2");
+
+    shutdown_replicas(replicas).await;
+}
+
+#[test]
+fn transport_socket_options() {
+
+    let config = RpcTransportConfig::default();
+    assert!(config.tcp_nodelay);
+
+    let mut config = RpcTransportConfig::default();
+    config.tcp_nodelay = false;
+    config.request_buffer_size = Some(2048);
+    config.receive_stream_window_size = Some(1 << 20);
+    let transport = RpcTransport::with_config(Box::new(node_rpc_addr), config);
+    assert!(!transport.config().tcp_nodelay);
+    assert!(transport.config().request_buffer_size == Some(2048));
+    assert!(transport.config().receive_stream_window_size == Some(1 << 20));
+    assert!(transport.config().endpoint(node_rpc_addr(1)).is_ok());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn invalid_peer_address_fails_instead_of_panicking() {
+    use chiselstore::StoreTransport;
+
+    let transport = RpcTransport::new(Box::new(|_| String::from("not a valid address")));
+    assert!(transport.fetch_decided(2, 0).await.is_none());
+    let err = transport.remove_member(2, 1).await.unwrap_err();
+    assert!(format!("{}", err).contains("Invalid peer address"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rpc_latency_histograms() {
+    let replicas = setup_replicas(2).await;
+
+    for _ in 0..5 {
+        query(1, String::from("SELECT 1+1;")).await.unwrap();
+    }
+
+    let metrics = replicas[0].store_server.metrics();
+    let histogram = metrics.histogram(&chiselstore::metrics::labelled("rpc_latency", "method", "execute"));
+    assert!(histogram.count() == 5);
+    assert!(histogram.percentile(99.0) >= histogram.percentile(50.0));
+    assert!(histogram.percentile(50.0) > std::time::Duration::from_micros(0));
+    assert!(metrics.percentiles().iter().any(|(name, _)| name.starts_with("rpc_latency{method=\"execute\"")));
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn schema_migration() {
+    use chiselstore::client::Client;
+
+    let replicas = setup_replicas(3).await;
+
+    let mut clients = Vec::new();
+    for id in 1..=3 {
+        clients.push(Client::connect(node_rpc_addr(id)).await.unwrap());
+    }
+    // databases outlive test runs, so migrate from whatever version they are at
+    let version = clients[0].status().await.unwrap().schema_version;
+    for client in clients.iter_mut() {
+        assert_eq!(client.status().await.unwrap().schema_version, version);
+    }
+
+    clients[1]
+        .migrate(version + 1, "CREATE TABLE IF NOT EXISTS test_migration (id integer PRIMARY KEY)")
+        .await
+        .unwrap();
+    for client in clients.iter_mut() {
+        while client.status().await.unwrap().schema_version != version + 1 {
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+    }
+    query(1, String::from("INSERT OR REPLACE INTO test_migration VALUES(1)")).await.unwrap();
+
+    // a migration to an older version is rejected without halting
+    assert!(clients[0].migrate(version, "DROP TABLE test_migration").await.is_err());
+    assert!(replicas.iter().all(|r| !r.store_server.is_halted()));
+
+    query(1, String::from("DROP TABLE test_migration")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn streaming_query_backpressure() {
+    use chiselstore::client::Client;
+    use chiselstore::rpc::proto::Consistency;
+
+    let mut config = StoreServerConfig::default();
+    config.stream_buffer_rows = 16;
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    // 10000 rows of 10 KB each, ~100 MB if the server buffered them all
+    let sql = "SELECT x, hex(zeroblob(5000)) FROM (WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 10000) SELECT x FROM c)";
+    let mut client = Client::connect(node_rpc_addr(1)).await.unwrap();
+    let mut rows = client.execute_stream(sql, Vec::new(), Consistency::RelaxedReads).await.unwrap();
+
+    let produced = replicas[0].store_server.metrics().counter("stream_rows");
+    for i in 1..=20 {
+        let row = rows.message().await.unwrap().unwrap();
+        assert_eq!(row.values[0], i.to_string());
+        tokio::time::sleep(tokio::time::Duration::from_millis(20)).await;
+    }
+    // the server reads ahead by the stream buffer plus what fits in the HTTP/2 windows
+    assert!(produced.get() < 20 + 16 + 64);
+
+    let mut count = 20;
+    while rows.message().await.unwrap().is_some() {
+        count += 1;
+    }
+    assert_eq!(count, 10000);
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn row_transform_masks_results() {
+    let replicas = setup_replicas(2).await;
+    for replica in replicas.iter() {
+        replica.store_server.set_row_transform(|sql, row| {
+            if sql.contains("test_transform") && !row.values.is_empty() {
+                row.values[0] = String::from("***");
+            }
+        });
+    }
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_transform (id integer PRIMARY KEY, ssn text)")).await.unwrap();
+    query(1, String::from("INSERT OR REPLACE INTO test_transform VALUES(1, '123-45-6789')")).await.unwrap();
+
+    let x = query(1, String::from("SELECT ssn FROM test_transform")).await.unwrap();
+    assert_eq!(x, "***");
+
+    // the stored data is untouched
+    for replica in replicas.iter() {
+        replica.store_server.clear_row_transform();
+    }
+    let y = query(2, String::from("SELECT ssn FROM test_transform")).await.unwrap();
+    assert_eq!(y, "123-45-6789");
+
+    query(1, String::from("DROP TABLE test_transform")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn slow_connect_does_not_block_other_peers() {
+    use chiselstore::StoreTransport;
+
+    let replicas = setup_replicas(2).await;
+
+    // peer 99 is a non-routable address, so connecting to it hangs
+    let transport = Arc::new(RpcTransport::new(Box::new(|id| match id {
+        99 => String::from("http://10.255.255.1:50099"),
+        id => node_rpc_addr(id),
+    })));
+    let slow = {
+        let transport = transport.clone();
+        tokio::task::spawn(async move { transport.fetch_decided(99, 0).await })
+    };
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+    let fetched = tokio::time::timeout(tokio::time::Duration::from_secs(2), transport.fetch_decided(1, 0)).await;
+    assert!(fetched.unwrap().is_some());
+
+    slow.abort();
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn inbound_queue_absorbs_bursts() {
+    use chiselstore::client::Client;
+    use chiselstore::metrics::labelled;
+
+    let mut config = StoreServerConfig::default();
+    config.inbound_queue_capacity = 1024;
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_inbound (id integer PRIMARY KEY)")).await.unwrap();
+
+    // a burst of pipelined writes, applied in order on both nodes
+    let client = Client::connect(node_rpc_addr(1)).await.unwrap();
+    let writes: Vec<_> = (0..100)
+        .map(|i| client.submit(format!("INSERT OR REPLACE INTO test_inbound VALUES({})", i), Vec::new()))
+        .collect();
+    for write in writes {
+        write.await.unwrap();
+    }
+    let x = query(1, String::from("SELECT COUNT(*) FROM test_inbound")).await.unwrap();
+    let y = query(2, String::from("SELECT COUNT(*) FROM test_inbound")).await.unwrap();
+    assert_eq!(x, "100");
+    assert_eq!(x, y);
+
+    for replica in replicas.iter() {
+        let peer = if replica.get_id() == 1 { "2" } else { "1" };
+        let dropped = replica.store_server.metrics().counter(&labelled("inbound_dropped", "peer", peer));
+        assert_eq!(dropped.get(), 0);
+    }
+
+    query(1, String::from("DROP TABLE test_inbound")).await.unwrap();
+
+    // once halted, a node rejects messages instead of queueing them
+    replicas[1].store_server.set_halt(true);
+    let mut client = RpcClient::connect(node_rpc_addr(2)).await.unwrap();
+    let status = client.proposal_forward(proto::ProposalForwardReq { from: 1, to: 2, ..Default::default() }).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unavailable);
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn writes_report_their_ballot() {
+    let mut replicas = setup_replicas(3).await;
+
+    async fn write(replica_id: u64, sql: &str) -> proto::Ballot {
+        let mut client = RpcClient::connect(node_rpc_addr(replica_id)).await.unwrap();
+        let response = client.execute(tonic::Request::new(Query {
+            sql: sql.to_string(),
+            ..Default::default()
+        })).await.unwrap();
+        response.into_inner().ballot.unwrap()
+    }
+
+    let before = write(1, "CREATE TABLE IF NOT EXISTS test_ballot (id integer PRIMARY KEY)").await;
+
+    // kill the leader and move to a new configuration without it
+    let leader_idx = replicas.iter().position(|r| r.is_leader()).unwrap();
+    let leader = replicas.remove(leader_idx);
+    leader.shutdown().await;
+    tokio::time::sleep(tokio::time::Duration::from_millis(5000)).await; // wait for leader election
+
+    let new_leader_idx = replicas.iter().position(|r| r.is_leader()).unwrap_or(0);
+    let new_cluster: Vec<u64> = replicas.iter().map(|r| r.get_id()).collect();
+    replicas[new_leader_idx].reconfigure(new_cluster);
+    tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await; // wait for reconfiguration
+
+    let living_replica_id = replicas[new_leader_idx].get_id();
+    let after = write(living_replica_id, "INSERT OR REPLACE INTO test_ballot VALUES(1)").await;
+    assert!(before != after);
+
+    // reads served locally were not decided under any ballot
+    let mut client = RpcClient::connect(node_rpc_addr(living_replica_id)).await.unwrap();
+    let response = client.execute(tonic::Request::new(Query {
+        sql: String::from("SELECT COUNT(*) FROM test_ballot"),
+        consistency: proto::Consistency::RelaxedReads as i32,
+        ..Default::default()
+    })).await.unwrap();
+    assert!(response.into_inner().ballot.is_none());
+
+    write(living_replica_id, "DROP TABLE test_ballot").await;
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn idle_pooled_connections_are_replaced() {
+    use chiselstore::metrics::peer_metric;
+    use chiselstore::StoreTransport;
+
+    let replicas = setup_replicas(2).await;
+
+    let mut config = RpcTransportConfig::default();
+    config.max_idle = Some(tokio::time::Duration::from_millis(50));
+    let transport = RpcTransport::with_config(Box::new(node_rpc_addr), config);
+    let metrics = transport.metrics();
+    let peer = node_rpc_addr(1);
+
+    // reused while fresh
+    assert!(transport.fetch_decided(1, 0).await.is_some());
+    assert!(transport.fetch_decided(1, 0).await.is_some());
+    assert_eq!(metrics.counter(&peer_metric("connection_pool_created", &peer)).get(), 1);
+
+    // replaced once idle past the TTL
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    assert!(transport.fetch_decided(1, 0).await.is_some());
+    assert_eq!(metrics.counter(&peer_metric("connection_pool_evicted", &peer)).get(), 1);
+    assert_eq!(metrics.counter(&peer_metric("connection_pool_created", &peer)).get(), 2);
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn in_flight_grows_without_quorum() {
+    use chiselstore::client::Client;
+
+    let mut replicas = setup_replicas(2).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_in_flight (id integer PRIMARY KEY)")).await.unwrap();
+
+    let leader_idx = replicas.iter().position(|r| r.is_leader()).unwrap();
+    let follower = replicas.remove(1 - leader_idx);
+    follower.shutdown().await;
+
+    let leader_id = replicas[0].get_id();
+    let mut client = Client::connect(node_rpc_addr(leader_id)).await.unwrap();
+    assert_eq!(client.status().await.unwrap().in_flight, 0);
+
+    // without the follower, no write can reach a quorum
+    let writes: Vec<_> = (0..5)
+        .map(|i| tokio::task::spawn(client.submit(format!("INSERT OR REPLACE INTO test_in_flight VALUES({})", i), Vec::new())))
+        .collect();
+    while client.status().await.unwrap().in_flight < 5 {
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+
+    for write in writes {
+        write.abort();
+    }
+    shutdown_replicas(replicas).await;
+
+    for db in ["node1.db", "node2.db"] {
+        sqlite::open(db).unwrap().execute("DROP TABLE IF EXISTS test_in_flight").unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn leader_change_fails_pending_write() {
+    let mut config = StoreServerConfig::default();
+    config.leader_change_check_interval = tokio::time::Duration::from_millis(50);
+    let mut replicas = setup_replicas_with_config(3, config).await;
+    query(1, String::from("SELECT 1+1;")).await.unwrap();
+
+    // writes forwarded to the dead leader are lost until a new one is elected
+    let leader_idx = replicas.iter().position(|r| r.is_leader()).unwrap();
+    let leader = replicas.remove(leader_idx);
+    let old_leader = leader.get_id();
+    leader.shutdown().await;
+
+    let follower_id = replicas[0].get_id();
+    let mut client = RpcClient::connect(node_rpc_addr(follower_id)).await.unwrap();
+    let start = std::time::Instant::now();
+    let err = client.execute(tonic::Request::new(Query {
+        sql: String::from("CREATE TABLE IF NOT EXISTS test_leader_change (id integer PRIMARY KEY)"),
+        ..Default::default()
+    })).await.unwrap_err();
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    assert_eq!(err.code(), tonic::Code::Unavailable);
+    let new_leader: u64 = err.metadata().get("leader").unwrap().to_str().unwrap().parse().unwrap();
+    assert!(new_leader != old_leader);
+
+    shutdown_replicas(replicas).await;
+
+    for db in ["node1.db", "node2.db", "node3.db"] {
+        sqlite::open(db).unwrap().execute("DROP TABLE IF EXISTS test_leader_change").unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn analytics_scan_does_not_slow_commits() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let mut replicas = setup_replicas(2).await;
+    let mut config = StoreServerConfig::default();
+    config.learner = true;
+    config.analytics = true;
+    replicas.push(start_replica_with_config(3, vec![1, 2], config).await);
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_analytics (id integer PRIMARY KEY)")).await.unwrap();
+
+    let scan_done = Arc::new(AtomicBool::new(false));
+    let scan = {
+        let scan_done = scan_done.clone();
+        tokio::task::spawn(async move {
+            let sql = "SELECT COUNT(*) FROM (WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 20000000) SELECT x FROM c)";
+            let res = query(3, String::from(sql)).await.unwrap();
+            scan_done.store(true, Ordering::SeqCst);
+            res
+        })
+    };
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+    let start = std::time::Instant::now();
+    for i in 0..10 {
+        query(1, format!("INSERT OR REPLACE INTO test_analytics VALUES({})", i)).await.unwrap();
+    }
+    assert!(start.elapsed() < std::time::Duration::from_secs(2));
+
+    // the analytics replica keeps applying the log during the scan
+    let decided_idx = replicas[0].store_server.get_decided_idx();
+    while replicas[2].store_server.get_decided_idx() < decided_idx {
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    assert!(!scan_done.load(Ordering::SeqCst));
+    assert_eq!(scan.await.unwrap(), "20000000");
+
+    query(1, String::from("DROP TABLE test_analytics")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn conflicting_node_id_halts() {
+    use proto::HeartbeatRequestReq;
+
+    // a node can't list itself as a peer
+    let transport = RpcTransport::new(Box::new(node_rpc_addr));
+    assert!(StoreServer::start(1, vec![1, 2], transport).is_err());
+
+    let heartbeat = HeartbeatRequestReq { from: 1, to: 1, round: 1 };
+
+    // without a peer token anyone can claim an id, so the node doesn't halt
+    let replicas = setup_replicas(2).await;
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    let err = client.heartbeat_request(tonic::Request::new(heartbeat.clone())).await.unwrap_err();
+    assert!(err.message().contains("claiming this node's id 1"));
+    assert!(!replicas[0].store_server.is_halted());
+    shutdown_replicas(replicas).await;
+
+    // another host of the cluster claiming to be node 1
+    let mut transport_config = RpcTransportConfig::default();
+    transport_config.peer_token = Some(PeerToken::new("peer-secret").unwrap());
+    let mut replicas = Vec::new();
+    for (id, peers) in [(1, vec![2]), (2, vec![1])] {
+        replicas.push(start_replica_with_service(id, peers, StoreServerConfig::default(), transport_config.clone(), RpcServerConfig::default(), |server| {
+            RpcService::peer(server, Some(PeerToken::new("peer-secret").unwrap()))
+        }).await);
+    }
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    let mut request = tonic::Request::new(heartbeat);
+    request.metadata_mut().insert("authorization", "Bearer peer-secret".parse().unwrap());
+    let err = client.heartbeat_request(request).await.unwrap_err();
+    assert!(err.message().contains("claiming this node's id 1"));
+    assert!(replicas[0].store_server.is_halted());
+    assert!(!replicas[1].store_server.is_halted());
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn hedged_read_beats_slow_replica() {
+    use chiselstore::client::ClusterClient;
+    use chiselstore::rpc::proto::Consistency;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let replicas = setup_replicas(2).await;
+    query(1, String::from("SELECT 1+1;")).await.unwrap();
+
+    // replica 1 behind a proxy that can hold back its answers
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = format!("http://{}", listener.local_addr().unwrap());
+    let slow = Arc::new(AtomicBool::new(false));
+    let proxy = tokio::spawn({
+        let slow = slow.clone();
+        async move {
+            loop {
+                let (inbound, _) = listener.accept().await.unwrap();
+                let outbound = tokio::net::TcpStream::connect(node_authority(1)).await.unwrap();
+                let (mut from_client, mut to_client) = inbound.into_split();
+                let (mut from_node, mut to_node) = outbound.into_split();
+                tokio::spawn(async move { tokio::io::copy(&mut from_client, &mut to_node).await });
+                let slow = slow.clone();
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 4096];
+                    while let Ok(n @ 1..) = from_node.read(&mut buf).await {
+                        if slow.load(Ordering::SeqCst) {
+                            tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
+                        }
+                        if to_client.write_all(&buf[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        }
+    });
+
+    let mut client = ClusterClient::connect(vec![proxy_addr.clone(), node_rpc_addr(2)]).await.unwrap();
+    client.hedge_delay = Some(tokio::time::Duration::from_millis(100));
+    client.refresh().await;
+    let decided_idx = replicas[0].store_server.get_decided_idx();
+    client.record_latency(&proxy_addr, tokio::time::Duration::from_millis(1), decided_idx);
+    client.record_latency(&node_rpc_addr(2), tokio::time::Duration::from_millis(50), decided_idx);
+    assert_eq!(client.nearest_node(), Some(proxy_addr.as_str()));
+
+    // the nearest replica is slow to answer
+    slow.store(true, Ordering::SeqCst);
+
+    let start = std::time::Instant::now();
+    let results = client.execute("SELECT 1+1", Vec::new(), Consistency::RelaxedReads).await.unwrap();
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    assert_eq!(results.rows[0].values[0], "2");
+
+    proxy.abort();
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn node_leaves_cluster() {
+    let mut replicas = setup_replicas(3).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_leave (id integer PRIMARY KEY)")).await.unwrap();
+
+    let follower_idx = replicas.iter().position(|r| !r.is_leader()).unwrap();
+    let follower = replicas.remove(follower_idx);
+    follower.store_server.leave_cluster().await.unwrap();
+    assert!(follower.store_server.is_halted());
+    follower.shutdown().await;
+
+    // the remaining two nodes form a healthy cluster
+    let leader_id = replicas.iter().find(|r| r.is_leader()).unwrap().get_id();
+    query(leader_id, String::from("INSERT OR REPLACE INTO test_leave VALUES(1)")).await.unwrap();
+    for r in replicas.iter() {
+        assert!(!r.store_server.is_halted());
+        assert_eq!(query(r.get_id(), String::from("SELECT COUNT(*) FROM test_leave")).await.unwrap(), "1");
+    }
+
+    query(leader_id, String::from("DROP TABLE test_leave")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn client_errors_are_classified() {
+    use chiselstore::client::Client;
+    use chiselstore::ClientError;
+
+    // nothing listens on node 9
+    let err = Client::connect(node_rpc_addr(9)).await.unwrap_err();
+    assert!(matches!(err, ClientError::Connect(_)));
+    assert!(err.is_retryable());
+
+    let replicas = setup_replicas(2).await;
+    let mut client = Client::connect(node_rpc_addr(1)).await.unwrap();
+    let err = client.execute("SELECT ?", Vec::new()).await.unwrap_err();
+    match err {
+        ClientError::Rejected(ref status) => assert_eq!(status.code(), tonic::Code::InvalidArgument),
+        ref e => panic!("unexpected error {:?}", e),
+    }
+    assert!(!err.is_retryable());
+
+    // both agree with the kind of the error
+    for code in [tonic::Code::Unavailable, tonic::Code::Aborted, tonic::Code::ResourceExhausted, tonic::Code::Cancelled, tonic::Code::InvalidArgument] {
+        let err = ClientError::from(tonic::Status::new(code, "error"));
+        assert_eq!(err.is_retryable(), err.kind().is_retryable());
+        assert_eq!(matches!(err, ClientError::Unavailable(_)), err.is_retryable());
+    }
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn throttled_sync_does_not_starve_heartbeats() {
+    let mut transport_config = RpcTransportConfig::default();
+    transport_config.sync_bytes_per_sec = Some(50_000);
+
+    // node 3 starts late, so it has to be synchronized
+    let mut replicas = Vec::new();
+    for id in 1..=2 {
+        let peers = (1..=3).filter(|&p| p != id).collect();
+        replicas.push(start_replica_with_transport_config(id, peers, StoreServerConfig::default(), transport_config.clone()).await);
+    }
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_sync_limit (id integer PRIMARY KEY, data text)")).await.unwrap();
+    for i in 0..200 {
+        query(1, format!("INSERT OR REPLACE INTO test_sync_limit VALUES({}, '{}')", i, "x".repeat(1000))).await.unwrap();
+    }
+    let leader = replicas[0].get_current_leader();
+    let leader_idx = replicas.iter().position(|r| r.get_id() == leader).unwrap();
+
+    replicas.push(start_replica_with_transport_config(3, vec![1, 2], StoreServerConfig::default(), transport_config).await);
+
+    // ~200 KB at 50 KB/s: heartbeats keep flowing and the leader stays put
+    let decided_idx = replicas[leader_idx].store_server.get_decided_idx();
+    while replicas[2].store_server.get_decided_idx() < decided_idx {
+        for r in replicas.iter() {
+            assert!(r.get_current_leader() == leader || r.get_current_leader() == 0);
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
+    let metrics = replicas[leader_idx].store_server.transport().metrics();
+    assert!(metrics.counter("sync_bytes").get() > 100_000);
+
+    query(1, String::from("DROP TABLE test_sync_limit")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn apply_batching_reduces_transactions() {
+    use chiselstore::client::Client;
+
+    let mut config = StoreServerConfig::default();
+    config.write_buffer_linger = tokio::time::Duration::from_millis(20);
+    config.apply_batch_size = 64;
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_apply_batch (id integer PRIMARY KEY)")).await.unwrap();
+    let metrics = replicas[0].store_server.metrics();
+    let before = metrics.counter("apply_transactions").get();
+
+    // a duplicate key fails on its own without failing the rest of its batch
+    let client = Client::connect(node_rpc_addr(1)).await.unwrap();
+    let start = std::time::Instant::now();
+    let writes: Vec<_> = (0..200)
+        .map(|i| client.submit(format!("INSERT INTO test_apply_batch VALUES({})", i % 100), Vec::new()))
+        .collect();
+    let mut failed = 0;
+    for write in writes {
+        if write.await.is_err() {
+            failed += 1;
+        }
+    }
+    let elapsed = start.elapsed();
+    assert_eq!(failed, 100);
+
+    let transactions = metrics.counter("apply_transactions").get() - before;
+    log(format!("applied 200 writes in {} transactions in {:?}", transactions, elapsed));
+    assert!(transactions < 200 / 4);
+    let x = query(1, String::from("SELECT COUNT(*) FROM test_apply_batch")).await.unwrap();
+    let y = query(2, String::from("SELECT COUNT(*) FROM test_apply_batch")).await.unwrap();
+    assert_eq!(x, "100");
+    assert_eq!(x, y);
+
+    query(1, String::from("DROP TABLE test_apply_batch")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn leader_warmup_skips_write_buffer() {
+    use chiselstore::client::Client;
+
+    let linger = tokio::time::Duration::from_secs(2);
+    let mut config = StoreServerConfig::default();
+    config.write_buffer_linger = linger;
+    config.leader_warmup = tokio::time::Duration::from_secs(60);
+    let replicas = setup_replicas_with_config(2, config).await;
+    replicas[0].store_server.noop().await.unwrap();
+    let leader = replicas[0].get_current_leader();
+    query(leader, String::from("CREATE TABLE IF NOT EXISTS test_leader_warmup (id integer PRIMARY KEY)")).await.unwrap();
+
+    // right after the election, concurrent writes commit without lingering
+    let client = Client::connect(node_rpc_addr(leader)).await.unwrap();
+    let start = std::time::Instant::now();
+    let writes: Vec<_> = (0..10)
+        .map(|i| client.submit(format!("INSERT INTO test_leader_warmup VALUES({})", i), Vec::new()))
+        .collect();
+    for write in writes {
+        write.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    log(format!("committed 10 writes after the election in {:?}", elapsed));
+    assert!(elapsed < linger);
+
+    query(leader, String::from("DROP TABLE test_leader_warmup")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn audit_log_records_committed_writes() {
+    use chiselstore::rpc::CLIENT_METADATA;
+
+    let audit_log = "node1.audit.log";
+    let _ = std::fs::remove_file(audit_log);
+    let mut config = StoreServerConfig::default();
+    config.audit_log = Some(std::path::PathBuf::from(audit_log));
+    let replicas = vec![
+        start_replica_with_config(1, vec![2], config).await,
+        start_replica(2, vec![1]).await,
+    ];
+
+    let writes = [
+        "CREATE TABLE IF NOT EXISTS test_audit (id integer PRIMARY KEY, name text)",
+        "INSERT INTO test_audit VALUES(1, 'a')",
+        "INSERT INTO test_audit VALUES(1, 'duplicate')",
+        "SELECT * FROM test_audit",
+        "UPDATE test_audit SET name = 'b' WHERE id = 1",
+        "DROP TABLE test_audit",
+    ];
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    for sql in writes {
+        let mut request = tonic::Request::new(Query {
+            sql: String::from(sql),
+            ..Default::default()
+        });
+        request.metadata_mut().insert(CLIENT_METADATA, "alice".parse().unwrap());
+        let _ = client.execute(request).await;
+    }
+
+    // the failed insert and the read are not audited
+    let audited = std::fs::read_to_string(audit_log).unwrap();
+    let lines: Vec<_> = audited.lines().collect();
+    let expected: Vec<_> = writes.iter().filter(|sql| !sql.contains("duplicate") && !sql.starts_with("SELECT")).collect();
+    assert_eq!(lines.len(), expected.len());
+    let mut last_idx = None;
+    for (line, sql) in lines.iter().zip(expected) {
+        assert!(line.contains("\"client\":\"alice\""));
+        assert!(line.contains(&format!("\"sql\":\"{}\"", sql)));
+        let idx: u64 = line.split("\"log_idx\":").nth(1).unwrap().split(',').next().unwrap().parse().unwrap();
+        assert!(last_idx.map_or(true, |last| idx > last));
+        last_idx = Some(idx);
+    }
+
+    shutdown_replicas(replicas).await;
+    let _ = std::fs::remove_file(audit_log);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn read_snapshot_sees_frozen_view() {
+    use chiselstore::client::Client;
+
+    let mut config = StoreServerConfig::default();
+    config.read_snapshot_timeout = tokio::time::Duration::from_millis(500);
+    let replicas = setup_replicas_with_config(2, config).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_read_snapshot (id integer PRIMARY KEY)")).await.unwrap();
+    query(1, String::from("INSERT INTO test_read_snapshot VALUES(1)")).await.unwrap();
+
+    let mut client = Client::connect(node_rpc_addr(1)).await.unwrap();
+    let snapshot = client.create_read_snapshot().await.unwrap();
+
+    // writes proceed while the snapshot is open, but it doesn't see them
+    query(1, String::from("INSERT INTO test_read_snapshot VALUES(2)")).await.unwrap();
+    let count = client.execute_at_snapshot(snapshot, "SELECT COUNT(*) FROM test_read_snapshot", Vec::new()).await.unwrap();
+    assert_eq!(count.rows[0].values[0], "1");
+    query(1, String::from("INSERT INTO test_read_snapshot VALUES(3)")).await.unwrap();
+    let count = client.execute_at_snapshot(snapshot, "SELECT COUNT(*) FROM test_read_snapshot", Vec::new()).await.unwrap();
+    assert_eq!(count.rows[0].values[0], "1");
+    assert_eq!(query(1, String::from("SELECT COUNT(*) FROM test_read_snapshot")).await.unwrap(), "3");
+
+    // only reads run against a snapshot
+    assert!(client.execute_at_snapshot(snapshot, "INSERT INTO test_read_snapshot VALUES(4)", Vec::new()).await.is_err());
+
+    client.release_read_snapshot(snapshot).await.unwrap();
+    assert!(client.execute_at_snapshot(snapshot, "SELECT 1", Vec::new()).await.is_err());
+
+    // a snapshot whose client went away is released after the timeout
+    let abandoned = client.create_read_snapshot().await.unwrap();
+    assert_eq!(replicas[0].store_server.metrics().gauge("read_snapshots").get(), 1);
+    tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
+    assert!(client.execute_at_snapshot(abandoned, "SELECT 1", Vec::new()).await.is_err());
+    assert_eq!(replicas[0].store_server.metrics().gauge("read_snapshots").get(), 0);
+
+    query(1, String::from("DROP TABLE test_read_snapshot")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn read_only_role_cannot_write() {
+    use chiselstore::rpc::AUTHORIZATION_METADATA;
+    use chiselstore::{Principal, Role};
+
+    let mut config = StoreServerConfig::default();
+    config.auth_tokens.insert(String::from("writer-token"), Principal::new("writer", Role::ReadWrite));
+    config.auth_tokens.insert(String::from("reader-token"), Principal::new("reader", Role::ReadOnly));
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    let request = |sql: &str, token: Option<&str>| {
+        let mut request = tonic::Request::new(Query {
+            sql: String::from(sql),
+            ..Default::default()
+        });
+        if let Some(token) = token {
+            request.metadata_mut().insert(AUTHORIZATION_METADATA, format!("Bearer {}", token).parse().unwrap());
+        }
+        request
+    };
+
+    client.execute(request("CREATE TABLE IF NOT EXISTS test_roles (id integer PRIMARY KEY)", Some("writer-token"))).await.unwrap();
+    client.execute(request("INSERT INTO test_roles VALUES(1)", Some("writer-token"))).await.unwrap();
+
+    let response = client.execute(request("SELECT COUNT(*) FROM test_roles", Some("reader-token"))).await.unwrap().into_inner();
+    assert_eq!(response.rows[0].values[0], "1");
+    let err = client.execute(request("INSERT INTO test_roles VALUES(2)", Some("reader-token"))).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied);
+
+    // the role comes from the token, so unknown or missing tokens are rejected
+    for token in [Some("forged-token"), None] {
+        let err = client.execute(request("SELECT COUNT(*) FROM test_roles", token)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unauthenticated);
+    }
+
+    let response = client.execute(request("SELECT COUNT(*) FROM test_roles", Some("writer-token"))).await.unwrap().into_inner();
+    assert_eq!(response.rows[0].values[0], "1");
+    client.execute(request("DROP TABLE test_roles", Some("writer-token"))).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rowids_match_across_nodes() {
+    use proto::TableChecksumReq;
+
+    let mut config = StoreServerConfig::default();
+    config.admin = true;
+    let replicas = setup_replicas_with_config(3, config).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_rowids (id integer PRIMARY KEY AUTOINCREMENT, name text)")).await.unwrap();
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_plain_rowids (name text)")).await.unwrap();
+
+    // deletes make SQLite reuse rowids in the plain table but not the AUTOINCREMENT one
+    for i in 0..100 {
+        query(1, format!("INSERT INTO test_rowids (name) VALUES('row {}')", i)).await.unwrap();
+        query(1, format!("INSERT INTO test_plain_rowids VALUES('row {}')", i)).await.unwrap();
+        if i % 10 == 9 {
+            query(1, String::from("DELETE FROM test_rowids WHERE id = (SELECT MAX(id) FROM test_rowids)")).await.unwrap();
+            query(1, String::from("DELETE FROM test_plain_rowids WHERE rowid = (SELECT MAX(rowid) FROM test_plain_rowids)")).await.unwrap();
+        }
+    }
+    let decided_idx = replicas[0].store_server.get_decided_idx();
+    for replica in replicas.iter() {
+        while replica.store_server.get_decided_idx() < decided_idx {
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+    }
+
+    for table in ["test_rowids", "test_plain_rowids", "sqlite_sequence"] {
+        let mut checksums = Vec::new();
+        for id in 1..=3 {
+            let mut client = RpcClient::connect(node_rpc_addr(id)).await.unwrap();
+            let checksum = client.table_checksum(tonic::Request::new(TableChecksumReq {
+                table: String::from(table),
+            })).await.unwrap().into_inner();
+            assert_eq!(checksum.decided_idx, decided_idx);
+            checksums.push(checksum);
+        }
+        assert!(checksums.iter().all(|c| c == &checksums[0]), "{} diverged: {:?}", table, checksums);
+    }
+    assert_eq!(query(2, String::from("SELECT seq FROM sqlite_sequence WHERE name = 'test_rowids'")).await.unwrap(), "100");
+
+    query(1, String::from("DROP TABLE test_rowids")).await.unwrap();
+    query(1, String::from("DROP TABLE test_plain_rowids")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dump_log_matches_across_nodes() {
+    use proto::DumpLogReq;
+
+    let mut config = StoreServerConfig::default();
+    config.admin = true;
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_dump_log (id integer PRIMARY KEY)")).await.unwrap();
+    for i in 0..10 {
+        query(2, format!("INSERT OR REPLACE INTO test_dump_log VALUES({})", i)).await.unwrap();
+    }
+    let decided_idx = replicas[0].store_server.get_decided_idx();
+    while replicas[1].store_server.get_decided_idx() < decided_idx {
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+
+    let mut dumps = Vec::new();
+    for id in 1..=2 {
+        let mut client = RpcClient::connect(node_rpc_addr(id)).await.unwrap();
+        let reply = client.dump_log(tonic::Request::new(DumpLogReq { from: 0, to: decided_idx })).await.unwrap();
+        dumps.push(reply.into_inner().entries);
+    }
+    assert_eq!(dumps[0].len() as u64, decided_idx);
+    assert_eq!(dumps[0], dumps[1]);
+
+    query(1, String::from("DROP TABLE test_dump_log")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[cfg(feature = "sqlcipher")]
+#[tokio::test(flavor = "multi_thread")]
+async fn encrypted_database_reopens_with_key() {
+    // nodes of their own, since the other tests can't open encrypted databases
+    fn remove_databases() {
+        for id in [7, 8] {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("node{}.db{}", id, suffix));
+            }
+        }
+    }
+    async fn start(config: &StoreServerConfig) -> Vec<Replica> {
+        vec![
+            start_replica_with_config(7, vec![8], config.clone()).await,
+            start_replica_with_config(8, vec![7], config.clone()).await,
+        ]
+    }
+
+    remove_databases();
+    let mut config = StoreServerConfig::default();
+    config.encryption_key = Some(String::from("correct horse battery staple"));
+
+    let replicas = start(&config).await;
+    query(7, String::from("CREATE TABLE IF NOT EXISTS test_encrypted (id integer PRIMARY KEY)")).await.unwrap();
+    query(7, String::from("INSERT OR REPLACE INTO test_encrypted VALUES(1)")).await.unwrap();
+    shutdown_replicas(replicas).await;
+
+    // the database can't be read without the key
+    assert!(sqlite::open("node7.db").unwrap().execute("SELECT * FROM test_encrypted").is_err());
+    let mut wrong_key = config.clone();
+    wrong_key.encryption_key = Some(String::from("wrong"));
+    wrong_key.membership = vec![7, 8];
+    let transport = RpcTransport::new(Box::new(node_rpc_addr));
+    assert!(matches!(
+        StoreServer::start_with_config(7, vec![8], transport, wrong_key),
+        Err(chiselstore::StoreError::WrongEncryptionKey(7))
+    ));
+
+    let replicas = start(&config).await;
+    let res = query(7, String::from("SELECT id FROM test_encrypted")).await.unwrap();
+    assert!(res == "1");
+    shutdown_replicas(replicas).await;
+
+    remove_databases();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn manual_clock_drives_timeouts() {
+    use chiselstore::clock::{Clock, ManualClock};
+    use tokio::time::Duration;
+
+    let clock = ManualClock::new();
+    let mut sleeper = {
+        let clock = clock.clone();
+        tokio::task::spawn(async move { clock.sleep(Duration::from_secs(3600)).await })
+    };
+    clock.advance(Duration::from_secs(1800));
+    assert!(tokio::time::timeout(Duration::from_millis(10), &mut sleeper).await.is_err());
+    clock.advance(Duration::from_secs(1800));
+    sleeper.await.unwrap();
+
+    // no leader is elected while the clock stands still
+    let mut config = StoreServerConfig::default();
+    config.clock = clock.clone();
+    let replicas = setup_replicas_with_config(2, config).await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(replicas.iter().all(|r| r.get_current_leader() == 0));
+
+    // a leader is elected once enough heartbeat rounds have passed
+    while replicas.iter().any(|r| r.get_current_leader() == 0) {
+        clock.advance(Duration::from_millis(100));
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    assert_eq!(replicas[0].get_current_leader(), replicas[1].get_current_leader());
+
+    // the loops sleep on the clock, so wake them up to shut down
+    let mut shutdown = tokio::task::spawn(shutdown_replicas(replicas));
+    loop {
+        clock.advance(Duration::from_millis(100));
+        if tokio::time::timeout(Duration::from_millis(5), &mut shutdown).await.is_ok() {
+            break;
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn read_only_transaction_skips_log() {
+    let replicas = setup_replicas(2).await;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_read_only_txn (id integer PRIMARY KEY)")).await.unwrap();
+    query(1, String::from("INSERT INTO test_read_only_txn VALUES(1), (2), (3)")).await.unwrap();
+    let leader = replicas.iter().find(|r| r.is_leader()).unwrap();
+    let decided_before = leader.store_server.get_decided_idx();
+
+    // both reads see the same snapshot, without going through the log
+    let mut client = RpcClient::connect(node_rpc_addr(leader.get_id())).await.unwrap();
+    let response = client.execute(tonic::Request::new(Query {
+        sql: String::from("BEGIN; SELECT COUNT(*) FROM test_read_only_txn; SELECT SUM(id) FROM test_read_only_txn; COMMIT;"),
+        consistency: proto::Consistency::RelaxedReads as i32,
+        ..Default::default()
+    })).await.unwrap().into_inner();
+    let values: Vec<_> = response.rows.iter().map(|r| r.values[0].clone()).collect();
+    assert_eq!(values, vec!["3", "6"]);
+    assert!(response.ballot.is_none());
+    assert_eq!(leader.store_server.get_decided_idx(), decided_before);
+
+    // a strong one only commits a no-op first, to confirm the leadership
+    let response = client.execute(tonic::Request::new(Query {
+        sql: String::from("BEGIN; SELECT COUNT(*) FROM test_read_only_txn; COMMIT;"),
+        ..Default::default()
+    })).await.unwrap().into_inner();
+    assert_eq!(response.rows[0].values[0], "3");
+    assert!(response.ballot.is_none());
+    assert_eq!(leader.store_server.get_decided_idx(), decided_before + 1);
+    let decided_before = leader.store_server.get_decided_idx();
+
+    // a transaction that also writes is still replicated
+    query(leader.get_id(), String::from("BEGIN; SELECT COUNT(*) FROM test_read_only_txn; INSERT INTO test_read_only_txn VALUES(4); COMMIT;")).await.unwrap();
+    assert!(leader.store_server.get_decided_idx() > decided_before);
+
+    query(1, String::from("DROP TABLE test_read_only_txn")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn send_flood_sheds_load() {
+    use chiselstore::StoreTransport;
+    use omnipaxos_core::ballot_leader_election::Ballot;
+    use omnipaxos_core::messages::{Decide, Message, PaxosMsg};
+
+    // a peer that never answers, so every send stays in flight
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = format!("http://{}", listener.local_addr().unwrap());
+    let mut config = RpcTransportConfig::default();
+    config.max_pending_sends = Some(8);
+    let transport = RpcTransport::with_config(Box::new(move |_| addr.clone()), config);
+
+    for ld in 0..1000 {
+        let msg = Message {
+            from: 1,
+            to: 2,
+            msg: PaxosMsg::Decide(Decide { n: Ballot::default(), ld }),
+        };
+        transport.send_sp(2, 1, msg).unwrap();
+    }
+
+    let metrics = transport.metrics();
+    assert!(metrics.gauge("sp_sends_in_flight").get() <= 8);
+    // after the first shed message, the peer waits to be resynchronized
+    // and nothing more is sent to it
+    assert!(metrics.counter("sp_sends_shed").get() >= 1);
+    assert!(metrics.counter("sp_messages_lost").get() >= 1000 - 8);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert_eq!(transport.take_lost_peers(), vec![2]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn messages_of_future_configurations() {
+    use chiselstore::{FutureConfigPolicy, StoreError};
+    use omnipaxos_core::ballot_leader_election::Ballot;
+    use omnipaxos_core::messages::{Decide, Message, PaxosMsg};
+
+    let mut config = StoreServerConfig::default();
+    config.future_config_policy = FutureConfigPolicy::Reject;
+    let replicas = vec![
+        start_replica(1, vec![2]).await,
+        start_replica_with_config(2, vec![1], config).await,
+    ];
+    query(1, String::from("SELECT 1")).await.unwrap();
+
+    // a decide of the next configuration, which neither node has adopted
+    let decide = |from: u64, to: u64| Message {
+        from,
+        to,
+        msg: PaxosMsg::Decide(Decide { n: Ballot::default(), ld: 1000 }),
+    };
+
+    // node 1 keeps the message until it adopts configuration 2
+    let buffering = &replicas[0].store_server;
+    buffering.recv_sp_msg(2, decide(2, 1)).unwrap();
+    assert_eq!(buffering.metrics().counter("future_config_buffered").get(), 1);
+
+    // node 2 rejects it
+    let rejecting = &replicas[1].store_server;
+    match rejecting.recv_sp_msg(2, decide(1, 2)) {
+        Err(StoreError::UnknownConfiguration { config_id: 2, current: 1 }) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(rejecting.metrics().counter("future_config_rejected").get(), 1);
+
+    // neither handled it against the current membership
+    for replica in replicas.iter() {
+        assert!(replica.store_server.get_decided_idx() < 1000);
+    }
+    assert_eq!(query(1, String::from("SELECT 1+1")).await.unwrap(), "2");
+
+    shutdown_replicas(replicas).await;
+}
+
+/// Length of the blob `large_blobs_are_returned_whole` reads, unusual
+/// enough that no other allocation is of the same size.
+const LARGE_BLOB_LEN: usize = 4 * 1024 * 1024 + 6;
+
+/// Counts the allocations of `LARGE_BLOB_LEN` bytes, i.e. the copies of the
+/// blob.
+struct BlobCopies;
+
+static BLOB_COPIES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+unsafe impl std::alloc::GlobalAlloc for BlobCopies {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        if layout.size() == LARGE_BLOB_LEN {
+            BLOB_COPIES.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: std::alloc::Layout, new_size: usize) -> *mut u8 {
+        if new_size == LARGE_BLOB_LEN {
+            BLOB_COPIES.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+        std::alloc::System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: BlobCopies = BlobCopies;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn large_blobs_are_returned_whole() {
+    use chiselstore::rpc::proto::rpc_server::Rpc;
+    use chiselstore::rpc::proto::{value, Consistency, Value};
+
+    let replicas = setup_replicas(2).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_large_blobs (id integer PRIMARY KEY, data blob)")).await.unwrap();
+    query(1, format!("INSERT INTO test_large_blobs VALUES(1, CAST(replace(hex(zeroblob({})), '0', 'x') AS BLOB))", LARGE_BLOB_LEN / 2)).await.unwrap();
+    let decided_idx = replicas.iter().map(|replica| replica.store_server.get_decided_idx()).max().unwrap();
+    while replicas[0].store_server.get_applied_idx() < decided_idx {
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+
+    let queries = || {
+        [vec![], vec![Value { value: Some(value::Value::Integer(1)) }]].map(|params| {
+            let sql = if params.is_empty() { "SELECT data FROM test_large_blobs WHERE id = 1" } else { "SELECT data FROM test_large_blobs WHERE id = ?" };
+            Query {
+                sql: String::from(sql),
+                params,
+                consistency: Consistency::RelaxedReads as i32,
+                ..Default::default()
+            }
+        })
+    };
+
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    for query in queries() {
+        let response = client.execute(tonic::Request::new(query)).await.unwrap().into_inner();
+        let data = &response.rows[0].values[0];
+        assert_eq!(data.len(), LARGE_BLOB_LEN);
+        assert!(data.bytes().all(|b| b == b'x'));
+    }
+
+    // served in-process, so that encoding the response doesn't copy it: the
+    // blob is only copied out of SQLite, into the response as is
+    let service = RpcService::new(replicas[0].store_server.clone());
+    for query in queries() {
+        let copies = BLOB_COPIES.load(std::sync::atomic::Ordering::SeqCst);
+        let response = service.execute(tonic::Request::new(query)).await.unwrap().into_inner();
+        assert_eq!(BLOB_COPIES.load(std::sync::atomic::Ordering::SeqCst) - copies, 1);
+        assert_eq!(response.rows[0].values[0].len(), LARGE_BLOB_LEN);
+    }
+
+    query(1, String::from("DROP TABLE test_large_blobs")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn floats_read_the_same_with_and_without_params() {
+    use chiselstore::rpc::proto::{value, Consistency, Value};
+
+    let replicas = setup_replicas(2).await;
+
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    for params in [vec![], vec![Value { value: Some(value::Value::Integer(1)) }]] {
+        let sql = if params.is_empty() { "SELECT 1.0, 0.1 * 3" } else { "SELECT 1.0, 0.1 * 3 WHERE ? = 1" };
+        let query = Query {
+            sql: String::from(sql),
+            params,
+            consistency: Consistency::RelaxedReads as i32,
+            ..Default::default()
+        };
+        let response = client.execute(tonic::Request::new(query)).await.unwrap().into_inner();
+        assert_eq!(response.rows[0].values, vec!["1.0", "0.3"]);
+    }
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn strong_read_without_leader_fails() {
+    let mut config = StoreServerConfig::default();
+    config.commit_timeout = Some(std::time::Duration::from_secs(1));
+    // node 2 never starts, so no leader can be elected
+    let replicas = vec![start_replica_with_config(1, vec![2], config).await];
+
+    // the read is not served from local state, which may be stale
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    let status = client.execute(tonic::Request::new(Query {
+        sql: String::from("SELECT 1+1"),
+        ..Default::default()
+    })).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unavailable);
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn retried_request_is_deduplicated_within_window() {
+    let mut config = StoreServerConfig::default();
+    config.dedup_window = 2;
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    async fn increment(request_id: u64) -> proto::QueryResults {
+        let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+        client.execute(tonic::Request::new(Query {
+            sql: String::from("UPDATE test_dedup SET n = n + 1"),
+            request_id,
+            ..Default::default()
+        })).await.unwrap().into_inner()
+    }
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_dedup (n integer)")).await.unwrap();
+    query(1, String::from("DELETE FROM test_dedup")).await.unwrap();
+    query(1, String::from("INSERT INTO test_dedup VALUES(0)")).await.unwrap();
+
+    // a retry within the window is not applied again
+    assert!(increment(1).await.warnings.is_empty());
+    assert!(!increment(1).await.warnings.is_empty());
+    assert_eq!(query(1, String::from("SELECT n FROM test_dedup")).await.unwrap(), "1");
+
+    // once two other requests were applied since, a retry is applied again,
+    // as expected: the window only remembers the two latest requests
+    increment(2).await;
+    increment(3).await;
+    assert!(increment(1).await.warnings.is_empty());
+    let x = query(1, String::from("SELECT n FROM test_dedup")).await.unwrap();
+    let y = query(2, String::from("SELECT n FROM test_dedup")).await.unwrap();
+    assert_eq!(x, "4");
+    assert_eq!(x, y);
+
+    query(1, String::from("DROP TABLE test_dedup")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn retried_request_is_deduplicated_across_reconfiguration() {
+    let mut config = StoreServerConfig::default();
+    config.dedup_window = 2;
+    let mut replicas = setup_replicas_with_config(3, config).await;
+
+    async fn increment(replica_id: u64, request_id: u64) -> proto::QueryResults {
+        let mut client = RpcClient::connect(node_rpc_addr(replica_id)).await.unwrap();
+        client.execute(tonic::Request::new(Query {
+            sql: String::from("UPDATE test_dedup_reconfiguration SET n = n + 1"),
+            request_id,
+            ..Default::default()
+        })).await.unwrap().into_inner()
+    }
+
+    // a request applied far into the log of the first configuration
+    let leader_id = replicas.iter().find(|r| r.is_leader()).unwrap().get_id();
+    query(leader_id, String::from("CREATE TABLE IF NOT EXISTS test_dedup_reconfiguration (n integer)")).await.unwrap();
+    query(leader_id, String::from("DELETE FROM test_dedup_reconfiguration")).await.unwrap();
+    query(leader_id, String::from("INSERT INTO test_dedup_reconfiguration VALUES(0)")).await.unwrap();
+    for _ in 0..20 {
+        query(leader_id, String::from("UPDATE test_dedup_reconfiguration SET n = n")).await.unwrap();
+    }
+    assert!(increment(leader_id, 1).await.warnings.is_empty());
+
+    // move to a new configuration, whose log starts over
+    let leader_idx = replicas.iter().position(|r| r.is_leader()).unwrap();
+    let leader = replicas.remove(leader_idx);
+    leader.shutdown().await;
+    tokio::time::sleep(tokio::time::Duration::from_millis(5000)).await; // wait for leader election
+    let new_leader_idx = replicas.iter().position(|r| r.is_leader()).unwrap_or(0);
+    let new_cluster: Vec<u64> = replicas.iter().map(|r| r.get_id()).collect();
+    replicas[new_leader_idx].reconfigure(new_cluster);
+    tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await; // wait for reconfiguration
+    let living_replica_id = replicas[new_leader_idx].get_id();
+
+    // the requests of the new configuration are the most recently active,
+    // however far the log of the first one went
+    assert!(increment(living_replica_id, 2).await.warnings.is_empty());
+    assert!(increment(living_replica_id, 3).await.warnings.is_empty());
+    let retry = increment(living_replica_id, 2).await;
+    assert_eq!(retry.warnings.len(), 1);
+    assert!(retry.warnings[0].contains(&format!("of configuration {}", retry.configuration_id)), "{:?}", retry.warnings);
+    assert!(increment(living_replica_id, 1).await.warnings.is_empty());
+    assert_eq!(query(living_replica_id, String::from("SELECT n FROM test_dedup_reconfiguration")).await.unwrap(), "4");
+
+    query(living_replica_id, String::from("DROP TABLE test_dedup_reconfiguration")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cluster_metrics_marks_unreachable_nodes() {
+    let mut replicas = setup_replicas(3).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_cluster_metrics (id integer PRIMARY KEY)")).await.unwrap();
+    query(1, String::from("INSERT INTO test_cluster_metrics VALUES(1)")).await.unwrap();
+
+    // take down a follower
+    let leader = replicas[0].get_current_leader();
+    let down_idx = replicas.iter().position(|r| r.get_id() != leader).unwrap();
+    let down = replicas.remove(down_idx);
+    let down_id = down.get_id();
+    down.shutdown().await;
+
+    // asked on the other follower, the leader gathers the metrics
+    let follower = replicas.iter().find(|r| r.get_id() != leader).unwrap().get_id();
+    let mut client = RpcClient::connect(node_rpc_addr(follower)).await.unwrap();
+    let metrics = client.cluster_metrics(tonic::Request::new(Void {})).await.unwrap().into_inner();
+    assert_eq!(metrics.leader, leader);
+    assert_eq!(metrics.nodes.len(), 3);
+    for node in metrics.nodes.iter() {
+        assert_eq!(node.reachable, node.id != down_id);
+    }
+    let leader_metrics = metrics.nodes.iter().find(|n| n.id == leader).unwrap();
+    assert_eq!(leader_metrics.lag, 0);
+    assert!(leader_metrics.decided_idx > 0);
+    assert!(leader_metrics.apply_transactions > 0);
+    assert!(leader_metrics.throughput > 0.0);
+    let down_metrics = metrics.nodes.iter().find(|n| n.id == down_id).unwrap();
+    assert_eq!(down_metrics.throughput, 0.0);
+
+    query(leader, String::from("DROP TABLE test_cluster_metrics")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+
+    sqlite::open(format!("node{}.db", down_id)).unwrap().execute("DROP TABLE IF EXISTS test_cluster_metrics").unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn status_reports_leader_changes() {
+    use chiselstore::client::Client;
+
+    let mut replicas = setup_replicas(5).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_leader_changes (id integer PRIMARY KEY)")).await.unwrap();
+
+    // force two more elections by killing the leader
+    for _ in 0..2 {
+        let leader_idx = replicas.iter().position(|r| r.is_leader()).unwrap();
+        let leader = replicas.remove(leader_idx);
+        let old_leader = leader.get_id();
+        leader.shutdown().await;
+        while replicas.iter().any(|r| r.get_current_leader() == old_leader || r.get_current_leader() == 0) {
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+    }
+
+    let mut client = Client::connect(node_rpc_addr(replicas[0].get_id())).await.unwrap();
+    let status = client.status().await.unwrap();
+    assert!(status.leader_changes >= 3);
+    assert!(status.leader_uptime_ms < 60_000);
+
+    shutdown_replicas(replicas).await;
+
+    for db in ["node1.db", "node2.db", "node3.db", "node4.db", "node5.db"] {
+        sqlite::open(db).unwrap().execute("DROP TABLE IF EXISTS test_leader_changes").unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn epochs_map_ballots_to_log_indices() {
+    let mut config = StoreServerConfig::default();
+    config.admin = true;
+    let mut replicas = setup_replicas_with_config(5, config).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_epochs (id integer PRIMARY KEY)")).await.unwrap();
+
+    // force two more elections by killing the leader, deciding an entry under each
+    for i in 0..2 {
+        let leader_idx = replicas.iter().position(|r| r.is_leader()).unwrap();
+        let leader = replicas.remove(leader_idx);
+        let old_leader = leader.get_id();
+        leader.shutdown().await;
+        while replicas.iter().any(|r| r.get_current_leader() == old_leader || r.get_current_leader() == 0) {
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+        query(replicas[0].get_id(), format!("INSERT INTO test_epochs VALUES({})", i)).await.unwrap();
+    }
+
+    let mut client = RpcClient::connect(node_rpc_addr(replicas[0].get_id())).await.unwrap();
+    let epochs = client.epochs(tonic::Request::new(Void {})).await.unwrap().into_inner().epochs;
+    assert!(epochs.len() >= 3);
+    for pair in epochs.windows(2) {
+        assert!(pair[0].first_idx < pair[1].first_idx);
+        assert!(pair[0].ballot.as_ref().unwrap().n < pair[1].ballot.as_ref().unwrap().n);
+    }
+
+    shutdown_replicas(replicas).await;
+
+    for db in ["node1.db", "node2.db", "node3.db", "node4.db", "node5.db"] {
+        sqlite::open(db).unwrap().execute("DROP TABLE IF EXISTS test_epochs").unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn validator_rejects_disallowed_statements() {
+    let replicas = setup_replicas(2).await;
+    for replica in replicas.iter() {
+        replica.store_server.set_sql_validator(chiselstore::server::statement_allowlist(&["CREATE", "INSERT", "SELECT", "DROP"]));
+    }
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_validator (id integer PRIMARY KEY)")).await.unwrap();
+    let decided_before = replicas[0].store_server.get_decided_idx();
+
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    for sql in ["PRAGMA user_version = 42", "ATTACH DATABASE 'other.db' AS other", "INSERT INTO test_validator VALUES(1); PRAGMA foreign_keys = OFF"] {
+        let err = client.execute(tonic::Request::new(Query {
+            sql: String::from(sql),
+            ..Default::default()
+        })).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+    // nothing entered the log
+    assert_eq!(replicas[0].store_server.get_decided_idx(), decided_before);
+    assert_eq!(query(1, String::from("SELECT COUNT(*) FROM test_validator")).await.unwrap(), "0");
+
+    query(1, String::from("DROP TABLE test_validator")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn priority_hook_prefers_leader() {
+    let mut replicas = setup_replicas(3).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_priority (id integer PRIMARY KEY)")).await.unwrap();
+
+    // make one of the followers report a lighter load
+    let leader_idx = replicas.iter().position(|r| r.is_leader()).unwrap();
+    let preferred = replicas.iter().find(|r| !r.is_leader()).unwrap().get_id();
+    for replica in replicas.iter() {
+        let priority = if replica.get_id() == preferred { 100 } else { 1 };
+        replica.store_server.set_priority_hook(move || priority);
+    }
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    // the preferred node wins the next election
+    let leader = replicas.remove(leader_idx);
+    let old_leader = leader.get_id();
+    leader.shutdown().await;
+    while replicas.iter().any(|r| r.get_current_leader() == old_leader || r.get_current_leader() == 0) {
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
+    assert!(replicas.iter().all(|r| r.get_current_leader() == preferred));
+
+    shutdown_replicas(replicas).await;
+
+    for db in ["node1.db", "node2.db", "node3.db"] {
+        sqlite::open(db).unwrap().execute("DROP TABLE IF EXISTS test_priority").unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn result_row_cap() {
+    use chiselstore::ResultLimitPolicy;
+
+    async fn execute(replica_id: u64, sql: &str) -> Result<proto::QueryResults, tonic::Status> {
+        let mut client = RpcClient::connect(node_rpc_addr(replica_id)).await.unwrap();
+        let response = client.execute(tonic::Request::new(Query {
+            sql: sql.to_string(),
+            ..Default::default()
+        })).await?;
+        Ok(response.into_inner())
+    }
+    let scan = "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100) SELECT i FROM n";
+
+    let mut config = StoreServerConfig::default();
+    config.max_result_rows = 10;
+    let replicas = setup_replicas_with_config(2, config.clone()).await;
+    assert_eq!(execute(1, scan).await.unwrap_err().code(), tonic::Code::OutOfRange);
+    assert_eq!(execute(1, "SELECT 1").await.unwrap().rows.len(), 1);
+    // a write over the limit is committed, so it succeeds with its rows truncated
+    execute(1, "CREATE TABLE IF NOT EXISTS test_result_row_cap (i integer)").await.unwrap();
+    let write = scan.replacen("SELECT i FROM n", "INSERT INTO test_result_row_cap SELECT i FROM n RETURNING i", 1);
+    let results = execute(1, &write).await.unwrap();
+    assert_eq!(results.rows.len(), 10);
+    assert!(results.warnings.iter().any(|w| w.contains("truncated")));
+    assert_eq!(execute(1, "SELECT COUNT(*) FROM test_result_row_cap").await.unwrap().rows[0].values[0], "100");
+    execute(1, "DROP TABLE test_result_row_cap").await.unwrap();
+    shutdown_replicas(replicas).await;
+
+    config.result_limit_policy = ResultLimitPolicy::Truncate;
+    let replicas = setup_replicas_with_config(2, config).await;
+    let results = execute(1, scan).await.unwrap();
+    assert_eq!(results.rows.len(), 10);
+    assert!(results.warnings.iter().any(|w| w.contains("truncated")));
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn apply_errors_are_recorded() {
+    let mut config = StoreServerConfig::default();
+    config.admin = true;
+    config.apply_error_policy = chiselstore::ApplyErrorPolicy::Skip;
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_apply_errors (id integer PRIMARY KEY)")).await.unwrap();
+    query(1, String::from("INSERT OR REPLACE INTO test_apply_errors VALUES(1)")).await.unwrap();
+
+    // the duplicate key fails to apply on every node
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    let failing = "INSERT INTO test_apply_errors VALUES(1)";
+    assert!(client.execute(tonic::Request::new(Query {
+        sql: String::from(failing),
+        ..Default::default()
+    })).await.is_err());
+    let decided_idx = replicas[0].store_server.get_decided_idx();
+    while replicas[1].store_server.get_decided_idx() < decided_idx {
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+
+    let mut recorded = Vec::new();
+    for id in 1..=2 {
+        let mut client = RpcClient::connect(node_rpc_addr(id)).await.unwrap();
+        let errors = client.apply_errors(tonic::Request::new(Void {})).await.unwrap().into_inner().errors;
+        let error = errors.into_iter().rev().find(|e| e.sql == failing).unwrap();
+        assert!(error.log_idx < decided_idx);
+        assert!(error.error.contains("UNIQUE"));
+        recorded.push(error.log_idx);
+    }
+    assert_eq!(recorded[0], recorded[1]);
+
+    query(1, String::from("DROP TABLE test_apply_errors")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+
+/// Value of the HTTP/2 setting `id` announced by the server at `addr`.
+async fn http2_setting(addr: (&str, u16), id: u16) -> Option<u32> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").await.unwrap();
+    // an empty SETTINGS frame
+    stream.write_all(&[0, 0, 0, 0x4, 0, 0, 0, 0, 0]).await.unwrap();
+    // the server's first frame is its SETTINGS
+    let mut header = [0; 9];
+    stream.read_exact(&mut header).await.unwrap();
+    assert_eq!(header[3], 0x4);
+    let mut payload = vec![0; u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize];
+    stream.read_exact(&mut payload).await.unwrap();
+    payload
+        .chunks(6)
+        .find(|setting| u16::from_be_bytes([setting[0], setting[1]]) == id)
+        .map(|setting| u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rpc_server_options() {
+    let config = RpcServerConfig::default();
+    assert!(config.max_concurrent_streams.is_none());
+
+    let mut server_config = RpcServerConfig::default();
+    server_config.max_concurrent_streams = Some(2);
+    server_config.concurrency_limit_per_connection = Some(2);
+    let mut replicas = Vec::new();
+    for (id, peers) in [(1, vec![2]), (2, vec![1])] {
+        let replica = start_replica_with_server_config(
+            id,
+            peers,
+            StoreServerConfig::default(),
+            RpcTransportConfig::default(),
+            server_config.clone(),
+        ).await;
+        replicas.push(replica);
+    }
+
+    // the limit is announced to clients in the HTTP/2 settings
+    assert_eq!(http2_setting(node_authority(1), SETTINGS_MAX_CONCURRENT_STREAMS).await, Some(2));
+
+    // requests over the limit wait for a stream instead of failing
+    let queries: Vec<_> = (0..10)
+        .map(|_| tokio::task::spawn(async { query(1, String::from("SELECT 1+1;")).await.unwrap() }))
+        .collect();
+    for q in queries {
+        assert_eq!(q.await.unwrap(), "2");
+    }
+
+    shutdown_replicas(replicas).await;
+}
+
+#[test]
+fn error_kinds_round_trip_through_status() {
+    use chiselstore::Error;
+
+    let errors = [
+        (Error::Consensus(String::from("quorum lost")), tonic::Code::Aborted),
+        (Error::Storage(String::from("disk I/O error")), tonic::Code::Internal),
+        (Error::Transport(String::from("connection refused")), tonic::Code::Unavailable),
+        (Error::Timeout(String::from("no reply")), tonic::Code::DeadlineExceeded),
+        (Error::NotLeader { leader: 3 }, tonic::Code::Unavailable),
+        (Error::NotLeader { leader: 0 }, tonic::Code::Unavailable),
+        (Error::InvalidRequest(String::from("bad parameters")), tonic::Code::InvalidArgument),
+        (Error::Unauthenticated(String::from("missing token")), tonic::Code::Unauthenticated),
+        (Error::PermissionDenied(String::from("admin disabled")), tonic::Code::PermissionDenied),
+        (Error::LimitExceeded(String::from("too many rows")), tonic::Code::OutOfRange),
+        (Error::FailedPrecondition(String::from("maintenance mode")), tonic::Code::FailedPrecondition),
+        (Error::Other(String::from("something else")), tonic::Code::Unknown),
+    ];
+    for (error, code) in errors {
+        let status = tonic::Status::from(error.clone());
+        assert_eq!(status.code(), code);
+        assert_eq!(Error::from(status), error);
+    }
+
+    // store errors map to their kind
+    let error = Error::from(chiselstore::StoreError::LeaderChanged { leader: 2 });
+    assert_eq!(error, Error::NotLeader { leader: 2 });
+    assert!(error.is_retryable());
+    let error = Error::from(chiselstore::StoreError::ParameterCount { expected: 1, got: 0 });
+    assert!(matches!(error, Error::InvalidRequest(_)));
+    assert!(!error.is_retryable());
+    assert!(matches!(Error::from(chiselstore::StoreError::Snapshot(String::from("disk full"))), Error::Storage(_)));
+    assert!(matches!(Error::from(chiselstore::StoreError::QueryTask(String::from("cancelled"))), Error::Transport(_)));
+    assert!(matches!(Error::from(chiselstore::StoreError::DuplicateNodeId(2)), Error::InvalidRequest(_)));
+    assert!(matches!(Error::from(chiselstore::StoreError::WrongEncryptionKey(1)), Error::FailedPrecondition(_)));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn automatic_snapshot_trims_log() {
+    let mut config = StoreServerConfig::default();
+    config.snapshot_interval_entries = 10;
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_auto_snapshot (id integer PRIMARY KEY)")).await.unwrap();
+    for i in 0..30 {
+        query(1, format!("INSERT OR REPLACE INTO test_auto_snapshot VALUES({})", i)).await.unwrap();
+    }
+
+    let leader = replicas.iter().find(|r| r.is_leader()).unwrap();
+    let start = std::time::Instant::now();
+    while leader.store_server.get_compacted_idx() == 0 {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "log was never trimmed");
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    assert!(leader.store_server.metrics().counter("snapshots").get() >= 1);
+    assert!(std::path::Path::new(&format!("node{}.snapshot.db", leader.get_id())).exists());
+
+    // the cluster keeps working on the trimmed log
+    query(1, String::from("INSERT OR REPLACE INTO test_auto_snapshot VALUES(30)")).await.unwrap();
+    let x = query(1, String::from("SELECT COUNT(*) FROM test_auto_snapshot")).await.unwrap();
+    let y = query(2, String::from("SELECT COUNT(*) FROM test_auto_snapshot")).await.unwrap();
+    assert_eq!(x, "31");
+    assert_eq!(x, y);
+
+    query(1, String::from("DROP TABLE test_auto_snapshot")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn follower_behind_trimmed_log_catches_up_from_snapshot() {
+    let mut config = StoreServerConfig::default();
+    config.snapshot_interval_entries = 10;
+    let mut replicas = setup_replicas_with_config(3, config.clone()).await;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_snapshot_catch_up (id integer PRIMARY KEY)")).await.unwrap();
+    for i in 0..30 {
+        query(1, format!("INSERT OR REPLACE INTO test_snapshot_catch_up VALUES({})", i)).await.unwrap();
+    }
+    let leader = replicas.iter().find(|r| r.is_leader()).unwrap();
+    let leader_id = leader.get_id();
+    let start = std::time::Instant::now();
+    while leader.store_server.get_compacted_idx() == 0 {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "log was never trimmed");
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+
+    // restart a follower without its database, behind the trimmed log
+    let follower = replicas.iter().position(|r| !r.is_leader()).unwrap();
+    let follower = replicas.remove(follower);
+    let follower_id = follower.get_id();
+    follower.shutdown().await;
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("node{}.db{}", follower_id, suffix));
+    }
+    for i in 30..40 {
+        query(leader_id, format!("INSERT OR REPLACE INTO test_snapshot_catch_up VALUES({})", i)).await.unwrap();
+    }
+    let peers = (1..=3).filter(|&id| id != follower_id).collect();
+    let follower = start_replica_with_config(follower_id, peers, config).await;
+    query(leader_id, String::from("INSERT OR REPLACE INTO test_snapshot_catch_up VALUES(40)")).await.unwrap();
+
+    let count = || -> Option<String> {
+        let conn = sqlite::open(format!("node{}.db", follower_id)).ok()?;
+        let mut stmt = conn.prepare("SELECT COUNT(*) FROM test_snapshot_catch_up").ok()?;
+        stmt.next().ok()?;
+        stmt.read::<i64>(0).ok().map(|count| count.to_string())
+    };
+    let start = std::time::Instant::now();
+    while count().as_deref() != Some("41") {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "follower never caught up: {:?}", count());
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    assert!(follower.store_server.metrics().counter("snapshots_restored").get() >= 1);
+
+    query(leader_id, String::from("DROP TABLE test_snapshot_catch_up")).await.unwrap();
+
+    replicas.push(follower);
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn old_snapshots_are_pruned() {
+    fn retained_snapshots(id: u64) -> Vec<u64> {
+        let prefix = format!("node{}.snapshot.", id);
+        let mut retained: Vec<u64> = std::fs::read_dir(".")
+            .unwrap()
+            .filter_map(|entry| {
+                let name = entry.unwrap().file_name().into_string().unwrap();
+                name.strip_prefix(&prefix)?.strip_suffix(".db")?.parse().ok()
+            })
+            .collect();
+        retained.sort_unstable();
+        retained
+    }
+
+    async fn write_until_snapshot(leader: &Replica, from: u64) -> u64 {
+        let compacted = leader.store_server.get_compacted_idx();
+        let mut i = from;
+        while leader.store_server.get_compacted_idx() == compacted {
+            query(leader.get_id(), format!("INSERT OR REPLACE INTO test_snapshot_retention VALUES({})", i)).await.unwrap();
+            i += 1;
+        }
+        i
+    }
+
+    for id in [1, 2] {
+        for idx in retained_snapshots(id) {
+            std::fs::remove_file(format!("node{}.snapshot.{}.db", id, idx)).unwrap();
+        }
+    }
+
+    let mut config = StoreServerConfig::default();
+    config.snapshot_interval_entries = 5;
+    config.snapshot_retention = 2;
+    let replicas = setup_replicas_with_config(2, config).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_snapshot_retention (id integer PRIMARY KEY)")).await.unwrap();
+    let leader = replicas.iter().find(|r| r.is_leader()).unwrap();
+    let id = leader.get_id();
+
+    // the first snapshot is pinned, as if a follower were fetching it
+    let mut next = write_until_snapshot(leader, 0).await;
+    let first = leader.store_server.get_compacted_idx();
+    let pin = leader.store_server.pin_snapshot(first).unwrap();
+    for _ in 0..3 {
+        next = write_until_snapshot(leader, next).await;
+    }
+    let start = std::time::Instant::now();
+    while retained_snapshots(id).len() > 3 {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "snapshots were never pruned");
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    let retained = retained_snapshots(id);
+    assert!(retained.contains(&first));
+    assert!(retained.contains(&leader.store_server.get_compacted_idx()));
+
+    // once unpinned, it is pruned with the next snapshot
+    drop(pin);
+    write_until_snapshot(leader, next).await;
+    let start = std::time::Instant::now();
+    while retained_snapshots(id).contains(&first) || retained_snapshots(id).len() > 2 {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "snapshot was never pruned");
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    let retained = retained_snapshots(id);
+    assert!(retained.contains(&leader.store_server.get_compacted_idx()));
+    assert!(leader.store_server.metrics().counter("snapshots_pruned").get() >= 1);
+
+    query(1, String::from("DROP TABLE test_snapshot_retention")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn idle_requests_are_evicted_across_reconfiguration() {
+    let mut config = StoreServerConfig::default();
+    config.dedup_idle_horizon = 5;
+    let mut replicas = setup_replicas_with_config(3, config).await;
+
+    async fn increment(replica_id: u64, request_id: u64) -> proto::QueryResults {
+        let mut client = RpcClient::connect(node_rpc_addr(replica_id)).await.unwrap();
+        client.execute(tonic::Request::new(Query {
+            sql: String::from("UPDATE test_dedup_idle_reconfiguration SET n = n + 1"),
+            request_id,
+            ..Default::default()
+        })).await.unwrap().into_inner()
+    }
+
+    // a request last active far into the log of the first configuration
+    let leader_id = replicas.iter().find(|r| r.is_leader()).unwrap().get_id();
+    query(leader_id, String::from("CREATE TABLE IF NOT EXISTS test_dedup_idle_reconfiguration (n integer)")).await.unwrap();
+    query(leader_id, String::from("DELETE FROM test_dedup_idle_reconfiguration")).await.unwrap();
+    query(leader_id, String::from("INSERT INTO test_dedup_idle_reconfiguration VALUES(0)")).await.unwrap();
+    for _ in 0..20 {
+        query(leader_id, String::from("UPDATE test_dedup_idle_reconfiguration SET n = n")).await.unwrap();
+    }
+    assert!(increment(leader_id, 1).await.warnings.is_empty());
+
+    // move to a new configuration, whose log starts over
+    let leader_idx = replicas.iter().position(|r| r.is_leader()).unwrap();
+    let leader = replicas.remove(leader_idx);
+    leader.shutdown().await;
+    tokio::time::sleep(tokio::time::Duration::from_millis(5000)).await; // wait for leader election
+    let new_leader_idx = replicas.iter().position(|r| r.is_leader()).unwrap_or(0);
+    let new_cluster: Vec<u64> = replicas.iter().map(|r| r.get_id()).collect();
+    replicas[new_leader_idx].reconfigure(new_cluster);
+    tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await; // wait for reconfiguration
+    let living_replica_id = replicas[new_leader_idx].get_id();
+
+    // idle for longer than the horizon in the new configuration, it is
+    // evicted, however far the log of the first one went
+    for _ in 0..10 {
+        query(living_replica_id, String::from("UPDATE test_dedup_idle_reconfiguration SET n = n")).await.unwrap();
+    }
+    assert!(increment(living_replica_id, 1).await.warnings.is_empty());
+    assert_eq!(query(living_replica_id, String::from("SELECT n FROM test_dedup_idle_reconfiguration")).await.unwrap(), "2");
+
+    query(living_replica_id, String::from("DROP TABLE test_dedup_idle_reconfiguration")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn idle_requests_are_evicted_from_dedup_window() {
+    let mut config = StoreServerConfig::default();
+    config.dedup_idle_horizon = 5;
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    async fn increment(request_id: u64) {
+        let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+        client.execute(tonic::Request::new(Query {
+            sql: String::from("UPDATE test_dedup_idle SET n = n + 1"),
+            request_id,
+            ..Default::default()
+        })).await.unwrap();
+    }
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_dedup_idle (n integer)")).await.unwrap();
+    query(1, String::from("DELETE FROM test_dedup_idle")).await.unwrap();
+    query(1, String::from("INSERT INTO test_dedup_idle VALUES(0)")).await.unwrap();
+
+    // a retry within the horizon is deduplicated
+    increment(1).await;
+    increment(1).await;
+    assert_eq!(query(1, String::from("SELECT n FROM test_dedup_idle")).await.unwrap(), "1");
+
+    // once idle for longer than the horizon, it is evicted on every node
+    for _ in 0..10 {
+        query(1, String::from("INSERT INTO test_dedup_idle VALUES(0)")).await.unwrap();
+    }
+    let decided_idx = replicas[0].store_server.get_decided_idx();
+    while replicas[1].store_server.get_decided_idx() < decided_idx {
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    for replica in replicas.iter() {
+        assert_eq!(replica.store_server.metrics().gauge("dedup_requests").get(), 0);
+    }
+    increment(1).await;
+    let x = query(1, String::from("SELECT MAX(n) FROM test_dedup_idle")).await.unwrap();
+    let y = query(2, String::from("SELECT MAX(n) FROM test_dedup_idle")).await.unwrap();
+    assert_eq!(x, "2");
+    assert_eq!(x, y);
+
+    query(1, String::from("DROP TABLE test_dedup_idle")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn extensions_apply_identically_on_every_node() {
+    let mut config = StoreServerConfig::default();
+    config.register_extension("app_constants", "1", |conn| {
+        conn.execute("CREATE TEMP VIEW IF NOT EXISTS app_constants AS SELECT 21 AS answer")
+    });
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_extensions (n integer)")).await.unwrap();
+    query(1, String::from("DELETE FROM test_extensions")).await.unwrap();
+    query(1, String::from("INSERT INTO test_extensions SELECT answer * 2 FROM app_constants")).await.unwrap();
+
+    let decided_idx = replicas[0].store_server.get_decided_idx();
+    while replicas[1].store_server.get_decided_idx() < decided_idx {
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    let x = query(1, String::from("SELECT n FROM test_extensions")).await.unwrap();
+    let y = query(2, String::from("SELECT n FROM test_extensions")).await.unwrap();
+    assert_eq!(x, "42");
+    assert_eq!(x, y);
+
+    // heartbeats and sequence paxos messages from a node with other
+    // extensions are rejected
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    let mut request = tonic::Request::new(proto::HeartbeatRequestReq { from: 2, to: 1, round: 0 });
+    request.metadata_mut().insert(chiselstore::rpc::EXTENSIONS_METADATA, tonic::metadata::MetadataValue::from(1u64));
+    let status = client.heartbeat_request(request).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Aborted);
+    let request = tonic::Request::new(proto::ProposalForwardReq { from: 2, to: 1, ..Default::default() });
+    let status = client.proposal_forward(request).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Aborted);
+    assert_eq!(replicas[0].store_server.metrics().counter("extension_mismatch").get(), 2);
+
+    query(1, String::from("DROP TABLE test_extensions")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stalled_change_subscriber_is_disconnected() {
+    use futures::StreamExt;
+
+    let mut config = StoreServerConfig::default();
+    config.admin = true;
+    config.change_buffer_capacity = 4;
+    config.change_overflow_policy = chiselstore::ChangeOverflowPolicy::Disconnect;
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_change_feed (n integer)")).await.unwrap();
+    query(1, String::from("DELETE FROM test_change_feed")).await.unwrap();
+
+    // the subscriber never reads, commits carry on regardless
+    let mut changes = replicas[0].store_server.subscribe_changes().unwrap();
+    let start = std::time::Instant::now();
+    for i in 0..20 {
+        query(1, format!("INSERT INTO test_change_feed VALUES({})", i)).await.unwrap();
+    }
+    assert!(start.elapsed() < std::time::Duration::from_secs(10));
+    assert_eq!(query(1, String::from("SELECT COUNT(*) FROM test_change_feed")).await.unwrap(), "20");
+
+    let metrics = replicas[0].store_server.metrics();
+    let start = std::time::Instant::now();
+    while metrics.counter("change_subscribers_disconnected").get() < 1 {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "subscriber was never disconnected");
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(metrics.gauge("change_subscribers").get(), 0);
+    assert!(matches!(changes.next().await, Some(Err(chiselstore::StoreError::SubscriberDisconnected))));
+    assert!(changes.next().await.is_none());
+
+    query(1, String::from("DROP TABLE test_change_feed")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn writes_report_proposal_and_commit_times() {
+    let replicas = setup_replicas(3).await;
+
+    let leader = replicas.iter().find(|r| r.is_leader()).unwrap().get_id();
+    query(leader, String::from("CREATE TABLE IF NOT EXISTS test_commit_times (n integer)")).await.unwrap();
+
+    let mut client = RpcClient::connect(node_rpc_addr(leader)).await.unwrap();
+    let results = client.execute(tonic::Request::new(Query {
+        sql: String::from("INSERT INTO test_commit_times VALUES(1)"),
+        ..Default::default()
+    })).await.unwrap().into_inner();
+    assert!(results.proposed_at_us > 0);
+    assert!(results.committed_at_us > results.proposed_at_us);
+
+    // reads are not proposed
+    let results = client.execute(tonic::Request::new(Query {
+        sql: String::from("SELECT COUNT(*) FROM test_commit_times"),
+        consistency: proto::Consistency::RelaxedReads as i32,
+        ..Default::default()
+    })).await.unwrap().into_inner();
+    assert_eq!(results.proposed_at_us, 0);
+    assert_eq!(results.committed_at_us, 0);
+
+    query(leader, String::from("DROP TABLE test_commit_times")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn client_and_peer_listeners_are_separate() {
+    let mut config = StoreServerConfig::default();
+    config.auth_tokens.insert(String::from("client-secret"), chiselstore::Principal::new("client", chiselstore::Role::ReadWrite));
+    let mut transport_config = RpcTransportConfig::default();
+    transport_config.peer_token = Some(PeerToken::new("peer-secret").unwrap());
+
+    // peers talk on the node addresses, clients on ports of their own
+    let mut replicas = Vec::new();
+    for (id, peers) in [(1, vec![2]), (2, vec![1])] {
+        replicas.push(start_replica_with_service(id, peers, config.clone(), transport_config.clone(), RpcServerConfig::default(), |server| {
+            RpcService::peer(server, Some(PeerToken::new("peer-secret").unwrap()))
+        }).await);
+    }
+    let mut client_listeners = Vec::new();
+    for replica in replicas.iter() {
+        let (host, port) = node_authority(replica.get_id());
+        let addr = format!("{}:{}", host, port + 100).parse().unwrap();
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+        let rpc = RpcService::client(replica.store_server.clone());
+        let handle = tokio::task::spawn(async move {
+            RpcServerConfig::default().builder()
+                .add_service(RpcServer::new(rpc))
+                .serve_with_shutdown(addr, shutdown_receiver.map(drop))
+                .await
+        });
+        client_listeners.push((shutdown_sender, handle));
+    }
+    tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await; // wait for leader election
+    let leader = replicas.iter().find(|r| r.is_leader()).unwrap().get_id();
+
+    fn client_addr(id: u64) -> String {
+        let (host, port) = node_authority(id);
+        format!("http://{}:{}", host, port + 100)
+    }
+    fn request<T>(msg: T, token: &str) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(msg);
+        request.metadata_mut().insert("authorization", format!("Bearer {}", token).parse().unwrap());
+        request
+    }
+
+    // clients query the client listener, with the client token
+    let mut client = RpcClient::connect(client_addr(leader)).await.unwrap();
+    for sql in ["CREATE TABLE IF NOT EXISTS test_listeners (n integer)", "DELETE FROM test_listeners", "INSERT INTO test_listeners VALUES(1)"] {
+        client.execute(request(Query { sql: sql.to_string(), ..Default::default() }, "client-secret")).await.unwrap();
+    }
+    let status = client.execute(request(Query { sql: String::from("SELECT 1"), ..Default::default() }, "peer-secret")).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    let heartbeat = proto::HeartbeatRequestReq { from: 2, to: 1, round: 0 };
+    let status = client.heartbeat_request(request(heartbeat.clone(), "peer-secret")).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unimplemented);
+
+    // the peer listener serves no queries, and only peers with the peer token
+    let mut peer = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    let status = peer.execute(request(Query { sql: String::from("SELECT 1"), ..Default::default() }, "client-secret")).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unimplemented);
+    let status = peer.heartbeat_request(request(heartbeat, "client-secret")).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+    // writes replicated over the peer listeners
+    let follower = if leader == 1 { 2 } else { 1 };
+    let decided_idx = replicas[(leader - 1) as usize].store_server.get_decided_idx();
+    while replicas[(follower - 1) as usize].store_server.get_decided_idx() < decided_idx {
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    let mut client = RpcClient::connect(client_addr(follower)).await.unwrap();
+    let results = client.execute(request(Query {
+        sql: String::from("SELECT COUNT(*) FROM test_listeners"),
+        consistency: proto::Consistency::RelaxedReads as i32,
+        ..Default::default()
+    }, "client-secret")).await.unwrap().into_inner();
+    assert_eq!(results.rows[0].values[0], "1");
+
+    let mut client = RpcClient::connect(client_addr(leader)).await.unwrap();
+    client.execute(request(Query { sql: String::from("DROP TABLE test_listeners"), ..Default::default() }, "client-secret")).await.unwrap();
+
+    for (shutdown_sender, handle) in client_listeners {
+        let _ = shutdown_sender.send(());
+        handle.await.unwrap().unwrap();
+    }
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn combined_listener_requires_peer_token() {
+    assert!(PeerToken::new("peer\nsecret").is_err());
+
+    let mut transport_config = RpcTransportConfig::default();
+    transport_config.peer_token = Some(PeerToken::new("peer-secret").unwrap());
+    let mut replicas = Vec::new();
+    for (id, peers) in [(1, vec![2]), (2, vec![1])] {
+        replicas.push(start_replica_with_transport_config(id, peers, StoreServerConfig::default(), transport_config.clone()).await);
+    }
+
+    // peers send the token the listener requires
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_combined_listener (n integer)")).await.unwrap();
+    query(1, String::from("DROP TABLE test_combined_listener")).await.unwrap();
+
+    let heartbeat = proto::HeartbeatRequestReq { from: 2, to: 1, round: 0 };
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    let status = client.heartbeat_request(heartbeat.clone()).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    let mut request = tonic::Request::new(heartbeat);
+    request.metadata_mut().insert("authorization", "Bearer peer-secret".parse().unwrap());
+    client.heartbeat_request(request).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn lost_quorum_reports_stalled_consensus() {
+    let mut config = StoreServerConfig::default();
+    config.stall_window = std::time::Duration::from_millis(1000);
+    let mut replicas = setup_replicas_with_config(3, config).await;
+
+    let leader = replicas.iter().find(|r| r.is_leader()).unwrap().get_id();
+    query(leader, String::from("CREATE TABLE IF NOT EXISTS test_stalled (n integer)")).await.unwrap();
+
+    async fn stalled(id: u64) -> bool {
+        let mut client = RpcClient::connect(node_rpc_addr(id)).await.unwrap();
+        client.status(tonic::Request::new(Void {})).await.unwrap().into_inner().consensus_stalled
+    }
+    assert!(!stalled(leader).await);
+
+    // cut the followers off, the leader's write waits for a quorum
+    for replica in replicas.iter_mut().filter(|r| r.get_id() != leader) {
+        replica.disconnect().await;
+    }
+    let write = tokio::task::spawn(async move {
+        query(leader, String::from("INSERT INTO test_stalled VALUES(1)")).await
+    });
+    let start = std::time::Instant::now();
+    while !stalled(leader).await {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "stall was never reported");
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
+    let leader_idx = replicas.iter().position(|r| r.get_id() == leader).unwrap();
+    assert_eq!(replicas[leader_idx].store_server.metrics().gauge("consensus_stalled").get(), 1);
+
+    // once the quorum is back, commands are decided again
+    for replica in replicas.iter_mut().filter(|r| r.get_id() != leader) {
+        replica.reconnect();
+    }
+    let start = std::time::Instant::now();
+    loop {
+        assert!(start.elapsed() < std::time::Duration::from_secs(30), "writes never resumed");
+        if let Some(id) = replicas.iter().find(|r| r.is_leader()).map(|r| r.get_id()) {
+            let mut client = RpcClient::connect(node_rpc_addr(id)).await.unwrap();
+            let write = client.execute(tonic::Request::new(Query {
+                sql: String::from("INSERT INTO test_stalled VALUES(2)"),
+                ..Default::default()
+            }));
+            if let Ok(Ok(_)) = tokio::time::timeout(std::time::Duration::from_secs(5), write).await {
+                break;
+            }
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
+    let start = std::time::Instant::now();
+    while replicas[leader_idx].store_server.is_consensus_stalled() {
+        assert!(start.elapsed() < std::time::Duration::from_secs(30), "stall was never cleared");
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
+    assert!(!stalled(leader).await);
+    write.abort();
+
+    for db in ["node1.db", "node2.db", "node3.db"] {
+        sqlite::open(db).unwrap().execute("DROP TABLE IF EXISTS test_stalled").unwrap();
+    }
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn full_peer_queue_resynchronizes_peer() {
+    use chiselstore::metrics::peer_metric;
+    use chiselstore::{StoreCommand, StoreError, StoreTransport};
+    use omnipaxos_core::ballot_leader_election::Ballot;
+    use omnipaxos_core::messages::{Decide, Message, PaxosMsg};
+
+    // a slow peer that never answers, so its queue fills up
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = format!("http://{}", listener.local_addr().unwrap());
+    let mut config = RpcTransportConfig::default();
+    config.peer_queue_depth = Some(4);
+    let transport = {
+        let addr = addr.clone();
+        RpcTransport::with_config(Box::new(move |_| addr.clone()), config)
+    };
+    let metrics = transport.metrics();
+
+    // once the queue is full, the peer is lost until it is resynchronized
+    for ld in 0..100 {
+        let msg = Message {
+            from: 1,
+            to: 2,
+            msg: PaxosMsg::Decide(Decide { n: Ballot::default(), ld }),
+        };
+        transport.send_sp(2, 1, msg).unwrap();
+    }
+    assert_eq!(metrics.gauge(&peer_metric("peer_queue_depth", &addr)).get(), 0);
+    assert!(metrics.counter(&peer_metric("peer_queue_dropped", &addr)).get() >= 4);
+    assert!(metrics.counter("sp_messages_lost").get() >= 100 - 1);
+    assert!(transport.take_lost_peers().is_empty());
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert_eq!(transport.take_lost_peers(), vec![2]);
+    assert!(transport.take_lost_peers().is_empty());
+
+    // forwarded proposals are never dropped silently
+    let mut rejected = 0;
+    for id in 0..10 {
+        let cmd = StoreCommand {
+            id,
+            sql: String::from("INSERT INTO test_peer_queue VALUES(1)"),
+            params: Vec::new(),
+            batch: Vec::new(),
+            schema_version: 0,
+            request_id: 0,
+            client: String::new(),
+            origin: 0,
+            statements: Vec::new(),
+            kind: CommandKind::Sql,
+        };
+        let msg = Message {
+            from: 1,
+            to: 2,
+            msg: PaxosMsg::ProposalForward(vec![cmd]),
+        };
+        match transport.send_sp(2, 1, msg) {
+            Ok(()) => {}
+            Err(StoreError::ProposalDropped { peer }) => {
+                assert_eq!(peer, 2);
+                rejected += 1;
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+    assert!(rejected >= 10 - 5);
+    assert_eq!(metrics.counter("proposals_dropped").get(), rejected);
+    assert!(metrics.gauge(&peer_metric("peer_queue_depth", &addr)).get() <= 4);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unreachable_peer_trips_circuit_breaker() {
+    use chiselstore::metrics::peer_metric;
+    use chiselstore::StoreTransport;
+    use omnipaxos_core::ballot_leader_election::Ballot;
+    use omnipaxos_core::messages::{Decide, Message, PaxosMsg};
+
+    // a port nothing listens on, so every connection attempt is refused
+    let addr = {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    };
+    let mut config = RpcTransportConfig::default();
+    config.peer_queue_depth = Some(1000);
+    config.connect_failure_threshold = Some(3);
+    config.circuit_open_duration = std::time::Duration::from_secs(60);
+    let transport = {
+        let addr = addr.clone();
+        RpcTransport::with_config(Box::new(move |_| addr.clone()), config)
+    };
+    let metrics = transport.metrics();
+
+    // every resynchronization of the peer fails, but only the first few
+    // attempt to connect
+    for ld in 0..10 {
+        let msg = Message {
+            from: 1,
+            to: 2,
+            msg: PaxosMsg::Decide(Decide { n: Ballot::default(), ld }),
+        };
+        transport.send_sp(2, 1, msg).unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while transport.take_lost_peers() != vec![2] {
+            assert!(std::time::Instant::now() < deadline, "the peer was never reported lost");
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+    assert_eq!(metrics.counter(&peer_metric("unreachable_dropped", &addr)).get(), 10);
+    assert_eq!(metrics.counter(&peer_metric("connect_failures", &addr)).get(), 3);
+    assert_eq!(metrics.gauge(&peer_metric("circuit_open", &addr)).get(), 1);
+    assert_eq!(metrics.gauge(&peer_metric("peer_queue_depth", &addr)).get(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn results_report_where_they_were_served() {
+    let replicas = setup_replicas(2).await;
+
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    let write = client.execute(tonic::Request::new(Query {
+        sql: String::from("CREATE TABLE IF NOT EXISTS test_served (n integer)"),
+        ..Default::default()
+    })).await.unwrap().into_inner();
+    assert!(!write.served_locally);
+
+    let read = client.execute(tonic::Request::new(Query {
+        sql: String::from("SELECT COUNT(*) FROM test_served"),
+        consistency: proto::Consistency::RelaxedReads as i32,
+        ..Default::default()
+    })).await.unwrap().into_inner();
+    assert!(read.served_locally);
+
+    query(1, String::from("DROP TABLE test_served")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn skewed_follower_clock_does_not_affect_staleness() {
+    use chiselstore::clock::SkewedClock;
+
+    // node 2 runs an hour ahead, node 3 half an hour behind
+    let skews: [i64; 3] = [0, 3_600_000, -1_800_000];
+    let mut replicas = Vec::new();
+    for id in 1..=3u64 {
+        let mut config = StoreServerConfig::default();
+        config.clock = match skews[id as usize - 1] {
+            0 => Arc::new(chiselstore::clock::SystemClock),
+            skew if skew > 0 => SkewedClock::ahead(std::time::Duration::from_millis(skew as u64)),
+            skew => SkewedClock::behind(std::time::Duration::from_millis(-skew as u64)),
+        };
+        let peers = (1..=3).filter(|&peer| peer != id).collect();
+        replicas.push(start_replica_with_config(id, peers, config).await);
+    }
+    replicas[0].store_server.noop().await.unwrap();
+    let leader = replicas.iter().find(|r| r.is_leader()).unwrap().get_id();
+    query(leader, String::from("CREATE TABLE IF NOT EXISTS test_clock_skew (n integer)")).await.unwrap();
+
+    // a follower cut off misses a write; how long for is measured on the
+    // leader's clock, whatever the follower's says
+    let follower_idx = replicas.iter().position(|r| r.get_id() != leader).unwrap();
+    let follower = replicas[follower_idx].get_id();
+    replicas[follower_idx].disconnect().await;
+    query(leader, String::from("INSERT INTO test_clock_skew VALUES(1)")).await.unwrap();
+    tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+    let leader_idx = replicas.iter().position(|r| r.get_id() == leader).unwrap();
+    let staleness = replicas[leader_idx]
+        .store_server
+        .get_staleness(replicas[follower_idx].store_server.get_decided_idx());
+    assert!(staleness >= std::time::Duration::from_millis(300), "staleness {:?}", staleness);
+    assert!(staleness < std::time::Duration::from_secs(60), "staleness {:?}", staleness);
+
+    // once caught up, followers are not stale, and their skew is reported
+    replicas[follower_idx].reconnect();
+    let leader_decided = replicas[leader_idx].store_server.get_decided_idx();
+    let start = std::time::Instant::now();
+    while replicas.iter().any(|r| r.store_server.get_decided_idx() < leader_decided) {
+        assert!(start.elapsed() < std::time::Duration::from_secs(30), "node {} never caught up", follower);
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    }
+    let mut client = RpcClient::connect(node_rpc_addr(leader)).await.unwrap();
+    let metrics = client.cluster_metrics(tonic::Request::new(Void {})).await.unwrap().into_inner();
+    assert_eq!(metrics.nodes.len(), 3);
+    for node in metrics.nodes.iter() {
+        assert!(node.reachable);
+        assert!(node.staleness_ms < 5_000, "node {} staleness {}ms", node.id, node.staleness_ms);
+        let expected = skews[node.id as usize - 1] - skews[leader as usize - 1];
+        assert!((node.clock_skew_ms - expected).abs() < 5_000, "node {} skew {}ms", node.id, node.clock_skew_ms);
+        assert_eq!(node.clock_skew_exceeded, expected != 0);
+    }
+
+    query(leader, String::from("DROP TABLE test_clock_skew")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bulk_import_loads_rows_on_every_node() {
+    let replicas = setup_replicas(3).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_bulk_import (id integer PRIMARY KEY, name text)")).await.unwrap();
+
+    // the table and columns are only named on the first row
+    let rows: Vec<proto::ImportRow> = (0..100_000i64)
+        .map(|id| proto::ImportRow {
+            table: if id == 0 { String::from("test_bulk_import") } else { String::new() },
+            columns: if id == 0 { vec![String::from("id"), String::from("name")] } else { Vec::new() },
+            values: vec![
+                proto::Value { value: Some(proto::value::Value::Integer(id)) },
+                proto::Value { value: Some(proto::value::Value::Text(format!("row {}", id))) },
+            ],
+        })
+        .collect();
+    let mut client = RpcClient::connect(node_rpc_addr(2)).await.unwrap();
+    let summary = client.bulk_import(futures::stream::iter(rows)).await.unwrap().into_inner();
+    assert_eq!(summary.rows_imported, 100_000);
+    assert_eq!(summary.rows_failed, 0);
+    assert_eq!(summary.batches, 100);
+    assert!(summary.errors.is_empty());
+
+    for replica in replicas.iter() {
+        let mut client = RpcClient::connect(node_rpc_addr(replica.get_id())).await.unwrap();
+        let start = std::time::Instant::now();
+        loop {
+            let results = client.execute(tonic::Request::new(Query {
+                sql: String::from("SELECT COUNT(*) FROM test_bulk_import"),
+                consistency: proto::Consistency::RelaxedReads as i32,
+                ..Default::default()
+            })).await.unwrap().into_inner();
+            if results.rows[0].values[0] == "100000" {
+                break;
+            }
+            assert!(start.elapsed() < std::time::Duration::from_secs(30), "node {} never imported every row", replica.get_id());
+            tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+        }
+    }
+
+    // a batch with an invalid row is not imported at all
+    let row = |id: i64, values: usize| proto::ImportRow {
+        table: String::from("test_bulk_import"),
+        columns: vec![String::from("id"), String::from("name")],
+        values: (0..values).map(|_| proto::Value { value: Some(proto::value::Value::Integer(id)) }).collect(),
+    };
+    let rows = vec![row(100_000, 2), row(100_001, 1), row(100_002, 2)];
+    let summary = client.bulk_import(futures::stream::iter(rows)).await.unwrap().into_inner();
+    assert_eq!(summary.rows_imported, 0);
+    assert_eq!(summary.rows_failed, 3);
+    assert_eq!(summary.errors.len(), 1);
+    assert_eq!(summary.errors[0].first_row, 0);
+    assert_eq!(summary.errors[0].rows, 3);
+    assert_eq!(query(1, String::from("SELECT COUNT(*) FROM test_bulk_import")).await.unwrap(), "100000");
+
+    query(1, String::from("DROP TABLE test_bulk_import")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn status_reports_quorum_health() {
+    let mut replicas = setup_replicas(5).await;
+    replicas[0].store_server.noop().await.unwrap();
+
+    async fn quorum(id: u64) -> (u64, u64, bool) {
+        let mut client = RpcClient::connect(node_rpc_addr(id)).await.unwrap();
+        let status = client.status(tonic::Request::new(Void {})).await.unwrap().into_inner();
+        (status.quorum_size, status.connected_voters, status.quorum_at_risk)
+    }
+    async fn wait_for_voters(id: u64, voters: u64) -> (u64, u64, bool) {
+        let start = std::time::Instant::now();
+        loop {
+            let quorum = quorum(id).await;
+            if quorum.1 == voters {
+                return quorum;
+            }
+            assert!(start.elapsed() < std::time::Duration::from_secs(30), "node {} never saw {} voters: {:?}", id, voters, quorum);
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+    }
+    assert_eq!(wait_for_voters(1, 5).await, (3, 5, false));
+
+    // with two nodes paused, a quorum is still connected
+    for replica in replicas.iter_mut().filter(|r| r.get_id() >= 4) {
+        replica.disconnect().await;
+    }
+    assert_eq!(wait_for_voters(1, 3).await, (3, 3, false));
+
+    // with a third one paused, it is not
+    replicas[2].disconnect().await;
+    assert_eq!(wait_for_voters(1, 2).await, (3, 2, true));
+    assert!(replicas[0].store_server.is_quorum_at_risk());
+
+    for replica in replicas.iter_mut().filter(|r| r.get_id() >= 3) {
+        replica.reconnect();
+    }
+    assert_eq!(wait_for_voters(1, 5).await, (3, 5, false));
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn client_stops_retrying_once_the_budget_is_depleted() {
+    use chiselstore::client::{Client, RetryPolicy};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // node 1 served on node 8's address, failing every request while the
+    // outage lasts
+    let replicas = setup_replicas(2).await;
+    let outage = Arc::new(AtomicBool::new(false));
+    let (host, port) = node_authority(8);
+    let listen_addr = format!("{}:{}", host, port).parse().unwrap();
+    let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+    let service = RpcServer::with_interceptor(RpcService::new(replicas[0].store_server.clone()), {
+        let outage = outage.clone();
+        move |request: tonic::Request<()>| {
+            if outage.load(Ordering::SeqCst) {
+                Err(tonic::Status::unavailable("outage"))
+            } else {
+                Ok(request)
+            }
+        }
+    });
+    let handle = tokio::task::spawn(
+        RpcServerConfig::default()
+            .builder()
+            .add_service(service)
+            .serve_with_shutdown(listen_addr, shutdown_receiver.map(drop)),
+    );
+
+    let policy = RetryPolicy {
+        max_retries: 3,
+        initial_backoff: std::time::Duration::from_millis(1),
+        max_backoff: std::time::Duration::from_millis(10),
+        budget_ratio: 0.5,
+        budget_capacity: 4.0,
+    };
+    let mut client = Client::connect(node_rpc_addr(8)).await.unwrap().with_retries(policy);
+    for _ in 0..4 {
+        client.status().await.unwrap();
+    }
+    assert_eq!(client.retry_budget().unwrap().tokens(), 4.0);
+
+    // the first requests retry until the budget is depleted, then requests
+    // fail fast
+    outage.store(true, Ordering::SeqCst);
+    for _ in 0..10 {
+        let err = client.status().await.unwrap_err();
+        assert!(err.is_retryable());
+    }
+    let budget = client.retry_budget().unwrap();
+    assert_eq!(budget.retries(), 4);
+    assert!(budget.tokens() < 1.0);
+    assert_eq!(budget.exhausted(), 9);
+
+    // successful requests refill it
+    outage.store(false, Ordering::SeqCst);
+    for _ in 0..2 {
+        client.status().await.unwrap();
+    }
+    assert_eq!(client.retry_budget().unwrap().tokens(), 1.0);
+
+    shutdown_sender.send(()).unwrap();
+    handle.await.unwrap().unwrap();
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn storage_stats_grow_with_inserted_rows() {
+    let replicas = setup_replicas(2).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_storage_stats (id integer PRIMARY KEY, payload text)")).await.unwrap();
+
+    let mut client = RpcClient::connect(node_rpc_addr(2)).await.unwrap();
+    let before = client.storage_stats(tonic::Request::new(Void {})).await.unwrap().into_inner();
+    assert!(before.page_size > 0);
+    let table = before.tables.iter().find(|t| t.name == "test_storage_stats").unwrap();
+    assert_eq!(table.rows, 0);
+
+    query(1, String::from(
+        "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 2000) \
+         INSERT INTO test_storage_stats SELECT i, printf('%.500c', 'x') FROM n",
+    )).await.unwrap();
+
+    // a consistent read, so the rows are counted even if asked on a follower
+    let after = client.storage_stats(tonic::Request::new(Void {})).await.unwrap().into_inner();
+    // pages freed by earlier tests are reused before the file grows
+    let used = |stats: &proto::StorageStatsReply| stats.page_count - stats.freelist_count;
+    assert!(used(&after) > used(&before), "{} pages used before, {} after", used(&before), used(&after));
+    assert!(after.decided_idx > before.decided_idx);
+    let table = after.tables.iter().find(|t| t.name == "test_storage_stats").unwrap();
+    assert_eq!(table.rows, 2000);
+    if let Some(pages) = table.pages {
+        assert!(pages * after.page_size >= 2000 * 500);
+    }
+
+    query(1, String::from("DROP TABLE test_storage_stats")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dry_run_write_reports_errors_without_committing() {
+    use chiselstore::client::Client;
+    use chiselstore::ClientError;
+
+    let replicas = setup_replicas(2).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_dry_run (id integer PRIMARY KEY)")).await.unwrap();
+    query(1, String::from("INSERT INTO test_dry_run VALUES(1)")).await.unwrap();
+    let decided_idx = replicas[0].store_server.get_decided_idx();
+
+    let leader = replicas.iter().find(|r| r.is_leader()).unwrap().get_id();
+    let mut client = Client::connect(node_rpc_addr(leader)).await.unwrap();
+
+    // a write violating a constraint fails
+    let err = client.dry_run("INSERT INTO test_dry_run VALUES(1)", Vec::new()).await.unwrap_err();
+    match err {
+        ClientError::Rejected(ref status) => {
+            assert_eq!(status.code(), tonic::Code::Internal);
+            assert!(status.message().contains("UNIQUE"), "{}", status.message());
+        }
+        ref e => panic!("unexpected error {:?}", e),
+    }
+
+    // one that would succeed is rolled back
+    let results = client.dry_run("INSERT INTO test_dry_run VALUES(2)", Vec::new()).await.unwrap();
+    assert!(results.warnings.iter().any(|w| w.contains("dry run")));
+
+    // neither entered the log or the database
+    assert_eq!(replicas[0].store_server.get_decided_idx(), decided_idx);
+    assert_eq!(query(1, String::from("SELECT COUNT(*) FROM test_dry_run")).await.unwrap(), "1");
+
+    query(1, String::from("DROP TABLE test_dry_run")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn forwarded_writes_record_the_forwarding_node() {
+    let replicas = setup_replicas(3).await;
+    replicas[0].store_server.noop().await.unwrap();
+    let leader = replicas.iter().find(|r| r.is_leader()).unwrap().get_id();
+    let follower = replicas.iter().find(|r| !r.is_leader()).unwrap().get_id();
+
+    async fn write(id: u64, sql: &str) -> proto::QueryResults {
+        let mut client = RpcClient::connect(node_rpc_addr(id)).await.unwrap();
+        client.execute(tonic::Request::new(Query {
+            sql: String::from(sql),
+            ..Default::default()
+        })).await.unwrap().into_inner()
+    }
+    let results = write(leader, "CREATE TABLE IF NOT EXISTS test_forwarding (n integer)").await;
+    assert_eq!(results.forwarded_from, 0);
+    let results = write(follower, "INSERT INTO test_forwarding VALUES(1)").await;
+    assert_eq!(results.forwarded_from, follower);
+
+    query(leader, String::from("DROP TABLE test_forwarding")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn multi_read_never_observes_partial_updates() {
+    use chiselstore::client::Client;
+    use chiselstore::ClientError;
+
+    let replicas = setup_replicas(2).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_multi_read_users (id integer PRIMARY KEY, orders integer)")).await.unwrap();
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_multi_read_orders (id integer PRIMARY KEY, user integer)")).await.unwrap();
+    query(1, String::from("INSERT INTO test_multi_read_users VALUES(1, 0)")).await.unwrap();
+    let follower = replicas.iter().find(|r| !r.is_leader()).unwrap().get_id();
+
+    // every write adds an order and bumps the user's count in one transaction
+    let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let writer_done = done.clone();
+    let writer = tokio::task::spawn(async move {
+        for id in 1..=30 {
+            query(1, format!(
+                "BEGIN; INSERT INTO test_multi_read_orders VALUES({}, 1); UPDATE test_multi_read_users SET orders = orders + 1 WHERE id = 1; COMMIT;",
+                id
+            ))
+            .await
+            .unwrap();
+        }
+        writer_done.store(true, std::sync::atomic::Ordering::SeqCst);
+    });
+
+    let mut client = Client::connect(node_rpc_addr(follower)).await.unwrap();
+    let mut reads = 0;
+    while !done.load(std::sync::atomic::Ordering::SeqCst) || reads == 0 {
+        for consistency in [proto::Consistency::Strong, proto::Consistency::RelaxedReads] {
+            let results = client
+                .multi_read(
+                    vec![
+                        ("SELECT orders FROM test_multi_read_users WHERE id = ?", vec![proto::Value { value: Some(proto::value::Value::Integer(1)) }]),
+                        ("SELECT COUNT(*) FROM test_multi_read_orders WHERE user = 1", Vec::new()),
+                    ],
+                    consistency,
+                )
+                .await
+                .unwrap();
+            assert_eq!(results.len(), 2);
+            assert_eq!(results[0].rows[0].values, results[1].rows[0].values);
+            reads += 1;
+        }
+    }
+    writer.await.unwrap();
+
+    // a strong multi read sees every write decided before it
+    let results = client
+        .multi_read(vec![("SELECT orders FROM test_multi_read_users", Vec::new())], proto::Consistency::Strong)
+        .await
+        .unwrap();
+    assert_eq!(results[0].rows[0].values, vec![String::from("30")]);
+
+    // writes are rejected
+    let err = client
+        .multi_read(vec![("DELETE FROM test_multi_read_orders", Vec::new())], proto::Consistency::Strong)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Rejected(_)), "{:?}", err);
+
+    query(1, String::from("DROP TABLE test_multi_read_users")).await.unwrap();
+    query(1, String::from("DROP TABLE test_multi_read_orders")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn temp_store_settings_are_applied() {
+    use chiselstore::TempStore;
+
+    let dir = std::env::temp_dir();
+    let mut config = StoreServerConfig::default();
+    config.temp_store = TempStore::Memory;
+    config.temp_store_directory = Some(dir.clone());
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    for replica in replicas.iter() {
+        let id = replica.get_id();
+        assert_eq!(query(id, String::from("PRAGMA temp_store")).await.unwrap(), "2");
+        assert_eq!(query(id, String::from("PRAGMA temp_store_directory")).await.unwrap(), dir.to_string_lossy());
+    }
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cached_leader_follows_elections() {
+    let mut replicas = setup_replicas(3).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_cached_leader (n integer)")).await.unwrap();
+    let old_leader = replicas.iter().find(|r| r.is_leader()).unwrap().get_id();
+    for replica in replicas.iter() {
+        assert_eq!(replica.store_server.get_current_leader(), old_leader);
+    }
+
+    // cut the leader off, the others elect one of them
+    let old_idx = replicas.iter().position(|r| r.get_id() == old_leader).unwrap();
+    replicas[old_idx].disconnect().await;
+    let others: Vec<usize> = (0..replicas.len()).filter(|&i| i != old_idx).collect();
+    let start = std::time::Instant::now();
+    let new_leader = loop {
+        let leaders: Vec<u64> = others.iter().map(|&i| replicas[i].store_server.get_current_leader()).collect();
+        if leaders[0] != old_leader && leaders[0] != 0 && leaders.iter().all(|&l| l == leaders[0]) {
+            break leaders[0];
+        }
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "no new leader elected");
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    };
+
+    // writes to the other follower are forwarded to the new leader
+    let follower = others.iter().map(|&i| replicas[i].get_id()).find(|&id| id != new_leader).unwrap();
+    let mut client = RpcClient::connect(node_rpc_addr(follower)).await.unwrap();
+    let results = client
+        .execute(tonic::Request::new(Query {
+            sql: String::from("INSERT INTO test_cached_leader VALUES(1)"),
+            ..Default::default()
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(results.ballot.unwrap().pid, new_leader);
+    assert_eq!(results.forwarded_from, follower);
+
+    replicas[old_idx].reconnect();
+    query(new_leader, String::from("DROP TABLE test_cached_leader")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn request_id_traces_a_write_across_nodes() {
+    let audit_log = |id: u64| format!("node{}.trace.audit.log", id);
+    let mut replicas = Vec::new();
+    for (id, peers) in [(1, vec![2]), (2, vec![1])] {
+        let _ = std::fs::remove_file(audit_log(id));
+        let mut config = StoreServerConfig::default();
+        config.audit_log = Some(std::path::PathBuf::from(audit_log(id)));
+        replicas.push(start_replica_with_config(id, peers, config).await);
+    }
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_trace (n integer)")).await.unwrap();
+    let leader = replicas.iter().find(|r| r.is_leader()).unwrap().get_id();
+    let follower = replicas.iter().find(|r| !r.is_leader()).unwrap().get_id();
+
+    let applied: Vec<u64> = replicas.iter().map(|r| r.store_server.metrics().counter("commands_applied").get()).collect();
+
+    // submitted to the follower, forwarded to the leader, applied on both
+    let mut client = RpcClient::connect(node_rpc_addr(follower)).await.unwrap();
+    client
+        .execute(tonic::Request::new(Query {
+            sql: String::from("INSERT INTO test_trace VALUES(1)"),
+            request_id: 4242,
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    query(leader, String::from("DROP TABLE test_trace")).await.unwrap();
+
+    for id in [leader, follower] {
+        let audited = std::fs::read_to_string(audit_log(id)).unwrap();
+        let line = audited.lines().find(|line| line.contains("\"request_id\":4242")).unwrap();
+        assert!(line.contains("INSERT INTO test_trace VALUES(1)"), "{}", line);
+    }
+    for (replica, before) in replicas.iter().zip(applied) {
+        assert!(replica.store_server.metrics().counter("commands_applied").get() > before);
+    }
+
+    shutdown_replicas(replicas).await;
+    for id in [1, 2] {
+        let _ = std::fs::remove_file(audit_log(id));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn apply_row_limit_fails_large_returning_clauses() {
+    let mut config = StoreServerConfig::default();
+    config.max_apply_rows = 100;
+    let replicas = setup_replicas_with_config(2, config).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_returning (n integer)")).await.unwrap();
+
+    async fn insert_returning(rows: u64) -> Result<proto::QueryResults, tonic::Status> {
+        let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+        let sql = format!(
+            "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < {}) INSERT INTO test_returning SELECT x FROM c RETURNING n",
+            rows
+        );
+        client
+            .execute(tonic::Request::new(Query { sql, ..Default::default() }))
+            .await
+            .map(|response| response.into_inner())
+    }
+
+    // within the limit, every row is returned
+    let results = insert_returning(100).await.unwrap();
+    assert_eq!(results.rows.len(), 100);
+
+    // beyond it, the command fails and is rolled back on every node
+    let status = insert_returning(101).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::OutOfRange);
+    assert!(status.message().contains("100 rows"), "{}", status.message());
+    let decided_idx = replicas[0].store_server.get_decided_idx();
+    while replicas[1].store_server.get_decided_idx() < decided_idx {
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    for db in ["node1.db", "node2.db"] {
+        let conn = sqlite::open(db).unwrap();
+        let mut stmt = conn.prepare("SELECT COUNT(*) FROM test_returning").unwrap();
+        stmt.next().unwrap();
+        assert_eq!(stmt.read::<i64>(0).unwrap(), 100);
+    }
+
+    query(1, String::from("DROP TABLE test_returning")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn apply_latency_records_slow_disk_writes() {
+    let replicas = setup_replicas(2).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_apply_latency (id integer PRIMARY KEY)")).await.unwrap();
+    let histogram = replicas[0].store_server.metrics().histogram("apply_latency");
+    let commits = replicas[0].store_server.metrics().histogram("commit_latency");
+    assert!(histogram.count() > 0);
+    assert!(commits.count() > 0);
+    let applied = (histogram.count(), commits.count());
+
+    // a slow disk: replica 1's database can't be written for a while
+    let locker = std::thread::spawn(|| {
+        let conn = sqlite::open("node1.db").unwrap();
+        conn.execute("BEGIN EXCLUSIVE").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        conn.execute("COMMIT").unwrap();
+    });
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    query(1, String::from("INSERT INTO test_apply_latency VALUES(1)")).await.unwrap();
+    locker.join().unwrap();
+
+    // replica 1 may be a follower, still finishing its apply
+    let start = std::time::Instant::now();
+    while histogram.count() == applied.0 || commits.count() == applied.1 {
+        assert!(start.elapsed() < std::time::Duration::from_secs(5), "slow apply was not recorded");
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    assert!(histogram.percentile(100.0) >= std::time::Duration::from_millis(200));
+
+    query(1, String::from("DROP TABLE test_apply_latency")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tampered_schema_triggers_configured_policy() {
+    use chiselstore::SchemaMismatchPolicy;
+
+    let replicas = setup_replicas(2).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_tampered (id integer PRIMARY KEY)")).await.unwrap();
+    let decided_idx = replicas[0].store_server.get_decided_idx();
+    while replicas[1].store_server.get_decided_idx() < decided_idx {
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    shutdown_replicas(replicas).await;
+
+    // the schema changes behind replica 1's back
+    sqlite::open("node1.db").unwrap().execute("ALTER TABLE test_tampered ADD COLUMN extra text").unwrap();
+
+    let mut config = StoreServerConfig::default();
+    config.membership = vec![1, 2];
+    config.schema_mismatch_policy = SchemaMismatchPolicy::Refuse;
+    let transport = RpcTransport::new(Box::new(node_rpc_addr));
+    assert!(matches!(
+        StoreServer::start_with_config(1, vec![2], transport, config.clone()),
+        Err(chiselstore::StoreError::SchemaMismatch { .. })
+    ));
+
+    // a trimmed log can't rebuild the database
+    config.schema_mismatch_policy = SchemaMismatchPolicy::Rebuild;
+    sqlite::open("node1.db").unwrap().execute("INSERT INTO chiselstore_state VALUES('log_trimmed', 1)").unwrap();
+    let transport = RpcTransport::new(Box::new(node_rpc_addr));
+    assert!(matches!(
+        StoreServer::start_with_config(1, vec![2], transport, config.clone()),
+        Err(chiselstore::StoreError::SchemaMismatch { .. })
+    ));
+    sqlite::open("node1.db").unwrap().execute("DELETE FROM chiselstore_state WHERE name = 'log_trimmed'").unwrap();
+
+    let transport = RpcTransport::new(Box::new(node_rpc_addr));
+    let server = StoreServer::start_with_config(1, vec![2], transport, config).unwrap();
+    drop(server);
+    let conn = sqlite::open("node1.db").unwrap();
+    let mut stmt = conn.prepare("SELECT COUNT(*) FROM sqlite_master WHERE name = 'test_tampered'").unwrap();
+    stmt.next().unwrap();
+    assert_eq!(stmt.read::<i64>(0).unwrap(), 0);
+
+    sqlite::open("node2.db").unwrap().execute("DROP TABLE test_tampered; DELETE FROM chiselstore_state WHERE name = 'schema'").unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn explain_analyze_reports_plan_and_row_counts() {
+    use chiselstore::client::Client;
+    use chiselstore::rpc::proto::{value, Value};
+
+    let replicas = setup_replicas(2).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_analyze (id integer PRIMARY KEY, name text)")).await.unwrap();
+    query(1, String::from("INSERT OR REPLACE INTO test_analyze VALUES(1, 'a'), (2, 'b'), (3, 'c')")).await.unwrap();
+
+    let mut client = Client::connect(node_rpc_addr(1)).await.unwrap();
+    let results = client
+        .explain_analyze(
+            "SELECT name FROM test_analyze; SELECT name FROM test_analyze WHERE id = ?",
+            vec![Value { value: Some(value::Value::Integer(2)) }],
+        )
+        .await
+        .unwrap();
+    assert_eq!(results.rows.len(), 4);
+    assert_eq!(results.analysis.len(), 2);
+    let scan = &results.analysis[0];
+    assert_eq!(scan.rows, 3);
+    assert!(scan.plan.iter().any(|step| step.starts_with("SCAN")), "{:?}", scan.plan);
+    assert!(scan.first_row_us <= scan.elapsed_us);
+    let search = &results.analysis[1];
+    assert_eq!(search.rows, 1);
+    assert!(search.plan.iter().any(|step| step.starts_with("SEARCH")), "{:?}", search.plan);
+
+    // writes can't be analyzed
+    let err = client.explain_analyze("DELETE FROM test_analyze", Vec::new()).await.unwrap_err();
+    assert!(format!("{}", err).contains("read-only"), "{}", err);
+
+    query(1, String::from("DROP TABLE test_analyze")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn oversized_sync_is_sent_in_chunks() {
+    use chiselstore::metrics::peer_metric;
+
+    let mut transport_config = RpcTransportConfig::default();
+    transport_config.max_sync_message_bytes = Some(16 * 1024);
+
+    // node 3 starts late, so it has to be synchronized with ~200 KB of log
+    let mut replicas = Vec::new();
+    for id in 1..=2 {
+        let peers = (1..=3).filter(|&p| p != id).collect();
+        replicas.push(start_replica_with_transport_config(id, peers, StoreServerConfig::default(), transport_config.clone()).await);
+    }
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_sync_chunks (id integer PRIMARY KEY, data text)")).await.unwrap();
+    for i in 0..200 {
+        query(1, format!("INSERT OR REPLACE INTO test_sync_chunks VALUES({}, '{}')", i, "x".repeat(1000))).await.unwrap();
+    }
+    let leader = replicas[0].get_current_leader();
+    let leader_idx = replicas.iter().position(|r| r.get_id() == leader).unwrap();
+
+    replicas.push(start_replica_with_transport_config(3, vec![1, 2], StoreServerConfig::default(), transport_config).await);
+
+    let decided_idx = replicas[leader_idx].store_server.get_decided_idx();
+    let start = std::time::Instant::now();
+    while replicas[2].store_server.get_decided_idx() < decided_idx {
+        assert!(start.elapsed() < std::time::Duration::from_secs(30), "node 3 never caught up");
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    }
+    let metrics = replicas[leader_idx].store_server.transport().metrics();
+    assert!(metrics.counter(&peer_metric("sync_chunks_sent", &node_rpc_addr(3))).get() > 10);
+    let conn = sqlite::open("node3.db").unwrap();
+    let mut stmt = conn.prepare("SELECT COUNT(*) FROM test_sync_chunks").unwrap();
+    stmt.next().unwrap();
+    assert_eq!(stmt.read::<i64>(0).unwrap(), 200);
+
+    query(1, String::from("DROP TABLE test_sync_chunks")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn status_reports_peer_connectivity() {
+    let mut replicas = setup_replicas(3).await;
+
+    async fn connectivity(id: u64) -> Vec<(u64, bool)> {
+        let mut client = RpcClient::connect(node_rpc_addr(id)).await.unwrap();
+        let status = client.status(tonic::Request::new(Void {})).await.unwrap().into_inner();
+        let mut peers: Vec<(u64, bool)> = status.peer_connectivity.into_iter().collect();
+        peers.sort_unstable();
+        peers
+    }
+    async fn wait_for_connectivity(id: u64, expected: Vec<(u64, bool)>) {
+        let start = std::time::Instant::now();
+        loop {
+            let peers = connectivity(id).await;
+            if peers == expected {
+                return;
+            }
+            assert!(start.elapsed() < std::time::Duration::from_secs(30), "node {} sees {:?}", id, peers);
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+    }
+    wait_for_connectivity(1, vec![(2, true), (3, true)]).await;
+
+    // node 3 is partitioned: the others lose it, and it loses them
+    replicas[2].disconnect().await;
+    wait_for_connectivity(1, vec![(2, true), (3, false)]).await;
+    wait_for_connectivity(2, vec![(1, true), (3, false)]).await;
+    let start = std::time::Instant::now();
+    while replicas[2].store_server.get_peer_connectivity() != vec![(1, false), (2, false)] {
+        assert!(start.elapsed() < std::time::Duration::from_secs(30), "node 3 never lost its peers");
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
+
+    replicas[2].reconnect();
+    wait_for_connectivity(3, vec![(1, true), (2, true)]).await;
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn write_without_quorum_times_out() {
+    let mut config = StoreServerConfig::default();
+    config.commit_timeout = Some(std::time::Duration::from_millis(500));
+    let mut replicas = setup_replicas_with_config(3, config).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_commit_timeout (id integer PRIMARY KEY)")).await.unwrap();
+    let leader = replicas[0].get_current_leader();
+
+    // the followers are cut off, so the write can't be decided
+    for replica in replicas.iter_mut().filter(|r| r.get_id() != leader) {
+        replica.disconnect().await;
+    }
+    let mut client = RpcClient::connect(node_rpc_addr(leader)).await.unwrap();
+    let start = std::time::Instant::now();
+    let status = client
+        .execute(tonic::Request::new(Query {
+            sql: String::from("INSERT INTO test_commit_timeout VALUES(1)"),
+            ..Default::default()
+        }))
+        .await
+        .unwrap_err();
+    let elapsed = start.elapsed();
+    assert_eq!(status.code(), tonic::Code::DeadlineExceeded);
+    assert!(status.message().contains("500ms"), "{}", status.message());
+    assert!(status.message().contains("decided index"), "{}", status.message());
+    assert!(status.message().contains("still leader"), "{}", status.message());
+    assert!(elapsed >= std::time::Duration::from_millis(500), "{:?}", elapsed);
+    assert!(elapsed < std::time::Duration::from_secs(5), "{:?}", elapsed);
+
+    for replica in replicas.iter_mut().filter(|r| r.get_id() != leader) {
+        replica.reconnect();
+    }
+    shutdown_replicas(replicas).await;
+    for db in ["node1.db", "node2.db", "node3.db"] {
+        sqlite::open(db).unwrap().execute("DROP TABLE IF EXISTS test_commit_timeout").unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn write_requiring_all_replicas_waits_for_their_acks() {
+    let mut config = StoreServerConfig::default();
+    config.commit_timeout = Some(std::time::Duration::from_secs(20));
+    let mut replicas = setup_replicas_with_config(3, config).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_min_replicas (id integer PRIMARY KEY)")).await.unwrap();
+    let leader = replicas[0].get_current_leader();
+
+    async fn write(leader: u64, id: u64, min_replicas: u64) -> Result<proto::QueryResults, tonic::Status> {
+        let mut client = RpcClient::connect(node_rpc_addr(leader)).await.unwrap();
+        client
+            .execute(tonic::Request::new(Query {
+                sql: format!("INSERT INTO test_min_replicas VALUES({})", id),
+                min_replicas,
+                ..Default::default()
+            }))
+            .await
+            .map(|response| response.into_inner())
+    }
+    let results = write(leader, 1, 3).await.unwrap();
+    assert_eq!(results.acknowledged_by, 3);
+    assert!(results.log_idx > 0);
+
+    // with a follower cut off, a quorum write returns, one requiring all waits
+    let follower_idx = replicas.iter().position(|r| r.get_id() != leader).unwrap();
+    replicas[follower_idx].disconnect().await;
+    let results = write(leader, 2, 0).await.unwrap();
+    assert_eq!(results.acknowledged_by, 2);
+    let mut all = tokio::task::spawn(write(leader, 3, 3));
+    assert!(tokio::time::timeout(std::time::Duration::from_secs(1), &mut all).await.is_err());
+
+    // once the follower is back and has accepted the write, it returns
+    replicas[follower_idx].reconnect();
+    let results = all.await.unwrap().unwrap();
+    assert_eq!(results.acknowledged_by, 3);
+
+    // more members than the cluster has can't be required, and the write
+    // is not committed
+    let status = write(leader, 4, 4).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    assert_eq!(query(leader, String::from("SELECT COUNT(*) FROM test_min_replicas WHERE id = 4")).await.unwrap(), "0");
+
+    query(1, String::from("DROP TABLE test_min_replicas")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn structured_commands_apply_statement_by_statement() {
+    use chiselstore::client::Client;
+    use chiselstore::rpc::proto::{value, Value};
+    use chiselstore::CommandFormat;
+
+    let mut config = StoreServerConfig::default();
+    config.command_format = CommandFormat::Structured;
+    let replicas = setup_replicas_with_config(2, config).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_structured (id integer PRIMARY KEY, name text)")).await.unwrap();
+
+    // each statement gets its own parameters
+    let mut client = Client::connect(node_rpc_addr(1)).await.unwrap();
+    let params = vec![
+        Value { value: Some(value::Value::Integer(1)) },
+        Value { value: Some(value::Value::Text(String::from("one"))) },
+        Value { value: Some(value::Value::Integer(2)) },
+    ];
+    client
+        .execute(
+            "INSERT INTO test_structured VALUES(?, ?); INSERT INTO test_structured VALUES(?, 'two')",
+            params,
+        )
+        .await
+        .unwrap();
+    let decided_idx = replicas[0].store_server.get_decided_idx();
+    while replicas[1].store_server.get_decided_idx() < decided_idx {
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    for db in ["node1.db", "node2.db"] {
+        let conn = sqlite::open(db).unwrap();
+        let mut stmt = conn.prepare("SELECT group_concat(id || ':' || name) FROM test_structured").unwrap();
+        stmt.next().unwrap();
+        assert_eq!(stmt.read::<String>(0).unwrap(), "1:one,2:two");
+    }
+
+    query(1, String::from("DROP TABLE test_structured")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+/// Compares the time nodes spend applying commands in each format. Run with
+/// `cargo test --release -- --ignored apply_cost_by_command_format --nocapture`.
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn apply_cost_by_command_format() {
+    use chiselstore::client::Client;
+    use chiselstore::rpc::proto::{value, Value};
+    use chiselstore::CommandFormat;
+
+    let mut means = Vec::new();
+    for format in [CommandFormat::Sql, CommandFormat::Structured] {
+        let mut config = StoreServerConfig::default();
+        config.command_format = format;
+        let replicas = setup_replicas_with_config(2, config).await;
+        query(1, String::from("CREATE TABLE IF NOT EXISTS bench_format (id integer PRIMARY KEY, a text, b text)")).await.unwrap();
+        let histogram = replicas[0].store_server.metrics().histogram("apply_latency");
+        let before = (histogram.count(), histogram.mean());
+
+        let mut client = Client::connect(node_rpc_addr(1)).await.unwrap();
+        for i in 0..2000 {
+            let params = (0..3)
+                .flat_map(|j| {
+                    vec![
+                        Value { value: Some(value::Value::Integer(i * 3 + j)) },
+                        Value { value: Some(value::Value::Text(format!("a {}", "x".repeat(100)))) },
+                        Value { value: Some(value::Value::Text(format!("b {}", "y".repeat(100)))) },
+                    ]
+                })
+                .collect();
+            client
+                .execute(
+                    "INSERT OR REPLACE INTO bench_format VALUES(?, ?, ?); \
+                     INSERT OR REPLACE INTO bench_format VALUES(?, ?, ?); \
+                     INSERT OR REPLACE INTO bench_format VALUES(?, ?, ?)",
+                    params,
+                )
+                .await
+                .unwrap();
+        }
+
+        // the mean of the commands applied since the table was created
+        let (count, mean) = (histogram.count(), histogram.mean());
+        let total = mean * count as u32 - before.1 * before.0 as u32;
+        let mean = total / (count - before.0) as u32;
+        println!("{:?}: {} commands, mean apply time {:?}", format, count - before.0, mean);
+        means.push(mean);
+
+        query(1, String::from("DROP TABLE bench_format")).await.unwrap();
+        shutdown_replicas(replicas).await;
+    }
+    assert!(means[1] <= means[0], "structured {:?}, SQL {:?}", means[1], means[0]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transport_stats_count_sent_messages_until_reset() {
+    use chiselstore::StoreTransport;
+    use omnipaxos_core::ballot_leader_election::Ballot;
+    use omnipaxos_core::messages::{Decide, Message, PaxosMsg};
+
+    let replicas = setup_replicas(2).await;
+    let transport = RpcTransport::new(Box::new(node_rpc_addr));
+    let decide = |to| Message {
+        from: 2,
+        to,
+        msg: PaxosMsg::Decide(Decide { n: Ballot::default(), ld: 0 }),
+    };
+
+    // five messages to node 1, one to node 9, which nothing listens for
+    for _ in 0..5 {
+        transport.send_sp(1, 1, decide(1)).unwrap();
+    }
+    transport.send_sp(9, 1, decide(9)).unwrap();
+    let start = std::time::Instant::now();
+    loop {
+        let stats = transport.stats_snapshot();
+        if stats.messages_sent.get("Decide") == Some(&5) && stats.connect_failures == 1 {
+            assert!(stats.bytes_sent > 0);
+            assert!(stats.connect_attempts >= 2, "{:?}", stats);
+            break;
+        }
+        assert!(start.elapsed() < std::time::Duration::from_secs(5), "{:?}", stats);
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+
+    transport.reset_stats();
+    let stats = transport.stats_snapshot();
+    assert!(stats.messages_sent.is_empty());
+    assert_eq!((stats.bytes_sent, stats.connect_attempts, stats.connect_failures), (0, 0, 0));
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn removed_peer_queue_is_cancelled_quietly() {
+    use chiselstore::metrics::peer_metric;
+    use chiselstore::{StoreCommand, StoreError, StoreTransport};
+    use omnipaxos_core::ballot_leader_election::Ballot;
+    use omnipaxos_core::messages::{Decide, Message, PaxosMsg};
+
+    // a slow peer that never answers, so messages stay queued to it
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = format!("http://{}", listener.local_addr().unwrap());
+    let mut config = RpcTransportConfig::default();
+    config.peer_queue_depth = Some(1000);
+    let transport = {
+        let addr = addr.clone();
+        RpcTransport::with_config(Box::new(move |_| addr.clone()), config)
+    };
+    let metrics = transport.metrics();
+    let decide = |ld| Message {
+        from: 1,
+        to: 2,
+        msg: PaxosMsg::Decide(Decide { n: Ballot::default(), ld }),
+    };
+
+    let forward = |id| Message {
+        from: 1,
+        to: 2,
+        msg: PaxosMsg::ProposalForward(vec![StoreCommand {
+            id,
+            sql: String::from("INSERT INTO test_removed_peer VALUES(1)"),
+            params: Vec::new(),
+            batch: Vec::new(),
+            schema_version: 0,
+            request_id: 0,
+            client: String::new(),
+            origin: 0,
+            statements: Vec::new(),
+            kind: CommandKind::Sql,
+        }]),
+    };
+
+    for ld in 0..50 {
+        transport.send_sp(2, 1, decide(ld)).unwrap();
+    }
+    transport.send_sp(2, 1, forward(9)).unwrap();
+    assert!(metrics.gauge(&peer_metric("peer_queue_depth", &addr)).get() > 0);
+
+    // node 2 is removed by the new configuration, failing the queued proposal
+    transport.set_peers(&[3]);
+    assert_eq!(metrics.gauge(&peer_metric("peer_queue_depth", &addr)).get(), 0);
+    assert_eq!(transport.take_dropped_proposals(), vec![(2, 9)]);
+
+    // later messages to it are dropped, forwarded proposals are failed
+    transport.send_sp(2, 1, decide(50)).unwrap();
+    assert!(matches!(transport.send_sp(2, 1, forward(1)), Err(StoreError::ProposalDropped { peer: 2 })));
+    assert_eq!(metrics.counter("removed_peer_dropped").get(), 2);
+
+    // the cancelled messages are never sent, so none of them fail
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    assert_eq!(metrics.gauge(&peer_metric("peer_queue_depth", &addr)).get(), 0);
+    assert_eq!(metrics.counter(&peer_metric("unreachable_dropped", &addr)).get(), 0);
+    assert_eq!(metrics.counter(&peer_metric("connect_failures", &addr)).get(), 0);
+    assert_eq!(metrics.counter(&peer_metric("peer_queue_dropped", &addr)).get(), 0);
+    drop(listener);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn client_reads_its_own_writes_on_any_node() {
+    use chiselstore::client::ClusterClient;
+    use chiselstore::rpc::proto::{value, Consistency, Value};
+
+    let replicas = setup_replicas(3).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_own_writes (id integer PRIMARY KEY)")).await.unwrap();
+
+    let leader = replicas.iter().find(|r| r.is_leader()).unwrap().get_id();
+    let follower = replicas.iter().find(|r| !r.is_leader()).unwrap().get_id();
+    let mut client = ClusterClient::connect((1..4).map(node_rpc_addr).collect()).await.unwrap();
+    client.refresh().await;
+    client.refresh_interval = std::time::Duration::from_secs(3600);
+
+    // writes go to the leader, relaxed reads to a follower
+    for id in 1..4 {
+        let latency = if id == follower { 1 } else { 50 };
+        client.record_latency(&node_rpc_addr(id), std::time::Duration::from_millis(latency), 0);
+    }
+    assert_eq!(client.nearest_node(), Some(node_rpc_addr(follower).as_str()));
+    assert_ne!(leader, follower);
+
+    for n in 1..=20 {
+        let write = client
+            .execute("INSERT INTO test_own_writes VALUES(?)", vec![Value { value: Some(value::Value::Integer(n)) }], Consistency::Strong)
+            .await
+            .unwrap();
+        assert!(!write.served_locally);
+        let read = client.execute("SELECT COUNT(*) FROM test_own_writes", Vec::new(), Consistency::RelaxedReads).await.unwrap();
+        assert!(read.served_locally);
+        assert_eq!(read.rows[0].values[0], n.to_string());
+    }
+
+    query(1, String::from("DROP TABLE test_own_writes")).await.unwrap();
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn killing_a_peer_emits_disconnected_event() {
+    use chiselstore::rpc::TransportEvent;
+    use chiselstore::StoreTransport;
+    use omnipaxos_core::ballot_leader_election::Ballot;
+    use omnipaxos_core::messages::{Decide, Message, PaxosMsg};
+
+    let mut replicas = setup_replicas(2).await;
+    let transport = RpcTransport::new(Box::new(node_rpc_addr));
+    let mut events = transport.subscribe_events();
+    let decide = || Message {
+        from: 1,
+        to: 2,
+        msg: PaxosMsg::Decide(Decide { n: Ballot::default(), ld: 0 }),
+    };
+    let peer = node_rpc_addr(2);
+
+    transport.send_sp(2, 1, decide()).unwrap();
+    let event = tokio::time::timeout(std::time::Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+    assert_eq!(event, TransportEvent::PeerConnected { peer: peer.clone() });
+
+    replicas.remove(1).shutdown().await;
+
+    // messages keep being sent until one finds the peer gone
+    let disconnected = async {
+        loop {
+            match events.recv().await.unwrap() {
+                TransportEvent::PeerDisconnected { peer: p } => return p,
+                _ => continue,
+            }
+        }
+    };
+    tokio::pin!(disconnected);
+    let deadline = tokio::time::Instant::now() + tokio::time::Duration::from_secs(10);
+    let disconnected = loop {
+        transport.send_sp(2, 1, decide()).unwrap();
+        tokio::select! {
+            p = &mut disconnected => break p,
+            _ = tokio::time::sleep(tokio::time::Duration::from_millis(50)) => {}
+        }
+        assert!(tokio::time::Instant::now() < deadline, "no disconnection reported");
+    };
+    assert_eq!(disconnected, peer);
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failed_unacknowledged_write_is_not_applied() {
+    use chiselstore::client::Client;
+    use chiselstore::rpc::proto::Consistency;
+
+    let mut config = StoreServerConfig::default();
+    config.commit_timeout = Some(std::time::Duration::from_millis(500));
+    let mut replicas = setup_replicas_with_config(3, config).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_unacknowledged (id integer PRIMARY KEY)")).await.unwrap();
+    let leader = replicas[0].get_current_leader();
+    let mut client = Client::connect(node_rpc_addr(leader)).await.unwrap();
+
+    // an unacknowledged write that commits is applied as usual
+    let results = client.execute_unacknowledged("INSERT INTO test_unacknowledged VALUES(1)", Vec::new()).await.unwrap();
+    assert!(results.warnings.iter().any(|w| w.starts_with("unacknowledged")), "{:?}", results.warnings);
+    tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+
+    // the followers are cut off, so the next one can't be decided
+    for replica in replicas.iter_mut().filter(|r| r.get_id() != leader) {
+        replica.disconnect().await;
+    }
+    let start = std::time::Instant::now();
+    client.execute_unacknowledged("INSERT INTO test_unacknowledged VALUES(2)", Vec::new()).await.unwrap();
+    assert!(start.elapsed() < std::time::Duration::from_millis(500));
+    let count = || async {
+        let mut client = Client::connect(node_rpc_addr(leader)).await.unwrap();
+        let results = client.execute_with_consistency("SELECT COUNT(*) FROM test_unacknowledged", Vec::new(), Consistency::RelaxedReads).await.unwrap();
+        results.rows[0].values[0].clone()
+    };
+    assert_eq!(count().await, "1");
+
+    tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
+    let metrics = replicas.iter().find(|r| r.get_id() == leader).unwrap().store_server.metrics();
+    assert_eq!(metrics.counter("unacknowledged_writes").get(), 2);
+    assert_eq!(metrics.counter("unacknowledged_failures").get(), 1);
+    assert_eq!(count().await, "1");
+
+    shutdown_replicas(replicas).await;
+    for db in ["node1.db", "node2.db", "node3.db"] {
+        sqlite::open(db).unwrap().execute("DROP TABLE IF EXISTS test_unacknowledged").unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn table_consistency_defaults_pick_how_queries_are_served() {
+    let mut config = StoreServerConfig::default();
+    config.table_consistency.insert(String::from("test_config"), chiselstore::Consistency::Strong);
+    config.table_consistency.insert(String::from("test_metrics"), chiselstore::Consistency::RelaxedReads);
+    let replicas = setup_replicas_with_config(2, config).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_config (key text PRIMARY KEY, value text)")).await.unwrap();
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_metrics (name text, value integer)")).await.unwrap();
+
+    let client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    let execute = |sql: &str, consistency: proto::Consistency| {
+        let query = Query {
+            sql: sql.to_string(),
+            consistency: consistency as i32,
+            ..Default::default()
+        };
+        let mut client = client.clone();
+        async move { client.execute(tonic::Request::new(query)).await.unwrap().into_inner() }
+    };
+
+    // writes are replicated whatever the table's default
+    let write = execute("INSERT OR REPLACE INTO test_config VALUES('mode', 'on')", proto::Consistency::TableDefault).await;
+    assert!(!write.served_locally);
+    assert!(write.ballot.is_some());
+    let write = execute("INSERT INTO test_metrics VALUES('requests', 1)", proto::Consistency::TableDefault).await;
+    assert!(!write.served_locally);
+
+    // reads of the configuration are strong, reads of metrics eventual
+    let read = execute("SELECT value FROM test_config WHERE key = 'mode'", proto::Consistency::TableDefault).await;
+    assert!(!read.served_locally);
+    assert_eq!(read.rows[0].values[0], "on");
+    let read = execute("SELECT COUNT(*) FROM test_metrics", proto::Consistency::TableDefault).await;
+    assert!(read.served_locally);
+
+    // joining both takes the strongest, and requests override the default
+    let read = execute("SELECT * FROM test_metrics JOIN test_config", proto::Consistency::TableDefault).await;
+    assert!(!read.served_locally);
+    let read = execute("SELECT * FROM test_metrics m, test_config c", proto::Consistency::TableDefault).await;
+    assert!(!read.served_locally);
+    let read = execute("SELECT * FROM test_metrics a, test_metrics b", proto::Consistency::TableDefault).await;
+    assert!(read.served_locally);
+    // tables that can't be told for certain are read strongly
+    let read = execute("SELECT * FROM test_metrics, pragma_table_info('test_metrics')", proto::Consistency::TableDefault).await;
+    assert!(!read.served_locally);
+    let read = execute("SELECT COUNT(*) FROM test_metrics", proto::Consistency::Strong).await;
+    assert!(!read.served_locally);
+    let read = execute("SELECT value FROM test_config", proto::Consistency::RelaxedReads).await;
+    assert!(read.served_locally);
+
+    query(1, String::from("DROP TABLE test_config")).await.unwrap();
+    query(1, String::from("DROP TABLE test_metrics")).await.unwrap();
+    drop(client);
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn slow_applies_grow_apply_lag() {
+    let replicas = setup_replicas(2).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_apply_lag (n integer)")).await.unwrap();
+
+    // writes that take a while to apply, submitted at once so that several
+    // are decided while the first ones are still being applied
+    let done = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let writes: Vec<_> = (0..10)
+        .map(|_| {
+            let done = done.clone();
+            tokio::task::spawn(async move {
+                let sql = "INSERT INTO test_apply_lag SELECT COUNT(*) FROM \
+                           (WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c LIMIT 500000) SELECT x FROM c)";
+                query(1, String::from(sql)).await.unwrap();
+                done.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            })
+        })
+        .collect();
+    let mut max_lag = 0;
+    while done.load(std::sync::atomic::Ordering::SeqCst) < writes.len() {
+        for replica in replicas.iter() {
+            max_lag = max_lag.max(replica.store_server.get_apply_lag());
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+    }
+    for write in writes {
+        write.await.unwrap();
+    }
+    assert!(max_lag >= 2, "{}", max_lag);
+
+    // once caught up, the gap closes, as Status reports
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    for replica in replicas.iter() {
+        while replica.store_server.get_apply_lag() > 0 {
+            assert!(std::time::Instant::now() < deadline);
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+    }
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    let status = client.status(tonic::Request::new(Void {})).await.unwrap().into_inner();
+    assert_eq!(status.apply_lag, 0);
+    assert_eq!(status.applied_idx, status.decided_idx);
+
+    query(1, String::from("DROP TABLE test_apply_lag")).await.unwrap();
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn large_log_triggers_snapshot() {
+    let mut config = StoreServerConfig::default();
+    config.snapshot_log_bytes = 16 * 1024;
+    config.snapshot_min_interval = std::time::Duration::from_millis(0);
+    let replicas = setup_replicas_with_config(2, config).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_log_bytes (id integer PRIMARY KEY, data text)")).await.unwrap();
+
+    // few entries, but large ones
+    let leader = replicas.iter().find(|r| r.is_leader()).unwrap();
+    assert_eq!(leader.store_server.metrics().counter("snapshots").get(), 0);
+    for i in 0..10 {
+        query(1, format!("INSERT OR REPLACE INTO test_log_bytes VALUES({}, '{}')", i, "x".repeat(4096))).await.unwrap();
+    }
+
+    let start = std::time::Instant::now();
+    while leader.store_server.get_compacted_idx() == 0 {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "log was never trimmed");
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    assert!(leader.store_server.metrics().counter("snapshots").get() >= 1);
+    assert!(leader.store_server.metrics().gauge("log_bytes").get() < 16 * 1024);
+
+    query(1, String::from("DROP TABLE test_log_bytes")).await.unwrap();
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn secondary_cluster_converges_with_primary() {
+    let mut config = StoreServerConfig::default();
+    config.admin = true;
+    let primary = setup_replicas_with_config(2, config).await;
+    let secondary = vec![
+        start_replica_with_config(4, vec![5], StoreServerConfig::default()).await,
+        start_replica_with_config(5, vec![4], StoreServerConfig::default()).await,
+    ];
+    // both clusters start from the same schema
+    for id in [1, 4] {
+        query(id, String::from("CREATE TABLE IF NOT EXISTS test_replication (id integer PRIMARY KEY, value text)")).await.unwrap();
+    }
+
+    let replication = tokio::task::spawn(chiselstore::replication::replicate(
+        secondary[0].store_server.clone(),
+        chiselstore::ReplicationConfig::new(node_rpc_addr(1)),
+    ));
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    for i in 0..20 {
+        query(1, format!("INSERT INTO test_replication VALUES({}, 'v{}')", i, i)).await.unwrap();
+    }
+    // writes are executed again in the primary's order
+    query(1, String::from("UPDATE test_replication SET value = 'last' WHERE id = 0")).await.unwrap();
+
+    let start = std::time::Instant::now();
+    while query(5, String::from("SELECT COUNT(*) FROM test_replication")).await.unwrap() != "20" {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "secondary never converged");
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(query(5, String::from("SELECT value FROM test_replication WHERE id = 0")).await.unwrap(), "last");
+    assert_eq!(
+        query(1, String::from("SELECT group_concat(value) FROM test_replication")).await.unwrap(),
+        query(4, String::from("SELECT group_concat(value) FROM test_replication")).await.unwrap(),
+    );
+    assert_eq!(secondary[0].store_server.metrics().counter("replicated_writes").get(), 21);
+    assert_eq!(secondary[0].store_server.metrics().gauge("replicated_configuration").get(), 1);
+
+    replication.abort();
+    for id in [1, 4] {
+        query(id, String::from("DROP TABLE test_replication")).await.unwrap();
+    }
+    shutdown_replicas(secondary).await;
+    shutdown_replicas(primary).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sql_trace_log_shows_bound_values_as_configured() {
+    let trace_log = |id: u64| format!("node{}.sql.trace.log", id);
+    let mut replicas = Vec::new();
+    for (id, peers) in [(1, vec![2]), (2, vec![1])] {
+        let _ = std::fs::remove_file(trace_log(id));
+        let mut config = StoreServerConfig::default();
+        config.sql_trace_log = Some(std::path::PathBuf::from(trace_log(id)));
+        // node 2 redacts the values it traces
+        config.audit_redact_sql = id == 2;
+        replicas.push(start_replica_with_config(id, peers, config).await);
+    }
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_sql_trace (id integer PRIMARY KEY, name text)")).await.unwrap();
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    client.execute(tonic::Request::new(Query {
+        sql: String::from("INSERT INTO test_sql_trace VALUES(?, ?)"),
+        params: vec![
+            Value { value: Some(value::Value::Integer(42)) },
+            Value { value: Some(value::Value::Text(String::from("O'Brien"))) },
+        ],
+        ..Default::default()
+    })).await.unwrap();
+    // numbered and named placeholders, and ones in comments, which aren't
+    client.execute(tonic::Request::new(Query {
+        sql: String::from("INSERT INTO test_sql_trace /* :skipped */ VALUES(?1, $name) -- ?"),
+        params: vec![
+            Value { value: Some(value::Value::Integer(43)) },
+            Value { value: Some(value::Value::Real(f64::INFINITY)) },
+        ],
+        ..Default::default()
+    })).await.unwrap();
+    query(1, String::from("DROP TABLE test_sql_trace")).await.unwrap();
+
+    // the trace log is written in the background
+    let start = std::time::Instant::now();
+    let traced_drop = |id: u64| std::fs::read_to_string(trace_log(id)).unwrap_or_default().contains("DROP TABLE test_sql_trace");
+    while !traced_drop(1) || !traced_drop(2) {
+        assert!(start.elapsed() < std::time::Duration::from_secs(5), "commands were not traced");
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+
+    let traced = std::fs::read_to_string(trace_log(1)).unwrap();
+    assert!(traced.contains("\"level\":\"trace\""));
+    assert!(traced.contains("\"sql\":\"INSERT INTO test_sql_trace /* :skipped */ VALUES(43, 9e999) -- ?\""));
+    assert!(traced.contains("\"sql\":\"INSERT INTO test_sql_trace VALUES(42, 'O''Brien')\""));
+    let redacted = std::fs::read_to_string(trace_log(2)).unwrap();
+    assert!(redacted.contains("\"sql\":\"INSERT INTO test_sql_trace VALUES(?, ?)\""));
+    assert!(!redacted.contains("Brien"));
+    assert!(!redacted.contains("9e999"));
+
+    shutdown_replicas(replicas).await;
+    for id in [1, 2] {
+        let _ = std::fs::remove_file(trace_log(id));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn messages_route_to_updated_node_address() {
+    use chiselstore::metrics::peer_metric;
+
+    let mut config = StoreServerConfig::default();
+    config.admin = true;
+    let replicas = setup_replicas_with_config(3, config).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_node_address (id integer PRIMARY KEY)")).await.unwrap();
+
+    // node 3 "moves" to where nothing listens; every member routes there
+    let moved = node_rpc_addr(9);
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    client.update_node_address(tonic::Request::new(proto::UpdateNodeAddressReq {
+        node_id: 3,
+        addr: moved.clone(),
+        local: false,
+    })).await.unwrap();
+    query(1, String::from("INSERT INTO test_node_address VALUES(1)")).await.unwrap();
+    for replica in replicas.iter().filter(|r| r.store_server.get_id() != 3) {
+        let transport = replica.store_server.transport();
+        let start = std::time::Instant::now();
+        while transport.metrics().counter(&peer_metric("unreachable_dropped", &moved)).get() == 0 {
+            assert!(start.elapsed() < std::time::Duration::from_secs(10), "messages were not routed to the new address");
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(transport.metrics().counter("node_address_updates").get(), 1);
+    }
+
+    // and back to where node 3 actually is, which catches up
+    client.update_node_address(tonic::Request::new(proto::UpdateNodeAddressReq {
+        node_id: 3,
+        addr: node_rpc_addr(3),
+        local: false,
+    })).await.unwrap();
+    query(1, String::from("INSERT INTO test_node_address VALUES(2)")).await.unwrap();
+    let start = std::time::Instant::now();
+    while replicas[2].store_server.get_applied_idx() < replicas[0].store_server.get_decided_idx() {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "node 3 never caught up");
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+
+    // the routing is recorded, to survive restarts
+    for id in [1, 2] {
+        let recorded = std::fs::read_to_string(format!("node{}.addrs", id)).unwrap();
+        assert_eq!(recorded, format!("3 {}\n", node_rpc_addr(3)));
+    }
+
+    query(1, String::from("DROP TABLE test_node_address")).await.unwrap();
+    shutdown_replicas(replicas).await;
+    for id in [1, 2, 3] {
+        let _ = std::fs::remove_file(format!("node{}.addrs", id));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn saturated_query_cap_records_wait_time() {
+    let mut config = StoreServerConfig::default();
+    config.max_concurrent_queries = 1;
+    let replicas = setup_replicas_with_config(2, config).await;
+    let server = replicas[0].store_server.clone();
+
+    let slow = "SELECT COUNT(*) FROM (WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 1000000) SELECT x FROM c)";
+    let queries: Vec<_> = (0..4)
+        .map(|_| {
+            let server = server.clone();
+            tokio::task::spawn(async move {
+                let options = chiselstore::QueryOptions {
+                    consistency: chiselstore::Consistency::RelaxedReads,
+                    ..Default::default()
+                };
+                server.query_with_options(slow, options).await.unwrap();
+            })
+        })
+        .collect();
+    for query in queries {
+        query.await.unwrap();
+    }
+
+    let metrics = server.metrics();
+    let (wait, execution) = (metrics.histogram("query_wait"), metrics.histogram("query_execution"));
+    assert_eq!(wait.count(), 4);
+    assert_eq!(execution.count(), 4);
+    // all but the first query waited for the one before
+    assert!(wait.percentile(90.0) >= execution.percentile(10.0) / 2);
+    assert!(wait.mean() > std::time::Duration::from_millis(0));
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn maintenance_mode_rejects_writes_cluster_wide() {
+    let mut config = StoreServerConfig::default();
+    config.admin = true;
+    let replicas = setup_replicas_with_config(3, config).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_maintenance (id integer PRIMARY KEY)")).await.unwrap();
+    query(1, String::from("INSERT INTO test_maintenance VALUES(1)")).await.unwrap();
+
+    let mut admin = RpcClient::connect(node_rpc_addr(2)).await.unwrap();
+    admin.set_maintenance_mode(tonic::Request::new(proto::MaintenanceModeReq { enabled: true })).await.unwrap();
+    for id in 1..=3 {
+        let mut client = RpcClient::connect(node_rpc_addr(id)).await.unwrap();
+        let write = client.execute(tonic::Request::new(Query {
+            sql: format!("INSERT INTO test_maintenance VALUES({})", id + 1),
+            ..Default::default()
+        })).await;
+        assert_eq!(write.unwrap_err().code(), tonic::Code::FailedPrecondition);
+        assert_eq!(query(id, String::from("SELECT COUNT(*) FROM test_maintenance")).await.unwrap(), "1");
+        let status = client.status(tonic::Request::new(Void {})).await.unwrap().into_inner();
+        assert!(status.maintenance);
+    }
+
+    // clients can neither exit maintenance mode nor slip writes past it
+    for sql in [
+        "PRAGMA application_id = 0",
+        "PRAGMA application_id = 0; DELETE FROM test_maintenance",
+        "/* exit */ PRAGMA main.application_id = 0",
+    ] {
+        let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+        let write = client.execute(tonic::Request::new(Query {
+            sql: String::from(sql),
+            ..Default::default()
+        })).await;
+        assert_eq!(write.unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+    assert_eq!(query(1, String::from("SELECT COUNT(*) FROM test_maintenance")).await.unwrap(), "1");
+    assert!(replicas[0].store_server.in_maintenance_mode().unwrap());
+
+    admin.set_maintenance_mode(tonic::Request::new(proto::MaintenanceModeReq { enabled: false })).await.unwrap();
+    query(3, String::from("INSERT INTO test_maintenance VALUES(5)")).await.unwrap();
+    assert_eq!(query(1, String::from("SELECT COUNT(*) FROM test_maintenance")).await.unwrap(), "2");
+
+    query(1, String::from("DROP TABLE test_maintenance")).await.unwrap();
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn large_proposal_forward_splits_into_ordered_batches() {
+    use chiselstore::{StoreCommand, StoreTransport};
+    use omnipaxos_core::messages::{Message, PaxosMsg};
+
+    let mut transport_config = RpcTransportConfig::default();
+    transport_config.max_forward_batch = Some(3);
+    let mut replicas = Vec::new();
+    for id in 1..=3 {
+        let peers = (1..=3).filter(|&peer| peer != id).collect();
+        replicas.push(start_replica_with_transport_config(id, peers, StoreServerConfig::default(), transport_config.clone()).await);
+    }
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_forward_batches (i integer, seq integer)")).await.unwrap();
+
+    // as if the follower queued many proposals before it knew the leader
+    let leader = replicas.iter().find(|r| r.is_leader()).unwrap().store_server.get_id();
+    let follower = replicas.iter().find(|r| !r.is_leader()).unwrap();
+    let metrics = follower.store_server.transport().metrics();
+    let (forwarded, batches) = (metrics.counter("proposals_forwarded").get(), metrics.counter("proposal_forward_batches").get());
+    let cmds = (0..20)
+        .map(|i| StoreCommand {
+            id: 1_000_000 + i,
+            sql: format!("INSERT INTO test_forward_batches VALUES({}, (SELECT COUNT(*) FROM test_forward_batches))", i),
+            params: Vec::new(),
+            batch: Vec::new(),
+            schema_version: 0,
+            request_id: 0,
+            client: String::new(),
+            origin: follower.store_server.get_id(),
+            statements: Vec::new(),
+            kind: CommandKind::Sql,
+        })
+        .collect();
+    let msg = Message {
+        from: follower.store_server.get_id(),
+        to: leader,
+        msg: PaxosMsg::ProposalForward(cmds),
+    };
+    follower.store_server.transport().send_sp(leader, 1, msg).unwrap();
+    assert_eq!(metrics.counter("proposals_forwarded").get() - forwarded, 20);
+    assert_eq!(metrics.counter("proposal_forward_batches").get() - batches, 7);
+
+    // every proposal arrived, in order
+    let start = std::time::Instant::now();
+    while query(leader, String::from("SELECT COUNT(*) FROM test_forward_batches")).await.unwrap() != "20" {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "forwarded proposals never committed");
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(query(leader, String::from("SELECT COUNT(*) FROM test_forward_batches WHERE i = seq")).await.unwrap(), "20");
+
+    query(1, String::from("DROP TABLE test_forward_batches")).await.unwrap();
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proposals_forwarded_to_unreachable_leader_fail() {
+    use chiselstore::metrics::peer_metric;
+    use chiselstore::{StoreCommand, StoreTransport};
+    use omnipaxos_core::messages::{Message, PaxosMsg};
+
+    // a port nothing listens on, so every connection attempt is refused
+    let addr = {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    };
+    let transport = {
+        let addr = addr.clone();
+        RpcTransport::with_config(Box::new(move |_| addr.clone()), RpcTransportConfig::default())
+    };
+    let cmd = |id| StoreCommand {
+        id,
+        sql: String::from("INSERT INTO test_unreachable_forward VALUES(1)"),
+        params: Vec::new(),
+        batch: Vec::new(),
+        schema_version: 0,
+        request_id: 0,
+        client: String::new(),
+        origin: 1,
+        statements: Vec::new(),
+        kind: CommandKind::Sql,
+    };
+    let msg = Message {
+        from: 1,
+        to: 2,
+        msg: PaxosMsg::ProposalForward(vec![cmd(7), cmd(8)]),
+    };
+    transport.send_sp(2, 1, msg).unwrap();
+
+    // the proposals are reported lost, for their commands to fail
+    let start = std::time::Instant::now();
+    let mut dropped = Vec::new();
+    while dropped.len() < 2 {
+        assert!(start.elapsed() < std::time::Duration::from_secs(5), "the lost proposals were never reported");
+        dropped.extend(transport.take_dropped_proposals());
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(dropped, vec![(2, 7), (2, 8)]);
+    assert_eq!(transport.metrics().counter(&peer_metric("unreachable_dropped", &addr)).get(), 1);
+    assert_eq!(transport.metrics().counter("proposals_dropped").get(), 1);
+    // forwarded proposals are not part of the log, so the peer is not lost
+    assert!(transport.take_lost_peers().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn node_address_updates_require_admin_role() {
+    use chiselstore::rpc::AUTHORIZATION_METADATA;
+    use chiselstore::{Principal, Role};
+
+    let mut config = StoreServerConfig::default();
+    config.admin = true;
+    config.auth_tokens.insert(String::from("writer-token"), Principal::new("writer", Role::ReadWrite));
+    config.auth_tokens.insert(String::from("admin-token"), Principal::new("admin", Role::Admin));
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    let request = |token: &str| {
+        let mut request = tonic::Request::new(proto::UpdateNodeAddressReq {
+            node_id: 2,
+            addr: node_rpc_addr(2),
+            local: false,
+        });
+        request.metadata_mut().insert(AUTHORIZATION_METADATA, format!("Bearer {}", token).parse().unwrap());
+        request
+    };
+
+    let err = client.update_node_address(request("writer-token")).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    assert!(std::fs::metadata("node1.addrs").is_err());
+
+    client.update_node_address(request("admin-token")).await.unwrap();
+    assert_eq!(std::fs::read_to_string("node1.addrs").unwrap(), format!("2 {}\n", node_rpc_addr(2)));
+
+    shutdown_replicas(replicas).await;
+    let _ = std::fs::remove_file("node1.addrs");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn client_rpcs_require_authentication() {
+    use chiselstore::rpc::AUTHORIZATION_METADATA;
+    use chiselstore::{Principal, Role};
+    use proto::DumpLogReq;
+
+    let mut config = StoreServerConfig::default();
+    config.admin = true;
+    config.auth_tokens.insert(String::from("reader-token"), Principal::new("reader", Role::ReadOnly));
+    config.auth_tokens.insert(String::from("writer-token"), Principal::new("writer", Role::ReadWrite));
+    config.auth_tokens.insert(String::from("admin-token"), Principal::new("admin", Role::Admin));
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    fn request<T>(msg: T, token: Option<&str>) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(msg);
+        if let Some(token) = token {
+            request.metadata_mut().insert(AUTHORIZATION_METADATA, format!("Bearer {}", token).parse().unwrap());
+        }
+        request
+    }
+
+    // a missing token fails authentication, not authorization
+    let err = client.status(request(Void {}, None)).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::Unauthenticated);
+    let err = client.epochs(request(Void {}, None)).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::Unauthenticated);
+    let err = client.release_read_snapshot(request(proto::ReadSnapshot { id: 1 }, None)).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::Unauthenticated);
+    client.status(request(Void {}, Some("reader-token"))).await.unwrap();
+    client.epochs(request(Void {}, Some("reader-token"))).await.unwrap();
+
+    // no-ops are writes
+    let err = client.no_op(request(Void {}, Some("reader-token"))).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    client.no_op(request(Void {}, Some("writer-token"))).await.unwrap();
+
+    // the log, apply errors and membership are for admins
+    let err = client.dump_log(request(DumpLogReq { from: 0, to: 1 }, Some("writer-token"))).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    let err = client.apply_errors(request(Void {}, Some("writer-token"))).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    let err = client.leave_cluster(request(Void {}, Some("writer-token"))).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    client.dump_log(request(DumpLogReq { from: 0, to: 1 }, Some("admin-token"))).await.unwrap();
+    client.apply_errors(request(Void {}, Some("admin-token"))).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn change_feed_requires_admin_role() {
+    use chiselstore::rpc::AUTHORIZATION_METADATA;
+    use chiselstore::{Principal, Role};
+
+    let mut config = StoreServerConfig::default();
+    config.admin = true;
+    config.auth_tokens.insert(String::from("writer-token"), Principal::new("writer", Role::ReadWrite));
+    config.auth_tokens.insert(String::from("admin-token"), Principal::new("admin", Role::Admin));
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    let request = |token: Option<&str>| {
+        let mut request = tonic::Request::new(Void {});
+        if let Some(token) = token {
+            request.metadata_mut().insert(AUTHORIZATION_METADATA, format!("Bearer {}", token).parse().unwrap());
+        }
+        request
+    };
+
+    // the feed carries every write's statements and parameters
+    let err = client.subscribe_changes(request(None)).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::Unauthenticated);
+    let err = client.subscribe_changes(request(Some("writer-token"))).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    client.subscribe_changes(request(Some("admin-token"))).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn table_checksum_handles_nulls() {
+    let mut config = StoreServerConfig::default();
+    config.admin = true;
+    let replicas = setup_replicas_with_config(2, config).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_checksum_nulls (id integer PRIMARY KEY, value text)")).await.unwrap();
+    query(1, String::from("INSERT INTO test_checksum_nulls VALUES(1, NULL)")).await.unwrap();
+
+    let with_null = replicas[0].store_server.table_checksum("test_checksum_nulls").unwrap();
+    assert_eq!(with_null.rows, 1);
+    // the text 'NULL' is not the same row
+    query(1, String::from("UPDATE test_checksum_nulls SET value = 'NULL'")).await.unwrap();
+    let with_text = replicas[0].store_server.table_checksum("test_checksum_nulls").unwrap();
+    assert_ne!(with_null.checksum, with_text.checksum);
+
+    query(1, String::from("DROP TABLE test_checksum_nulls")).await.unwrap();
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn audit_log_identifies_authenticated_principal() {
+    use chiselstore::rpc::{AUTHORIZATION_METADATA, CLIENT_METADATA};
+    use chiselstore::{Principal, Role};
+
+    let audit_log = "node1.audit.log";
+    let _ = std::fs::remove_file(audit_log);
+    let mut config = StoreServerConfig::default();
+    config.auth_tokens.insert(String::from("writer-token"), Principal::new("writer", Role::ReadWrite));
+    let mut audited_config = config.clone();
+    audited_config.audit_log = Some(std::path::PathBuf::from(audit_log));
+    let replicas = vec![
+        start_replica_with_config(1, vec![2], audited_config).await,
+        start_replica_with_config(2, vec![1], config).await,
+    ];
+
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    for sql in ["CREATE TABLE IF NOT EXISTS test_audit_principal (id integer)", "DROP TABLE test_audit_principal"] {
+        let mut request = tonic::Request::new(Query {
+            sql: String::from(sql),
+            ..Default::default()
+        });
+        request.metadata_mut().insert(AUTHORIZATION_METADATA, "Bearer writer-token".parse().unwrap());
+        // the claimed identity is not trusted
+        request.metadata_mut().insert(CLIENT_METADATA, "admin".parse().unwrap());
+        client.execute(request).await.unwrap();
+    }
+
+    let audited = std::fs::read_to_string(audit_log).unwrap();
+    assert_eq!(audited.lines().count(), 2);
+    assert!(audited.lines().all(|line| line.contains("\"client\":\"writer\"")));
+
+    shutdown_replicas(replicas).await;
+    let _ = std::fs::remove_file(audit_log);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn read_snapshots_belong_to_their_client() {
+    use chiselstore::rpc::AUTHORIZATION_METADATA;
+    use chiselstore::{Principal, Role};
+
+    let mut config = StoreServerConfig::default();
+    config.auth_tokens.insert(String::from("alice-token"), Principal::new("alice", Role::ReadOnly));
+    config.auth_tokens.insert(String::from("bob-token"), Principal::new("bob", Role::ReadOnly));
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    fn request<T>(msg: T, token: &str) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(msg);
+        request.metadata_mut().insert(AUTHORIZATION_METADATA, format!("Bearer {}", token).parse().unwrap());
+        request
+    }
+    let id = client.create_read_snapshot(request(Void {}, "alice-token")).await.unwrap().into_inner().id;
+
+    // another client can neither read nor release it
+    let snapshot_query = |token| request(Query { sql: String::from("SELECT 1"), read_snapshot: id, ..Default::default() }, token);
+    let err = client.execute(snapshot_query("bob-token")).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    let err = client.release_read_snapshot(request(proto::ReadSnapshot { id }, "bob-token")).await.unwrap_err();
+    assert_eq!(err.code(), tonic::Code::PermissionDenied);
+
+    client.execute(snapshot_query("alice-token")).await.unwrap();
+    client.release_read_snapshot(request(proto::ReadSnapshot { id }, "alice-token")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dedup_window_remembers_applied_results_per_client() {
+    use chiselstore::rpc::CLIENT_METADATA;
+
+    let replicas = setup_replicas(2).await;
+
+    async fn execute(sql: &str, client: &str, request_id: u64) -> Result<proto::QueryResults, tonic::Status> {
+        let mut rpc = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+        let mut request = tonic::Request::new(Query {
+            sql: String::from(sql),
+            request_id,
+            ..Default::default()
+        });
+        request.metadata_mut().insert(CLIENT_METADATA, client.parse().unwrap());
+        rpc.execute(request).await.map(|response| response.into_inner())
+    }
+
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_dedup_results (id integer PRIMARY KEY, n integer)")).await.unwrap();
+    query(1, String::from("DELETE FROM test_dedup_results")).await.unwrap();
+    query(1, String::from("INSERT INTO test_dedup_results VALUES(1, 0)")).await.unwrap();
+
+    // a failed request is not remembered, so its retry is applied
+    assert!(execute("INSERT INTO test_dedup_results VALUES(1, 0)", "alice", 1).await.is_err());
+    query(1, String::from("DELETE FROM test_dedup_results WHERE id = 1")).await.unwrap();
+    let retry = execute("INSERT INTO test_dedup_results VALUES(1, 0)", "alice", 1).await.unwrap();
+    assert!(retry.warnings.is_empty());
+
+    // a retry gets the rows the request was applied with
+    let increment = "UPDATE test_dedup_results SET n = n + 1 WHERE id = 1; SELECT n FROM test_dedup_results WHERE id = 1";
+    let applied = execute(increment, "alice", 2).await.unwrap();
+    assert_eq!(applied.rows[0].values[0], "1");
+    let retry = execute(increment, "alice", 2).await.unwrap();
+    assert!(!retry.warnings.is_empty());
+    assert_eq!(retry.rows, applied.rows);
+
+    // request ids are scoped by client
+    let other = execute(increment, "bob", 2).await.unwrap();
+    assert!(other.warnings.is_empty());
+    assert_eq!(other.rows[0].values[0], "2");
+
+    // every node keeps the same window in its database
+    let decided_idx = replicas[0].store_server.get_decided_idx();
+    while replicas.iter().any(|replica| replica.store_server.get_applied_idx() < decided_idx) {
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    for replica in replicas.iter() {
+        assert_eq!(replica.store_server.metrics().gauge("dedup_requests").get(), 3);
+    }
+
+    query(1, String::from("DROP TABLE test_dedup_results")).await.unwrap();
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn client_writes_carry_request_ids() {
+    use chiselstore::client::Client;
+
+    let replicas = setup_replicas(2).await;
+    let leader = replicas.iter().find(|r| r.is_leader()).unwrap();
+    let mut client = Client::connect(node_rpc_addr(leader.get_id())).await.unwrap().with_retries(Default::default());
+
+    // every write has a request id of its own, kept across retries
+    client.execute("CREATE TABLE IF NOT EXISTS test_client_request_ids (n integer)", Vec::new()).await.unwrap();
+    for _ in 0..3 {
+        client.execute("INSERT INTO test_client_request_ids VALUES(1)", Vec::new()).await.unwrap();
+    }
+    assert_eq!(leader.store_server.metrics().gauge("dedup_requests").get(), 4);
+    client.execute("DROP TABLE test_client_request_ids", Vec::new()).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn read_your_writes_is_scoped_by_configuration() {
+    let mut replicas = setup_replicas(3).await;
+
+    async fn execute(replica_id: u64, query: Query) -> proto::QueryResults {
+        let mut client = RpcClient::connect(node_rpc_addr(replica_id)).await.unwrap();
+        client.execute(tonic::Request::new(query)).await.unwrap().into_inner()
+    }
+
+    let leader_id = replicas.iter().find(|r| r.is_leader()).unwrap().get_id();
+    let before = execute(leader_id, Query {
+        sql: String::from("CREATE TABLE IF NOT EXISTS test_configuration_index (id integer PRIMARY KEY)"),
+        ..Default::default()
+    }).await;
+    assert_eq!(before.configuration_id, 1);
+
+    // move to a new configuration, whose log starts over
+    let leader_idx = replicas.iter().position(|r| r.is_leader()).unwrap();
+    let leader = replicas.remove(leader_idx);
+    leader.shutdown().await;
+    tokio::time::sleep(tokio::time::Duration::from_millis(5000)).await; // wait for leader election
+    let new_leader_idx = replicas.iter().position(|r| r.is_leader()).unwrap_or(0);
+    let new_cluster: Vec<u64> = replicas.iter().map(|r| r.get_id()).collect();
+    replicas[new_leader_idx].reconfigure(new_cluster);
+    tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await; // wait for reconfiguration
+
+    // an index of the earlier configuration is applied already, however large
+    let living_replica_id = replicas[new_leader_idx].get_id();
+    let read = tokio::time::timeout(tokio::time::Duration::from_secs(1), execute(living_replica_id, Query {
+        sql: String::from("SELECT COUNT(*) FROM test_configuration_index"),
+        min_index: before.log_idx + 1_000_000,
+        min_index_configuration: before.configuration_id,
+        ..Default::default()
+    })).await.unwrap();
+    assert_eq!(read.rows[0].values[0], "0");
+
+    let after = execute(living_replica_id, Query {
+        sql: String::from("DROP TABLE test_configuration_index"),
+        ..Default::default()
+    }).await;
+    assert!(after.configuration_id > before.configuration_id);
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn write_not_acknowledged_in_time_still_succeeds() {
+    let mut config = StoreServerConfig::default();
+    config.commit_timeout = Some(std::time::Duration::from_secs(1));
+    let mut replicas = setup_replicas_with_config(3, config).await;
+    query(1, String::from("CREATE TABLE IF NOT EXISTS test_unacknowledged_replicas (id integer PRIMARY KEY)")).await.unwrap();
+    let leader = replicas[0].get_current_leader();
+    let follower_idx = replicas.iter().position(|r| r.get_id() != leader).unwrap();
+    replicas[follower_idx].disconnect().await;
+
+    // the write is committed by a quorum, so it succeeds with a warning
+    let mut client = RpcClient::connect(node_rpc_addr(leader)).await.unwrap();
+    let results = client
+        .execute(tonic::Request::new(Query {
+            sql: String::from("INSERT INTO test_unacknowledged_replicas VALUES(1)"),
+            min_replicas: 3,
+            ..Default::default()
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(results.acknowledged_by, 2);
+    assert!(results.warnings.iter().any(|warning| warning.contains("accepted by 2 of the 3")));
+    assert_eq!(query(leader, String::from("SELECT COUNT(*) FROM test_unacknowledged_replicas")).await.unwrap(), "1");
+
+    replicas[follower_idx].reconnect();
+    query(1, String::from("DROP TABLE test_unacknowledged_replicas")).await.unwrap();
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn journal_mode_is_configurable() {
+    use chiselstore::JournalMode;
+
+    let mut config = StoreServerConfig::default();
+    config.journal_mode = JournalMode::Delete;
+    let replicas = setup_replicas_with_config(2, config).await;
+
+    for replica in replicas.iter() {
+        assert_eq!(query(replica.get_id(), String::from("PRAGMA journal_mode")).await.unwrap(), "delete");
+    }
+
+    shutdown_replicas(replicas).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn partial_sync_transfers_of_earlier_rounds_are_discarded() {
+    let replicas = setup_replicas(2).await;
+    let mut client = RpcClient::connect(node_rpc_addr(1)).await.unwrap();
+    let chunk = |from: u64, n: u32, chunk: u32| proto::AcceptSyncReq {
+        from,
+        to: 1,
+        n: Some(proto::Ballot { n, priority: 0, pid: from }),
+        chunk,
+        chunks: 3,
+        ..Default::default()
+    };
+
+    // node 2 starts a transfer, then node 3 starts one in a later round
+    client.accept_sync(chunk(2, 100, 0)).await.unwrap();
+    client.accept_sync(chunk(3, 101, 0)).await.unwrap();
+    let metrics = replicas[0].store_server.transport().metrics();
+    assert_eq!(metrics.counter("sync_transfers_expired").get(), 1);
+
+    // the rest of node 2's transfer is rejected, so that it starts over
+    let status = client.accept_sync(chunk(2, 100, 1)).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Aborted);
+
+    shutdown_replicas(replicas).await;
+}